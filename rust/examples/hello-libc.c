@@ -0,0 +1,55 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+#include <sys/utsname.h>
+
+/* What the startup code of glibc and musl (static) sets up, plus stdio on a
+ * file and the heap. Built with a riscv64-linux-gnu and a riscv64-linux-musl
+ * toolchain, see the Makefile. */
+int main(int argc, const char *argv[]) {
+	printf("Hello, World! (argc=%d)\n", argc);
+
+	struct utsname uts;
+	if (uname(&uts) != 0)
+		return 1;
+	printf("machine: %s\n", uts.machine);
+
+	char exe[4096];
+	ssize_t len = readlink("/proc/self/exe", exe, sizeof(exe) - 1);
+	if (len < 0)
+		return 2;
+	exe[len] = '\0';
+	printf("exe: %s\n", strrchr(exe, '/') + 1);
+
+	/* File I/O: Write a temporary file, read it back. */
+	char path[] = "/tmp/hello-libc-XXXXXX";
+	int fd = mkstemp(path);
+	FILE *file = fd >= 0 ? fdopen(fd, "w+") : NULL;
+	if (file == NULL)
+		return 3;
+	unlink(path);
+	for (int i = 0; i < 3; i++)
+		fprintf(file, "line %d\n", i);
+	rewind(file);
+	char line[64];
+	int lines = 0;
+	while (fgets(line, sizeof(line), file) != NULL)
+		lines++;
+	fclose(file);
+	printf("read %d lines\n", lines);
+
+	/* malloc: Small blocks from the heap, large ones are mmap()-ed. */
+	size_t total = 0;
+	for (size_t size = 16; size <= (4 << 20); size *= 2) {
+		unsigned char *block = malloc(size);
+		if (block == NULL)
+			return 4;
+		memset(block, 0xaa, size);
+		for (size_t i = 0; i < size; i += 4096)
+			total += block[i] == 0xaa;
+		free(block);
+	}
+	printf("malloc: %zu pages\n", total);
+	return 0;
+}
@@ -0,0 +1,51 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define NBLOCKS 64
+#define ROUNDS 8
+
+/* Allocate blocks of up to 128K (up to 8M per round), check that they do not
+ * overlap and free them again, in varying order so that the heap grows and shrinks. */
+int main(int argc, const char *argv[]) {
+	size_t total = 0;
+	for (int round = 0; round < ROUNDS; round++) {
+		unsigned char *blocks[NBLOCKS];
+		size_t sizes[NBLOCKS];
+		for (int i = 0; i < NBLOCKS; i++) {
+			sizes[i] = ((i * 7919 + round * 104729) % 128 + 1) * 1024;
+			blocks[i] = malloc(sizes[i]);
+			if (blocks[i] == NULL) {
+				printf("malloc(%zu) failed (round=%d, i=%d)\n", sizes[i], round, i);
+				return 1;
+			}
+			memset(blocks[i], i + round, sizes[i]);
+			total += sizes[i];
+		}
+
+		for (int i = 0; i < NBLOCKS; i++) {
+			for (size_t j = 0; j < sizes[i]; j += 512) {
+				if (blocks[i][j] != (unsigned char)(i + round)) {
+					printf("corrupted block %d (round=%d)\n", i, round);
+					return 1;
+				}
+			}
+		}
+
+		for (int i = 0; i < NBLOCKS; i++)
+			free(blocks[(round % 2) ? i : NBLOCKS - 1 - i]);
+	}
+
+	/* Memory from a grown heap must be zeroed. */
+	unsigned *zeroed = calloc(1 << 20, sizeof(unsigned));
+	for (size_t i = 0; zeroed && i < (1 << 20); i++) {
+		if (zeroed[i] != 0) {
+			printf("calloc() memory not zeroed\n");
+			return 1;
+		}
+	}
+	free(zeroed);
+
+	printf("allocated %zu MiB in total\n", total >> 20);
+	return 0;
+}
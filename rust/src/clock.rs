@@ -0,0 +1,77 @@
+/*
+ * Where the guest's time comes from: The host clocks, or a virtual clock that only
+ * depends on the number of retired instructions, so that timings are reproducible
+ * (and do not depend on the JIT or the machine the simulator runs on).
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum ClockSource {
+    /// The host's clocks.
+    Host,
+    /// Derived from the retired-instruction count.
+    Virtual,
+}
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+pub const CLOCK_REALTIME_COARSE: usize = 5;
+pub const CLOCK_TAI: usize = 11;
+pub const TIMER_ABSTIME: usize = 1;
+
+/* CLOCK_REALTIME of the virtual clock starts at 2024-01-01T00:00:00Z. */
+const VIRTUAL_EPOCH_NS: u128 = 1_704_067_200 * 1_000_000_000;
+
+/* Clock ticks per second of times() (sysconf(_SC_CLK_TCK), AT_CLKTCK). */
+pub const CLK_TCK: u64 = 100;
+
+/* Frequency of the `time` CSR (rdtime), the timebase of QEMU's virt machine. */
+pub const TIMEBASE_HZ: u64 = 10_000_000;
+
+#[derive(Debug, Clone)]
+pub struct Clock {
+    pub source: ClockSource,
+    /// Instructions per second of the virtual clock.
+    pub freq_hz: u64,
+    /// Time the guest slept with the virtual clock, nobody actually waits for it.
+    pub slept_ns: u128,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self { source: ClockSource::Host, freq_hz: 1_000_000_000, slept_ns: 0 }
+    }
+}
+
+impl Clock {
+    /* Nanoseconds since the guest started, for the virtual clock. */
+    pub fn virtual_ns(&self, instret: u64) -> u128 {
+        instret as u128 * 1_000_000_000 / self.freq_hz as u128 + self.slept_ns
+    }
+
+    /* Time of `clockid` of the virtual clock, as (seconds, nanoseconds). */
+    pub fn virtual_time(&self, clockid: usize, instret: u64) -> (i64, i64) {
+        let ns = match clockid {
+            CLOCK_REALTIME | CLOCK_REALTIME_COARSE | CLOCK_TAI =>
+                VIRTUAL_EPOCH_NS + self.virtual_ns(instret),
+            _ => self.virtual_ns(instret)
+        };
+        ((ns / 1_000_000_000) as i64, (ns % 1_000_000_000) as i64)
+    }
+
+    /* The `time` CSR: CLOCK_MONOTONIC in TIMEBASE_HZ ticks. */
+    pub fn timebase_ticks(&self, instret: u64) -> u64 {
+        let ns = match self.source {
+            ClockSource::Host => {
+                let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+                unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+                ts.tv_sec as u128 * 1_000_000_000 + ts.tv_nsec as u128
+            },
+            ClockSource::Virtual => self.virtual_ns(instret)
+        };
+        (ns * TIMEBASE_HZ as u128 / 1_000_000_000) as u64
+    }
+
+    pub fn virtual_resolution_ns(&self) -> i64 {
+        std::cmp::max(1, 1_000_000_000 / self.freq_hz) as i64
+    }
+}
@@ -1,167 +1,514 @@
+use std::collections::HashMap;
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
+use crate::clock::Clock;
+use crate::exec;
+use crate::fds::FdTable;
 use crate::insts::*;
+use crate::loader;
+use crate::mem::*;
+use crate::net;
+use crate::signals;
+use crate::strace;
 use crate::syms;
+use crate::sys;
 use crate::tbs::*;
-use syscalls::{syscall, Sysno};
-
-const MAX_ADDR: usize = 1 << 24;
+use crate::threads::{self, Process};
+use crate::vfs;
+
+/* What to do when the guest does a load or store that is not naturally aligned.
+ * The RISC-V spec. allows both emulating and trapping, real hardware often traps. */
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum MisalignedPolicy {
+    /// Do the access (byte-wise), like it always was.
+    Allow,
+    /// Raise a misaligned-address fault, stopping the guest.
+    Trap,
+    /// Do the access, but count misaligned accesses per instruction.
+    Count
+}
 
+pub const CSR_FFLAGS: u16 = 0x001;
+pub const CSR_FRM: u16 = 0x002;
+pub const CSR_FCSR: u16 = 0x003;
+pub const CSR_CYCLE: u16 = 0xc00;
+pub const CSR_TIME: u16 = 0xc01;
+pub const CSR_INSTRET: u16 = 0xc02;
+
+/*
+ * One hart, i.e. one thread of the guest. The other threads (see threads.rs) have CPUs
+ * of their own, sharing the memory, fd table, signal actions, TB cache and the Process.
+ */
 #[repr(C)]
 pub struct CPU {
     pub pc: i64,
     pub regs: [u64; 32],
     pub fregs: [u64; 32],
-    pub memory: Memory,
-    pub remapped_filenos: std::collections::HashMap<usize, usize>,
-    pub debug_syscalls: bool,
-    pub jit_enabled: bool
+    /// fflags and frm. Only kept for the guest to read back, nothing sets the flags.
+    pub fcsr: u32,
+    pub memory: Arc<Memory>,
+    pub fds: FdTable,
+    /// Trace syscalls like strace, off by default.
+    pub strace: Option<strace::Strace>,
+    pub syscalls: sys::SyscallTable,
+    pub jit_enabled: bool,
+    /// Load bias of the executable (non-zero for position-independent executables).
+    pub load_bias: u64,
+    /// Thread pointer of the main thread, TLS symbols are relative to it.
+    pub tls_base: u64,
+    /// Directory with the RISC-V dynamic linker and libraries.
+    pub sysroot: Option<std::path::PathBuf>,
+    /// What the guest may do with sockets.
+    pub network: net::NetworkPolicy,
+    pub misaligned: MisalignedPolicy,
+    /* PC of the load/store instruction -> number of misaligned accesses
+     * (only populated with MisalignedPolicy::Count), of all threads. */
+    pub misaligned_accesses: Arc<Mutex<HashMap<i64, u64>>>,
+    /// Number of retired instructions, drives the virtual clock (of this thread).
+    pub instret: u64,
+    pub clock: Clock,
+    /// Handlers, blocked mask and pending signals of the guest.
+    pub signals: signals::Signals,
+    /// Thread id of the guest thread, the host thread's.
+    pub tid: i32,
+    /// CLONE_CHILD_CLEARTID/set_tid_address(): Zeroed and woken up when the thread exits.
+    pub clear_child_tid: u64,
+    /// set_robust_list(): Only remembered, the list is not walked when the thread exits.
+    pub robust_list: u64,
+    /// rseq(): Address, length and signature of the registered struct rseq.
+    pub rseq: Option<(usize, usize, u32)>,
+    /// Address and loaded value of the last LR, for SC.
+    pub reservation: Option<(usize, u64)>,
+    pub process: Arc<Process>,
+    pub jit: Arc<JIT>,
+    /// CLONE_VFORK: The parent waits until the child closes this, with execve() or exit().
+    pub vfork_done: Option<OwnedFd>,
+    /// What execve() of a host (not RISC-V) binary does.
+    pub host_exec: exec::HostExecPolicy,
+    /// Host path of the guest's executable, what /proc/self/exe points to.
+    pub exe: Option<std::path::PathBuf>,
+    /// The guest's file system with --mount/--mount-tar, the host's one if None.
+    pub vfs: Option<Arc<vfs::Vfs>>,
 }
 
 impl CPU {
+    #[allow(dead_code)]
     pub fn new(jit_enabled: bool) -> Self {
-        CPU {
+        Self::with_layout(jit_enabled, MemoryLayout::default())
+    }
+
+    pub fn with_layout(jit_enabled: bool, layout: MemoryLayout) -> Self {
+        let cpu = CPU {
             pc: 0,
             regs: [0x0; 32],
             fregs: [0xffffffffffffffff; 32],
-            memory: Memory::new(),
-            remapped_filenos: std::collections::HashMap::new(),
-            debug_syscalls: true,
-            jit_enabled
+            fcsr: 0,
+            memory: Arc::new(Memory::new(layout)),
+            fds: FdTable::with_stdio(),
+            strace: None,
+            syscalls: sys::SyscallTable::default(),
+            jit_enabled,
+            load_bias: 0,
+            tls_base: 0,
+            sysroot: None,
+            network: net::NetworkPolicy::Host,
+            misaligned: MisalignedPolicy::Allow,
+            misaligned_accesses: Arc::default(),
+            instret: 0,
+            clock: Clock::default(),
+            signals: signals::Signals::default(),
+            tid: unsafe { libc::gettid() },
+            clear_child_tid: 0,
+            robust_list: 0,
+            rseq: None,
+            reservation: None,
+            process: Arc::default(),
+            jit: Arc::new(JIT::new()),
+            vfork_done: None,
+            host_exec: exec::HostExecPolicy::Host,
+            exe: None,
+            vfs: None,
+        };
+        cpu.process.register(cpu.tid, cpu.signals.queue_handle());
+        cpu
+    }
+
+    /* The CPU of a new thread (clone()) that starts out with this one's registers. */
+    pub fn new_thread(&self) -> CPU {
+        CPU {
+            pc: self.pc,
+            regs: self.regs,
+            fregs: self.fregs,
+            fcsr: self.fcsr,
+            memory: self.memory.clone(),
+            fds: self.fds.share(),
+            strace: self.strace.clone(),
+            syscalls: self.syscalls.clone(),
+            jit_enabled: self.jit_enabled,
+            load_bias: self.load_bias,
+            tls_base: self.tls_base,
+            sysroot: self.sysroot.clone(),
+            network: self.network,
+            misaligned: self.misaligned,
+            misaligned_accesses: self.misaligned_accesses.clone(),
+            instret: self.instret,
+            clock: self.clock.clone(),
+            signals: self.signals.new_thread(),
+            tid: 0,
+            clear_child_tid: 0,
+            robust_list: 0,
+            rseq: None,
+            reservation: None,
+            process: self.process.clone(),
+            jit: self.jit.clone(),
+            vfork_done: None,
+            host_exec: self.host_exec,
+            exe: self.exe.clone(),
+            vfs: self.vfs.clone(),
         }
     }
 
     pub fn load_and_exec(
             &mut self,
             elf_file: &elf::ElfBytes<'_, elf::endian::AnyEndian>,
-            argv: Option<Vec<&str>>) -> Result<(i32, JIT), Error> {
-        self.pc = elf_file.ehdr.e_entry as i64;
-        let (sections, _) = elf_file
-            .section_headers_with_strtab()
-            .map_err(|e| Error::ELF(format!("{}", e)))
-            .map(|(sections, strtab)| (sections.unwrap(), strtab))?;
-
-        let _symbols = syms::SymbolTreeNode::build(&syms::get_symbols(elf_file));
-
-        for section in sections {
-            if section.sh_flags & (elf::abi::SHF_ALLOC as u64) != 0 {
-                let data = elf_file.section_data(&section)
-                    .map_err(|e| Error::ELF(format!("{}", e)))
-                    .and_then(|(data, compression)|
-                        if let Some(c) = compression {
-                            Err(Error::ELF(format!("unsupported compression: {:?}", c)))
-                        } else {
-                            Ok(data)
-                        })?;
-                self.memory.copy_bulk(section.sh_addr, data)
+            argv: Option<Vec<&[u8]>>,
+            envp: Option<Vec<&[u8]>>) -> Result<(i32, Arc<JIT>), Error> {
+        let symbols = self.load(elf_file, argv, envp)?;
+        self.run(symbols.as_deref())
+    }
+
+    /*
+     * execve() of a RISC-V executable: The new image replaces the old one in a fresh
+     * address space. Registers are zeroed, handled signals go back to SIG_DFL, CLOEXEC
+     * fds are closed and TBs of the old image are forgotten, the rest (fds, pending
+     * signals, the mask, ...) stays. Only called once all other threads are gone.
+     */
+    pub fn exec(
+            &mut self,
+            elf_file: &elf::ElfBytes<'_, elf::endian::AnyEndian>,
+            argv: Vec<&[u8]>,
+            envp: Vec<&[u8]>) -> Result<(), Error> {
+        self.memory = Arc::new(Memory::new(self.memory.layout.clone()));
+        self.regs = [0x0; 32];
+        self.fregs = [0xffffffffffffffff; 32];
+        self.fcsr = 0;
+        self.tls_base = 0;
+        self.clear_child_tid = 0;
+        self.robust_list = 0;
+        self.rseq = None;
+        self.reservation = None;
+        self.vfork_done = None;
+        self.jit = Arc::new(JIT::new());
+        self.signals.exec();
+        self.fds.close_on_exec();
+        self.load(elf_file, Some(argv), Some(envp)).map(|_| ())
+    }
+
+    /* Load an executable (and its interpreter) like Linux, returns its symbols. */
+    fn load<'a>(
+            &mut self,
+            elf_file: &elf::ElfBytes<'a, elf::endian::AnyEndian>,
+            argv: Option<Vec<&[u8]>>,
+            envp: Option<Vec<&[u8]>>) -> Result<Option<Box<syms::SymbolTreeNode<'a>>>, Error> {
+        self.memory.layout.validate()?;
+        self.load_bias = loader::load_bias(elf_file, self.memory.layout.pie_base as u64);
+        let image = loader::load_elf(&self.memory, elf_file, self.load_bias)?;
+        self.memory.mm().setup_heap(image.end)?;
+
+        let mut stack_top = self.memory.layout.stack_top();
+        if let Some((tp, top)) = loader::setup_tls(&self.memory, elf_file, stack_top)? {
+            self.set_reg(REG_TP, tp);
+            self.tls_base = tp;
+            stack_top = top;
+        }
+        let symbols = syms::SymbolTreeNode::build(
+            &syms::get_symbols(elf_file, self.load_bias, self.tls_base));
+
+        let interp_base = match loader::interpreter(elf_file)? {
+            Some(interp) => {
+                let interp_image = self.load_interpreter(&interp)?;
+                self.pc = interp_image.entry as i64;
+                interp_image.bias
+            },
+            None => {
+                /* Static-pie: Nobody else would relocate the executable. */
+                if elf_file.ehdr.e_type == elf::abi::ET_DYN {
+                    loader::apply_relocations(&self.memory, elf_file, self.load_bias)?;
+                }
+                self.pc = image.entry as i64;
+                0
+            }
+        };
+
+        let sp = loader::setup_stack(&self.memory, &image, stack_top, interp_base,
+            &argv.unwrap_or_default(), &envp.unwrap_or_default())?;
+        self.set_reg(REG_SP, sp as u64);
+        Ok(symbols)
+    }
+
+    /* Load an ELF file given with --load, position-independent ones are placed at `base`. */
+    pub fn load_elf_image(
+            &mut self,
+            elf_file: &elf::ElfBytes<'_, elf::endian::AnyEndian>,
+            base: Option<u64>) -> Result<loader::Image, Error> {
+        let base = base.unwrap_or(self.memory.layout.pie_base as u64);
+        let bias = loader::load_bias(elf_file, base);
+        let image = loader::load_elf(&self.memory, elf_file, bias)?;
+        if elf_file.ehdr.e_type == elf::abi::ET_DYN {
+            loader::apply_relocations(&self.memory, elf_file, bias)?;
+        }
+        Ok(image)
+    }
+
+    /*
+     * Run images placed into memory by load_elf_image() or loader::load_flat() (e.g. a
+     * bootloader plus an application) from `entry`. The heap starts after the highest
+     * image, symbols (for diagnostics) can come from a separate ELF file.
+     */
+    pub fn exec_images(
+            &mut self,
+            images: &[loader::Image],
+            entry: u64,
+            symbols_elf: Option<&elf::ElfBytes<'_, elf::endian::AnyEndian>>,
+            argv: Option<Vec<&[u8]>>,
+            envp: Option<Vec<&[u8]>>) -> Result<(i32, Arc<JIT>), Error> {
+        self.memory.layout.validate()?;
+        let end = images.iter().map(|image| image.end).max().unwrap_or(0);
+        self.memory.mm().setup_heap(end)?;
+        let symbols = symbols_elf.and_then(|elf_file| syms::SymbolTreeNode::build(
+            &syms::get_symbols(elf_file, 0, 0)));
+
+        /* AT_PHDR and friends describe the first ELF image, if there is any. */
+        let mut image = images.iter().find(|image| image.phnum != 0).cloned()
+            .unwrap_or_default();
+        image.entry = entry;
+        let stack_top = self.memory.layout.stack_top();
+        let sp = loader::setup_stack(&self.memory, &image, stack_top, 0,
+            &argv.unwrap_or_default(), &envp.unwrap_or_default())?;
+        self.set_reg(REG_SP, sp as u64);
+        self.pc = entry as i64;
+        self.run(symbols.as_deref())
+    }
+
+    /*
+     * Run the guest (this being its first thread) until it exits: With exit_group() from
+     * any thread, or once all threads did exit(). Returns the exit status and TB cache.
+     */
+    fn run(&mut self, symbols: Option<&syms::SymbolTreeNode>) -> Result<(i32, Arc<JIT>), Error> {
+        match self.run_thread(symbols) {
+            Some(status) => self.process.exit_thread(self.tid, status),
+            None => self.process.remove(self.tid)
+        }
+        match self.process.wait() {
+            (Ok(status), _) => Ok((status, self.jit.clone())),
+            (Err(e), pc) => {
+                /* Reported with the pc of the thread that ran into it. */
+                self.pc = pc;
+                Err(e)
             }
         }
+    }
 
-        /* TODO: There must be a better way... What address to use as TOS? */
-        let top_of_stack = MAX_ADDR - (MAX_ADDR >> 2);
-        self.set_reg(REG_SP, top_of_stack as u64);
-        if let Some(argv) = argv {
-            /*
-             * Setup argv for the guest.
-             * Layout (argv is not passed like a normal function call argument!):
-             *   - memory[TOS] = argc;
-             *   - memory[TOS + 8] = argv[0];
-             *   - memory[TOS + 16] = argv[1];
-             *   - memory[TOS + 24] = argv[2];
-             *   - ...
-             * The individual strings that make up argv are after argv itself,
-             * so in addresses higher than TOS (stack grows downwards after all).
-             */
-            let argc = argv.len();
-            let argv_size: usize = argv.iter().map(|s| s.len() + 1).sum();
-            self.memory.store_u64(top_of_stack, argc as u64);
-
-            let mut argv_pos = top_of_stack + (2  + argv.len()) * 8 + argv_size;
-            for (i, arg) in argv.iter().enumerate() {
-                self.memory.store_u64(top_of_stack + (1 + i) * 8, argv_pos as u64);
-                for (j, c) in arg.as_bytes().iter().enumerate() {
-                    self.memory.store_u8(argv_pos + j, *c);
-                }
-                argv_pos += arg.as_bytes().len();
-                self.memory.store_u8(argv_pos, b'\0');
-                argv_pos += 1;
+    /* The body of a thread created by clone(), on a host thread of its own. */
+    pub fn run_clone(&mut self) {
+        match self.run_thread(None) {
+            Some(status) => {
+                threads::clear_child_tid(self);
+                self.process.exit_thread(self.tid, status);
+            },
+            None => self.process.remove(self.tid)
+        }
+        if self.tid == unsafe { libc::getpid() } {
+            threads::exit_process(self.process.wait());
+        }
+    }
+
+    /*
+     * Run until this thread exits (returns its exit status), or is stopped: The guest
+     * as a whole exits (self.process has the outcome then), or another thread execve()s.
+     */
+    fn run_thread(&mut self, symbols: Option<&syms::SymbolTreeNode>) -> Option<i32> {
+        let mut jit = self.jit.clone();
+        let mut symbols = symbols;
+        let e = loop {
+            match self.run_tb(&jit, symbols) {
+                Ok(()) => {},
+                /* A new image, with a new TB cache. */
+                Err(Error::Exec) => {
+                    jit = self.jit.clone();
+                    symbols = None;
+                },
+                Err(e) => break e
+            }
+            if self.process.must_stop(self.tid) {
+                return None
             }
+        };
+        match e {
+            Error::ThreadExit(status) => return Some(status),
+            Error::Exit(status) => self.process.exit(self.tid, Ok(status), self.pc),
+            e => self.process.exit(self.tid, Err(e), self.pc)
+        }
+        None
+    }
+
+    /* A fault in the stack guard the guest does not handle itself is most likely fatal,
+     * tell the user where it happened. */
+    fn stack_overflow(&self, e: &Error, symbols: Option<&syms::SymbolTreeNode>) -> Option<String> {
+        let Error::SegFault(addr) = *e else {
+            return None
+        };
+        let handled = self.signals.action(signals::SIGSEGV)
+            .is_ok_and(|action| action.handler != signals::SIG_DFL);
+        if !self.memory.layout.stack_guard().contains(&addr) || handled {
+            return None
         }
+        let func = symbols
+            .and_then(|s| s.lookup(self.pc))
+            .map(|(name, _)| name)
+            .unwrap_or("???");
+        Some(format!("stack overflow in {} (pc={:#08x?}, sp={:#08x?}, addr={:#08x?})",
+                     func, self.pc, self.get_reg(REG_SP), addr))
+    }
 
-        let mut jit = JIT::new();
-        let exitcode = loop {
-            match self.step(&mut jit, None) {
-                Ok(_) => continue,
-                Err(Error::Exit(exitcode)) => break exitcode,
-                Err(e) => return Err(e)
+    /* Execute one TB, faults become signals. Those are delivered at the end. */
+    fn run_tb(&mut self, jit: &JIT, symbols: Option<&syms::SymbolTreeNode>)
+            -> Result<(), Error> {
+        if let Err(e) = self.step(jit, None) {
+            let Some(info) = signals::fault_signal(self, &e) else {
+                return Err(e)
+            };
+            if let Some(msg) = self.stack_overflow(&e, symbols) {
+                eprintln!("[simrv64i] {}", msg);
             }
+            self.signals.force(info);
+        }
+        /* TB boundary: The only place where signals are delivered. */
+        if self.signals.has_deliverable() {
+            signals::deliver(self)?;
+        }
+        Ok(())
+    }
+
+    /* Load the dynamic linker (from the sysroot or the VFS) right after the heap of the
+     * executable. It relocates itself (and the executable), so that is not done here. */
+    fn load_interpreter(&mut self, path: &str) -> Result<loader::Image, Error> {
+        let host_path = self.sysroot_path(path.as_bytes())
+            .unwrap_or_else(|| std::path::PathBuf::from(path));
+        let raw = match &self.vfs {
+            Some(vfs) => vfs.read(&vfs::normalize(&vfs.cwd(), path.as_bytes())).map_err(|e|
+                Error::ELF(format!("cannot read interpreter {:?} (not mounted?): {}", path,
+                    std::io::Error::from_raw_os_error(e.into_raw()))))?,
+            None => std::fs::read(&host_path).map_err(|e| Error::ELF(
+                format!("cannot read interpreter {:?} (use --sysroot?): {}", host_path, e)))?
         };
+        let interp_elf = elf::ElfBytes::<'_, elf::endian::AnyEndian>::minimal_parse(&raw)
+            .map_err(|e| Error::ELF(format!("{:?}: {}", host_path, e)))?;
+        if interp_elf.ehdr.e_type != elf::abi::ET_DYN ||
+           interp_elf.ehdr.e_machine != elf::abi::EM_RISCV {
+            return Err(Error::ELF(format!("{:?}: not a RV64 shared object", host_path)))
+        }
 
-        Ok((exitcode, jit))
+        let base = self.memory.mm().heap.end as u64;
+        let bias = loader::load_bias(&interp_elf, base);
+        loader::load_elf(&self.memory, &interp_elf, bias)
     }
 
-    pub fn step(&mut self, jit: &mut JIT, syms: Option<&syms::SymbolTreeNode>)
+    /*
+     * Absolute guest paths are looked up in the sysroot first (like qemu-user's -L),
+     * so that the dynamic linker finds the RISC-V libraries and not the host ones.
+     */
+    pub fn sysroot_path(&self, path: &[u8]) -> Option<std::path::PathBuf> {
+        let sysroot = self.sysroot.as_ref()?;
+        let path = std::path::Path::new(std::ffi::OsStr::from_bytes(path));
+        let rel = path.strip_prefix("/").ok()?;
+        let host_path = sysroot.join(rel);
+        host_path.symlink_metadata().is_ok().then_some(host_path)
+    }
+
+    pub fn step(&mut self, jit: &JIT, syms: Option<&syms::SymbolTreeNode>)
             -> Result<i64, Error> {
         /* Check if this TB was already executed:
          * TODO: Link TBs together, with successor pointers, so that we don't have
          * to do a lookup into a hashmap so often....
          */
-        if let Some(tb) = jit.tbs.get(&self.pc) {
+        if let Some(tb) = jit.get(self.pc) {
             let count = tb.exec_count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
             if let Some(f) = tb.jit_fn {
                 /* We have a JITed version of this TB! */
-                let pc = f(self.regs.as_mut_ptr(), self.memory.data.as_mut_ptr()) as i64;
+                let pc = f(self.regs.as_mut_ptr(), self.memory.host_ptr()) as i64;
                 self.pc = pc;
+                self.instret += tb.instrs.len() as u64;
                 return Ok(pc)
             }
 
             if !tb.jit_failed && count > TB_KICK_IN_JIT {
-                unsafe {
-                    /* Bypass the borrow checker: The better design that avoids this would
-                     * be not to have the JIT own the TBs.
-                     */
-                    (*(jit as *const JIT as *mut JIT)).kick_in()
-                };
+                unsafe { jit.kick_in() };
             }
 
             for (inst, size) in &tb.instrs {
                 inst.exec(*size as i64, self)?;
+                self.instret += 1;
             }
             return Ok(self.pc)
         }
 
-        jit.buffer.clear();
+        let mut instrs = Vec::with_capacity(32);
         let pc = self.pc;
         loop {
-            let raw = self.memory.load_u32(self.pc as usize);
+            let raw = self.memory.fetch_inst(self.pc as usize)?;
             let (instr, size) = Inst::parse(raw)?;
             let instr = instr.simplify();
             instr.exec(size as i64, self)?;
+            self.instret += 1;
             let ends_tb = instr.is_terminator();
-            jit.buffer.push((instr, size as u8));
+            instrs.push((instr, size as u8));
             if ends_tb {
                 break;
             }
         }
 
+        /* The JIT-ed code accesses the guest memory via casted pointers, so
+         * there is no way to trap or count misaligned accesses there. */
+        let jit_failed = !self.jit_enabled ||
+            instrs.iter().any(|(inst, _)| inst.is_atomic()) ||
+            (self.misaligned != MisalignedPolicy::Allow &&
+             instrs.iter().any(|(inst, _)| inst.is_wide_memory_access()));
+
         let tb = TranslationBlock {
             start: pc,
             exec_count: std::sync::atomic::AtomicI64::new(1),
             valid: true,
-            instrs: jit.buffer.clone(),
+            instrs,
             label: syms.and_then(|s| s
                 .lookup(pc)
                 .filter(|(_, start)| *start == pc)
-                .map(|(name, _)| std::rc::Rc::from(name))),
+                .map(|(name, _)| Arc::from(name))),
 
-            jit_failed: !self.jit_enabled,
+            jit_failed,
             jit_fn: None
         };
 
-        jit.tbs.insert(pc, tb);
+        jit.insert(tb);
         Ok(pc)
     }
 
+    /* Called by loads/stores whose address is not a multiple of the access width,
+     * self.pc still points to the instruction doing the access at that point. */
+    pub fn misaligned_access(&mut self, addr: usize, width: u8) -> Result<(), Error> {
+        match self.misaligned {
+            MisalignedPolicy::Allow => Ok(()),
+            MisalignedPolicy::Trap => Err(Error::MisalignedAccess { addr, width }),
+            MisalignedPolicy::Count => {
+                *self.misaligned_accesses.lock().unwrap().entry(self.pc).or_insert(0) += 1;
+                Ok(())
+            }
+        }
+    }
+
     pub fn get_reg(&self, reg: Reg) -> u64 {
         self.regs[reg as usize]
     }
@@ -201,162 +548,195 @@ impl CPU {
         self.fregs[reg as usize] = val.to_bits();
     }
 
-    pub unsafe fn ecall(&mut self) -> Result<(), Error> {
-        const RISCV_SYSNO_CLOSE:    u64 = 57;
-        const RISCV_SYSNO_READ:     u64 = 63;
-        const RISCV_SYSNO_WRITE:    u64 = 64;
-        const RISCV_SYSNO_NEWFSTAT: u64 = 80;
-        const RISCV_SYSNO_EXIT:     u64 = 93;
-        const RISCV_SYSNO_BRK:      u64 = 214;
-        const RISCV_SYSNO_OPEN:     u64 = 430;
-
-        let a0 = self.get_reg(REG_A0) as usize;
-        let a1 = self.get_reg(REG_A1) as usize;
-        let a2 = self.get_reg(REG_A2) as usize;
-
-        // Linux Syscall Numbers, for whatever reason, are different on different architectures.
-        // See https://jborza.com/post/2021-05-11-riscv-linux-syscalls/. Let's hope at least
-        // the arguments are the same and that the rust crate `syscalls` are those of the host.
-        let syscall = self.get_reg(REG_A7);
-        let res = match syscall {
-            RISCV_SYSNO_CLOSE => {
-                let fd = self.remapped_filenos.get(&a0).cloned().unwrap_or(a0);
-                let res = syscall!(Sysno::close, fd);
-                if self.debug_syscalls {
-                    eprintln!("[simrv64i] syscall: `close`({}) -> {:?}", fd, res);
-                }
-                res
-            },
-            RISCV_SYSNO_READ => {
-                let fd = self.remapped_filenos.get(&a0).cloned().unwrap_or(a0);
-                let addr = self.memory.data.as_ptr().add(a1);
-                let res = syscall!(Sysno::read, fd, addr as usize, a2);
-                if self.debug_syscalls {
-                    eprintln!("[simrv64i] syscall: `read`({}, {:?}, {}) -> {:?}",
-                        fd, addr, a2, res);
-                }
-                res
-            },
-            RISCV_SYSNO_WRITE => {
-                let fd = self.remapped_filenos.get(&a0).cloned().unwrap_or(a0);
-                let addr = self.memory.data.as_ptr().add(a1);
-                let res = syscall!(Sysno::write, fd, addr as usize, a2);
-                if self.debug_syscalls {
-                    eprintln!("[simrv64i] syscall: `write`({}, {:?}, {}) -> {:?}",
-                        fd, addr, a2, res);
-                }
-                res
-            },
-            RISCV_SYSNO_NEWFSTAT => {
-                let fd = self.remapped_filenos.get(&a0).cloned().unwrap_or(a0);
-                let addr = self.memory.data.as_ptr().add(a1);
-                let res = syscall!(Sysno::newfstatat, fd, addr as usize);
-                if self.debug_syscalls {
-                    eprintln!("[simrv64i] syscall: `newfstat`({}, {:?}) -> {:?}",
-                        fd, addr, res);
-                }
-                res
-            },
-            RISCV_SYSNO_EXIT => {
-                if self.debug_syscalls {
-                    eprintln!("[simrv64i] syscall: `exit`({:#08x}) -> !", a0);
-                }
-                return Err(Error::Exit(a0 as i32))
-            },
-            RISCV_SYSNO_BRK => {
-                if self.debug_syscalls {
-                    eprintln!("[simrv64i] syscall: `brk` (ignored)");
-                }
-                Ok(0)
-            },
-            RISCV_SYSNO_OPEN => {
-                let filepath = self.memory.data.as_ptr().add(a0);
-                let res = syscall!(Sysno::open, filepath as usize, a1);
-                if self.debug_syscalls {
-                    eprintln!("[simrv64i] syscall: `open`({:?}, {}) -> {:?}",
-                        std::ffi::CStr::from_ptr(filepath as *const i8), a1, res);
-                }
-                res
-            },
-            _ => todo!()
-        };
+    /* The unprivileged CSRs, all others are illegal instructions (SIGILL) in user mode. */
+    pub fn read_csr(&self, csr: u16) -> Result<u64, Error> {
+        match csr {
+            CSR_FFLAGS => Ok((self.fcsr & 0x1f) as u64),
+            CSR_FRM => Ok((self.fcsr >> 5) as u64 & 0x7),
+            CSR_FCSR => Ok((self.fcsr & 0xff) as u64),
+            /* One instruction per cycle. */
+            CSR_CYCLE | CSR_INSTRET => Ok(self.instret),
+            CSR_TIME => Ok(self.clock.timebase_ticks(self.instret)),
+            _ => Err(Error::Illegal)
+        }
+    }
 
-        self.set_reg(REG_A0, match res {
-            Ok(val) => val as u64,
-            Err(errno) => -(errno.into_raw() as i64) as u64
-        });
+    pub fn write_csr(&mut self, csr: u16, val: u64) -> Result<(), Error> {
+        let val = val as u32;
+        match csr {
+            CSR_FFLAGS => self.fcsr = (self.fcsr & !0x1f) | (val & 0x1f),
+            CSR_FRM => self.fcsr = (self.fcsr & 0x1f) | ((val & 0x7) << 5),
+            CSR_FCSR => self.fcsr = val & 0xff,
+            _ => return Err(Error::Illegal)
+        }
         Ok(())
     }
-}
 
-pub struct Memory {
-    data: Vec<u8>
+    pub unsafe fn ecall(&mut self) -> Result<(), Error> {
+        sys::dispatch(self)
+    }
 }
 
-impl Memory {
-    pub fn new() -> Memory {
-        let mut data = Vec::<u8>::new();
-        data.reserve_exact(MAX_ADDR);
-        data.resize(MAX_ADDR, 0x0);
-        Self { data }
-    }
+/* Hand-assembled guest code for the tests of the modules that need a running CPU. */
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::loader::test::{elf_file, parse, BODY};
+    use elf::abi::*;
 
-    pub fn copy_bulk(&mut self, addr: u64, src: &[u8]) {
-        self.data[(addr as usize)..(addr as usize + src.len())].copy_from_slice(src);
+    pub fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+        ((imm as u32) << 20) | (rs1 << 15) | (rd << 7) | 0x13
+    }
+    pub fn ld(rd: u32, rs1: u32, imm: i32) -> u32 {
+        ((imm as u32) << 20) | (rs1 << 15) | (3 << 12) | (rd << 7) | 0x03
     }
+    pub fn sd(rs2: u32, rs1: u32, imm: i32) -> u32 {
+        let imm = imm as u32;
+        ((imm >> 5) << 25) | (rs2 << 20) | (rs1 << 15) | (3 << 12) | ((imm & 0x1f) << 7) | 0x23
+    }
+    pub fn branch(funct3: u32, rs1: u32, rs2: u32, offset: i32) -> u32 {
+        let imm = offset as u32;
+        (((imm >> 12) & 1) << 31) | (((imm >> 5) & 0x3f) << 25) | (rs2 << 20) | (rs1 << 15)
+            | (funct3 << 12) | (((imm >> 1) & 0xf) << 8) | (((imm >> 11) & 1) << 7) | 0x63
+    }
+    pub fn auipc(rd: u32) -> u32 { (rd << 7) | 0x17 }
+    pub const ECALL: u32 = 0x00000073;
 
-    pub fn load_u8(&self, addr: usize) -> u8 {
-        self.data[addr]
+    /* Load `code` into a fresh RWX mapping and run it, `data(base)` is put at +0x100. */
+    pub fn run(code: &[u32], data: impl Fn(u64) -> Vec<u8>) -> (CPU, Result<i32, Error>) {
+        run_on(CPU::new(false), code, data)
     }
 
-    pub fn load_u16(&self, addr: usize) -> u16 {
-        (self.data[addr]) as u16 |
-        ((self.data[addr + 1] as u16) << 8)
+    /* Like run(), on a CPU the caller set up (JIT, policies, ...). */
+    pub fn run_on(mut cpu: CPU, code: &[u32], data: impl Fn(u64) -> Vec<u8>)
+            -> (CPU, Result<i32, Error>) {
+        let prot = PROT_READ | PROT_WRITE | PROT_EXEC;
+        let base = cpu.memory.mm().mmap(Placement::Hint(0), PAGE_SIZE, prot, false, None, 0).unwrap();
+        let bytes: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        cpu.memory.copy_bulk(base as u64, &bytes).unwrap();
+        cpu.memory.copy_bulk(base as u64 + 0x100, &data(base as u64)).unwrap();
+        let res = cpu.exec_images(&[], base as u64, None, None, None);
+        (cpu, res.map(|(exitcode, _)| exitcode))
     }
 
-    pub fn load_u32(&self, addr: usize) -> u32 {
-        (self.data[addr]) as u32 |
-        ((self.data[addr + 1] as u32) << 8) |
-        ((self.data[addr + 2] as u32) << 16) |
-        ((self.data[addr + 3] as u32) << 24)
+    /* A misaligned ld and sd, three times in a loop, under each MisalignedPolicy. */
+    fn run_misaligned(policy: MisalignedPolicy, jit_enabled: bool)
+            -> (CPU, u64, Result<i32, Error>) {
+        let (t0, t1, a0, a7) = (5, 6, 10, 17);
+        let code = [
+            auipc(t0), addi(t1, 0, 3),
+            ld(a0, t0, 0x101), sd(a0, t0, 0x111), addi(t1, t1, -1), branch(1, t1, 0, -12),
+            addi(a7, 0, 93), ECALL,
+        ];
+        let mut cpu = CPU::new(jit_enabled);
+        cpu.misaligned = policy;
+        let base = std::cell::Cell::new(0);
+        let (cpu, res) = run_on(cpu, &code, |b| {
+            base.set(b);
+            vec![0, 42, 0, 0, 0, 0, 0, 0, 0]
+        });
+        (cpu, base.get(), res)
     }
 
-    pub fn load_u64(&self, addr: usize) -> u64 {
-        (self.data[addr]) as u64 |
-        ((self.data[addr + 1] as u64) << 8) |
-        ((self.data[addr + 2] as u64) << 16) |
-        ((self.data[addr + 3] as u64) << 24) |
-        ((self.data[addr + 4] as u64) << 32) |
-        ((self.data[addr + 5] as u64) << 40) |
-        ((self.data[addr + 6] as u64) << 48) |
-        ((self.data[addr + 7] as u64) << 56)
+    #[test]
+    fn misaligned_access() {
+        let (cpu, _, res) = run_misaligned(MisalignedPolicy::Allow, false);
+        assert_eq!(res.ok(), Some(42));
+        assert!(cpu.misaligned_accesses.lock().unwrap().is_empty());
+
+        let (cpu, base, res) = run_misaligned(MisalignedPolicy::Count, false);
+        assert_eq!(res.ok(), Some(42));
+        let counts = cpu.misaligned_accesses.lock().unwrap().clone();
+        let (ld_pc, sd_pc) = (base as i64 + 8, base as i64 + 12);
+        assert_eq!(counts, HashMap::from([(ld_pc, 3), (sd_pc, 3)]));
+
+        let (cpu, _, res) = run_misaligned(MisalignedPolicy::Trap, false);
+        assert!(matches!(res, Err(Error::Signal(signals::SIGBUS))), "{:?}", res);
+        assert!(cpu.misaligned_accesses.lock().unwrap().is_empty());
     }
 
-    pub fn store_u8(&mut self, addr: usize, val: u8) {
-        self.data[addr] = val;
+    /* The JIT-ed code cannot trap or count, so those TBs are left to the interpreter. */
+    #[test]
+    fn misaligned_access_no_jit() {
+        for (policy, jit_failed) in [(MisalignedPolicy::Allow, false),
+                                     (MisalignedPolicy::Count, true)] {
+            let (cpu, base, res) = run_misaligned(policy, true);
+            assert_eq!(res.ok(), Some(42));
+            let tb = cpu.jit.get(base as i64 + 8).unwrap();
+            assert_eq!(tb.jit_failed, jit_failed, "{:?}", policy);
+        }
     }
 
-    pub fn store_u16(&mut self, addr: usize, val: u16) {
-        self.data[addr] = (val & 0xff) as u8;
-        self.data[addr + 1] = ((val >> 8) & 0xff) as u8;
+    /* Endless recursion runs into the guard below the stack, that is a SIGSEGV. */
+    #[test]
+    fn stack_overflow() {
+        let sp = 2;
+        let code = [addi(sp, sp, -2048), sd(0, sp, 0), branch(0, 0, 0, -8)];
+        let (cpu, res) = run(&code, |_| Vec::new());
+        assert!(matches!(res, Err(Error::Signal(signals::SIGSEGV))), "{:?}", res);
+
+        let guard = cpu.memory.layout.stack_guard();
+        let addr = cpu.get_reg(REG_SP) as usize;
+        assert!(guard.contains(&addr) && addr + 2048 >= guard.end, "{:#x}", addr);
+        let msg = cpu.stack_overflow(&Error::SegFault(addr), None).unwrap();
+        assert!(msg.starts_with("stack overflow in ???"), "{}", msg);
+        assert!(msg.ends_with(&format!("addr={:#08x?})", addr)), "{}", msg);
+        assert_eq!(cpu.stack_overflow(&Error::SegFault(guard.start - 1), None), None);
+        assert_eq!(cpu.stack_overflow(&Error::Illegal, None), None);
     }
 
-    pub fn store_u32(&mut self, addr: usize, val: u32) {
-        self.data[addr] = (val & 0xff) as u8;
-        self.data[addr + 1] = ((val >> 8) & 0xff) as u8;
-        self.data[addr + 2] = ((val >> 16) & 0xff) as u8;
-        self.data[addr + 3] = ((val >> 24) & 0xff) as u8;
+    /* Absolute guest paths are redirected if (and only if) the sysroot has them. */
+    #[test]
+    fn sysroot_path() {
+        let dir = std::env::temp_dir().join(format!("simrv64i-sysroot-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::write(dir.join("lib/ld.so"), b"").unwrap();
+        std::os::unix::fs::symlink("missing", dir.join("lib/dangling")).unwrap();
+
+        let mut cpu = CPU::new(false);
+        assert_eq!(cpu.sysroot_path(b"/lib/ld.so"), None);
+        cpu.sysroot = Some(dir.clone());
+        assert_eq!(cpu.sysroot_path(b"/lib/ld.so"), Some(dir.join("lib/ld.so")));
+        assert_eq!(cpu.sysroot_path(b"/lib/dangling"), Some(dir.join("lib/dangling")));
+        assert_eq!(cpu.sysroot_path(b"/lib/missing.so"), None);
+        assert_eq!(cpu.sysroot_path(b"lib/ld.so"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    pub fn store_u64(&mut self, addr: usize, val: u64) {
-        self.data[addr] = (val & 0xff) as u8;
-        self.data[addr + 1] = ((val >> 8) & 0xff) as u8;
-        self.data[addr + 2] = ((val >> 16) & 0xff) as u8;
-        self.data[addr + 3] = ((val >> 24) & 0xff) as u8;
-        self.data[addr + 4] = ((val >> 32) & 0xff) as u8;
-        self.data[addr + 5] = ((val >> 40) & 0xff) as u8;
-        self.data[addr + 6] = ((val >> 48) & 0xff) as u8;
-        self.data[addr + 7] = ((val >> 56) & 0xff) as u8;
+    /* PT_INTERP: The dynamic linker comes from the sysroot and gets control first. */
+    #[test]
+    fn interpreter() {
+        let dir = std::env::temp_dir().join(format!("simrv64i-interp-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        let interp = b"/lib/ld-riscv64.so.1\0";
+        let (rx, len) = ((PF_R | PF_X) as u64, interp.len() as u64);
+        let phdrs = [
+            [PT_INTERP as u64, PF_R as u64, BODY, 0x11000, len, len, 1],
+            [PT_LOAD as u64, rx, 0, 0x10000, BODY + len, BODY + len, 0x1000],
+        ];
+        let exe = elf_file(ET_EXEC, 0x11000, &phdrs, interp);
+        let ld_phdrs = [[PT_LOAD as u64, rx, 0, 0, BODY, BODY, 0]];
+        let ld = |e_type| elf_file(e_type, 0x800, &ld_phdrs, &[]);
+        let load = |cpu: &mut CPU| cpu.load(&parse(&exe), None, None).map(|_| ());
+
+        let mut cpu = CPU::new(false);
+        cpu.sysroot = Some(dir.clone());
+        let err = load(&mut cpu).unwrap_err();
+        assert!(matches!(&err, Error::ELF(msg) if msg.contains("cannot read interpreter")),
+                "{:?}", err);
+        std::fs::write(dir.join("lib/ld-riscv64.so.1"), ld(ET_EXEC)).unwrap();
+        let err = load(&mut CPU::new(false)).unwrap_err();
+        assert!(matches!(&err, Error::ELF(msg) if msg.contains("--sysroot")), "{:?}", err);
+        let err = load(&mut cpu).unwrap_err();
+        assert!(matches!(&err, Error::ELF(msg) if msg.contains("not a RV64 shared object")),
+                "{:?}", err);
+
+        std::fs::write(dir.join("lib/ld-riscv64.so.1"), ld(ET_DYN)).unwrap();
+        let mut cpu = CPU::new(false);
+        cpu.sysroot = Some(dir.clone());
+        load(&mut cpu).unwrap();
+        let heap_end = cpu.memory.mm().heap.end as i64;
+        assert_eq!(cpu.pc, heap_end + 0x800);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
-
@@ -0,0 +1,126 @@
+/*
+ * execve() of the guest. RISC-V executables are run by the simulator itself, the new
+ * image replaces the old one (see CPU::exec()). `#!` scripts are run by their
+ * interpreter, which is looked at the same way. Anything else is a host binary, whether
+ * and how those run is up to the HostExecPolicy.
+ */
+use std::ffi::{CStr, CString};
+use syscalls::Errno;
+
+use crate::signals::Signals;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum HostExecPolicy {
+    /// Replace the simulator with the host binary, the guest's fds become its fds.
+    Host,
+    /// Fail with EACCES.
+    Deny,
+}
+
+/* Linux looks at this much of a file to find out what it is (BINPRM_BUF_SIZE). */
+pub const HEADER_SIZE: usize = 256;
+
+const ELFCLASS64: u8 = 2;
+const EM_RISCV: u16 = 243;
+
+#[derive(Debug, PartialEq)]
+pub enum Executable {
+    RiscV,
+    /// Interpreter and its optional argument, from the `#!` line.
+    Script(CString, Option<CString>),
+    Host,
+}
+
+impl Executable {
+    /* What the first HEADER_SIZE bytes of a file say it is. */
+    pub fn from_header(header: &[u8]) -> Result<Executable, Errno> {
+        if header.len() >= 20 && header.starts_with(b"\x7fELF") && header[4] == ELFCLASS64
+            && u16::from_le_bytes([header[18], header[19]]) == EM_RISCV {
+            return Ok(Executable::RiscV)
+        }
+        let Some(line) = header.strip_prefix(b"#!") else {
+            return Ok(Executable::Host)
+        };
+        /* Like Linux: The interpreter ends at the first blank, the rest is one argument. */
+        let line = line.split(|c| *c == b'\n').next().unwrap_or_default().trim_ascii();
+        let (interp, arg) = match line.iter().position(|c| *c == b' ' || *c == b'\t') {
+            Some(pos) => (&line[..pos], Some(line[pos..].trim_ascii())),
+            None => (line, None)
+        };
+        if interp.is_empty() {
+            return Err(Errno::ENOEXEC)
+        }
+        let interp = CString::new(interp).map_err(|_| Errno::ENOEXEC)?;
+        let arg = arg.filter(|arg| !arg.is_empty()).map(CString::new).transpose()
+            .map_err(|_| Errno::ENOEXEC)?;
+        Ok(Executable::Script(interp, arg))
+    }
+}
+
+fn last_errno() -> Errno {
+    Errno::new(std::io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO))
+}
+
+/*
+ * Replace the simulator with a host binary. The guest fds that stay open (`fds`, guest
+ * fd -> host fd) become the same fds of the new program, all others are closed (the
+ * simulator's own host fds are O_CLOEXEC). Only returns if it fails before the fds were
+ * rearranged, afterwards there is no guest left to return to.
+ */
+pub fn host_exec(path: &CStr, argv: &[CString], envp: &[CString], fds: &[(usize, i32)],
+                 signals: &Signals) -> Errno {
+    /* Above all guest fds, so that rearranging them does not clobber any. */
+    let min = fds.iter().map(|(fd, _)| fd + 1).max().unwrap_or(0).max(3);
+    let mut moved = Vec::with_capacity(fds.len());
+    for (fd, host_fd) in fds {
+        match unsafe { libc::fcntl(*host_fd, libc::F_DUPFD_CLOEXEC, min as libc::c_int) } {
+            -1 => {
+                let errno = last_errno();
+                for (_, tmp) in moved {
+                    unsafe { libc::close(tmp) };
+                }
+                return errno
+            },
+            tmp => moved.push((*fd, tmp))
+        }
+    }
+
+    /* The point of no return. */
+    signals.prepare_host_exec();
+    for fd in (0..3).filter(|fd| !fds.iter().any(|(guest, _)| guest == fd)) {
+        unsafe { libc::close(fd as libc::c_int) };
+    }
+    for (fd, tmp) in moved {
+        unsafe { libc::dup2(tmp, fd as libc::c_int) };
+    }
+    let ptrs = |strings: &[CString]| strings.iter().map(|s| s.as_ptr())
+        .chain(std::iter::once(std::ptr::null())).collect::<Vec<_>>();
+    let (argv, envp) = (ptrs(argv), ptrs(envp));
+    unsafe { libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr()) };
+    eprintln!("[simrv64i] execve of host binary {:?} failed: {}", path, last_errno());
+    std::process::exit(127)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn executables() {
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(64, 0);
+        elf[18] = 243;
+        assert_eq!(Executable::from_header(&elf), Ok(Executable::RiscV));
+        elf[18] = 62; /* EM_X86_64 */
+        assert_eq!(Executable::from_header(&elf), Ok(Executable::Host));
+
+        let script = |s: &[u8]| Executable::from_header(s);
+        assert_eq!(script(b"#!/bin/sh\necho"), Ok(Executable::Script(c"/bin/sh".into(), None)));
+        assert_eq!(script(b"#! /usr/bin/env  python3 -u \r\n"),
+                   Ok(Executable::Script(c"/usr/bin/env".into(), Some(c"python3 -u".into()))));
+        assert_eq!(script(b"#!\n"), Err(Errno::ENOEXEC));
+        assert_eq!(script(b"#!/bin/s\0h\n"), Err(Errno::ENOEXEC));
+        assert_eq!(script(b"#!/bin/sh -\0x\n"), Err(Errno::ENOEXEC));
+        assert_eq!(script(b"echo hello"), Ok(Executable::Host));
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * The guest's file descriptor table. Guest fd numbers are allocated like the kernel
+ * does it (lowest free number) and refer to host fds that belong to the guest alone,
+ * so a guest closing or redirecting its stdio never affects the simulator's own.
+ * All threads of a guest share one table (CLONE_FILES).
+ */
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::{Arc, Mutex, MutexGuard};
+use syscalls::Errno;
+
+use crate::vfs::OpenFile;
+
+/* RLIMIT_NOFILE of the guest. */
+pub const MAX_FDS: usize = 1024;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Shared by all duplicates, the host fd is closed with the last of them.
+    file: Arc<OwnedFd>,
+    cloexec: bool,
+    /// Files opened through the VFS (with --mount), shared by duplicates as well.
+    opened: Option<Arc<OpenFile>>,
+}
+
+#[derive(Debug, Default)]
+pub struct FdTable {
+    fds: Arc<Mutex<Vec<Option<Entry>>>>,
+}
+
+impl FdTable {
+    /* Another handle to the same table, for a new thread. */
+    pub fn share(&self) -> FdTable {
+        FdTable { fds: self.fds.clone() }
+    }
+
+    /* A table of its own with the same entries, for the child of a fork(). */
+    pub fn duplicate(&self) -> FdTable {
+        FdTable { fds: Arc::new(Mutex::new(self.lock().clone())) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Option<Entry>>> {
+        self.fds.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /* Guest fds 0, 1 and 2 are duplicates of the simulator's stdio (if open). */
+    pub fn with_stdio() -> Self {
+        let mut table = Self::default();
+        for fd in 0..3 {
+            let dupped = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3) };
+            if dupped >= 0 {
+                table.install(fd as usize, unsafe { OwnedFd::from_raw_fd(dupped) });
+            }
+        }
+        table
+    }
+
+    fn entry(fds: &[Option<Entry>], fd: usize) -> Result<&Entry, Errno> {
+        fds.get(fd).and_then(|entry| entry.as_ref()).ok_or(Errno::EBADF)
+    }
+
+    fn lowest_free(fds: &[Option<Entry>], min: usize) -> Result<usize, Errno> {
+        (min..MAX_FDS).find(|fd| !matches!(fds.get(*fd), Some(Some(_)))).ok_or(Errno::EMFILE)
+    }
+
+    /* Returns the entry that was at `fd` before, dropping it closes it. */
+    fn set(fds: &mut Vec<Option<Entry>>, fd: usize, entry: Entry) -> Option<Entry> {
+        if fds.len() <= fd {
+            fds.resize(fd + 1, None);
+        }
+        fds[fd].replace(entry)
+    }
+
+    /*
+     * Another thread can close `fd` right after this, and a new file can get the host
+     * fd number. Like with Linux, a guest that closes fds still in use gets what it asked for.
+     */
+    pub fn host_fd(&self, fd: usize) -> Result<usize, Errno> {
+        Self::entry(&self.lock(), fd).map(|entry| entry.file.as_raw_fd() as usize)
+    }
+
+    /* Register a host fd under the lowest free guest fd. */
+    pub fn insert(&mut self, file: OwnedFd, cloexec: bool) -> Result<usize, Errno> {
+        let mut fds = self.lock();
+        let fd = Self::lowest_free(&fds, 0)?;
+        Self::set(&mut fds, fd, Entry { file: Arc::new(file), cloexec, opened: None });
+        Ok(fd)
+    }
+
+    /* insert() for a file opened through the VFS. */
+    pub fn insert_opened(&mut self, file: OwnedFd, cloexec: bool, opened: OpenFile)
+            -> Result<usize, Errno> {
+        let mut fds = self.lock();
+        let fd = Self::lowest_free(&fds, 0)?;
+        let entry = Entry { file: Arc::new(file), cloexec, opened: Some(Arc::new(opened)) };
+        Self::set(&mut fds, fd, entry);
+        Ok(fd)
+    }
+
+    /* What the VFS opened `fd` as, None for pipes, sockets, stdio and such. */
+    pub fn opened(&self, fd: usize) -> Result<Option<Arc<OpenFile>>, Errno> {
+        Self::entry(&self.lock(), fd).map(|entry| entry.opened.clone())
+    }
+
+    /* Register a host fd as guest fd `fd`, replacing whatever was there. */
+    pub fn install(&mut self, fd: usize, file: OwnedFd) {
+        let entry = Entry { file: Arc::new(file), cloexec: false, opened: None };
+        Self::set(&mut self.lock(), fd, entry);
+    }
+
+    /* Only drops the guest's reference, duplicates stay open. */
+    pub fn close(&mut self, fd: usize) -> Result<(), Errno> {
+        let entry = self.lock().get_mut(fd).and_then(Option::take);
+        entry.map(|_| ()).ok_or(Errno::EBADF)
+    }
+
+    /* dup(), or fcntl(F_DUPFD) with `min` set: The lowest free fd >= `min`. */
+    pub fn dup(&mut self, fd: usize, min: usize, cloexec: bool) -> Result<usize, Errno> {
+        let mut fds = self.lock();
+        let entry = Entry { cloexec, ..Self::entry(&fds, fd)?.clone() };
+        let newfd = Self::lowest_free(&fds, min)?;
+        Self::set(&mut fds, newfd, entry);
+        Ok(newfd)
+    }
+
+    /* dup2()/dup3(), the caller handles `oldfd == newfd`. */
+    pub fn dup2(&mut self, oldfd: usize, newfd: usize, cloexec: bool) -> Result<usize, Errno> {
+        let mut fds = self.lock();
+        let entry = Entry { cloexec, ..Self::entry(&fds, oldfd)?.clone() };
+        if newfd >= MAX_FDS {
+            return Err(Errno::EBADF)
+        }
+        let old = Self::set(&mut fds, newfd, entry);
+        /* Closing can block (e.g. on NFS), not while holding the lock. */
+        drop(fds);
+        drop(old);
+        Ok(newfd)
+    }
+
+    pub fn cloexec(&self, fd: usize) -> Result<bool, Errno> {
+        Self::entry(&self.lock(), fd).map(|entry| entry.cloexec)
+    }
+
+    pub fn set_cloexec(&mut self, fd: usize, cloexec: bool) -> Result<(), Errno> {
+        self.lock().get_mut(fd).and_then(Option::as_mut).map(|entry| entry.cloexec = cloexec)
+            .ok_or(Errno::EBADF)
+    }
+
+    /* What a successful execve() does to the table. */
+    pub fn close_on_exec(&mut self) {
+        for entry in self.lock().iter_mut() {
+            if entry.as_ref().is_some_and(|entry| entry.cloexec) {
+                *entry = None;
+            }
+        }
+    }
+
+    /* Guest fd -> host fd of everything an execve() keeps open. */
+    pub fn inheritable(&self) -> Vec<(usize, i32)> {
+        self.lock().iter().enumerate()
+            .filter_map(|(fd, entry)| entry.as_ref().filter(|entry| !entry.cloexec)
+                .map(|entry| (fd, entry.file.as_raw_fd())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn pipe() -> (OwnedFd, OwnedFd) {
+        let mut fds = [-1; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn allocation() {
+        let mut fds = FdTable::default();
+        let (r, w) = pipe();
+        assert_eq!(fds.insert(r, false), Ok(0));
+        assert_eq!(fds.insert(w, false), Ok(1));
+        assert_eq!(fds.dup(1, 0, false), Ok(2));
+        assert_eq!(fds.dup(1, 10, true), Ok(10));
+        assert_eq!(fds.close(1), Ok(()));
+        assert_eq!(fds.close(1), Err(Errno::EBADF));
+        assert_eq!(fds.dup(2, 0, false), Ok(1));
+        assert_eq!(fds.dup2(0, 2, false), Ok(2));
+        assert_eq!(fds.dup2(0, MAX_FDS, false), Err(Errno::EBADF));
+        assert_eq!(fds.host_fd(3), Err(Errno::EBADF));
+        fds.close_on_exec();
+        assert_eq!(fds.host_fd(10), Err(Errno::EBADF));
+        assert!(fds.host_fd(2).is_ok());
+        for _ in 3..MAX_FDS {
+            fds.dup(0, 0, false).unwrap();
+        }
+        assert_eq!(fds.dup(0, 0, false), Err(Errno::EMFILE));
+    }
+
+    /* The host fd is closed with the last guest fd referring to it, not before. */
+    #[test]
+    fn close_drops_reference() {
+        let mut fds = FdTable::default();
+        let (r, w) = pipe();
+        let mut reader = std::fs::File::from(r);
+        let w = fds.insert(w, false).unwrap();
+        let dupped = fds.dup(w, 0, false).unwrap();
+        fds.close(w).unwrap();
+
+        let mut file = unsafe { std::fs::File::from_raw_fd(fds.host_fd(dupped).unwrap() as i32) };
+        file.write_all(b"still open").unwrap();
+        std::mem::forget(file);
+        fds.close(dupped).unwrap();
+
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "still open");
+    }
+}
@@ -0,0 +1,190 @@
+/*
+ * Non-ELF images (flat binaries, Intel HEX and Motorola S-records), like the ones
+ * bootloaders and firmware are often delivered as.
+ */
+use crate::insts::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    Raw,
+    IHex,
+    SRec,
+    Elf,
+}
+
+/* Consecutive bytes of an image that go to `addr`. */
+#[derive(Debug, Default, PartialEq)]
+pub struct Chunk {
+    pub addr: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Image {
+    pub chunks: Vec<Chunk>,
+    /// Start address, if the image contains one.
+    pub entry: Option<u64>,
+}
+
+impl Image {
+    fn push(&mut self, addr: u64, data: &[u8]) {
+        match self.chunks.last_mut() {
+            Some(chunk) if chunk.addr + chunk.data.len() as u64 == addr => {
+                chunk.data.extend_from_slice(data)
+            }
+            _ => self.chunks.push(Chunk { addr, data: data.to_vec() }),
+        }
+    }
+}
+
+/* Guess the format from the file contents and extension if not given explicitly. */
+pub fn detect_format(path: &std::path::Path, bytes: &[u8]) -> Format {
+    if bytes.starts_with(b"\x7fELF") {
+        return Format::Elf;
+    }
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    match ext.to_ascii_lowercase().as_str() {
+        "hex" | "ihex" | "ihx" => Format::IHex,
+        "srec" | "s19" | "s28" | "s37" | "mot" => Format::SRec,
+        _ if bytes.starts_with(b":") => Format::IHex,
+        _ if bytes.starts_with(b"S0") => Format::SRec,
+        _ => Format::Raw,
+    }
+}
+
+fn parse_hex_bytes(lineno: usize, hex: &str) -> Result<Vec<u8>, Error> {
+    if hex.len() & 1 != 0 || !hex.is_ascii() {
+        return Err(Error::Image(format!("line {}: invalid hex string", lineno)));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|e| Error::Image(format!("line {}: {}", lineno, e)))
+        })
+        .collect()
+}
+
+fn be_addr(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |addr, b| (addr << 8) | *b as u64)
+}
+
+/* Intel HEX: `:LLAAAATT<data>CC`, with 20 bit segment and 32 bit linear addressing. */
+pub fn parse_ihex(text: &str) -> Result<Image, Error> {
+    let mut image = Image::default();
+    let mut base: u64 = 0;
+    for (lineno, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| Error::Image(format!("line {}: missing ':'", lineno)))
+            .and_then(|hex| parse_hex_bytes(lineno, hex))?;
+        if record.len() < 5 || record.len() != 5 + record[0] as usize {
+            return Err(Error::Image(format!("line {}: invalid record length", lineno)));
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(Error::Image(format!("line {}: checksum mismatch", lineno)));
+        }
+
+        let (addr, ty, data) = (be_addr(&record[1..3]), record[3], &record[4..record.len() - 1]);
+        match (ty, data.len()) {
+            (0x00, _) => image.push(base + addr, data),
+            (0x01, _) => break,
+            (0x02, 2) => base = be_addr(data) << 4,
+            (0x03, 4) => image.entry = Some((be_addr(&data[0..2]) << 4) + be_addr(&data[2..4])),
+            (0x04, 2) => base = be_addr(data) << 16,
+            (0x05, 4) => image.entry = Some(be_addr(data)),
+            _ => {
+                return Err(Error::Image(format!(
+                    "line {}: invalid record type {:#04x}",
+                    lineno, ty
+                )))
+            }
+        }
+    }
+    Ok(image)
+}
+
+/* Motorola S-records: `S<type><count><address><data><checksum>`. */
+pub fn parse_srec(text: &str) -> Result<Image, Error> {
+    let mut image = Image::default();
+    for (lineno, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() {
+            continue;
+        }
+        if line.len() < 4 || !line.starts_with('S') {
+            return Err(Error::Image(format!("line {}: not a S-record", lineno)));
+        }
+        let ty = line.as_bytes()[1];
+        let record = parse_hex_bytes(lineno, &line[2..])?;
+        if record.is_empty() || record.len() != 1 + record[0] as usize {
+            return Err(Error::Image(format!("line {}: invalid record length", lineno)));
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+            return Err(Error::Image(format!("line {}: checksum mismatch", lineno)));
+        }
+
+        let addr_len = match ty {
+            b'0' | b'1' | b'5' | b'9' => 2,
+            b'2' | b'6' | b'8' => 3,
+            b'3' | b'7' => 4,
+            _ => return Err(Error::Image(format!("line {}: invalid record type", lineno))),
+        };
+        if record.len() < 2 + addr_len {
+            return Err(Error::Image(format!("line {}: record too short", lineno)));
+        }
+        let addr = be_addr(&record[1..1 + addr_len]);
+        let data = &record[1 + addr_len..record.len() - 1];
+        match ty {
+            b'1' | b'2' | b'3' => image.push(addr, data),
+            b'7' | b'8' | b'9' => image.entry = Some(addr),
+            /* Header and record counts. */
+            _ => {}
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ihex() {
+        assert!(parse_ihex(":0400000013050000E5\n").is_err());
+
+        let text = ":020000040001F9\n\
+                    :0400000013050000E4\n\
+                    :040004007300000085\n\
+                    :0400000500010000F6\n\
+                    :00000001FF\n";
+        let image = parse_ihex(text).unwrap();
+        assert_eq!(image.entry, Some(0x10000));
+        assert_eq!(
+            image.chunks,
+            vec![Chunk {
+                addr: 0x10000,
+                data: vec![0x13, 0x05, 0x00, 0x00, 0x73, 0x00, 0x00, 0x00]
+            }]
+        );
+    }
+
+    #[test]
+    fn srec() {
+        let text = "S00600004844521B\n\
+                    S3090001000013050000DD\n\
+                    S30900010004730000007E\n\
+                    S70500010000F9\n";
+        let image = parse_srec(text).unwrap();
+        assert_eq!(image.entry, Some(0x10000));
+        assert_eq!(
+            image.chunks,
+            vec![Chunk {
+                addr: 0x10000,
+                data: vec![0x13, 0x05, 0x00, 0x00, 0x73, 0x00, 0x00, 0x00]
+            }]
+        );
+    }
+}
@@ -1,16 +1,23 @@
 #![allow(clippy::identity_op)]
 #![allow(clippy::eq_op)]
 
+use std::sync::atomic::Ordering::SeqCst;
+
 use crate::cpu;
+use crate::mem::{PROT_READ, PROT_WRITE};
 
 pub type Reg = u8;
 pub type FReg = u8;
 pub const REG_ZR: Reg = 0;
 pub const REG_RA: Reg = 1;
 pub const REG_SP: Reg = 2;
+pub const REG_TP: Reg = 4;
 pub const REG_A0: Reg = 10;
 pub const REG_A1: Reg = 11;
 pub const REG_A2: Reg = 12;
+pub const REG_A3: Reg = 13;
+pub const REG_A4: Reg = 14;
+pub const REG_A5: Reg = 15;
 pub const REG_A7: Reg = 17;
 
 fn sign_extend(x: u32, nbits: u32) -> u32 {
@@ -34,6 +41,9 @@ pub enum ALU {
     Rem, RemW, RemU, RemUW
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AMO { Swap, Add, XOr, And, Or, Min, Max, MinU, MaxU }
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
 pub enum FPU {
@@ -51,8 +61,7 @@ pub enum Inst {
     Unknown,
     NOP, // RV does not actually have a NOP, but its still usefull as explicit entry.
 
-    // Not even the non-privileged CSRs are implemented yet.
-    #[allow(dead_code)]
+    // Only the unprivileged CSRs: fflags/frm/fcsr and the cycle/time/instret counters.
     CtrlStatusReg { op: CSR, dst: Reg, src: Reg, csr: u16 },
 
     Load { dst: Reg, width: u8, base: Reg, offset: i32, signext: bool },
@@ -66,6 +75,12 @@ pub enum Inst {
     ALUReg { op: ALU, dst: Reg, src1: Reg, src2: Reg },
     LoadUpperImmediate { dst: Reg, imm: u32 },
     AddUpperImmediateToPC { dst: Reg, imm: u32 },
+    Fence,
+
+    // "A" extension instructions (aq/rl are ignored, all of them are sequentially consistent):
+    LoadReserved { dst: Reg, width: u8, addr: Reg },
+    StoreConditional { dst: Reg, width: u8, addr: Reg, src: Reg },
+    AtomicMemOp { op: AMO, dst: Reg, width: u8, addr: Reg, src: Reg },
 
     // "F" and "D" extension instructions:
     LoadFP { dst: FReg, width: u8, base: Reg, offset: i32 },
@@ -89,8 +104,16 @@ pub enum Inst {
 pub enum Error {
     Illegal,
     Exit(i32),
+    ThreadExit(i32),
+    Exec,
+    Signal(i32),
     InvalidEncoding(&'static str),
     Unimplemented(&'static str),
+    MisalignedAccess { addr: usize, width: u8 },
+    SegFault(usize),
+    Layout(String),
+    Image(String),
+    UnknownSyscall(u64),
     ELF(String),
     JIT(String),
     IO(std::io::Error)
@@ -421,6 +444,28 @@ fn parse_instruction(raw: u32) -> Result<(Inst, usize), Error> {
                 ((raw & 0xfe000000) >> (25 - 5)) |
                 ((raw & 0x00000f80) >> ( 7 - 0)), 12) as i32
         },
+        0b0000111 => Inst::LoadFP {
+            dst: get_rd(raw),
+            width: match get_funct3(raw) {
+                0b010 => 4,
+                0b011 => 8,
+                _ => return Err(Error::InvalidEncoding("invalid FP load width"))
+            },
+            base: get_rs1(raw),
+            offset: sign_extend((raw & 0xfff00000) >> 20, 12) as i32
+        },
+        0b0100111 => Inst::StoreFP {
+            src: get_rs2(raw),
+            width: match get_funct3(raw) {
+                0b010 => 4,
+                0b011 => 8,
+                _ => return Err(Error::InvalidEncoding("invalid FP store width"))
+            },
+            base: get_rs1(raw),
+            offset: sign_extend(
+                ((raw & 0xfe000000) >> (25 - 5)) |
+                ((raw & 0x00000f80) >> ( 7 - 0)), 12) as i32
+        },
         0b0010011 => {
             let dst = get_rd(raw);
             let src1 = get_rs1(raw);
@@ -484,6 +529,37 @@ fn parse_instruction(raw: u32) -> Result<(Inst, usize), Error> {
                 _ => return Err(Error::InvalidEncoding("system instruction"))
             }
         },
+        0b0001111 => match get_funct3(raw) {
+            0b000 | 0b001 => Inst::Fence, // FENCE, FENCE.I
+            _ => return Err(Error::InvalidEncoding("misc-mem instruction"))
+        },
+        0b0101111 => {
+            let width = match get_funct3(raw) {
+                0b010 => 4,
+                0b011 => 8,
+                _ => return Err(Error::InvalidEncoding("invalid AMO width"))
+            };
+            let (dst, addr, src) = (get_rd(raw), get_rs1(raw), get_rs2(raw));
+            match raw >> 27 {
+                0b00010 if src == 0 => Inst::LoadReserved { dst, width, addr },
+                0b00011 => Inst::StoreConditional { dst, width, addr, src },
+                funct5 => Inst::AtomicMemOp {
+                    op: match funct5 {
+                        0b00001 => AMO::Swap,
+                        0b00000 => AMO::Add,
+                        0b00100 => AMO::XOr,
+                        0b01100 => AMO::And,
+                        0b01000 => AMO::Or,
+                        0b10000 => AMO::Min,
+                        0b10100 => AMO::Max,
+                        0b11000 => AMO::MinU,
+                        0b11100 => AMO::MaxU,
+                        _ => return Err(Error::InvalidEncoding("unknown AMO"))
+                    },
+                    dst, width, addr, src
+                }
+            }
+        },
         0b0011011 => match (get_funct7(raw), get_funct3(raw)) {
             (_, 0b000) => Inst::ALUImm {
                 op: ALU::AddW,
@@ -566,11 +642,38 @@ fn simplify_instruction(inst: Inst) -> Inst {
     }
 }
 
+/* The value an AMO stores. For the .W ones both operands are sign-extended, which keeps
+ * the order of the lower 32 bits, signed and unsigned. */
+fn amo(op: AMO, old: u64, src: u64) -> u64 {
+    match op {
+        AMO::Swap => src,
+        AMO::Add  => old.wrapping_add(src),
+        AMO::XOr  => old ^ src,
+        AMO::And  => old & src,
+        AMO::Or   => old | src,
+        AMO::Min  => std::cmp::min(old as i64, src as i64) as u64,
+        AMO::Max  => std::cmp::max(old as i64, src as i64) as u64,
+        AMO::MinU => std::cmp::min(old, src),
+        AMO::MaxU => std::cmp::max(old, src),
+    }
+}
+
 fn execute_instruction(cpu: &mut cpu::CPU, inst: Inst, inst_size: i64) -> Result<(), Error> {
     fn calc_address(cpu: &cpu::CPU, base: Reg, offset: i32) -> usize {
         (cpu.get_reg(base) as i64 + offset as i64) as usize
     }
 
+    /* Like calc_address(), but for actual memory accesses, where the
+     * misaligned-access policy of the CPU has to be applied. */
+    fn calc_mem_address(cpu: &mut cpu::CPU, base: Reg, offset: i32, width: u8)
+            -> Result<usize, Error> {
+        let addr = calc_address(cpu, base, offset);
+        if addr & (width as usize - 1) != 0 {
+            cpu.misaligned_access(addr, width)?;
+        }
+        Ok(addr)
+    }
+
     match inst {
         Inst::NOP => {},
         Inst::LoadUpperImmediate { dst, imm } => {
@@ -608,33 +711,33 @@ fn execute_instruction(cpu: &mut cpu::CPU, inst: Inst, inst_size: i64) -> Result
             }
         },
         Inst::Load { dst, width, base, offset, signext: false } => {
-            let addr = calc_address(cpu, base, offset);
+            let addr = calc_mem_address(cpu, base, offset, width)?;
             cpu.set_reg(dst, match width {
-                1 => cpu.memory.load_u8(addr)  as u64,
-                2 => cpu.memory.load_u16(addr) as u64,
-                4 => cpu.memory.load_u32(addr) as u64,
-                8 => cpu.memory.load_u64(addr),
+                1 => cpu.memory.load_u8(addr)? as u64,
+                2 => cpu.memory.load_u16(addr)? as u64,
+                4 => cpu.memory.load_u32(addr)? as u64,
+                8 => cpu.memory.load_u64(addr)?,
                 _ => unimplemented!()
             });
         },
         Inst::Load { dst, width, base, offset, signext: true } => {
-            let addr = calc_address(cpu, base, offset);
+            let addr = calc_mem_address(cpu, base, offset, width)?;
             cpu.set_reg(dst, match width {
-                1 => cpu.memory.load_u8(addr) as i8 as i64 as u64,
-                2 => cpu.memory.load_u16(addr) as i16 as i64 as u64,
-                4 => cpu.memory.load_u32(addr) as i32 as i64 as u64,
-                8 => cpu.memory.load_u64(addr) as i64 as u64,
+                1 => cpu.memory.load_u8(addr)? as i8 as i64 as u64,
+                2 => cpu.memory.load_u16(addr)? as i16 as i64 as u64,
+                4 => cpu.memory.load_u32(addr)? as i32 as i64 as u64,
+                8 => cpu.memory.load_u64(addr)? as i64 as u64,
                 _ => unimplemented!()
             });
         },
         Inst::Store { src, width, base, offset } => {
-            let addr = calc_address(cpu, base, offset);
+            let addr = calc_mem_address(cpu, base, offset, width)?;
             let val = cpu.get_reg(src);
             match width {
-                1 => cpu.memory.store_u8(addr, val as u8),
-                2 => cpu.memory.store_u16(addr, val as u16),
-                4 => cpu.memory.store_u32(addr, val as u32),
-                8 => cpu.memory.store_u64(addr, val),
+                1 => cpu.memory.store_u8(addr, val as u8)?,
+                2 => cpu.memory.store_u16(addr, val as u16)?,
+                4 => cpu.memory.store_u32(addr, val as u32)?,
+                8 => cpu.memory.store_u64(addr, val)?,
                 _ => unimplemented!()
             }
         },
@@ -696,23 +799,79 @@ fn execute_instruction(cpu: &mut cpu::CPU, inst: Inst, inst_size: i64) -> Result
             })
         },
         Inst::ECall { _priv } => unsafe { cpu.ecall() }?,
+        Inst::CtrlStatusReg { op, dst, src, csr } => {
+            /* The immediate variants have a 5 bit immediate instead of rs1. */
+            let operand = match op {
+                CSR::RW | CSR::RS | CSR::RC => cpu.get_reg(src),
+                CSR::RWI | CSR::RSI | CSR::RCI => src as u64
+            };
+            let old = cpu.read_csr(csr)?;
+            /* csrrs/csrrc with x0 (or a zero immediate) do not write, e.g. rdcycle. */
+            match op {
+                CSR::RW | CSR::RWI => cpu.write_csr(csr, operand)?,
+                CSR::RS | CSR::RSI if src != 0 => cpu.write_csr(csr, old | operand)?,
+                CSR::RC | CSR::RCI if src != 0 => cpu.write_csr(csr, old & !operand)?,
+                _ => {}
+            }
+            cpu.set_reg(dst, old);
+        },
+        Inst::Fence => std::sync::atomic::fence(SeqCst),
+        Inst::LoadReserved { dst, width, addr } => {
+            let addr = cpu.get_reg(addr) as usize;
+            let val = match width {
+                4 => cpu.memory.atomic_u32(addr, PROT_READ)?.load(SeqCst) as i32 as i64 as u64,
+                _ => cpu.memory.atomic_u64(addr, PROT_READ)?.load(SeqCst)
+            };
+            cpu.reservation = Some((addr, val));
+            cpu.set_reg(dst, val);
+        },
+        Inst::StoreConditional { dst, width, addr, src } => {
+            /* Succeeds if the location still holds what LR loaded. Like with qemu, stores
+             * of the same value in between (ABA) go unnoticed, no real code cares. */
+            let addr = cpu.get_reg(addr) as usize;
+            let val = cpu.get_reg(src);
+            let stored = match cpu.reservation.take() {
+                Some((reserved, old)) if reserved == addr => match width {
+                    4 => cpu.memory.atomic_u32(addr, PROT_WRITE)?
+                        .compare_exchange(old as u32, val as u32, SeqCst, SeqCst).is_ok(),
+                    _ => cpu.memory.atomic_u64(addr, PROT_WRITE)?
+                        .compare_exchange(old, val, SeqCst, SeqCst).is_ok()
+                },
+                _ => false
+            };
+            cpu.set_reg(dst, if stored { 0 } else { 1 });
+        },
+        Inst::AtomicMemOp { op, dst, width, addr, src } => {
+            let addr = cpu.get_reg(addr) as usize;
+            let src = cpu.get_reg(src);
+            let prot = PROT_READ | PROT_WRITE;
+            let old = match width {
+                4 => cpu.memory.atomic_u32(addr, prot)?
+                    .fetch_update(SeqCst, SeqCst, |old| {
+                        Some(amo(op, old as i32 as i64 as u64, src as i32 as i64 as u64) as u32)
+                    }).unwrap() as i32 as i64 as u64,
+                _ => cpu.memory.atomic_u64(addr, prot)?
+                    .fetch_update(SeqCst, SeqCst, |old| Some(amo(op, old, src))).unwrap()
+            };
+            cpu.set_reg(dst, old);
+        },
         Inst::LoadFP { dst, width: 4, base, offset } => {
-            let addr = calc_address(cpu, base, offset);
-            cpu.set_freg_f32(dst, f32::from_bits(cpu.memory.load_u32(addr)));
+            let addr = calc_mem_address(cpu, base, offset, 4)?;
+            cpu.set_freg_f32(dst, f32::from_bits(cpu.memory.load_u32(addr)?));
         },
         Inst::LoadFP { dst, width: 8, base, offset } => {
-            let addr = calc_address(cpu, base, offset);
-            cpu.set_freg_f64(dst, f64::from_bits(cpu.memory.load_u64(addr)));
+            let addr = calc_mem_address(cpu, base, offset, 8)?;
+            cpu.set_freg_f64(dst, f64::from_bits(cpu.memory.load_u64(addr)?));
         },
         Inst::StoreFP { src, width: 4, base, offset } => {
-            let addr = calc_address(cpu, base, offset);
+            let addr = calc_mem_address(cpu, base, offset, 4)?;
             let val = cpu.get_freg_f32(src);
-            cpu.memory.store_u32(addr, val.to_bits());
+            cpu.memory.store_u32(addr, val.to_bits())?;
         },
         Inst::StoreFP { src, width: 8, base, offset } => {
-            let addr = calc_address(cpu, base, offset);
+            let addr = calc_mem_address(cpu, base, offset, 8)?;
             let val = cpu.get_freg_f64(src);
-            cpu.memory.store_u64(addr, val.to_bits());
+            cpu.memory.store_u64(addr, val.to_bits())?;
         },
 
         _ => unimplemented!()
@@ -750,6 +909,21 @@ impl Inst {
         }
     }
 
+    pub fn is_wide_memory_access(&self) -> bool {
+        match self {
+            Inst::Load { width, .. } | Inst::Store { width, .. } |
+            Inst::LoadFP { width, .. } | Inst::StoreFP { width, .. } => *width > 1,
+            _ => false
+        }
+    }
+
+    /* The JIT-ed code has no atomic accesses, TBs with these are never compiled. */
+    pub fn is_atomic(&self) -> bool {
+        matches!(self,
+            Inst::Fence | Inst::LoadReserved { .. } | Inst::StoreConditional { .. } |
+            Inst::AtomicMemOp { .. })
+    }
+
     #[allow(unused)]
     pub fn is_call(&self) -> bool {
         matches!(self,
@@ -0,0 +1,555 @@
+use crate::insts::Error;
+use crate::mem::*;
+
+/* Where and how an ELF file ended up in guest memory. */
+#[derive(Debug, Clone, Default)]
+pub struct Image {
+    /// Difference between the link-time and the actual addresses (0 for ET_EXEC).
+    pub bias: u64,
+    pub entry: u64,
+    /// Address of the program headers in guest memory (for AT_PHDR).
+    pub phdr: u64,
+    pub phent: u64,
+    pub phnum: u64,
+    /// First address after the highest segment, i.e. the initial program break.
+    pub end: usize,
+}
+
+fn segment_prot(p_flags: u32) -> u8 {
+    let mut prot = 0;
+    if p_flags & elf::abi::PF_R != 0 {
+        prot |= PROT_READ;
+    }
+    if p_flags & elf::abi::PF_W != 0 {
+        prot |= PROT_WRITE;
+    }
+    if p_flags & elf::abi::PF_X != 0 {
+        prot |= PROT_EXEC;
+    }
+    prot
+}
+
+/*
+ * Position-independent executables (ET_DYN) can be loaded anywhere: Put the lowest
+ * segment at `base`. Executables of type ET_EXEC are loaded where they are linked.
+ */
+pub fn load_bias(elf_file: &elf::ElfBytes<'_, elf::endian::AnyEndian>, base: u64) -> u64 {
+    if elf_file.ehdr.e_type != elf::abi::ET_DYN {
+        return 0
+    }
+    let lowest = elf_file.segments()
+        .and_then(|segments| segments.iter()
+            .filter(|phdr| phdr.p_type == elf::abi::PT_LOAD)
+            .map(|phdr| phdr.p_vaddr)
+            .min())
+        .unwrap_or(0);
+    base.wrapping_sub(lowest & !(PAGE_SIZE as u64 - 1))
+}
+
+/*
+ * Load all PT_LOAD segments of `elf_file` into `memory`, shifted by `bias`. Like the
+ * Linux kernel does it, segments are mapped page-wise with the permissions from p_flags,
+ * and everything from p_filesz to p_memsz (.bss) is zero-filled. Section headers are
+ * not used at all, so stripped binaries work as well.
+ */
+pub fn load_elf(
+        memory: &Memory,
+        elf_file: &elf::ElfBytes<'_, elf::endian::AnyEndian>,
+        bias: u64) -> Result<Image, Error> {
+    let segments = elf_file.segments()
+        .ok_or_else(|| Error::ELF("no program headers".to_string()))?;
+
+    let stack_guard = memory.layout.stack_guard();
+    let ehdr = &elf_file.ehdr;
+    let mut image = Image {
+        bias,
+        entry: ehdr.e_entry.wrapping_add(bias),
+        phdr: 0,
+        phent: ehdr.e_phentsize as u64,
+        phnum: ehdr.e_phnum as u64,
+        end: 0
+    };
+    let mut entry_is_executable = false;
+    for phdr in segments.iter().filter(|phdr| phdr.p_type == elf::abi::PT_LOAD) {
+        let (vaddr, filesz, memsz) = (phdr.p_vaddr.wrapping_add(bias) as usize,
+                                      phdr.p_filesz as usize, phdr.p_memsz as usize);
+        if filesz > memsz {
+            return Err(Error::ELF(format!("segment at {:#x}: p_filesz > p_memsz", vaddr)))
+        }
+
+        let file_end = phdr.p_offset.checked_add(phdr.p_filesz).ok_or_else(|| Error::ELF(
+            format!("segment at {:#x}: p_offset + p_filesz overflows", vaddr)))?;
+
+        let start = vaddr & !(PAGE_SIZE - 1);
+        let end = match vaddr.checked_add(memsz).and_then(page_align_up) {
+            Some(end) if end <= stack_guard.start => end,
+            _ => return Err(Error::Layout(format!(
+                "segment {:#x}..{:#x} overlaps with the stack", vaddr, vaddr.wrapping_add(memsz))))
+        };
+
+        let data = elf_file.segment_data(&phdr)
+            .map_err(|e| Error::ELF(format!("{}", e)))?;
+        memory.copy_bulk(vaddr as u64, &data[..filesz])?;
+        memory.zero_bulk(vaddr + filesz, memsz - filesz)?;
+
+        /* Segments can share a page (e.g. the end of .text and the start of .data),
+         * in that case the page gets the permissions of both. */
+        memory.mm().map(start, end - start, segment_prot(phdr.p_flags));
+
+        if phdr.p_flags & elf::abi::PF_X != 0 &&
+           (vaddr as u64..(vaddr + memsz) as u64).contains(&image.entry) {
+            entry_is_executable = true;
+        }
+        /* The program headers are usually part of the first segment. */
+        if (phdr.p_offset..file_end).contains(&ehdr.e_phoff) {
+            image.phdr = vaddr as u64 + (ehdr.e_phoff - phdr.p_offset);
+        }
+        image.end = std::cmp::max(image.end, vaddr + memsz);
+    }
+    if let Some(phdr) = segments.iter().find(|phdr| phdr.p_type == elf::abi::PT_PHDR) {
+        image.phdr = phdr.p_vaddr.wrapping_add(bias);
+    }
+
+    if !entry_is_executable {
+        return Err(Error::ELF(format!(
+            "entry point {:#x} is not inside an executable segment", image.entry)))
+    }
+    Ok(image)
+}
+
+/*
+ * Copy a flat image (raw binary or one chunk of a HEX/S-record file) to `addr`. There
+ * are no segments telling us what is code and what is data, so everything is RWX.
+ */
+pub fn load_flat(memory: &Memory, addr: u64, data: &[u8]) -> Result<Image, Error> {
+    let (vaddr, len) = (addr as usize, data.len());
+    let stack_guard = memory.layout.stack_guard();
+    if vaddr.checked_add(len).is_none_or(|end| end > stack_guard.start) {
+        return Err(Error::Layout(format!(
+            "image {:#x}..{:#x} overlaps with the stack", vaddr, vaddr.wrapping_add(len))))
+    }
+    memory.copy_bulk(addr, data)?;
+    let start = vaddr & !(PAGE_SIZE - 1);
+    memory.mm().map(start, vaddr + len - start, PROT_READ | PROT_WRITE | PROT_EXEC);
+    Ok(Image { entry: addr, end: vaddr + len, ..Default::default() })
+}
+
+/* Path of the dynamic linker (PT_INTERP), if the executable is dynamically linked. */
+pub fn interpreter(elf_file: &elf::ElfBytes<'_, elf::endian::AnyEndian>)
+        -> Result<Option<String>, Error> {
+    let phdr = match elf_file.segments()
+            .and_then(|segments| segments.iter().find(|phdr| phdr.p_type == elf::abi::PT_INTERP)) {
+        Some(phdr) => phdr,
+        None => return Ok(None)
+    };
+    let data = elf_file.segment_data(&phdr).map_err(|e| Error::ELF(format!("{}", e)))?;
+    let path = std::ffi::CStr::from_bytes_until_nul(data)
+        .map_err(|_| Error::ELF("PT_INTERP is not NUL-terminated".to_string()))?;
+    path.to_str()
+        .map(|path| Some(path.to_string()))
+        .map_err(|_| Error::ELF(format!("invalid PT_INTERP: {:?}", path)))
+}
+
+const ELF64_RELA_SIZE: u64 = 24;
+const ELF64_SYM_SIZE: u64 = 24;
+
+/*
+ * Apply the dynamic relocations a static-pie needs. Binaries usually relocate themselves
+ * as well (glibc's _dl_relocate_static_pie, musl's _dlstart), but RELA relocations are
+ * idempotent, so doing it here does not hurt and helps binaries that don't. Only the
+ * simple ones are done, others (TLS_*, COPY, ...) are left to the binary.
+ */
+pub fn apply_relocations(
+        memory: &Memory,
+        elf_file: &elf::ElfBytes<'_, elf::endian::AnyEndian>,
+        bias: u64) -> Result<(), Error> {
+    let dynamic = match elf_file.dynamic().map_err(|e| Error::ELF(format!("{}", e)))? {
+        Some(dynamic) => dynamic,
+        None => return Ok(())
+    };
+
+    let (mut rela, mut relasz, mut jmprel, mut pltrelsz, mut symtab) = (0, 0, 0, 0, 0);
+    for entry in dynamic.iter() {
+        match entry.d_tag {
+            elf::abi::DT_RELA => rela = entry.d_ptr().wrapping_add(bias),
+            elf::abi::DT_RELASZ => relasz = entry.d_val(),
+            elf::abi::DT_JMPREL => jmprel = entry.d_ptr().wrapping_add(bias),
+            elf::abi::DT_PLTRELSZ => pltrelsz = entry.d_val(),
+            elf::abi::DT_SYMTAB => symtab = entry.d_ptr().wrapping_add(bias),
+            _ => {}
+        }
+    }
+
+    for (table, size) in [(rela, relasz), (jmprel, pltrelsz)] {
+        for addr in (table..(table + size)).step_by(ELF64_RELA_SIZE as usize) {
+            let addr = addr as usize;
+            let r_offset = memory.load_u64(addr)?.wrapping_add(bias);
+            let r_info = memory.load_u64(addr + 8)?;
+            let r_addend = memory.load_u64(addr + 16)?;
+            let (r_sym, r_type) = (r_info >> 32, (r_info & 0xffffffff) as u32);
+
+            /* S: Value of the symbol, undefined (weak) symbols resolve to 0. */
+            let sym_value = || -> Result<u64, Error> {
+                let sym = (symtab + r_sym * ELF64_SYM_SIZE) as usize;
+                let st_shndx = memory.load_u16(sym + 6)?;
+                let st_value = memory.load_u64(sym + 8)?;
+                Ok(if st_shndx == elf::abi::SHN_UNDEF { 0 } else { st_value.wrapping_add(bias) })
+            };
+
+            let value = match r_type {
+                elf::abi::R_RISCV_NONE => continue,
+                elf::abi::R_RISCV_RELATIVE => bias.wrapping_add(r_addend),
+                elf::abi::R_RISCV_64 => sym_value()?.wrapping_add(r_addend),
+                elf::abi::R_RISCV_JUMP_SLOT => sym_value()?,
+                /* E.g. R_RISCV_IRELATIVE needs the guest to call the resolver, libc does it. */
+                _ => continue
+            };
+            memory.copy_bulk(r_offset, &value.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/* Thread control block (tcbhead_t): The DTV pointer and a pointer for the libc. */
+const TCB_SIZE: usize = 16;
+
+/*
+ * Set up the TLS block of the main thread from the PT_TLS template (.tdata followed by
+ * the zeroed .tbss). RISC-V uses TLS variant I: The TCB is right below the thread
+ * pointer, which points to the TLS block of the executable, so that TLS symbols are
+ * at tp + st_value. The block is put at the top of the stack, returns the thread
+ * pointer and the new top of the stack (below the TCB).
+ */
+pub fn setup_tls(
+        memory: &Memory,
+        elf_file: &elf::ElfBytes<'_, elf::endian::AnyEndian>,
+        stack_top: usize) -> Result<Option<(u64, usize)>, Error> {
+    let phdr = match elf_file.segments()
+            .and_then(|segments| segments.iter().find(|phdr| phdr.p_type == elf::abi::PT_TLS)) {
+        Some(phdr) => phdr,
+        None => return Ok(None)
+    };
+    let (filesz, memsz) = (phdr.p_filesz as usize, phdr.p_memsz as usize);
+    let align = std::cmp::max(phdr.p_align as usize, TCB_SIZE);
+    if !align.is_power_of_two() || filesz > memsz {
+        return Err(Error::ELF(format!("invalid PT_TLS segment: {:x?}", phdr)))
+    }
+
+    let tp = stack_top.checked_sub(memsz)
+        .map(|tp| tp & !(align - 1))
+        .filter(|tp| *tp >= memory.layout.stack().start + TCB_SIZE)
+        .ok_or_else(|| Error::Layout("TLS block does not fit on the stack".to_string()))?;
+    let data = elf_file.segment_data(&phdr).map_err(|e| Error::ELF(format!("{}", e)))?;
+    memory.zero_bulk(tp - TCB_SIZE, TCB_SIZE)?;
+    memory.copy_bulk(tp as u64, &data[..filesz])?;
+    memory.zero_bulk(tp + filesz, memsz - filesz)?;
+    Ok(Some((tp as u64, tp - TCB_SIZE)))
+}
+
+/* Auxiliary vector entry types, see <elf.h>. */
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+const AUXV_LEN: usize = 17;
+
+/* One bit per single-letter ISA extension ('A' is bit 0), like Linux reports it. */
+const fn isa_bit(ext: u8) -> u64 {
+    1 << (ext - b'A')
+}
+pub const RISCV_HWCAP: u64 = isa_bit(b'I') | isa_bit(b'M') | isa_bit(b'A') | isa_bit(b'C');
+
+/*
+ * Build the initial process stack like Linux does for a new process and return the
+ * initial stack pointer. Everything is placed below `stack_top`, `interp_base` is where
+ * the dynamic linker was loaded (0 for statically linked executables). From the stack
+ * pointer upwards (all 8 byte words):
+ *
+ *   argc
+ *   argv[0], ..., argv[argc - 1], NULL
+ *   envp[0], ..., envp[envc - 1], NULL
+ *   auxv[0].type, auxv[0].value, ..., AT_NULL, 0
+ *   (padding)
+ *   AT_RANDOM bytes, argv and envp strings, execfn
+ *
+ * The stack pointer is 16-byte aligned, as the psABI requires.
+ */
+pub fn setup_stack(
+        memory: &Memory,
+        image: &Image,
+        stack_top: usize,
+        interp_base: u64,
+        argv: &[&[u8]],
+        envp: &[&[u8]]) -> Result<usize, Error> {
+    /* Only called after checking that everything fits, so `pos` cannot underflow. */
+    fn push_bytes(memory: &Memory, pos: &mut usize, bytes: &[u8]) -> Result<u64, Error> {
+        *pos -= bytes.len();
+        memory.copy_bulk(*pos as u64, bytes)?;
+        Ok(*pos as u64)
+    }
+    fn push_cstr(memory: &Memory, pos: &mut usize, s: &[u8]) -> Result<u64, Error> {
+        push_bytes(memory, pos, b"\0")?;
+        push_bytes(memory, pos, s)
+    }
+
+    /* The strings, AT_RANDOM bytes, the words and up to 15 bytes of alignment padding. */
+    let execfn = argv.first().copied().unwrap_or(b"");
+    let strings: usize = argv.iter().chain(envp).chain([&execfn]).map(|s| s.len() + 1).sum();
+    let words = 1 + (argv.len() + 1) + (envp.len() + 1) + 2 * AUXV_LEN;
+    let stack = memory.layout.stack();
+    stack_top.checked_sub(strings + 16 + words * 8 + 15)
+        .filter(|bottom| *bottom >= stack.start + PAGE_SIZE)
+        .ok_or_else(|| Error::Layout("argv and envp do not fit on the stack".to_string()))?;
+
+    let mut pos = stack_top;
+    let pos = &mut pos;
+    let execfn = push_cstr(memory, pos, execfn)?;
+    let mut envp_addrs = envp.iter().rev()
+        .map(|s| push_cstr(memory, pos, s))
+        .collect::<Result<Vec<_>, _>>()?;
+    envp_addrs.reverse();
+    let mut argv_addrs = argv.iter().rev()
+        .map(|s| push_cstr(memory, pos, s))
+        .collect::<Result<Vec<_>, _>>()?;
+    argv_addrs.reverse();
+
+    let mut random = [0u8; 16];
+    let res = unsafe { libc::getrandom(random.as_mut_ptr() as *mut libc::c_void, random.len(), 0) };
+    if res != random.len() as isize {
+        return Err(Error::IO(std::io::Error::last_os_error()))
+    }
+    let random = push_bytes(memory, pos, &random)?;
+
+    let auxv: [(u64, u64); AUXV_LEN] = [
+        (AT_PHDR, image.phdr),
+        (AT_PHENT, image.phent),
+        (AT_PHNUM, image.phnum),
+        (AT_PAGESZ, PAGE_SIZE as u64),
+        (AT_BASE, interp_base),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, image.entry),
+        (AT_UID, unsafe { libc::getuid() } as u64),
+        (AT_EUID, unsafe { libc::geteuid() } as u64),
+        (AT_GID, unsafe { libc::getgid() } as u64),
+        (AT_EGID, unsafe { libc::getegid() } as u64),
+        (AT_HWCAP, RISCV_HWCAP),
+        (AT_CLKTCK, 100),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random),
+        (AT_EXECFN, execfn),
+        (AT_NULL, 0),
+    ];
+
+    let sp = (*pos - words * 8) & !0xf;
+
+    let mut words = Vec::with_capacity(words);
+    words.push(argv.len() as u64);
+    words.extend(argv_addrs);
+    words.push(0);
+    words.extend(envp_addrs);
+    words.push(0);
+    for (ty, val) in auxv {
+        words.extend([ty, val]);
+    }
+    for (i, word) in words.into_iter().enumerate() {
+        memory.store_u64(sp + i * 8, word)?;
+    }
+    Ok(sp)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use elf::abi::*;
+
+    /* Where elf_file() puts `body`, the segments refer to it by file offset. */
+    pub const BODY: u64 = 0x1000;
+
+    /*
+     * A minimal little-endian RISC-V ELF file without sections. The program headers are
+     * [p_type, p_flags, p_offset, p_vaddr, p_filesz, p_memsz, p_align].
+     */
+    pub fn elf_file(e_type: u16, entry: u64, phdrs: &[[u64; 7]], body: &[u8]) -> Vec<u8> {
+        let mut file = vec![0u8; BODY as usize];
+        file[0..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        let mut put = |offset: usize, bytes: &[u8]| {
+            file[offset..(offset + bytes.len())].copy_from_slice(bytes)
+        };
+        put(16, &e_type.to_le_bytes());
+        put(18, &EM_RISCV.to_le_bytes());
+        put(20, &1u32.to_le_bytes());
+        put(24, &entry.to_le_bytes());
+        put(32, &64u64.to_le_bytes());
+        put(52, &64u16.to_le_bytes());
+        put(54, &56u16.to_le_bytes());
+        put(56, &(phdrs.len() as u16).to_le_bytes());
+        put(58, &64u16.to_le_bytes());
+        for (i, phdr) in phdrs.iter().enumerate() {
+            let offset = 64 + i * 56;
+            put(offset, &(phdr[0] as u32).to_le_bytes());
+            put(offset + 4, &(phdr[1] as u32).to_le_bytes());
+            put(offset + 8, &phdr[2].to_le_bytes());
+            put(offset + 16, &phdr[3].to_le_bytes());
+            put(offset + 24, &phdr[3].to_le_bytes());
+            for (j, value) in phdr[4..].iter().enumerate() {
+                put(offset + 32 + j * 8, &value.to_le_bytes());
+            }
+        }
+        file.extend(body);
+        file
+    }
+
+    pub fn parse(bytes: &[u8]) -> elf::ElfBytes<'_, elf::endian::AnyEndian> {
+        elf::ElfBytes::minimal_parse(bytes).unwrap()
+    }
+
+    /* Segments land page-wise with their permissions, .bss is zeroed. */
+    #[test]
+    fn load_segments() {
+        let memory = Memory::new(MemoryLayout::default());
+        let (rx, rw) = ((PF_R | PF_X) as u64, (PF_R | PF_W) as u64);
+        let mut body = vec![0x13; 0x20];
+        body.extend(b"datadata");
+        let phdrs = [
+            [PT_LOAD as u64, rx, 0, 0x10000, BODY + 0x20, BODY + 0x20, 0x1000],
+            [PT_LOAD as u64, rw, BODY + 0x20, 0x12ff0, 8, 0x30, 0x1000],
+        ];
+        let bytes = elf_file(ET_EXEC, 0x10000 + BODY, &phdrs, &body);
+        memory.copy_bulk(0x12ff0, &[0xff; 0x1010]).unwrap();
+
+        let file = parse(&bytes);
+        let image = load_elf(&memory, &file, load_bias(&file, 0x40000)).unwrap();
+        assert_eq!((image.bias, image.entry, image.phdr), (0, 0x11000, 0x10040));
+        assert_eq!(image.end, 0x13020);
+        assert_eq!((image.phent, image.phnum), (56, 2));
+        assert_eq!(memory.load_u32(0x11000).unwrap(), 0x13131313);
+        assert!(memory.store_u8(0x11000, 0).is_err());
+        assert_eq!(memory.guest_slice(0x12ff0, 8).unwrap(), b"datadata");
+        assert!(memory.guest_slice(0x12ff8, 0x28).unwrap().iter().all(|b| *b == 0));
+        memory.store_u8(0x13fff, 1).unwrap();
+        assert!(memory.load_u8(0x14000).is_err());
+
+        /* Broken headers are errors, whatever is wrong with them. */
+        let broken = [
+            (0x12ff0, phdrs[1]),
+            (0x11000, [PT_LOAD as u64, rx, u64::MAX - 8, 0x10000, 0x10, 0x10, 0x1000]),
+            (0x11000, [PT_LOAD as u64, rx, BODY, 0x10000, 0x20, 0x10, 0x1000]),
+            (0x11000, [PT_LOAD as u64, rx, BODY, u64::MAX - 8, 0x10, 0x10, 0x1000]),
+        ];
+        for (entry, phdr) in broken {
+            let bytes = elf_file(ET_EXEC, entry, &[phdrs[0], phdr], &body);
+            assert!(load_elf(&memory, &parse(&bytes), 0).is_err());
+        }
+    }
+
+    /* A PIE's lowest segment goes to the base, its relocations are applied there. */
+    #[test]
+    fn relocations() {
+        let memory = Memory::new(MemoryLayout::default());
+        let load = |vaddr: u64| [PT_LOAD as u64, (PF_R | PF_X) as u64, 0, vaddr, 0x10, 0x10, 0];
+        assert_eq!(load_bias(&parse(&elf_file(ET_EXEC, 0, &[load(0x10000)], &[])), 0x40000), 0);
+        assert_eq!(load_bias(&parse(&elf_file(ET_DYN, 0, &[load(0x1234)], &[])), 0x40000),
+                   0x3f000);
+
+        let dynamic = [
+            (DT_RELA, 0x3000), (DT_RELASZ, 4 * ELF64_RELA_SIZE), (DT_JMPREL, 0x3100),
+            (DT_PLTRELSZ, ELF64_RELA_SIZE), (DT_SYMTAB, 0x3200), (DT_NULL, 0),
+        ];
+        let body = dynamic.iter()
+            .flat_map(|(tag, val)| [tag.to_le_bytes(), val.to_le_bytes()].concat())
+            .collect::<Vec<_>>();
+        let dyn_phdr = [PT_DYNAMIC as u64, PF_R as u64, BODY, 0x2000, body.len() as u64,
+                        body.len() as u64, 8];
+        let bytes = elf_file(ET_DYN, 0, &[load(0), dyn_phdr], &body);
+        let file = parse(&bytes);
+        let bias = load_bias(&file, 0x40000);
+        assert_eq!(bias, 0x40000);
+
+        /* The tables are read from guest memory, where the binary would have them. */
+        let at = |addr: u64| bias + addr;
+        memory.mm().map(at(0x3000) as usize, 0x1000, PROT_READ | PROT_WRITE);
+        let rela = |addr: u64, offset: u64, sym: u64, ty: u32, addend: u64| {
+            let entry = [offset, (sym << 32) | ty as u64, addend];
+            memory.copy_bulk(at(addr), &entry.map(u64::to_le_bytes).concat()).unwrap();
+        };
+        rela(0x3000, 0x3800, 0, R_RISCV_RELATIVE, 0x100);
+        rela(0x3018, 0x3808, 1, R_RISCV_64, 8);
+        rela(0x3030, 0x3810, 2, R_RISCV_64, 4);
+        rela(0x3048, 0x3818, 1, R_RISCV_TLS_DTPMOD64, 0);
+        rela(0x3100, 0x3820, 1, R_RISCV_JUMP_SLOT, 0);
+        /* Symbol 1 is defined (st_shndx 1) at 0x500, symbol 2 is undefined. */
+        memory.store_u16(at(0x3218) as usize + 6, 1).unwrap();
+        memory.store_u64(at(0x3218) as usize + 8, 0x500).unwrap();
+        memory.store_u64(at(0x3230) as usize + 8, 0x600).unwrap();
+        memory.store_u64(at(0x3818) as usize, 0xdead).unwrap();
+
+        apply_relocations(&memory, &file, bias).unwrap();
+        let words = (0..5).map(|i| memory.load_u64(at(0x3800 + i * 8) as usize).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(words, vec![bias + 0x100, bias + 0x508, 4, 0xdead, bias + 0x500]);
+    }
+
+    /* TLS variant I: The zeroed TCB right below the aligned tp, .tdata and .tbss above. */
+    #[test]
+    fn tls() {
+        let memory = Memory::new(MemoryLayout::default());
+        let stack_top = memory.layout.stack().end - 8;
+        memory.copy_bulk((stack_top - 0x100) as u64, &[0xff; 0x100]).unwrap();
+        let tls = |align: u64| [PT_TLS as u64, PF_R as u64, BODY, 0x2000, 5, 0x30, align];
+        let bytes = elf_file(ET_EXEC, 0, &[tls(64)], b"tdata");
+
+        let (tp, top) = setup_tls(&memory, &parse(&bytes), stack_top).unwrap().unwrap();
+        let tp = tp as usize;
+        assert_eq!(tp % 64, 0);
+        assert!(tp + 0x30 <= stack_top && tp + 0x30 + 64 > stack_top);
+        assert_eq!(top, tp - TCB_SIZE);
+        assert_eq!(memory.guest_slice(tp, 5).unwrap(), b"tdata");
+        assert!(memory.guest_slice(tp + 5, 0x30 - 5).unwrap().iter().all(|b| *b == 0));
+        assert!(memory.guest_slice(top, TCB_SIZE).unwrap().iter().all(|b| *b == 0));
+        assert_eq!(memory.load_u8(top - 1).unwrap(), 0xff);
+
+        /* At least the TCB's alignment, and only powers of two. */
+        let bytes = elf_file(ET_EXEC, 0, &[tls(1)], b"tdata");
+        let (tp, _) = setup_tls(&memory, &parse(&bytes), stack_top).unwrap().unwrap();
+        assert_eq!(tp as usize % TCB_SIZE, 0);
+        let bytes = elf_file(ET_EXEC, 0, &[tls(24)], b"tdata");
+        assert!(setup_tls(&memory, &parse(&bytes), stack_top).is_err());
+        let bytes = elf_file(ET_EXEC, 0, &[], b"");
+        assert!(setup_tls(&memory, &parse(&bytes), stack_top).unwrap().is_none());
+    }
+
+    /* argc, argv and envp as Linux lays them out. Too much for the stack fails cleanly,
+     * before anything is written below the guard. */
+    #[test]
+    fn stack() {
+        let memory = Memory::new(MemoryLayout::default());
+        let stack = memory.layout.stack();
+        let image = Image::default();
+        let argv: [&[u8]; 2] = [b"prog", b"\xffarg"];
+        let sp = setup_stack(&memory, &image, stack.end, 0, &argv, &[b"A=\xfe"]).unwrap();
+        assert_eq!(sp % 16, 0);
+        assert_eq!(memory.load_u64(sp).unwrap(), 2);
+        /* Arguments are bytes, whether they are UTF-8 or not. */
+        let arg1 = memory.load_u64(sp + 16).unwrap() as usize;
+        assert_eq!(memory.guest_cstr(arg1).unwrap().to_bytes(), b"\xffarg");
+        let env0 = memory.load_u64(sp + 32).unwrap() as usize;
+        assert_eq!(memory.guest_cstr(env0).unwrap().to_bytes(), b"A=\xfe");
+
+        let huge = vec![b'x'; stack.len()];
+        assert!(setup_stack(&memory, &image, stack.end, 0, &[b"prog", &huge], &[]).is_err());
+        assert!(setup_stack(&memory, &image, stack.start + 64, 0, &[b"prog"], &[]).is_err());
+    }
+}
@@ -1,11 +1,26 @@
 #![allow(clippy::just_underscores_and_digits)]
 #![allow(clippy::upper_case_acronyms)]
 
+mod clock;
 mod cpu;
 mod dbg;
+mod exec;
+mod fds;
+mod images;
 mod insts;
+mod loader;
+mod mem;
+mod net;
+mod policy;
+mod signals;
+mod strace;
 mod syms;
+mod sys;
+mod tar;
 mod tbs;
+mod threads;
+mod vfs;
+mod vma;
 
 use std::io::Write;
 
@@ -16,7 +31,36 @@ use clap::Parser;
 #[command(author, version, about)]
 struct Args {
     #[arg(short, long)]
-    file: String,
+    file: Option<String>,
+
+    /// Load an image (instead of --file) and run it, can be given multiple times. Raw
+    /// binaries need ADDR, for HEX/S-record files it is added to their addresses.
+    #[arg(long, value_name = "FILE[@ADDR]", value_parser = parse_load)]
+    load: Vec<LoadArg>,
+
+    /// Where the guest's clocks get their time from.
+    #[arg(long, value_enum, default_value_t = clock::ClockSource::Host)]
+    clock: clock::ClockSource,
+
+    /// Instructions per second of the virtual clock (`--clock=virtual`).
+    #[arg(long, value_name = "HZ", default_value_t = 1_000_000_000)]
+    clock_freq: u64,
+
+    /// Print the memory mappings of the guest (like /proc/self/maps) when it exits.
+    #[arg(long)]
+    maps: bool,
+
+    /// Format of the --load images (default: guessed from contents and extension).
+    #[arg(long, value_enum)]
+    format: Option<images::Format>,
+
+    /// Where to start executing the --load images (default: entry of the first image).
+    #[arg(long, value_parser = parse_size)]
+    entry: Option<usize>,
+
+    /// ELF file with the symbols of the --load images.
+    #[arg(long, value_name = "ELF")]
+    symbols: Option<std::path::PathBuf>,
 
     #[arg(short, long)]
     dump: bool,
@@ -33,14 +77,169 @@ struct Args {
     #[arg(short, long)]
     tb_stats: bool,
 
+    /// How to handle misaligned loads/stores of the guest.
+    #[arg(long, value_enum, default_value_t = cpu::MisalignedPolicy::Allow)]
+    misaligned: cpu::MisalignedPolicy,
+
+    /// Trace syscalls like strace, optionally only some (e.g. `openat,read` or `%file`).
+    #[arg(long, value_name = "FILTER", num_args = 0..=1, require_equals = true,
+          default_missing_value = "all")]
+    strace: Option<strace::Filter>,
+
+    /// Write the syscall trace to a file instead of stderr.
+    #[arg(long, value_name = "FILE", requires = "strace")]
+    strace_output: Option<std::path::PathBuf>,
+
+    /// What to do when the guest does a syscall the simulator does not implement.
+    #[arg(long, value_enum, default_value_t = sys::UnknownSyscallPolicy::Warn)]
+    unknown_syscalls: sys::UnknownSyscallPolicy,
+
+    /// What the guest may do with sockets.
+    #[arg(long, value_enum, default_value_t = net::NetworkPolicy::Host)]
+    network: net::NetworkPolicy,
+
+    /// What execve() of the guest does with executables that are not RISC-V ones.
+    #[arg(long, value_enum, default_value_t = exec::HostExecPolicy::Host)]
+    exec_host: exec::HostExecPolicy,
+
+    /// Directory with the RISC-V dynamic linker and shared libraries.
+    #[arg(short = 'L', long)]
+    sysroot: Option<std::path::PathBuf>,
+
+    /// Mount a host directory for the guest, read-only unless `:rw` is given. With mounts,
+    /// the guest sees nothing of the host's file system but them (and host binaries cannot
+    /// be executed).
+    #[arg(long, value_name = "GUEST=DIR[:ro|:rw]", value_parser = vfs::parse_mount)]
+    mount: Vec<vfs::MountSpec>,
+
+    /// Mount the contents of a tar archive (read-only, kept in memory) for the guest.
+    #[arg(long, value_name = "GUEST=ARCHIVE", value_parser = vfs::parse_mount_tar)]
+    mount_tar: Vec<vfs::MountSpec>,
+
+    /// Working directory of the guest with mounts.
+    #[arg(long, value_name = "GUEST_DIR", default_value = "/")]
+    cwd: String,
+
+    /// Allow, deny or log syscalls (names or classes like for --strace), can be given
+    /// multiple times: `--policy all=deny --policy %memory,read,write=allow`. The last rule
+    /// that matches a syscall wins.
+    #[arg(long, value_name = "FILTER=ACTION")]
+    policy: Vec<policy::Rule>,
+
+    /// Set an environment variable for the guest.
+    #[arg(short = 'E', value_name = "KEY=VAL")]
+    set_env: Vec<String>,
+
+    /// Remove an environment variable from the guest's environment.
+    #[arg(short = 'U', value_name = "KEY")]
+    unset_env: Vec<String>,
+
+    /// Do not pass the simulator's environment on to the guest.
+    #[arg(long)]
+    clear_env: bool,
+
+    /// Size of the guest address space (e.g. `64M`).
+    #[arg(long, value_parser = parse_size)]
+    memory_size: Option<usize>,
+
+    /// Highest address of the guest stack (default: end of the address space).
+    #[arg(long, value_parser = parse_size)]
+    stack_top: Option<usize>,
+
+    /// Size of the guest stack (e.g. `8M`).
+    #[arg(long, value_parser = parse_size)]
+    stack_size: Option<usize>,
+
+    /// Size of the unmapped region below the stack.
+    #[arg(long, value_parser = parse_size)]
+    stack_guard_size: Option<usize>,
+
+    /// Maximum size of the heap (program break) of the guest.
+    #[arg(long, value_parser = parse_size)]
+    heap_size: Option<usize>,
+
     args: Vec<String>,
 }
 
-fn dump_hottest_tbs(elf_file: &elf::ElfBytes<'_, elf::endian::AnyEndian>, jit: &tbs::JIT) {
-    let _ = elf_file;
+/* Parses sizes and addresses like `4096`, `0x1000`, `64K`, `8M` or `1G`. */
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (num, shift) = match s.chars().last() {
+        Some('k' | 'K') => (&s[..s.len() - 1], 10),
+        Some('m' | 'M') => (&s[..s.len() - 1], 20),
+        Some('g' | 'G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let val = match num.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => num.parse::<usize>(),
+    }
+    .map_err(|e| format!("invalid size {:?}: {}", s, e))?;
+    val.checked_shl(shift)
+        .filter(|v| v >> shift == val)
+        .ok_or_else(|| format!("size {:?} too large", s))
+}
+
+#[derive(Debug, Clone)]
+struct LoadArg {
+    path: std::path::PathBuf,
+    addr: Option<u64>,
+}
+
+fn parse_load(s: &str) -> Result<LoadArg, String> {
+    match s.rsplit_once('@') {
+        Some((path, addr)) => Ok(LoadArg {
+            path: path.into(),
+            addr: Some(parse_size(addr)? as u64),
+        }),
+        None => Ok(LoadArg {
+            path: s.into(),
+            addr: None,
+        }),
+    }
+}
+
+fn memory_layout(args: &Args) -> mem::MemoryLayout {
+    let mut layout = mem::MemoryLayout::default();
+    if let Some(size) = args.memory_size {
+        layout.memory_size = size;
+    }
+    if let Some(top) = args.stack_top {
+        layout.stack_top = Some(top);
+    }
+    if let Some(size) = args.stack_size {
+        layout.stack_size = size;
+    }
+    if let Some(size) = args.stack_guard_size {
+        layout.stack_guard_size = size;
+    }
+    if let Some(size) = args.heap_size {
+        layout.heap_size = size;
+    }
+    layout
+}
+
+/* The guest inherits the environment of the simulator, modified by -E/-U/--clear-env. */
+fn guest_env(args: &Args) -> Vec<String> {
+    let mut env: Vec<(String, String)> = if args.clear_env {
+        Vec::new()
+    } else {
+        std::env::vars_os()
+            .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+            .collect()
+    };
+    env.retain(|(k, _)| !args.unset_env.contains(k));
+    for kv in &args.set_env {
+        let (k, v) = kv.split_once('=').unwrap_or((kv.as_str(), ""));
+        env.retain(|(k2, _)| k2 != k);
+        env.push((k.to_string(), v.to_string()));
+    }
+    env.into_iter().map(|(k, v)| format!("{}={}", k, v)).collect()
+}
+
+fn dump_hottest_tbs(jit: &tbs::JIT) {
     const MIN_TB_FREQ: i64 = 5;
-    let mut tbs = jit
-        .tbs
+    let all = jit.tbs.read().unwrap();
+    let mut tbs = all
         .values()
         .filter_map(|tb| {
             let freq = tb.exec_count.load(std::sync::atomic::Ordering::Relaxed);
@@ -54,7 +253,7 @@ fn dump_hottest_tbs(elf_file: &elf::ElfBytes<'_, elf::endian::AnyEndian>, jit: &
     tbs.sort_by(|(f1, _), (f2, _)| f2.cmp(f1));
     eprintln!(
         "[simrv64i] JIT: TBs, #total={}, #freq-above-{}={}",
-        jit.tbs.len(),
+        all.len(),
         MIN_TB_FREQ,
         tbs.len()
     );
@@ -66,25 +265,113 @@ fn dump_hottest_tbs(elf_file: &elf::ElfBytes<'_, elf::endian::AnyEndian>, jit: &
     }
 }
 
-fn execute(args: &Args, elf_file: elf::ElfBytes<'_, elf::endian::AnyEndian>, _: &Vec<u8>) {
-    let _ = args;
-    let mut cpu = cpu::CPU::new(args.jit);
+fn dump_misaligned_accesses(
+    elf_file: Option<&elf::ElfBytes<'_, elf::endian::AnyEndian>>,
+    cpu: &cpu::CPU,
+) {
+    let symbols = elf_file.and_then(|elf_file| {
+        syms::SymbolTreeNode::build(&syms::get_symbols(elf_file, cpu.load_bias, cpu.tls_base))
+    });
+    let accesses = cpu.misaligned_accesses.lock().unwrap();
+    let mut accesses = accesses.iter().collect::<Vec<_>>();
+    accesses.sort_by(|(pc1, n1), (pc2, n2)| n2.cmp(n1).then(pc1.cmp(pc2)));
+    eprintln!(
+        "[simrv64i] misaligned accesses: #total={}, #instructions={}",
+        accesses.iter().map(|(_, n)| **n).sum::<u64>(),
+        accesses.len()
+    );
+    for (pc, n) in accesses {
+        let sym = symbols
+            .as_ref()
+            .and_then(|s| s.lookup(*pc))
+            .map(|(name, start)| format!(" <{}+{:#x}>", name, pc - start))
+            .unwrap_or_default();
+        eprintln!("[simrv64i] misaligned: {:#08x?}{}: {}", pc, sym, n);
+    }
+}
+
+fn new_cpu(args: &Args) -> cpu::CPU {
+    let layout = memory_layout(args);
+    if let Err(e) = layout.validate() {
+        eprintln!("[simrv64i]: invalid memory layout: {:?}", e);
+        std::process::exit(1);
+    }
+
+    let mut cpu = cpu::CPU::with_layout(args.jit, layout);
+    cpu.misaligned = args.misaligned;
+    cpu.sysroot = args.sysroot.clone();
+    cpu.network = args.network;
+    cpu.host_exec = args.exec_host;
+    cpu.syscalls.unknown = args.unknown_syscalls;
+    cpu.syscalls.policy = policy::Policy::new(args.policy.clone());
+    cpu.clock.source = args.clock;
+    cpu.clock.freq_hz = std::cmp::max(args.clock_freq, 1);
+    signals::install_host_handlers(&mut cpu.signals);
+
+    let mounts = [args.mount.as_slice(), args.mount_tar.as_slice()].concat();
+    if !mounts.is_empty() {
+        if args.sysroot.is_some() {
+            eprintln!("[simrv64i]: --sysroot does not work with mounts, mount it at / instead");
+            std::process::exit(1);
+        }
+        let vfs = vfs::Vfs::new(&mounts).unwrap_or_else(|e| {
+            eprintln!("[simrv64i]: {}", e);
+            std::process::exit(1);
+        });
+        let cwd = vfs::normalize(b"/", args.cwd.as_bytes());
+        if let Err(e) = vfs.chdir(&cwd) {
+            eprintln!("[simrv64i]: invalid --cwd {:?}: {}", args.cwd, e);
+            std::process::exit(1);
+        }
+        cpu.vfs = Some(std::sync::Arc::new(vfs));
+    }
 
-    /* Avoid that the guest closes stderr. */
-    let stderr_dupped = unsafe { libc::dup(2) };
-    assert!(stderr_dupped != -1);
-    cpu.remapped_filenos.insert(2, stderr_dupped as usize);
+    if let Some(filter) = &args.strace {
+        let out: Box<dyn Write + Send> = match &args.strace_output {
+            Some(path) => match std::fs::File::create(path) {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    eprintln!("[simrv64i]: error creating {:?}: {}", path, e);
+                    std::process::exit(1);
+                }
+            },
+            None => Box::new(std::io::stderr()),
+        };
+        cpu.strace = Some(strace::Strace::new(out, filter.clone()));
+    }
+    cpu
+}
 
-    let mut argv: Vec<&str> = args.args.iter().map(|s| s.as_str()).collect();
-    argv.insert(0, args.file.as_str());
+fn finish(
+    args: &Args,
+    cpu: &cpu::CPU,
+    elf_file: Option<&elf::ElfBytes<'_, elf::endian::AnyEndian>>,
+    res: Result<(i32, std::sync::Arc<tbs::JIT>), insts::Error>,
+) -> ! {
+    if cpu.misaligned == cpu::MisalignedPolicy::Count {
+        dump_misaligned_accesses(elf_file, cpu);
+    }
+    if args.maps {
+        eprintln!("[simrv64i] memory mappings:");
+        cpu.memory
+            .mm()
+            .dump_maps(&mut std::io::stderr())
+            .expect("I/O error");
+    }
 
-    match cpu.load_and_exec(&elf_file, Some(argv)) {
+    match res {
         Ok((exitcode, jit)) => {
             if args.tb_stats {
-                dump_hottest_tbs(&elf_file, &jit);
+                dump_hottest_tbs(&jit);
             }
             std::process::exit(exitcode);
         }
+        Err(insts::Error::Signal(sig)) => {
+            if [signals::SIGILL, signals::SIGBUS, signals::SIGSEGV].contains(&sig) {
+                eprintln!("[simrv64i] killed by signal {} (pc={:#08x?})", sig, cpu.pc);
+            }
+            signals::exit_by_signal(sig);
+        }
         Err(e) => {
             eprintln!("[simrv64i]: error(pc={:#08x?}): {:?}", cpu.pc, e);
             std::process::exit(1);
@@ -92,6 +379,132 @@ fn execute(args: &Args, elf_file: elf::ElfBytes<'_, elf::endian::AnyEndian>, _:
     }
 }
 
+fn execute(args: &Args, file: &str, elf_file: elf::ElfBytes<'_, elf::endian::AnyEndian>) -> ! {
+    let mut cpu = new_cpu(args);
+    cpu.exe = std::fs::canonicalize(file).ok();
+
+    let mut argv: Vec<&[u8]> = args.args.iter().map(|s| s.as_bytes()).collect();
+    argv.insert(0, file.as_bytes());
+
+    let envp = guest_env(args);
+    let envp: Vec<&[u8]> = envp.iter().map(|s| s.as_bytes()).collect();
+
+    let res = cpu.load_and_exec(&elf_file, Some(argv), Some(envp));
+    finish(args, &cpu, Some(&elf_file), res)
+}
+
+fn read_file(path: &std::path::Path) -> Vec<u8> {
+    match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("[simrv64i]: error reading {:?}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_elf<'a>(path: &std::path::Path, bytes: &'a [u8]) -> elf::ElfBytes<'a, elf::endian::AnyEndian> {
+    match elf::ElfBytes::minimal_parse(bytes) {
+        Ok(elf_file) => elf_file,
+        Err(e) => {
+            eprintln!("[simrv64i]: error parsing ELF file {:?}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/* Load one --load image, returns where its parts ended up and its start address. */
+fn load_image(
+    args: &Args,
+    cpu: &mut cpu::CPU,
+    load: &LoadArg,
+) -> Result<(Vec<loader::Image>, Option<u64>), insts::Error> {
+    let bytes = read_file(&load.path);
+    let format = args
+        .format
+        .unwrap_or_else(|| images::detect_format(&load.path, &bytes));
+    let image = match format {
+        images::Format::Elf => {
+            let elf_file = parse_elf(&load.path, &bytes);
+            let image = cpu.load_elf_image(&elf_file, load.addr)?;
+            let entry = image.entry;
+            return Ok((vec![image], Some(entry)));
+        }
+        images::Format::Raw => {
+            let addr = load.addr.ok_or_else(|| {
+                insts::Error::Image("raw images need an address (FILE@ADDR)".to_string())
+            })?;
+            images::Image {
+                chunks: vec![images::Chunk { addr, data: bytes }],
+                entry: Some(addr),
+            }
+        }
+        images::Format::IHex | images::Format::SRec => {
+            let text = String::from_utf8_lossy(&bytes);
+            let mut image = if format == images::Format::IHex {
+                images::parse_ihex(&text)
+            } else {
+                images::parse_srec(&text)
+            }?;
+            let offset = load.addr.unwrap_or(0);
+            for chunk in &mut image.chunks {
+                chunk.addr = chunk.addr.wrapping_add(offset);
+            }
+            image.entry = image
+                .entry
+                .or(image.chunks.first().map(|chunk| chunk.addr))
+                .map(|entry| entry.wrapping_add(offset));
+            image
+        }
+    };
+
+    let loaded = image
+        .chunks
+        .iter()
+        .map(|chunk| loader::load_flat(&cpu.memory, chunk.addr, &chunk.data))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((loaded, image.entry))
+}
+
+fn execute_images(args: &Args) -> ! {
+    let mut cpu = new_cpu(args);
+    let mut loaded = Vec::new();
+    let mut entry = args.entry.map(|entry| entry as u64);
+    for load in &args.load {
+        match load_image(args, &mut cpu, load) {
+            Ok((images, image_entry)) => {
+                loaded.extend(images);
+                entry = entry.or(image_entry);
+            }
+            Err(e) => {
+                eprintln!("[simrv64i]: error loading {:?}: {:?}", load.path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let symbols_file = args.symbols.as_ref().map(|path| (path, read_file(path)));
+    let symbols_elf = symbols_file
+        .as_ref()
+        .map(|(path, bytes)| parse_elf(path, bytes));
+
+    let file = args.load[0].path.to_string_lossy();
+    let mut argv: Vec<&[u8]> = args.args.iter().map(|s| s.as_bytes()).collect();
+    argv.insert(0, file.as_bytes());
+
+    let envp = guest_env(args);
+    let envp: Vec<&[u8]> = envp.iter().map(|s| s.as_bytes()).collect();
+
+    let res = cpu.exec_images(
+        &loaded,
+        entry.unwrap_or(0),
+        symbols_elf.as_ref(),
+        Some(argv),
+        Some(envp),
+    );
+    finish(args, &cpu, symbols_elf.as_ref(), res)
+}
+
 fn dump_text_section(
     elf_file: elf::ElfBytes<'_, elf::endian::AnyEndian>,
     _: &Vec<u8>,
@@ -154,31 +567,29 @@ fn dump_text_section(
 
 fn main() {
     let args = Args::parse();
-    let path = std::path::PathBuf::from(&args.file);
-    let raw_file = match std::fs::read(path) {
-        Ok(bytes) => bytes,
-        Err(e) => {
-            eprintln!("[simrv64i]: error reading {:?}: {}", &args.file, e);
+    if !args.load.is_empty() {
+        execute_images(&args);
+    }
+
+    let file = match &args.file {
+        Some(file) => file.as_str(),
+        None => {
+            eprintln!("[simrv64i]: either --file or --load is required");
             std::process::exit(1);
         }
     };
+    let path = std::path::PathBuf::from(file);
+    let raw_file = read_file(&path);
+    let elf_file = parse_elf(&path, &raw_file);
 
-    let elf_file: elf::ElfBytes<'_, elf::endian::AnyEndian> =
-        match elf::ElfBytes::minimal_parse(raw_file.as_slice()) {
-            Ok(elf_file) => elf_file,
-            Err(e) => {
-                eprintln!("[simrv64i]: error parsing ELF file {:?}: {}", &args.file, e);
-                std::process::exit(1);
-            }
-        };
-
+    /* ET_DYN: Position-independent executables (e.g. static-pie). */
     if elf_file.ehdr.class != elf::file::Class::ELF64
-        || elf_file.ehdr.e_type != elf::abi::ET_EXEC
+        || !matches!(elf_file.ehdr.e_type, elf::abi::ET_EXEC | elf::abi::ET_DYN)
         || elf_file.ehdr.e_machine != elf::abi::EM_RISCV
     {
         eprintln!(
             "[simrv64i]: error processing ELF file {:?}: Not a RV64 executable",
-            &args.file
+            file
         );
         std::process::exit(1);
     }
@@ -189,22 +600,18 @@ fn main() {
     }
 
     if args.exec {
-        execute(&args, elf_file, &raw_file);
+        execute(&args, file, elf_file);
     }
 }
 
 #[cfg(test)]
 mod test {
     use std::io::{Read, Write};
-    use std::os::fd::FromRawFd;
+    use std::os::fd::{FromRawFd, OwnedFd};
     use std::path::PathBuf;
 
-    fn run_example(
-        filename: &str,
-        argv: Option<Vec<&str>>,
-        stdin: Option<&[u8]>,
-        jit_enabled: bool,
-    ) -> (String, i32) {
+    /* Read an example binary, building it first if necessary. */
+    fn example_binary(filename: &str) -> Vec<u8> {
         let mut filepath = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         filepath.push(filename);
 
@@ -217,21 +624,27 @@ mod test {
             assert_eq!(unsafe { libc::system(cmd.as_ptr() as *const i8) }, 0);
         }
 
-        let binary_file = std::fs::read(filepath).unwrap();
+        std::fs::read(filepath).unwrap()
+    }
+
+    fn run_example(
+        filename: &str,
+        argv: Option<Vec<&str>>,
+        stdin: Option<&[u8]>,
+        jit_enabled: bool,
+    ) -> (String, i32) {
+        let binary_file = example_binary(filename);
         let elf_file =
             elf::ElfBytes::<'_, elf::endian::AnyEndian>::minimal_parse(&binary_file).unwrap();
         assert!(
             elf_file.ehdr.class == elf::file::Class::ELF64
-                && elf_file.ehdr.e_type == elf::abi::ET_EXEC
+                && matches!(elf_file.ehdr.e_type, elf::abi::ET_EXEC | elf::abi::ET_DYN)
                 && elf_file.ehdr.e_machine == elf::abi::EM_RISCV
         );
 
         let mut cpu = crate::cpu::CPU::new(jit_enabled);
-
-        /* Avoid that the guest closes stderr. */
-        let stderr_dupped = unsafe { libc::dup(2) };
-        assert!(stderr_dupped != -1);
-        cpu.remapped_filenos.insert(2, stderr_dupped as usize);
+        cpu.exe = std::fs::canonicalize(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(filename))
+            .ok();
 
         /* Redirect stdout to a pipe so that we can capture it.
          * Note that if the guest writes more than the kernel is willing
@@ -239,14 +652,14 @@ mod test {
          * pipe in a parallel thread? */
         let mut stdout_pipe: [i32; 2] = [-1, -1];
         assert!(0 == unsafe { libc::pipe(stdout_pipe.as_mut_ptr()) });
-        cpu.remapped_filenos
-            .insert(/*stdout:*/ 1, stdout_pipe[1] as usize);
+        cpu.fds
+            .install(/*stdout:*/ 1, unsafe { OwnedFd::from_raw_fd(stdout_pipe[1]) });
 
         if let Some(stdin) = stdin {
             let mut stdin_pipe: [i32; 2] = [-1, -1];
             assert!(0 == unsafe { libc::pipe(stdin_pipe.as_mut_ptr()) });
-            cpu.remapped_filenos
-                .insert(/*stdin:*/ 0, stdin_pipe[0] as usize);
+            cpu.fds
+                .install(/*stdin:*/ 0, unsafe { OwnedFd::from_raw_fd(stdin_pipe[0]) });
 
             /* If the input is larger than what the kernel is willing to buffer in
              * the kernel, then this will block and the test will never finish.
@@ -256,7 +669,10 @@ mod test {
             example_stdin_file.write_all(stdin).unwrap();
         }
 
-        let (exitcode, _) = cpu.load_and_exec(&elf_file, argv).unwrap();
+        let argv = argv.map(|argv| argv.iter().map(|s| s.as_bytes()).collect());
+        let (exitcode, _) = cpu.load_and_exec(&elf_file, argv, None).unwrap();
+        /* Closes the write end of the pipe (unless the guest did). */
+        drop(cpu);
 
         let mut example_stdout_file = unsafe { std::fs::File::from_raw_fd(stdout_pipe[0]) };
         let mut example_stdout = String::new();
@@ -278,6 +694,46 @@ mod test {
         );
     }
 
+    #[test]
+    fn example_hello_world_custom_write() {
+        let binary_file = example_binary("./examples/hello-world.elf");
+        let elf_file =
+            elf::ElfBytes::<'_, elf::endian::AnyEndian>::minimal_parse(&binary_file).unwrap();
+
+        /* Capture stdout via a replaced `write` instead of a pipe. */
+        let output = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let captured = output.clone();
+        let mut cpu = crate::cpu::CPU::new(false);
+        cpu.syscalls.register(
+            crate::sys::SYS_WRITE,
+            "write",
+            &[],
+            move |cpu, [fd, buf, len, ..]| {
+                assert_eq!(fd, 1);
+                Ok(cpu.memory.guest_slice(buf, len).map(|buf| {
+                    captured.lock().unwrap().extend_from_slice(buf);
+                    buf.len()
+                }))
+            },
+        );
+
+        let (exitcode, _) = cpu
+            .load_and_exec(&elf_file, Some(vec![b"hello-world.elf".as_slice()]), None)
+            .unwrap();
+        assert_eq!(exitcode, 42);
+        assert_eq!(
+            output.lock().unwrap().as_slice(),
+            b"Hello, World! (argc=1)\nargv[0] = 'hello-world.elf'\n"
+        );
+    }
+
+    #[test]
+    fn example_malloc() {
+        let (stdout, exitcode) = run_example("./examples/malloc.elf", None, None, false);
+        assert_eq!(exitcode, 0);
+        assert_eq!(stdout.as_str(), "allocated 32 MiB in total\n");
+    }
+
     #[test]
     fn example_bubblesort() {
         let input = "8\n3\n5\n6\n9\n1\n4\n2\n7\n";
@@ -306,4 +762,30 @@ mod test {
         assert_eq!(exitcode, 0);
         assert_eq!(stdout.as_str(), "#solutions: 92 (grid_size=8)\n");
     }
+
+    /* Static glibc and musl binaries, with everything their startup code does. */
+    fn check_hello_libc(filename: &str) {
+        let argv = vec![filename, "foo"];
+        let path = format!("./examples/{}", filename);
+        let (stdout, exitcode) = run_example(&path, Some(argv), None, false);
+        assert_eq!(exitcode, 0);
+        assert_eq!(
+            stdout,
+            format!(
+                "Hello, World! (argc=2)\nmachine: riscv64\nexe: {}\nread 3 lines\n\
+                 malloc: 2055 pages\n",
+                filename
+            )
+        );
+    }
+
+    #[test]
+    fn example_hello_glibc() {
+        check_hello_libc("hello-libc.glibc.elf");
+    }
+
+    #[test]
+    fn example_hello_musl() {
+        check_hello_libc("hello-libc.musl.elf");
+    }
 }
@@ -0,0 +1,848 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU16, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::insts::Error;
+use crate::vma::{Vma, VmaList};
+use syscalls::Errno;
+
+pub const PAGE_SIZE: usize = 4096;
+
+/* Longest C string (e.g. a path) syscalls will read from the guest, including the NUL. */
+pub const MAX_GUEST_CSTR_LEN: usize = 4096;
+
+pub const PROT_READ: u8 = 0b001;
+pub const PROT_WRITE: u8 = 0b010;
+pub const PROT_EXEC: u8 = 0b100;
+
+pub fn is_page_aligned(addr: usize) -> bool {
+    addr & (PAGE_SIZE - 1) == 0
+}
+
+/* None if there is no such page, e.g. for lengths from the guest close to usize::MAX. */
+pub fn page_align_up(addr: usize) -> Option<usize> {
+    addr.div_ceil(PAGE_SIZE).checked_mul(PAGE_SIZE)
+}
+
+/*
+ * Layout of the guest address space (addresses grow downwards here):
+ *
+ *   memory_size  +-----------------------+
+ *                |         ...           |
+ *   stack_top    +-----------------------+
+ *                | stack (stack_size)    |
+ *                +-----------------------+
+ *                | guard (unmapped)      |
+ *                +-----------------------+
+ *                | mmap() (top-down)     |
+ *                |   (free)              |
+ *   heap.end     +-----------------------+
+ *                | heap (heap_size)      |
+ *   heap.start   +-----------------------+ <- end of the loaded image
+ *                | .text/.data/.bss/...  |
+ *   0x0          +-----------------------+
+ */
+#[derive(Debug, Clone)]
+pub struct MemoryLayout {
+    /// Size of the guest address space, guest addresses are offsets into the host buffer.
+    pub memory_size: usize,
+    /// Highest address of the stack, defaults to the end of the address space.
+    pub stack_top: Option<usize>,
+    pub stack_size: usize,
+    /// Size of the unmapped region right below the stack.
+    pub stack_guard_size: usize,
+    /// Maximum size of the heap that starts right after the loaded image.
+    pub heap_size: usize,
+    /// Where position-independent executables get loaded.
+    pub pie_base: usize,
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        Self {
+            memory_size: 64 << 20,
+            stack_top: None,
+            stack_size: 8 << 20,
+            stack_guard_size: 64 << 10,
+            heap_size: 16 << 20,
+            pie_base: 0x10000,
+        }
+    }
+}
+
+impl MemoryLayout {
+    pub fn stack_top(&self) -> usize {
+        self.stack_top.unwrap_or(self.memory_size)
+    }
+
+    pub fn stack(&self) -> std::ops::Range<usize> {
+        let top = self.stack_top();
+        top.saturating_sub(self.stack_size)..top
+    }
+
+    pub fn stack_guard(&self) -> std::ops::Range<usize> {
+        let stack = self.stack();
+        stack.start.saturating_sub(self.stack_guard_size)..stack.start
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let top = self.stack_top();
+        if ![self.memory_size, top, self.stack_size, self.stack_guard_size]
+                .into_iter().all(is_page_aligned) {
+            return Err(Error::Layout(format!("{:x?}: not page aligned", self)))
+        }
+        if top > self.memory_size || self.stack_size + self.stack_guard_size > top {
+            return Err(Error::Layout(format!("{:x?}: stack does not fit", self)))
+        }
+        Ok(())
+    }
+}
+
+/*
+ * The memory of a guest, shared by all of its harts (threads). Like on real hardware,
+ * they access it concurrently without any locking: `data` never moves and aligned
+ * accesses are atomic, races beyond that are the guest's business. The page permissions
+ * are atomics, so that they can be checked while another hart mprotect()s. Everything
+ * else about the mappings (the VMAs, brk(), ...) is behind a lock, mm().
+ */
+pub struct Memory {
+    data: Box<[UnsafeCell<u8>]>,
+    /* Protection bits per page, 0 means unmapped. Only checked by the
+     * interpreter, JIT-ed TBs access `data` directly. Only changed with mm held. */
+    perms: Box<[AtomicU8]>,
+    pub layout: MemoryLayout,
+    mm: Mutex<Mappings>,
+}
+
+/* See above, `data` is only accessed through raw pointers or atomics. */
+unsafe impl Sync for Memory {}
+
+pub struct Mappings {
+    pub heap: std::ops::Range<usize>,
+    /// Current program break, starts at heap.start.
+    pub brk: usize,
+    /// What was mapped by mmap().
+    pub vmas: VmaList,
+}
+
+/* The mappings can only be looked at or changed with this, see Memory::mm(). */
+pub struct MmGuard<'a> {
+    memory: &'a Memory,
+    mappings: MutexGuard<'a, Mappings>,
+}
+
+/* Where mmap() should put a mapping. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Placement {
+    /// Anywhere, preferably at the given address.
+    Hint(usize),
+    /// MAP_FIXED: Exactly there, replacing whatever was mapped.
+    Fixed(usize),
+    /// MAP_FIXED_NOREPLACE: Exactly there, if nothing is mapped there yet.
+    FixedNoReplace(usize),
+}
+
+impl Memory {
+    pub fn new(layout: MemoryLayout) -> Memory {
+        /* vec![0; n] ends up as calloc(), so untouched pages cost nothing. */
+        let data = Box::into_raw(vec![0x0u8; layout.memory_size].into_boxed_slice());
+        let data = unsafe { Box::from_raw(data as *mut [UnsafeCell<u8>]) };
+        let perms = (0..(layout.memory_size / PAGE_SIZE)).map(|_| AtomicU8::new(0)).collect();
+        let stack = layout.stack();
+        let mappings = Mappings { heap: 0..0, brk: 0, vmas: VmaList::default() };
+        let memory = Self { data, perms, layout, mm: Mutex::new(mappings) };
+        memory.set_prot(stack.start, stack.len(), PROT_READ | PROT_WRITE);
+        memory
+    }
+
+    /* Serializes changes to the mappings (mmap(), brk(), ...). */
+    pub fn mm(&self) -> MmGuard<'_> {
+        let mappings = self.mm.lock().unwrap_or_else(|e| e.into_inner());
+        MmGuard { memory: self, mappings }
+    }
+
+    fn ptr(&self, addr: usize) -> *mut u8 {
+        UnsafeCell::raw_get(self.data.as_ptr()).wrapping_add(addr)
+    }
+
+    fn byte(&self, addr: usize) -> u8 {
+        unsafe { *self.ptr(addr) }
+    }
+
+    fn set_byte(&self, addr: usize, val: u8) {
+        unsafe { *self.ptr(addr) = val }
+    }
+
+    fn fill_zero(&self, start: usize, end: usize) {
+        unsafe { std::ptr::write_bytes(self.ptr(start), 0, end - start) }
+    }
+
+    fn prot(&self, addr: usize) -> u8 {
+        self.perms[addr / PAGE_SIZE].load(Ordering::Relaxed)
+    }
+
+    /* Callers hold mm. */
+    fn set_prot(&self, addr: usize, len: usize, prot: u8) {
+        let (first, last) = (addr / PAGE_SIZE, (addr + len).div_ceil(PAGE_SIZE));
+        for perms in &self.perms[first..last] {
+            perms.store(prot, Ordering::Relaxed);
+        }
+    }
+
+    fn check(&self, addr: usize, len: usize, prot: u8) -> Result<(), Error> {
+        let end = match addr.checked_add(len) {
+            Some(end) if end <= self.data.len() => end,
+            _ => return Err(Error::SegFault(addr))
+        };
+        for page in (addr / PAGE_SIZE)..=((end - 1) / PAGE_SIZE) {
+            if self.perms[page].load(Ordering::Relaxed) & prot != prot {
+                return Err(Error::SegFault(std::cmp::max(addr, page * PAGE_SIZE)))
+            }
+        }
+        Ok(())
+    }
+
+    /* Base pointer of the guest memory for JIT-ed code. */
+    pub fn host_ptr(&self) -> *mut u8 {
+        self.ptr(0)
+    }
+
+    /*
+     * Access to guest buffers on behalf of syscalls. Everything the host kernel
+     * reads or writes must go through these, failures are reported as errno
+     * values so that they can be returned to the guest (EFAULT, like Linux does).
+     */
+    pub fn guest_slice(&self, addr: usize, len: usize) -> Result<&[u8], Errno> {
+        if len == 0 {
+            return Ok(&[])
+        }
+        self.check(addr, len, PROT_READ).map_err(|_| Errno::EFAULT)?;
+        Ok(unsafe { std::slice::from_raw_parts(self.ptr(addr), len) })
+    }
+
+    /* Other harts may write to the same buffer meanwhile, just like the host kernel
+     * would race with them. */
+    #[allow(clippy::mut_from_ref)]
+    pub fn guest_slice_mut(&self, addr: usize, len: usize) -> Result<&mut [u8], Errno> {
+        if len == 0 {
+            return Ok(&mut [])
+        }
+        self.check(addr, len, PROT_WRITE).map_err(|_| Errno::EFAULT)?;
+        Ok(unsafe { std::slice::from_raw_parts_mut(self.ptr(addr), len) })
+    }
+
+    pub fn guest_cstr(&self, addr: usize) -> Result<&std::ffi::CStr, Errno> {
+        /* Within the guest memory, none of the additions below can overflow. */
+        if addr >= self.data.len() {
+            return Err(Errno::EFAULT)
+        }
+        let mut pos = addr;
+        while pos - addr < MAX_GUEST_CSTR_LEN {
+            /* Check page by page, the string may end right before unmapped memory. */
+            let chunk_end = std::cmp::min((pos / PAGE_SIZE + 1) * PAGE_SIZE,
+                                          addr + MAX_GUEST_CSTR_LEN);
+            self.check(pos, chunk_end - pos, PROT_READ).map_err(|_| Errno::EFAULT)?;
+            if let Some(nul) = (pos..chunk_end).position(|addr| self.byte(addr) == 0) {
+                let bytes = self.guest_slice(addr, pos + nul + 1 - addr)?;
+                return Ok(std::ffi::CStr::from_bytes_with_nul(bytes).unwrap())
+            }
+            pos = chunk_end;
+        }
+        Err(Errno::ENAMETOOLONG)
+    }
+
+    pub fn copy_bulk(&self, addr: u64, src: &[u8]) -> Result<(), Error> {
+        let addr = addr as usize;
+        if src.len() > self.data.len() || addr > self.data.len() - src.len() {
+            return Err(Error::SegFault(addr))
+        }
+        unsafe { std::ptr::copy_nonoverlapping(src.as_ptr(), self.ptr(addr), src.len()) };
+        Ok(())
+    }
+
+    pub fn zero_bulk(&self, addr: usize, len: usize) -> Result<(), Error> {
+        if len > self.data.len() || addr > self.data.len() - len {
+            return Err(Error::SegFault(addr))
+        }
+        self.fill_zero(addr, addr + len);
+        Ok(())
+    }
+
+    /* Instruction fetch: Only read the upper half if it is not a compressed
+     * instruction, it might be in the next (unmapped) page otherwise. */
+    pub fn fetch_inst(&self, addr: usize) -> Result<u32, Error> {
+        self.check(addr, 2, PROT_EXEC)?;
+        let lower = self.byte(addr) as u32 | ((self.byte(addr + 1) as u32) << 8);
+        if lower & 0b11 != 0b11 {
+            return Ok(lower)
+        }
+        self.check(addr + 2, 2, PROT_EXEC)?;
+        Ok(lower | ((self.byte(addr + 2) as u32) << 16) | ((self.byte(addr + 3) as u32) << 24))
+    }
+
+    pub fn load_u8(&self, addr: usize) -> Result<u8, Error> {
+        self.check(addr, 1, PROT_READ)?;
+        Ok(self.byte(addr))
+    }
+
+    pub fn load_u16(&self, addr: usize) -> Result<u16, Error> {
+        self.check(addr, 2, PROT_READ)?;
+        if addr.is_multiple_of(2) {
+            return Ok(unsafe { AtomicU16::from_ptr(self.aligned(addr)) }.load(Ordering::Relaxed))
+        }
+        Ok(self.byte(addr) as u16 |
+           ((self.byte(addr + 1) as u16) << 8))
+    }
+
+    pub fn load_u32(&self, addr: usize) -> Result<u32, Error> {
+        self.check(addr, 4, PROT_READ)?;
+        if addr.is_multiple_of(4) {
+            return Ok(unsafe { AtomicU32::from_ptr(self.aligned(addr)) }.load(Ordering::Relaxed))
+        }
+        Ok(self.byte(addr) as u32 |
+           ((self.byte(addr + 1) as u32) << 8) |
+           ((self.byte(addr + 2) as u32) << 16) |
+           ((self.byte(addr + 3) as u32) << 24))
+    }
+
+    pub fn load_u64(&self, addr: usize) -> Result<u64, Error> {
+        self.check(addr, 8, PROT_READ)?;
+        if addr.is_multiple_of(8) {
+            return Ok(unsafe { AtomicU64::from_ptr(self.aligned(addr)) }.load(Ordering::Relaxed))
+        }
+        Ok(self.byte(addr) as u64 |
+           ((self.byte(addr + 1) as u64) << 8) |
+           ((self.byte(addr + 2) as u64) << 16) |
+           ((self.byte(addr + 3) as u64) << 24) |
+           ((self.byte(addr + 4) as u64) << 32) |
+           ((self.byte(addr + 5) as u64) << 40) |
+           ((self.byte(addr + 6) as u64) << 48) |
+           ((self.byte(addr + 7) as u64) << 56))
+    }
+
+    pub fn store_u8(&self, addr: usize, val: u8) -> Result<(), Error> {
+        self.check(addr, 1, PROT_WRITE)?;
+        self.set_byte(addr, val);
+        Ok(())
+    }
+
+    pub fn store_u16(&self, addr: usize, val: u16) -> Result<(), Error> {
+        self.check(addr, 2, PROT_WRITE)?;
+        if addr.is_multiple_of(2) {
+            unsafe { AtomicU16::from_ptr(self.aligned(addr)) }.store(val, Ordering::Relaxed);
+            return Ok(())
+        }
+        self.set_byte(addr, (val & 0xff) as u8);
+        self.set_byte(addr + 1, ((val >> 8) & 0xff) as u8);
+        Ok(())
+    }
+
+    pub fn store_u32(&self, addr: usize, val: u32) -> Result<(), Error> {
+        self.check(addr, 4, PROT_WRITE)?;
+        if addr.is_multiple_of(4) {
+            unsafe { AtomicU32::from_ptr(self.aligned(addr)) }.store(val, Ordering::Relaxed);
+            return Ok(())
+        }
+        self.set_byte(addr, (val & 0xff) as u8);
+        self.set_byte(addr + 1, ((val >> 8) & 0xff) as u8);
+        self.set_byte(addr + 2, ((val >> 16) & 0xff) as u8);
+        self.set_byte(addr + 3, ((val >> 24) & 0xff) as u8);
+        Ok(())
+    }
+
+    pub fn store_u64(&self, addr: usize, val: u64) -> Result<(), Error> {
+        self.check(addr, 8, PROT_WRITE)?;
+        if addr.is_multiple_of(8) {
+            unsafe { AtomicU64::from_ptr(self.aligned(addr)) }.store(val, Ordering::Relaxed);
+            return Ok(())
+        }
+        self.set_byte(addr, (val & 0xff) as u8);
+        self.set_byte(addr + 1, ((val >> 8) & 0xff) as u8);
+        self.set_byte(addr + 2, ((val >> 16) & 0xff) as u8);
+        self.set_byte(addr + 3, ((val >> 24) & 0xff) as u8);
+        self.set_byte(addr + 4, ((val >> 32) & 0xff) as u8);
+        self.set_byte(addr + 5, ((val >> 40) & 0xff) as u8);
+        self.set_byte(addr + 6, ((val >> 48) & 0xff) as u8);
+        self.set_byte(addr + 7, ((val >> 56) & 0xff) as u8);
+        Ok(())
+    }
+
+    /*
+     * Naturally aligned loads and stores are single-copy atomic (RVWMO), another hart
+     * never sees half of a store. The interpreter goes through host atomics for them,
+     * misaligned accesses are done byte by byte.
+     */
+    fn aligned<T>(&self, addr: usize) -> *mut T {
+        self.ptr(addr) as *mut T
+    }
+
+    /*
+     * LR/SC and AMOs (the A extension): `prot` is what the access needs. They are
+     * atomic with respect to the other harts as well, those are host threads.
+     * Misaligned ones always fault, whatever the MisalignedPolicy is.
+     */
+    pub fn atomic_u32(&self, addr: usize, prot: u8) -> Result<&AtomicU32, Error> {
+        if !addr.is_multiple_of(4) {
+            return Err(Error::MisalignedAccess { addr, width: 4 })
+        }
+        self.check(addr, 4, prot)?;
+        Ok(unsafe { AtomicU32::from_ptr(self.ptr(addr) as *mut u32) })
+    }
+
+    pub fn atomic_u64(&self, addr: usize, prot: u8) -> Result<&AtomicU64, Error> {
+        if !addr.is_multiple_of(8) {
+            return Err(Error::MisalignedAccess { addr, width: 8 })
+        }
+        self.check(addr, 8, prot)?;
+        Ok(unsafe { AtomicU64::from_ptr(self.ptr(addr) as *mut u64) })
+    }
+}
+
+impl MmGuard<'_> {
+    /* Reserve the heap right after the loaded image (ending at `image_end`), its pages
+     * only get mapped when the program break grows. */
+    pub fn setup_heap(&mut self, image_end: usize) -> Result<(), Error> {
+        let heap_size = self.memory.layout.heap_size;
+        let (start, end) = match page_align_up(image_end).zip(page_align_up(heap_size)) {
+            Some((start, size)) => (start, start.saturating_add(size)),
+            None => (image_end, usize::MAX)
+        };
+        if end > self.memory.layout.stack_guard().start {
+            return Err(Error::Layout(format!(
+                "heap ({:#x}..{:#x}) overlaps with the stack", start, end)))
+        }
+        self.heap = start..end;
+        self.brk = start;
+        Ok(())
+    }
+
+    /*
+     * brk(): Move the program break to `addr` and return the new one. Like Linux, requests
+     * outside of the heap (e.g. brk(0)) or onto an mmap()-ed range just return the current
+     * break. New pages are zero-filled, the ones above the break are unmapped when it shrinks.
+     */
+    pub fn set_brk(&mut self, addr: usize) -> usize {
+        if !(self.heap.start..=self.heap.end).contains(&addr) {
+            return self.brk
+        }
+        /* Both are within the heap, which is in the guest memory. */
+        let align = |addr: usize| addr.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let (old_end, new_end) = (align(self.brk), align(addr));
+        if new_end > old_end && self.vmas.overlaps(old_end, new_end) {
+            return self.brk
+        }
+        if new_end > old_end {
+            self.memory.fill_zero(old_end, new_end);
+            self.map(old_end, new_end - old_end, PROT_READ | PROT_WRITE);
+        } else if new_end < old_end {
+            self.unmap(new_end, old_end - new_end);
+        }
+        self.brk = addr;
+        addr
+    }
+
+    /* Add `prot` to the permissions of all pages in addr..(addr + len). */
+    pub fn map(&mut self, addr: usize, len: usize, prot: u8) {
+        let (first, last) = (addr / PAGE_SIZE, (addr + len).div_ceil(PAGE_SIZE));
+        for perms in &self.memory.perms[first..last] {
+            perms.fetch_or(prot, Ordering::Relaxed);
+        }
+    }
+
+    pub fn unmap(&mut self, addr: usize, len: usize) {
+        self.memory.set_prot(addr, len, 0);
+    }
+
+    /* A page is in use if it is accessible or mmap()-ed (with PROT_NONE). */
+    fn is_free(&self, start: usize, end: usize) -> bool {
+        end <= self.memory.data.len() &&
+            (start..end).step_by(PAGE_SIZE).all(|page| self.memory.prot(page) == 0) &&
+            !self.vmas.overlaps(start, end)
+    }
+
+    /* Whether there is a mapping at `addr` at all, accessible or not. */
+    pub fn is_mapped(&self, addr: usize) -> bool {
+        let page = addr & !(PAGE_SIZE - 1);
+        !self.is_free(page, page + PAGE_SIZE)
+    }
+
+    /* Top-down search between the heap and the stack guard, like Linux does it. */
+    fn find_free(&self, len: usize) -> Option<usize> {
+        let lowest = std::cmp::max(self.heap.end, PAGE_SIZE);
+        let mut end = self.memory.layout.stack_guard().start;
+        while end >= lowest + len {
+            let start = end - len;
+            /* Continue below the highest used page in the candidate range. */
+            match (start..end).step_by(PAGE_SIZE).rev().find(|addr| !self.is_free(*addr, addr + PAGE_SIZE)) {
+                Some(used) => end = used,
+                None => return Some(start)
+            }
+        }
+        None
+    }
+
+    fn check_range(&self, addr: usize, len: usize) -> Result<(usize, usize), Errno> {
+        if !is_page_aligned(addr) || len == 0 {
+            return Err(Errno::EINVAL)
+        }
+        let end = page_align_up(len).and_then(|len| addr.checked_add(len)).ok_or(Errno::ENOMEM)?;
+        if end > self.memory.data.len() {
+            return Err(Errno::ENOMEM)
+        }
+        Ok((addr, end))
+    }
+
+    /*
+     * mmap(): Returns the address of the new, zero-filled mapping. The contents of
+     * file mappings have to be copied in by the caller (copy_bulk()).
+     */
+    pub fn mmap(&mut self, placement: Placement, len: usize, prot: u8, shared: bool,
+                name: Option<String>, offset: u64) -> Result<usize, Errno> {
+        if len == 0 {
+            return Err(Errno::EINVAL)
+        }
+        let len = page_align_up(len).ok_or(Errno::ENOMEM)?;
+        let start = match placement {
+            Placement::Fixed(addr) => {
+                let (start, end) = self.check_range(addr, len)?;
+                self.munmap(start, end - start)?;
+                start
+            },
+            Placement::FixedNoReplace(addr) => {
+                let (start, end) = self.check_range(addr, len)?;
+                if !self.is_free(start, end) {
+                    return Err(Errno::EEXIST)
+                }
+                start
+            },
+            Placement::Hint(addr) => match self.check_range(addr, len) {
+                Ok((start, end)) if addr != 0 && start >= self.heap.end && self.is_free(start, end) => start,
+                _ => self.find_free(len).ok_or(Errno::ENOMEM)?
+            }
+        };
+
+        self.memory.fill_zero(start, start + len);
+        self.memory.set_prot(start, len, prot);
+        self.vmas.insert(Vma { start, end: start + len, prot, shared, name, offset });
+        Ok(start)
+    }
+
+    pub fn munmap(&mut self, addr: usize, len: usize) -> Result<(), Errno> {
+        let (start, end) = self.check_range(addr, len)?;
+        self.unmap(start, end - start);
+        self.vmas.remove(start, end);
+        Ok(())
+    }
+
+    pub fn mprotect(&mut self, addr: usize, len: usize, prot: u8) -> Result<(), Errno> {
+        let (start, end) = self.check_range(addr, len)?;
+        let all_mapped = (start..end).step_by(PAGE_SIZE)
+            .all(|page| self.memory.prot(page) != 0 || self.vmas.find(page).is_some());
+        if !all_mapped {
+            return Err(Errno::ENOMEM)
+        }
+        self.memory.set_prot(start, end - start, prot);
+        self.vmas.protect(start, end, prot);
+        Ok(())
+    }
+
+    /* mremap() of (a part of) a single mapping, growing in place if possible. */
+    pub fn mremap(&mut self, addr: usize, old_len: usize, new_len: usize, may_move: bool,
+                  fixed: Option<usize>) -> Result<usize, Errno> {
+        let (start, old_end) = self.check_range(addr, old_len)?;
+        let new_len = match page_align_up(new_len) {
+            Some(0) | None => return Err(Errno::EINVAL),
+            Some(new_len) => new_len
+        };
+        let vma = self.vmas.find(start).cloned().ok_or(Errno::EFAULT)?;
+        if old_end > vma.end {
+            return Err(Errno::EFAULT)
+        }
+        /* Only the part that gets remapped is of interest from here on. */
+        self.vmas.remove(start, old_end);
+        self.vmas.insert(Vma { start, end: old_end,
+                               offset: vma.offset + (start - vma.start) as u64, ..vma.clone() });
+        let old_len = old_end - start;
+
+        if fixed.is_none() {
+            if new_len <= old_len {
+                self.munmap(start + new_len, old_len - new_len).ok();
+                self.vmas.resize(start, start + new_len);
+                return Ok(start)
+            }
+            let new_end = start.checked_add(new_len).ok_or(Errno::ENOMEM)?;
+            if new_end <= self.memory.layout.stack_guard().start && self.is_free(old_end, new_end) {
+                self.memory.fill_zero(old_end, new_end);
+                self.memory.set_prot(old_end, new_end - old_end, vma.prot);
+                self.vmas.resize(start, new_end);
+                return Ok(start)
+            }
+            if !may_move {
+                return Err(Errno::ENOMEM)
+            }
+        }
+
+        let placement = match fixed {
+            Some(_) if !may_move => return Err(Errno::EINVAL),
+            Some(dest) => {
+                let (dest, dest_end) = self.check_range(dest, new_len)?;
+                if dest < old_end && start < dest_end {
+                    return Err(Errno::EINVAL)
+                }
+                Placement::Fixed(dest)
+            },
+            None => Placement::Hint(0)
+        };
+        let dest = self.mmap(placement, new_len, vma.prot, vma.shared, vma.name.clone(),
+                             vma.offset + (start - vma.start) as u64)?;
+        let copy_len = std::cmp::min(old_len, new_len);
+        unsafe { std::ptr::copy(self.memory.ptr(start), self.memory.ptr(dest), copy_len) };
+        self.munmap(start, old_len)?;
+        Ok(dest)
+    }
+
+    /*
+     * madvise(MADV_DONTNEED): Anonymous private memory (mappings, the heap and the stack)
+     * reads as zero afterwards. Linux would fault file-backed pages (including those of
+     * the loaded images) in from the file again. We do not keep the files, so those are
+     * left alone, just like shared mappings.
+     */
+    pub fn discard(&mut self, addr: usize, len: usize) -> Result<(), Errno> {
+        let (start, end) = self.check_range(addr, len)?;
+        let stack = self.memory.layout.stack();
+        for page in (start..end).step_by(PAGE_SIZE) {
+            let anonymous = match self.vmas.find(page) {
+                Some(vma) => !vma.shared && vma.name.is_none(),
+                None => self.heap.contains(&page) || stack.contains(&page)
+            };
+            if anonymous {
+                self.memory.fill_zero(page, page + PAGE_SIZE);
+            }
+        }
+        Ok(())
+    }
+
+    /* Like /proc/self/maps, everything that is mapped, including the loaded images. */
+    pub fn dump_maps(&self, w: &mut dyn std::io::Write) -> std::io::Result<()> {
+        let stack = self.memory.layout.stack();
+        let describe = |addr: usize| {
+            let prot = self.memory.prot(addr);
+            match self.vmas.find(addr) {
+                /* Adjacent anonymous mappings are shown as one, like Linux merges them. */
+                Some(vma) if vma.name.is_none() => Some((prot, vma.shared, 0, 0, String::new())),
+                Some(vma) => Some((prot, vma.shared, vma.start, vma.offset,
+                                   vma.name.clone().unwrap_or_default())),
+                None if prot == 0 => None,
+                None if self.heap.contains(&addr) => Some((prot, false, 0, 0, "[heap]".to_string())),
+                None if stack.contains(&addr) => Some((prot, false, 0, 0, "[stack]".to_string())),
+                None => Some((prot, false, 0, 0, String::new()))
+            }
+        };
+
+        let mut addr = 0;
+        while addr < self.memory.data.len() {
+            let Some(desc) = describe(addr) else {
+                addr += PAGE_SIZE;
+                continue
+            };
+            let mut end = addr + PAGE_SIZE;
+            while end < self.memory.data.len() && describe(end).as_ref() == Some(&desc) {
+                end += PAGE_SIZE;
+            }
+            let (prot, shared, vma_start, offset, name) = desc;
+            let offset = if vma_start != 0 { offset + (addr - vma_start) as u64 } else { 0 };
+            writeln!(w, "{:08x}-{:08x} {}{}{}{} {:08x} 00:00 0 {}", addr, end,
+                     if prot & PROT_READ != 0 { 'r' } else { '-' },
+                     if prot & PROT_WRITE != 0 { 'w' } else { '-' },
+                     if prot & PROT_EXEC != 0 { 'x' } else { '-' },
+                     if shared { 's' } else { 'p' }, offset, name)?;
+            addr = end;
+        }
+        Ok(())
+    }
+}
+
+impl std::ops::Deref for MmGuard<'_> {
+    type Target = Mappings;
+
+    fn deref(&self) -> &Mappings {
+        &self.mappings
+    }
+}
+
+impl std::ops::DerefMut for MmGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Mappings {
+        &mut self.mappings
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn brk() {
+        let memory = Memory::new(MemoryLayout::default());
+        memory.mm().setup_heap(0x12345).unwrap();
+        let start = memory.mm().heap.start;
+        assert_eq!(start, 0x13000);
+        assert_eq!(memory.mm().set_brk(0), start);
+        assert!(memory.load_u8(start).is_err());
+
+        assert_eq!(memory.mm().set_brk(start + 0x1800), start + 0x1800);
+        memory.store_u8(start + 0x1fff, 0xaa).unwrap();
+        assert!(memory.load_u8(start + 0x2000).is_err());
+
+        /* Shrinking unmaps, growing again gives zero-filled pages. */
+        assert_eq!(memory.mm().set_brk(start + 0x800), start + 0x800);
+        assert!(memory.load_u8(start + 0x1fff).is_err());
+        assert_eq!(memory.mm().set_brk(start + 0x2000), start + 0x2000);
+        assert_eq!(memory.load_u8(start + 0x1fff).unwrap(), 0);
+
+        /* Not into the stack (or anything else above the heap). */
+        let end = memory.mm().heap.end;
+        assert_eq!(memory.mm().set_brk(end + PAGE_SIZE), start + 0x2000);
+        assert_eq!(memory.mm().set_brk(end), end);
+    }
+
+    /* Growing the heap must not clobber a MAP_FIXED mapping in it. */
+    #[test]
+    fn brk_collision() {
+        let memory = Memory::new(MemoryLayout::default());
+        memory.mm().setup_heap(0x12345).unwrap();
+        let start = memory.mm().heap.start;
+        let fixed = start + 4 * PAGE_SIZE;
+        let rw = PROT_READ | PROT_WRITE;
+        assert_eq!(memory.mm().mmap(Placement::Fixed(fixed), PAGE_SIZE, rw, false, None, 0),
+                   Ok(fixed));
+        memory.store_u8(fixed, 0xaa).unwrap();
+
+        assert_eq!(memory.mm().set_brk(start + PAGE_SIZE), start + PAGE_SIZE);
+        assert_eq!(memory.mm().set_brk(fixed + 1), start + PAGE_SIZE);
+        assert_eq!(memory.mm().set_brk(fixed + 8 * PAGE_SIZE), start + PAGE_SIZE);
+        assert_eq!(memory.load_u8(fixed).unwrap(), 0xaa);
+        assert!(memory.load_u8(start + PAGE_SIZE).is_err());
+        assert_eq!(memory.mm().set_brk(fixed), fixed);
+    }
+
+    /* Only anonymous private memory is discarded, each VMA in the range decides. */
+    #[test]
+    fn discard() {
+        let memory = Memory::new(MemoryLayout::default());
+        memory.mm().setup_heap(0x12345).unwrap();
+        let heap = memory.mm().heap.start;
+        memory.mm().set_brk(heap + PAGE_SIZE);
+        let rw = PROT_READ | PROT_WRITE;
+        let image = heap - PAGE_SIZE;
+        memory.mm().map(image, PAGE_SIZE, rw);
+
+        /* Four adjacent mappings: private, shared, file-backed, private. */
+        let base = memory.mm().mmap(Placement::Hint(0), 4 * PAGE_SIZE, rw, false, None, 0).unwrap();
+        let mapping = |i: usize, shared: bool, name: Option<&str>| {
+            let addr = base + i * PAGE_SIZE;
+            let name = name.map(|name| name.to_string());
+            memory.mm().mmap(Placement::Fixed(addr), PAGE_SIZE, rw, shared, name, 0).unwrap()
+        };
+        let pages = [mapping(0, false, None), mapping(1, true, None),
+                     mapping(2, false, Some("/etc/passwd")), mapping(3, false, None)];
+        for addr in pages.iter().chain([&image, &heap]) {
+            memory.store_u8(*addr, 0xaa).unwrap();
+        }
+
+        memory.mm().discard(base, 4 * PAGE_SIZE).unwrap();
+        let values = pages.iter().map(|addr| memory.load_u8(*addr).unwrap()).collect::<Vec<_>>();
+        assert_eq!(values, vec![0, 0xaa, 0xaa, 0]);
+        memory.mm().discard(image, 2 * PAGE_SIZE).unwrap();
+        assert_eq!(memory.load_u8(image).unwrap(), 0xaa);
+        assert_eq!(memory.load_u8(heap).unwrap(), 0);
+    }
+
+    /* Bad guest pointers are EFAULT, whatever they point to (or do not). */
+    #[test]
+    fn guest_access() {
+        let memory = Memory::new(MemoryLayout::default());
+        let (stack, end) = (memory.layout.stack(), memory.layout.memory_size);
+        for addr in [0, PAGE_SIZE, stack.start - 1, end, usize::MAX - 2, usize::MAX] {
+            assert_eq!(memory.guest_cstr(addr), Err(Errno::EFAULT));
+        }
+
+        /* At the end of the memory: Terminated right there, or running off it. */
+        memory.copy_bulk((end - 4) as u64, b"abc\0").unwrap();
+        assert_eq!(memory.guest_cstr(end - 4).unwrap().to_bytes(), b"abc");
+        memory.store_u8(end - 1, b'd').unwrap();
+        assert_eq!(memory.guest_cstr(end - 4), Err(Errno::EFAULT));
+
+        /* Strings across pages are fine, up to MAX_GUEST_CSTR_LEN with the NUL. */
+        let addr = stack.start + PAGE_SIZE / 2;
+        memory.copy_bulk(addr as u64, &[b'x'; MAX_GUEST_CSTR_LEN]).unwrap();
+        assert_eq!(memory.guest_cstr(addr), Err(Errno::ENAMETOOLONG));
+        memory.store_u8(addr + MAX_GUEST_CSTR_LEN - 1, 0).unwrap();
+        assert_eq!(memory.guest_cstr(addr).unwrap().to_bytes().len(), MAX_GUEST_CSTR_LEN - 1);
+
+        /* guest_slice_mut() needs PROT_WRITE, guest_slice() only PROT_READ. */
+        memory.mm().mprotect(stack.start, PAGE_SIZE, PROT_READ).unwrap();
+        assert!(memory.guest_slice(stack.start, 16).is_ok());
+        assert_eq!(memory.guest_slice_mut(stack.start, 16).err(), Some(Errno::EFAULT));
+        assert_eq!(memory.guest_slice_mut(stack.start + PAGE_SIZE - 8, 16).err(),
+                   Some(Errno::EFAULT));
+        assert!(memory.guest_slice_mut(stack.start + PAGE_SIZE, 16).is_ok());
+        assert_eq!(memory.guest_slice(usize::MAX - 8, 16).err(), Some(Errno::EFAULT));
+    }
+
+    #[test]
+    fn mmap() {
+        let memory = Memory::new(MemoryLayout::default());
+        memory.mm().setup_heap(0x12345).unwrap();
+        let rw = PROT_READ | PROT_WRITE;
+
+        /* Top-down, right below the stack guard. */
+        let a = memory.mm().mmap(Placement::Hint(0), 0x3000, rw, false, None, 0).unwrap();
+        assert_eq!(a + 0x3000, memory.layout.stack_guard().start);
+        let b = memory.mm().mmap(Placement::Hint(0), 0x1000, PROT_READ, false, None, 0).unwrap();
+        assert_eq!(b + 0x1000, a);
+        assert!(memory.store_u8(b, 1).is_err());
+
+        /* Punch a hole into the middle, the rest stays mapped. */
+        memory.store_u8(a + 0x2000, 0xaa).unwrap();
+        memory.mm().munmap(a + 0x1000, 0x1000).unwrap();
+        assert!(memory.load_u8(a + 0x1000).is_err());
+        assert_eq!(memory.load_u8(a + 0x2000).unwrap(), 0xaa);
+        assert_eq!(memory.mm().mmap(Placement::FixedNoReplace(a), 0x1000, rw, false, None, 0),
+                   Err(Errno::EEXIST));
+        assert_eq!(memory.mm().mmap(Placement::Hint(0), 0x1000, rw, false, None, 0),
+                   Ok(a + 0x1000));
+
+        memory.mm().mprotect(a, 0x1000, PROT_READ).unwrap();
+        assert!(memory.store_u8(a, 1).is_err());
+        let heap = memory.mm().heap.start;
+        assert_eq!(memory.mm().mprotect(heap, 0x1000, PROT_READ), Err(Errno::ENOMEM));
+
+        /* b can not grow in place (a is right above it), so it has to move. */
+        assert_eq!(memory.mm().mremap(b, 0x1000, 0x2000, false, None), Err(Errno::ENOMEM));
+        let c = memory.mm().mremap(b, 0x1000, 0x2000, true, None).unwrap();
+        assert_eq!(c + 0x2000, b);
+        assert!(memory.load_u8(b).is_err());
+        assert_eq!(memory.load_u8(c + 0x1fff).unwrap(), 0);
+        assert_eq!(memory.mm().mremap(c, 0x2000, 0x1000, false, None), Ok(c));
+
+        /* Lengths that do not fit into the address space (or a usize, once page aligned). */
+        let mut mm = memory.mm();
+        assert_eq!(mm.mmap(Placement::Hint(0), usize::MAX, rw, false, None, 0), Err(Errno::ENOMEM));
+        assert_eq!(mm.mmap(Placement::Fixed(a), usize::MAX, rw, false, None, 0),
+                   Err(Errno::ENOMEM));
+        assert_eq!(mm.munmap(a, usize::MAX), Err(Errno::ENOMEM));
+        assert_eq!(mm.mprotect(a, usize::MAX, PROT_READ), Err(Errno::ENOMEM));
+        assert_eq!(mm.mremap(c, usize::MAX, 0x1000, true, None), Err(Errno::ENOMEM));
+        assert_eq!(mm.mremap(c, 0x1000, usize::MAX, true, None), Err(Errno::EINVAL));
+        assert_eq!(mm.discard(a, usize::MAX), Err(Errno::ENOMEM));
+        drop(mm);
+
+        let mut maps = Vec::new();
+        memory.mm().dump_maps(&mut maps).unwrap();
+        let maps = String::from_utf8(maps).unwrap();
+        assert!(maps.contains(&format!("{:08x}-{:08x} r--p", c, c + 0x1000)));
+        assert!(maps.contains(&format!("{:08x}-{:08x} r--p", a, a + 0x1000)));
+        assert!(maps.contains(&format!("{:08x}-{:08x} rw-p", a + 0x1000, a + 0x3000)));
+        assert!(maps.contains("rw-p 00000000 00:00 0 [stack]"));
+    }
+}
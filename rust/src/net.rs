@@ -0,0 +1,233 @@
+/*
+ * Socket addresses and the guest's network policy. sockaddr_un/_in/_in6 have the same
+ * layout on riscv64 and on the (64 bit, little-endian) hosts we run on, but they are
+ * parsed anyway: The policy has to look at them, and the guest can pass any garbage.
+ */
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use syscalls::Errno;
+
+pub const AF_UNSPEC: u16 = 0;
+pub const AF_UNIX: u16 = 1;
+pub const AF_INET: u16 = 2;
+pub const AF_INET6: u16 = 10;
+
+const SOCKADDR_IN_SIZE: usize = 16;
+const SOCKADDR_IN6_SIZE: usize = 28;
+const SOCKADDR_UN_SIZE: usize = 110;
+/* sizeof(struct sockaddr_storage) */
+pub const SOCKADDR_MAX_SIZE: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum NetworkPolicy {
+    /// Whatever the host allows.
+    Host,
+    /// Only Unix sockets and the loopback interface (binding to the wildcard address,
+    /// explicitly or implicitly, binds to loopback).
+    Loopback,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SockAddr {
+    /// sun_path as given, can be abstract (leading NUL) or unnamed (empty).
+    Unix(Vec<u8>),
+    V4(SocketAddrV4),
+    V6(SocketAddrV6),
+    /// Other families are passed on as they are.
+    Other(u16, Vec<u8>),
+}
+
+impl SockAddr {
+    /* Parse a struct sockaddr of `bytes.len()` (the addrlen) bytes. */
+    pub fn from_bytes(bytes: &[u8]) -> Result<SockAddr, Errno> {
+        if bytes.len() < 2 {
+            return Err(Errno::EINVAL)
+        }
+        let family = u16::from_le_bytes([bytes[0], bytes[1]]);
+        let be16 = |pos: usize| u16::from_be_bytes(bytes[pos..(pos + 2)].try_into().unwrap());
+        let be32 = |pos: usize| u32::from_be_bytes(bytes[pos..(pos + 4)].try_into().unwrap());
+        match family {
+            AF_UNIX if bytes.len() <= SOCKADDR_UN_SIZE => Ok(SockAddr::Unix(bytes[2..].to_vec())),
+            AF_INET if bytes.len() >= SOCKADDR_IN_SIZE =>
+                Ok(SockAddr::V4(SocketAddrV4::new(Ipv4Addr::from(be32(4)), be16(2)))),
+            AF_INET6 if bytes.len() >= SOCKADDR_IN6_SIZE => {
+                let ip: [u8; 16] = bytes[8..24].try_into().unwrap();
+                let scope_id = u32::from_le_bytes(bytes[24..28].try_into().unwrap());
+                Ok(SockAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), be16(2), be32(4), scope_id)))
+            },
+            AF_UNIX | AF_INET | AF_INET6 => Err(Errno::EINVAL),
+            _ => Ok(SockAddr::Other(family, bytes[2..].to_vec()))
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SOCKADDR_MAX_SIZE);
+        match self {
+            SockAddr::Unix(path) => {
+                bytes.extend_from_slice(&AF_UNIX.to_le_bytes());
+                bytes.extend_from_slice(path);
+            },
+            SockAddr::V4(addr) => {
+                bytes.extend_from_slice(&AF_INET.to_le_bytes());
+                bytes.extend_from_slice(&addr.port().to_be_bytes());
+                bytes.extend_from_slice(&addr.ip().octets());
+                bytes.resize(SOCKADDR_IN_SIZE, 0);
+            },
+            SockAddr::V6(addr) => {
+                bytes.extend_from_slice(&AF_INET6.to_le_bytes());
+                bytes.extend_from_slice(&addr.port().to_be_bytes());
+                bytes.extend_from_slice(&addr.flowinfo().to_be_bytes());
+                bytes.extend_from_slice(&addr.ip().octets());
+                bytes.extend_from_slice(&addr.scope_id().to_le_bytes());
+            },
+            SockAddr::Other(family, data) => {
+                bytes.extend_from_slice(&family.to_le_bytes());
+                bytes.extend_from_slice(data);
+            }
+        }
+        bytes
+    }
+}
+
+impl NetworkPolicy {
+    /* Address family (domain) of socket(). */
+    pub fn check_domain(self, domain: usize) -> Result<(), Errno> {
+        match self {
+            NetworkPolicy::Host => Ok(()),
+            NetworkPolicy::Loopback
+                if [AF_UNIX, AF_INET, AF_INET6].map(usize::from).contains(&domain) => Ok(()),
+            NetworkPolicy::Loopback => Err(Errno::EAFNOSUPPORT)
+        }
+    }
+
+    /*
+     * Addresses of bind() (`bind` set), connect() and sendto(). The wildcard address
+     * means the local host for connect() anyway, for bind() it becomes loopback.
+     */
+    pub fn check_addr(self, addr: SockAddr, bind: bool) -> Result<SockAddr, Errno> {
+        if self == NetworkPolicy::Host {
+            return Ok(addr)
+        }
+        match addr {
+            SockAddr::Unix(_) | SockAddr::Other(AF_UNSPEC, _) => Ok(addr),
+            SockAddr::V4(v4) if v4.ip().is_loopback() => Ok(addr),
+            SockAddr::V4(v4) if v4.ip().is_unspecified() => match bind {
+                true => Ok(SockAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, v4.port()))),
+                false => Ok(addr)
+            },
+            SockAddr::V6(v6) if v6.ip().is_loopback()
+                || v6.ip().to_ipv4_mapped().is_some_and(|ip| ip.is_loopback()) => Ok(addr),
+            SockAddr::V6(mut v6) if v6.ip().is_unspecified() => {
+                if bind {
+                    v6.set_ip(Ipv6Addr::LOCALHOST);
+                }
+                Ok(SockAddr::V6(v6))
+            },
+            _ if bind => Err(Errno::EADDRNOTAVAIL),
+            _ => Err(Errno::ENETUNREACH)
+        }
+    }
+
+    /*
+     * listen(), sendto() and sendmsg() on an unbound INET socket (`local` is the wildcard
+     * address with port 0) make the host bind it to the wildcard address, which lets in
+     * traffic from outside. Returns the loopback address to bind it to first instead, the
+     * IPv4-mapped one if the destination `to` is IPv4-mapped.
+     */
+    pub fn loopback_bind(self, local: &SockAddr, to: Option<&SockAddr>) -> Option<SockAddr> {
+        if self == NetworkPolicy::Host {
+            return None
+        }
+        match local {
+            SockAddr::V4(v4) if v4.ip().is_unspecified() && v4.port() == 0 =>
+                Some(SockAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))),
+            SockAddr::V6(v6) if v6.ip().is_unspecified() && v6.port() == 0 => {
+                let ip = match to {
+                    Some(SockAddr::V6(to)) if to.ip().to_ipv4_mapped().is_some() =>
+                        Ipv4Addr::LOCALHOST.to_ipv6_mapped(),
+                    _ => Ipv6Addr::LOCALHOST
+                };
+                Some(SockAddr::V6(SocketAddrV6::new(ip, 0, 0, 0)))
+            },
+            _ => None
+        }
+    }
+}
+
+const SOL_SOCKET: i32 = 1;
+const SCM_RIGHTS: i32 = 1;
+/* sizeof(struct cmsghdr): u64 cmsg_len, i32 cmsg_level, i32 cmsg_type. */
+const CMSG_HDR_SIZE: usize = 16;
+
+/*
+ * Replace every fd passed with SCM_RIGHTS in a control message buffer (which has the
+ * same layout everywhere), guest fds with host fds for sendmsg() and the other way
+ * around for recvmsg().
+ */
+pub fn map_scm_rights(control: &mut [u8], mut f: impl FnMut(i32) -> Result<i32, Errno>)
+        -> Result<(), Errno> {
+    let mut pos = 0;
+    while pos + CMSG_HDR_SIZE <= control.len() {
+        let len = u64::from_le_bytes(control[pos..(pos + 8)].try_into().unwrap()) as usize;
+        if len < CMSG_HDR_SIZE || len > control.len() - pos {
+            break
+        }
+        let level = i32::from_le_bytes(control[(pos + 8)..(pos + 12)].try_into().unwrap());
+        let kind = i32::from_le_bytes(control[(pos + 12)..(pos + 16)].try_into().unwrap());
+        if level == SOL_SOCKET && kind == SCM_RIGHTS {
+            for fd in control[(pos + CMSG_HDR_SIZE)..(pos + len)].chunks_exact_mut(4) {
+                let mapped = f(i32::from_le_bytes((&*fd).try_into().unwrap()))?;
+                fd.copy_from_slice(&mapped.to_le_bytes());
+            }
+        }
+        pos += (len + 7) & !7;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sockaddr() {
+        let v4 = [2, 0, 0x1f, 0x90, 127, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+        let addr = SockAddr::from_bytes(&v4).unwrap();
+        assert_eq!(addr, SockAddr::V4("127.0.0.1:8080".parse().unwrap()));
+        assert_eq!(addr.to_bytes(), v4);
+        assert_eq!(SockAddr::from_bytes(&v4[..8]), Err(Errno::EINVAL));
+
+        let v6 = SockAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 443, 0x12345, 3));
+        assert_eq!(SockAddr::from_bytes(&v6.to_bytes()), Ok(v6));
+        let unix = SockAddr::Unix(b"\0abstract".to_vec());
+        assert_eq!(SockAddr::from_bytes(&unix.to_bytes()), Ok(unix));
+    }
+
+    #[test]
+    fn loopback_policy() {
+        let policy = NetworkPolicy::Loopback;
+        let addr = |s: &str| SockAddr::V4(s.parse().unwrap());
+        assert_eq!(policy.check_addr(addr("127.0.0.2:80"), false), Ok(addr("127.0.0.2:80")));
+        assert_eq!(policy.check_addr(addr("0.0.0.0:80"), true), Ok(addr("127.0.0.1:80")));
+        assert_eq!(policy.check_addr(addr("10.0.0.1:80"), false), Err(Errno::ENETUNREACH));
+        assert_eq!(policy.check_addr(addr("10.0.0.1:80"), true), Err(Errno::EADDRNOTAVAIL));
+        let v6 = SockAddr::V6("[::ffff:127.0.0.1]:80".parse().unwrap());
+        assert_eq!(policy.check_addr(v6.clone(), false), Ok(v6));
+        let v6 = SockAddr::V6("[2001:db8::1]:80".parse().unwrap());
+        assert_eq!(policy.check_addr(v6, false), Err(Errno::ENETUNREACH));
+        assert_eq!(policy.check_domain(AF_UNIX as usize), Ok(()));
+        assert_eq!(policy.check_domain(16 /* AF_NETLINK */), Err(Errno::EAFNOSUPPORT));
+
+        /* Unbound sockets are bound to loopback, bound ones are left alone. */
+        assert_eq!(policy.loopback_bind(&addr("0.0.0.0:0"), None), Some(addr("127.0.0.1:0")));
+        assert_eq!(policy.loopback_bind(&addr("127.0.0.1:8080"), None), None);
+        assert_eq!(policy.loopback_bind(&SockAddr::Unix(Vec::new()), None), None);
+        let (any, mapped) = ("[::]:0".parse().unwrap(), "[::ffff:127.0.0.1]:80".parse().unwrap());
+        assert_eq!(policy.loopback_bind(&SockAddr::V6(any), None),
+                   Some(SockAddr::V6("[::1]:0".parse().unwrap())));
+        assert_eq!(policy.loopback_bind(&SockAddr::V6(any), Some(&SockAddr::V6(mapped))),
+                   Some(SockAddr::V6("[::ffff:127.0.0.1]:0".parse().unwrap())));
+        let host = NetworkPolicy::Host;
+        assert_eq!(host.check_addr(addr("10.0.0.1:80"), true), Ok(addr("10.0.0.1:80")));
+        assert_eq!(host.loopback_bind(&addr("0.0.0.0:0"), None), None);
+    }
+}
@@ -0,0 +1,86 @@
+/*
+ * Which syscalls the guest may do (`--policy`), e.g. for running untrusted programs
+ * together with --mount and --network=loopback. Rules are strace filters (names and
+ * classes like `%file` or `%network`, see strace::Filter) with an action. The last rule
+ * that matches a syscall decides, syscalls that no rule matches are allowed.
+ */
+use crate::strace::Filter;
+use crate::sys::ArgKind;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Allow,
+    /// Fail with EPERM, and say so on stderr.
+    Deny,
+    /// Allow, and print the call (like strace) on stderr.
+    Log,
+}
+
+/* `FILTER=ACTION`, e.g. `%network,execve=deny`. */
+#[derive(Debug, Clone)]
+pub struct Rule {
+    filter: Filter,
+    action: Action,
+}
+
+impl std::str::FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (filter, action) = s.rsplit_once('=')
+            .ok_or_else(|| format!("invalid policy rule {:?} (FILTER=ACTION)", s))?;
+        let action = match action {
+            "allow" => Action::Allow,
+            "deny" => Action::Deny,
+            "log" => Action::Log,
+            _ => return Err(format!("invalid policy action {:?} (allow, deny or log)", action))
+        };
+        Ok(Rule { filter: filter.parse()?, action })
+    }
+}
+
+/* A guest can always leave, and return from signal handlers. */
+const NEVER_DENIED: &[&str] = &["exit", "exit_group", "rt_sigreturn"];
+
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Policy { rules }
+    }
+
+    pub fn action(&self, name: &str, args: &[ArgKind]) -> Action {
+        let action = self.rules.iter().rev()
+            .find(|rule| rule.filter.matches(name, args))
+            .map_or(Action::Allow, |rule| rule.action);
+        match action {
+            Action::Deny if NEVER_DENIED.contains(&name) => Action::Allow,
+            action => action
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let rules = ["all=deny", "%memory,read,write=allow", "%file=log", "openat=deny"];
+        let policy = Policy::new(rules.iter().map(|rule| rule.parse().unwrap()).collect());
+        assert_eq!(policy.action("socket", &[ArgKind::Int]), Action::Deny);
+        assert_eq!(policy.action("mmap", &[ArgKind::Ptr]), Action::Allow);
+        assert_eq!(policy.action("write", &[ArgKind::Fd]), Action::Allow);
+        assert_eq!(policy.action("newfstatat", &[ArgKind::Fd, ArgKind::CStr]), Action::Log);
+        assert_eq!(policy.action("openat", &[ArgKind::Fd, ArgKind::CStr]), Action::Deny);
+        assert_eq!(policy.action("exit_group", &[ArgKind::Int]), Action::Allow);
+        assert_eq!(Policy::default().action("socket", &[]), Action::Allow);
+
+        assert!("%network".parse::<Rule>().is_err());
+        assert!("%network=kill".parse::<Rule>().is_err());
+        assert!("=deny".parse::<Rule>().is_err());
+    }
+}
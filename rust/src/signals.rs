@@ -0,0 +1,819 @@
+/*
+ * Signals of the guest. Handlers, the blocked mask and the pending signals are all
+ * emulated here and not left to the host kernel: Signals are only delivered between
+ * TBs (see CPU::run()), by pushing a struct rt_sigframe like the riscv64 kernel does.
+ * Signals sent to the simulator are caught and passed on to the guest, faults the
+ * simulator detects (illegal instructions, bad accesses) become SIGILL/SIGSEGV/SIGBUS.
+ */
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use syscalls::Errno;
+
+use crate::cpu::CPU;
+use crate::insts::*;
+use crate::mem::*;
+
+/* The generic Linux numbers, the same on the hosts we run on. */
+pub const NSIG: usize = 64;
+pub const SIGHUP: i32 = 1;
+pub const SIGINT: i32 = 2;
+pub const SIGQUIT: i32 = 3;
+pub const SIGILL: i32 = 4;
+pub const SIGBUS: i32 = 7;
+pub const SIGKILL: i32 = 9;
+pub const SIGUSR1: i32 = 10;
+pub const SIGSEGV: i32 = 11;
+pub const SIGUSR2: i32 = 12;
+pub const SIGPIPE: i32 = 13;
+pub const SIGALRM: i32 = 14;
+pub const SIGTERM: i32 = 15;
+pub const SIGCHLD: i32 = 17;
+pub const SIGCONT: i32 = 18;
+pub const SIGSTOP: i32 = 19;
+pub const SIGTSTP: i32 = 20;
+pub const SIGTTIN: i32 = 21;
+pub const SIGTTOU: i32 = 22;
+pub const SIGURG: i32 = 23;
+pub const SIGVTALRM: i32 = 26;
+pub const SIGPROF: i32 = 27;
+pub const SIGWINCH: i32 = 28;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+pub const SA_ONSTACK: u64 = 0x08000000;
+pub const SA_RESTART: u64 = 0x10000000;
+pub const SA_NODEFER: u64 = 0x40000000;
+pub const SA_RESETHAND: u64 = 0x80000000;
+
+/* si_code values. */
+pub const SI_USER: i32 = 0;
+pub const SI_TKILL: i32 = -6;
+pub const ILL_ILLOPC: i32 = 1;
+pub const SEGV_MAPERR: i32 = 1;
+pub const SEGV_ACCERR: i32 = 2;
+pub const BUS_ADRALN: i32 = 1;
+
+pub const SS_ONSTACK: i32 = 1;
+pub const SS_DISABLE: i32 = 2;
+pub const MINSIGSTKSZ: u64 = 2048;
+
+pub const fn sigbit(sig: i32) -> u64 {
+    1 << (sig - 1)
+}
+
+/* Neither can be blocked, caught or ignored. */
+const UNBLOCKABLE: u64 = sigbit(SIGKILL) | sigbit(SIGSTOP);
+
+/* struct sigaction of the kernel: handler, flags, mask (riscv64 has no sa_restorer). */
+pub const GUEST_SIGACTION_SIZE: usize = 24;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SigAction {
+    /// Address of the handler, or SIG_DFL/SIG_IGN.
+    pub handler: u64,
+    pub flags: u64,
+    /// Blocked in addition while the handler runs.
+    pub mask: u64,
+}
+
+impl SigAction {
+    pub fn from_bytes(bytes: &[u8]) -> SigAction {
+        let u64_at = |pos: usize| u64::from_le_bytes(bytes[pos..(pos + 8)].try_into().unwrap());
+        SigAction { handler: u64_at(0), flags: u64_at(8), mask: u64_at(16) }
+    }
+
+    pub fn to_bytes(self) -> [u8; GUEST_SIGACTION_SIZE] {
+        let mut bytes = [0u8; GUEST_SIGACTION_SIZE];
+        bytes[0..8].copy_from_slice(&self.handler.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.mask.to_le_bytes());
+        bytes
+    }
+}
+
+pub const SIGINFO_SIZE: usize = 128;
+
+/* The parts of siginfo_t the simulator fills in. */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SigInfo {
+    pub signo: i32,
+    pub code: i32,
+    /// si_addr, for faults.
+    pub addr: Option<u64>,
+    /// si_pid and si_uid of the sender otherwise.
+    pub pid: i32,
+    pub uid: u32,
+}
+
+impl SigInfo {
+    /* Sent by kill()/tkill() of the guest (SI_USER/SI_TKILL) to itself. */
+    pub fn user(signo: i32, code: i32) -> SigInfo {
+        SigInfo { signo, code, addr: None,
+                  pid: unsafe { libc::getpid() }, uid: unsafe { libc::getuid() } }
+    }
+
+    pub fn fault(signo: i32, code: i32, addr: u64) -> SigInfo {
+        SigInfo { signo, code, addr: Some(addr), pid: 0, uid: 0 }
+    }
+
+    fn to_bytes(self) -> [u8; SIGINFO_SIZE] {
+        let mut bytes = [0u8; SIGINFO_SIZE];
+        bytes[0..4].copy_from_slice(&self.signo.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.code.to_le_bytes());
+        match self.addr {
+            Some(addr) => bytes[16..24].copy_from_slice(&addr.to_le_bytes()),
+            None => {
+                bytes[16..20].copy_from_slice(&self.pid.to_le_bytes());
+                bytes[20..24].copy_from_slice(&self.uid.to_le_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+/* stack_t of sigaltstack(): ss_sp, ss_flags (padded to 8 bytes), ss_size. */
+pub const GUEST_STACK_T_SIZE: usize = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AltStack {
+    pub sp: u64,
+    pub flags: i32,
+    pub size: u64,
+}
+
+impl Default for AltStack {
+    fn default() -> Self {
+        AltStack { sp: 0, flags: SS_DISABLE, size: 0 }
+    }
+}
+
+impl AltStack {
+    pub fn from_bytes(bytes: &[u8]) -> AltStack {
+        AltStack {
+            sp: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            flags: i32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            size: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; GUEST_STACK_T_SIZE] {
+        let mut bytes = [0u8; GUEST_STACK_T_SIZE];
+        bytes[0..8].copy_from_slice(&self.sp.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.size.to_le_bytes());
+        bytes
+    }
+
+    pub fn contains(&self, sp: u64) -> bool {
+        self.flags & SS_DISABLE == 0 && sp > self.sp && sp - self.sp <= self.size
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+}
+
+fn default_action(sig: i32) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGCONT | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        _ => DefaultAction::Terminate
+    }
+}
+
+/*
+ * Signal state of a guest thread. The actions (and the trampoline) are shared by all
+ * threads, the mask, the alternate stack and the pending signals are per thread.
+ * Standard and real-time signals alike are pending at most once, a signal sent again
+ * before it was delivered is lost.
+ */
+#[derive(Debug)]
+pub struct Signals {
+    actions: Arc<Mutex<[SigAction; NSIG]>>,
+    /// Pending signals and mask, other threads send signals through it.
+    queue: Arc<SigQueue>,
+    pub altstack: AltStack,
+    /// Guest address of `li a7, 139; ecall`, the return address of all handlers.
+    trampoline: Arc<Mutex<Option<u64>>>,
+    /// Address after the ecall of a syscall that failed with EINTR and its original a0,
+    /// it is restarted if the signal that interrupted it is ignored or SA_RESTART.
+    pub interrupted: Option<(i64, u64)>,
+    /// The mask to restore after a handler that rt_sigsuspend() waited for.
+    saved_mask: Option<u64>,
+}
+
+/* The part of a thread's signal state that other threads can reach. */
+#[derive(Debug)]
+pub struct SigQueue {
+    pending: AtomicU64,
+    /// Only written by the thread itself.
+    blocked: AtomicU64,
+    infos: Mutex<[SigInfo; NSIG]>,
+}
+
+impl Default for SigQueue {
+    fn default() -> Self {
+        SigQueue {
+            pending: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            infos: Mutex::new([SigInfo::default(); NSIG]),
+        }
+    }
+}
+
+impl SigQueue {
+    fn push(&self, info: SigInfo) {
+        let mut infos = self.infos.lock().unwrap();
+        if self.pending.load(Ordering::Relaxed) & sigbit(info.signo) == 0 {
+            infos[info.signo as usize - 1] = info;
+            self.pending.fetch_or(sigbit(info.signo), Ordering::Relaxed);
+        }
+    }
+
+    fn take(&self, sig: i32) -> SigInfo {
+        let infos = self.infos.lock().unwrap();
+        self.pending.fetch_and(!sigbit(sig), Ordering::Relaxed);
+        infos[sig as usize - 1]
+    }
+
+    fn blocked(&self) -> u64 {
+        self.blocked.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Signals {
+            actions: Arc::new(Mutex::new([SigAction::default(); NSIG])),
+            queue: Arc::default(),
+            altstack: AltStack::default(),
+            trampoline: Arc::default(),
+            interrupted: None,
+            saved_mask: None,
+        }
+    }
+}
+
+fn valid(sig: i32) -> bool {
+    (1..=NSIG as i32).contains(&sig)
+}
+
+impl Signals {
+    /* For a new thread: The same actions and mask, nothing pending and no alternate stack. */
+    pub fn new_thread(&self) -> Signals {
+        let signals = Signals {
+            actions: self.actions.clone(),
+            trampoline: self.trampoline.clone(),
+            ..Signals::default()
+        };
+        signals.queue.blocked.store(self.blocked(), Ordering::Relaxed);
+        signals
+    }
+
+    /*
+     * For the child of a fork(): Copies of the actions, the mask and the alternate stack,
+     * nothing pending. Its own copies, the other threads are gone in the child.
+     */
+    pub fn fork(&self) -> Signals {
+        let signals = Signals {
+            actions: Arc::new(Mutex::new(*self.actions())),
+            trampoline: Arc::new(Mutex::new(*self.trampoline.lock().unwrap())),
+            altstack: self.altstack,
+            ..Signals::default()
+        };
+        signals.queue.blocked.store(self.blocked(), Ordering::Relaxed);
+        signals
+    }
+
+    /* execve(): Handlers are gone with the old image, ignored signals stay ignored. */
+    pub fn exec(&mut self) {
+        for action in self.actions().iter_mut().filter(|action| action.handler != SIG_IGN) {
+            *action = SigAction::default();
+        }
+        *self.trampoline.lock().unwrap() = None;
+        self.altstack = AltStack::default();
+        self.interrupted = None;
+        self.saved_mask = None;
+    }
+
+    /*
+     * Before an execve() of a host binary: Signals the guest ignores are ignored by the
+     * host for it, the simulator's handlers would be reset to SIG_DFL.
+     */
+    pub fn prepare_host_exec(&self) {
+        for sig in HOST_SIGNALS {
+            let handler = match self.actions()[sig as usize - 1].handler == SIG_IGN {
+                true => libc::SIG_IGN,
+                false => libc::SIG_DFL
+            };
+            unsafe { libc::signal(sig, handler) };
+        }
+    }
+
+    pub fn queue_handle(&self) -> Arc<SigQueue> {
+        self.queue.clone()
+    }
+
+    fn actions(&self) -> MutexGuard<'_, [SigAction; NSIG]> {
+        self.actions.lock().unwrap()
+    }
+
+    pub fn action(&self, sig: i32) -> Result<SigAction, Errno> {
+        match valid(sig) {
+            true => Ok(self.actions()[sig as usize - 1]),
+            false => Err(Errno::EINVAL)
+        }
+    }
+
+    /*
+     * Returns the old action. Pending signals that are now ignored are discarded (only
+     * the ones of this thread, the others are ignored at delivery).
+     */
+    pub fn set_action(&mut self, sig: i32, action: SigAction) -> Result<SigAction, Errno> {
+        let old = self.action(sig)?;
+        if sigbit(sig) & UNBLOCKABLE != 0 {
+            return Err(Errno::EINVAL)
+        }
+        self.actions()[sig as usize - 1] = action;
+        if self.ignored(sig) {
+            self.queue.pending.fetch_and(!sigbit(sig), Ordering::Relaxed);
+        }
+        Ok(old)
+    }
+
+    pub fn blocked(&self) -> u64 {
+        self.queue.blocked()
+    }
+
+    /*
+     * The host's mask follows the guest's for the signals we catch: A blocked signal
+     * stays pending in the host kernel and does not interrupt syscalls with EINTR.
+     */
+    pub fn set_blocked(&mut self, mask: u64) {
+        let blocked = mask & !UNBLOCKABLE;
+        self.queue.blocked.store(blocked, Ordering::Relaxed);
+        unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for sig in HOST_SIGNALS.iter().filter(|sig| blocked & sigbit(**sig) != 0) {
+                libc::sigaddset(&mut set, *sig);
+            }
+            libc::pthread_sigmask(libc::SIG_SETMASK, &set, std::ptr::null_mut());
+        }
+    }
+
+    /* rt_sigpending(): Including what the host kernel holds back for us. */
+    pub fn pending(&self) -> u64 {
+        let mut host = 0;
+        unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigpending(&mut set);
+            for sig in HOST_SIGNALS {
+                if libc::sigismember(&set, sig) == 1 {
+                    host |= sigbit(sig);
+                }
+            }
+        }
+        self.queue.pending.load(Ordering::Relaxed) | host | host_pending()
+    }
+
+    fn ignored(&self, sig: i32) -> bool {
+        let action = self.actions()[sig as usize - 1];
+        action.handler == SIG_IGN
+            || (action.handler == SIG_DFL && default_action(sig) == DefaultAction::Ignore)
+    }
+
+    /* Ignored signals are discarded right away, unless they are blocked. */
+    pub fn queue(&mut self, info: SigInfo) {
+        if !self.ignored(info.signo) || self.blocked() & sigbit(info.signo) != 0 {
+            self.queue.push(info);
+        }
+    }
+
+    /*
+     * Send a signal to another thread (with thread id `tid`) of the guest. Unless it
+     * blocks the signal, its blocking syscall is interrupted so that it sees it.
+     */
+    pub fn send(&self, tid: i32, queue: &SigQueue, info: SigInfo) {
+        let blocked = queue.blocked() & sigbit(info.signo) != 0;
+        if !self.ignored(info.signo) || blocked {
+            queue.push(info);
+        }
+        if !blocked {
+            kick(tid);
+        }
+    }
+
+    fn queue_host_signals(&mut self) {
+        let host = HOST_PENDING.with(|pending| pending.swap(0, Ordering::Relaxed));
+        for sig in (1..=NSIG as i32).filter(|sig| host & sigbit(*sig) != 0) {
+            self.queue(SigInfo { signo: sig, code: SI_USER, ..SigInfo::default() });
+        }
+    }
+
+    /* Faults cannot be blocked or ignored: The guest dies if it tries to. */
+    pub fn force(&mut self, info: SigInfo) {
+        let index = info.signo as usize - 1;
+        let blocked = self.blocked();
+        if blocked & sigbit(info.signo) != 0 || self.actions()[index].handler == SIG_IGN {
+            self.actions()[index] = SigAction::default();
+            self.queue.blocked.store(blocked & !sigbit(info.signo), Ordering::Relaxed);
+        }
+        self.queue.pending.fetch_and(!sigbit(info.signo), Ordering::Relaxed);
+        self.queue.push(info);
+    }
+
+    pub fn has_deliverable(&self) -> bool {
+        (self.queue.pending.load(Ordering::Relaxed) | host_pending()) & !self.blocked() != 0
+    }
+
+    /* The lowest-numbered deliverable signal, like the kernel picks them. */
+    fn next(&mut self) -> Option<SigInfo> {
+        self.queue_host_signals();
+        let deliverable = self.queue.pending.load(Ordering::Relaxed) & !self.blocked();
+        if deliverable == 0 {
+            return None
+        }
+        Some(self.queue.take(deliverable.trailing_zeros() as i32 + 1))
+    }
+
+    /*
+     * rt_sigsuspend(): Wait with `mask` blocked until a signal that is not ignored is
+     * pending. The host signals (and kicks from other threads) are blocked while
+     * checking, so none can slip through.
+     */
+    pub fn suspend(&mut self, mask: u64) {
+        self.saved_mask = Some(self.blocked());
+        self.set_blocked(mask);
+        unsafe {
+            let (mut all, mut wait): (libc::sigset_t, libc::sigset_t) = std::mem::zeroed();
+            libc::sigemptyset(&mut all);
+            for sig in HOST_SIGNALS {
+                libc::sigaddset(&mut all, sig);
+            }
+            if KICK_SIGNAL.load(Ordering::Relaxed) != 0 {
+                libc::sigaddset(&mut all, KICK_SIGNAL.load(Ordering::Relaxed));
+            }
+            libc::pthread_sigmask(libc::SIG_BLOCK, &all, &mut wait);
+            loop {
+                self.queue_host_signals();
+                if self.queue.pending.load(Ordering::Relaxed) & !self.blocked() != 0 {
+                    break
+                }
+                libc::sigsuspend(&wait);
+            }
+            libc::pthread_sigmask(libc::SIG_SETMASK, &wait, std::ptr::null_mut());
+        }
+    }
+
+    /* Mapped at the first delivery, in its own page like the kernel's vDSO. */
+    fn trampoline(&self, memory: &Memory) -> Result<u64, Errno> {
+        let mut trampoline = self.trampoline.lock().unwrap();
+        if let Some(addr) = *trampoline {
+            return Ok(addr)
+        }
+        let addr = memory.mm().mmap(Placement::Hint(0), PAGE_SIZE, PROT_READ | PROT_EXEC, false,
+                                    Some("[vdso]".to_string()), 0)?;
+        /* li a7, 139 (SYS_rt_sigreturn); ecall */
+        let code = [0x08b00893u32, 0x00000073u32];
+        let bytes: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        memory.copy_bulk(addr as u64, &bytes).map_err(|_| Errno::EFAULT)?;
+        *trampoline = Some(addr as u64);
+        Ok(addr as u64)
+    }
+}
+
+/*
+ * struct ucontext of riscv64: uc_flags, uc_link, uc_stack (stack_t), uc_sigmask (padded
+ * to 128 bytes) and the 16-byte aligned uc_mcontext. That is sc_regs (pc, then x1-x31)
+ * followed by the F/D state, f0-f31 and fcsr, in a union sized for the Q extension.
+ */
+const UC_STACK: usize = 16;
+const UC_SIGMASK: usize = 40;
+const UC_MCONTEXT: usize = 176;
+const MC_FPREGS: usize = 256;
+const MC_FCSR: usize = MC_FPREGS + 256;
+const UCONTEXT_SIZE: usize = UC_MCONTEXT + MC_FPREGS + 528;
+/* struct rt_sigframe: siginfo_t, then the ucontext. */
+pub const RT_SIGFRAME_SIZE: usize = SIGINFO_SIZE + UCONTEXT_SIZE;
+
+fn put_u64(buf: &mut [u8], pos: usize, val: u64) {
+    buf[pos..(pos + 8)].copy_from_slice(&val.to_le_bytes());
+}
+
+fn get_u64(buf: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(buf[pos..(pos + 8)].try_into().unwrap())
+}
+
+/* Restart the syscall that was interrupted right before, the kernel would have done so. */
+fn restart_interrupted(cpu: &mut CPU, pc: &mut u64, a0: &mut u64) {
+    if let Some((after, orig_a0)) = cpu.signals.interrupted.take() {
+        if after == *pc as i64 {
+            *pc -= 4;
+            *a0 = orig_a0;
+        }
+    }
+}
+
+fn setup_frame(cpu: &mut CPU, info: SigInfo, action: SigAction) -> Result<(), Error> {
+    let sp = cpu.get_reg(REG_SP);
+    let alt = cpu.signals.altstack;
+    let use_altstack = action.flags & SA_ONSTACK != 0 && alt.flags & SS_DISABLE == 0;
+    let top = match use_altstack && !alt.contains(sp) {
+        true => alt.sp + alt.size,
+        false => sp
+    };
+    let frame = (top as usize).wrapping_sub(RT_SIGFRAME_SIZE) & !0xf;
+    let trampoline = cpu.signals.trampoline(&cpu.memory)
+        .map_err(|_| Error::Signal(SIGSEGV))?;
+
+    let (mut pc, mut a0) = (cpu.pc as u64, cpu.get_reg(REG_A0));
+    if action.flags & SA_RESTART != 0 {
+        restart_interrupted(cpu, &mut pc, &mut a0);
+    }
+    cpu.signals.interrupted = None;
+
+    let mut buf = vec![0u8; RT_SIGFRAME_SIZE];
+    buf[..SIGINFO_SIZE].copy_from_slice(&info.to_bytes());
+    let uc = &mut buf[SIGINFO_SIZE..];
+    let mut stack = alt;
+    if alt.contains(sp) {
+        stack.flags |= SS_ONSTACK;
+    }
+    uc[UC_STACK..(UC_STACK + GUEST_STACK_T_SIZE)].copy_from_slice(&stack.to_bytes());
+    let mask = cpu.signals.saved_mask.take().unwrap_or(cpu.signals.blocked());
+    put_u64(uc, UC_SIGMASK, mask);
+    let mcontext = &mut uc[UC_MCONTEXT..];
+    put_u64(mcontext, 0, pc);
+    for reg in 1..32 {
+        let val = if reg == REG_A0 as usize { a0 } else { cpu.regs[reg] };
+        put_u64(mcontext, reg * 8, val);
+    }
+    for (reg, val) in cpu.fregs.iter().enumerate() {
+        put_u64(mcontext, MC_FPREGS + reg * 8, *val);
+    }
+    mcontext[MC_FCSR..(MC_FCSR + 4)].copy_from_slice(&cpu.fcsr.to_le_bytes());
+
+    /* No room for the frame: The kernel kills the guest with SIGSEGV, too. */
+    match cpu.memory.guest_slice_mut(frame, RT_SIGFRAME_SIZE) {
+        Ok(dst) => dst.copy_from_slice(&buf),
+        Err(_) => return Err(Error::Signal(SIGSEGV))
+    }
+
+    let sig = info.signo;
+    let mut blocked = cpu.signals.blocked() | action.mask;
+    if action.flags & SA_NODEFER == 0 {
+        blocked |= sigbit(sig);
+    }
+    cpu.signals.set_blocked(blocked);
+    if action.flags & SA_RESETHAND != 0 {
+        cpu.signals.actions()[sig as usize - 1] = SigAction::default();
+    }
+
+    cpu.set_reg(REG_A0, sig as u64);
+    cpu.set_reg(REG_A1, frame as u64);
+    cpu.set_reg(REG_A2, (frame + SIGINFO_SIZE) as u64);
+    cpu.set_reg(REG_SP, frame as u64);
+    cpu.set_reg(REG_RA, trampoline);
+    cpu.pc = action.handler as i64;
+    Ok(())
+}
+
+/*
+ * Deliver pending signals that are not blocked. At most one handler is entered,
+ * the others follow at the next TB boundary (i.e. the handler's first TB).
+ */
+pub fn deliver(cpu: &mut CPU) -> Result<(), Error> {
+    while let Some(info) = cpu.signals.next() {
+        let action = cpu.signals.actions()[info.signo as usize - 1];
+        match (action.handler, default_action(info.signo)) {
+            (SIG_DFL, DefaultAction::Terminate) => return Err(Error::Signal(info.signo)),
+            (SIG_DFL, DefaultAction::Stop) => {
+                /* Job control is left to the host: Stop the whole simulator. */
+                unsafe { libc::raise(libc::SIGSTOP) };
+            },
+            (SIG_DFL, DefaultAction::Ignore) | (SIG_IGN, _) => {},
+            _ => return setup_frame(cpu, info, action)
+        }
+        let (mut pc, mut a0) = (cpu.pc as u64, cpu.get_reg(REG_A0));
+        restart_interrupted(cpu, &mut pc, &mut a0);
+        cpu.pc = pc as i64;
+        cpu.set_reg(REG_A0, a0);
+    }
+    if let Some(mask) = cpu.signals.saved_mask.take() {
+        cpu.signals.set_blocked(mask);
+    }
+    Ok(())
+}
+
+/* rt_sigreturn(): Restore what setup_frame() saved, the frame is at the guest's sp. */
+pub fn sigreturn(cpu: &mut CPU) -> Result<u64, Error> {
+    let frame = cpu.get_reg(REG_SP) as usize;
+    let Ok(buf) = cpu.memory.guest_slice(frame, RT_SIGFRAME_SIZE).map(<[u8]>::to_vec) else {
+        return Err(Error::Signal(SIGSEGV))
+    };
+    let uc = &buf[SIGINFO_SIZE..];
+    let mcontext = &uc[UC_MCONTEXT..];
+    for reg in 1..32 {
+        cpu.regs[reg] = get_u64(mcontext, reg * 8);
+    }
+    for (reg, val) in cpu.fregs.iter_mut().enumerate() {
+        *val = get_u64(mcontext, MC_FPREGS + reg * 8);
+    }
+    cpu.fcsr = u32::from_le_bytes(mcontext[MC_FCSR..(MC_FCSR + 4)].try_into().unwrap()) & 0xff;
+    cpu.signals.set_blocked(get_u64(uc, UC_SIGMASK));
+    let stack = AltStack::from_bytes(&uc[UC_STACK..(UC_STACK + GUEST_STACK_T_SIZE)]);
+    if !cpu.signals.altstack.contains(cpu.get_reg(REG_SP)) {
+        cpu.signals.altstack = AltStack { flags: stack.flags & !SS_ONSTACK, ..stack };
+    }
+    /* The ecall of rt_sigreturn still adds 4 to the pc. */
+    cpu.pc = get_u64(mcontext, 0) as i64 - 4;
+    Ok(cpu.get_reg(REG_A0))
+}
+
+/* The signal a fault the simulator detected at cpu.pc becomes, if it is the guest's fault. */
+pub fn fault_signal(cpu: &CPU, e: &Error) -> Option<SigInfo> {
+    match *e {
+        Error::Illegal | Error::InvalidEncoding(_) =>
+            Some(SigInfo::fault(SIGILL, ILL_ILLOPC, cpu.pc as u64)),
+        Error::SegFault(addr) => {
+            let code = if cpu.memory.mm().is_mapped(addr) { SEGV_ACCERR } else { SEGV_MAPERR };
+            Some(SigInfo::fault(SIGSEGV, code, addr as u64))
+        },
+        Error::MisalignedAccess { addr, .. } =>
+            Some(SigInfo::fault(SIGBUS, BUS_ADRALN, addr as u64)),
+        _ => None
+    }
+}
+
+/*
+ * Signals sent to the simulator that are passed on to the guest. Synchronous ones
+ * are the simulator's own faults, job control signals stop the simulator itself.
+ */
+const HOST_SIGNALS: [i32; 13] = [
+    SIGHUP, SIGINT, SIGQUIT, SIGUSR1, SIGUSR2, SIGPIPE, SIGALRM, SIGTERM, SIGCHLD, SIGURG,
+    SIGVTALRM, SIGPROF, SIGWINCH,
+];
+
+thread_local! {
+    /* Caught but not yet queued for the guest (that needs the CPU). Per host thread, the
+     * host kernel picks one that does not block the signal, like Linux would for the guest.
+     * Const-initialized, so there is no lazy initialization in the handler. */
+    static HOST_PENDING: AtomicU64 = const { AtomicU64::new(0) };
+}
+
+fn host_pending() -> u64 {
+    HOST_PENDING.with(|pending| pending.load(Ordering::Relaxed))
+}
+
+extern "C" fn host_handler(sig: libc::c_int) {
+    HOST_PENDING.with(|pending| pending.fetch_or(sigbit(sig), Ordering::Relaxed));
+}
+
+/* Not one of the guest's signals: Only interrupts a blocking host syscall with EINTR. */
+static KICK_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn kick_handler(_: libc::c_int) {}
+
+/* Make a guest thread look at its pending signals (and whether the guest is exiting). */
+pub fn kick(tid: i32) {
+    let sig = KICK_SIGNAL.load(Ordering::Relaxed);
+    if sig != 0 {
+        unsafe { libc::syscall(libc::SYS_tgkill, libc::getpid(), tid, sig) };
+    }
+}
+
+/*
+ * Installed without SA_RESTART, so that blocking host syscalls return EINTR and the
+ * guest's handler can run. Signals that were ignored when the simulator was started
+ * (e.g. by nohup) stay ignored for the guest, except SIGPIPE: Rust ignores that one.
+ */
+pub fn install_host_handlers(signals: &mut Signals) {
+    for sig in HOST_SIGNALS {
+        unsafe {
+            let mut old: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(sig, std::ptr::null(), &mut old);
+            if old.sa_sigaction == libc::SIG_IGN && sig != SIGPIPE {
+                signals.actions()[sig as usize - 1].handler = SIG_IGN;
+                continue
+            }
+            let mut act: libc::sigaction = std::mem::zeroed();
+            act.sa_sigaction = host_handler as extern "C" fn(libc::c_int) as usize;
+            libc::sigemptyset(&mut act.sa_mask);
+            libc::sigaction(sig, &act, std::ptr::null_mut());
+        }
+    }
+    /* The first real-time signal the host libc leaves to applications. */
+    unsafe {
+        let sig = libc::SIGRTMIN();
+        let mut act: libc::sigaction = std::mem::zeroed();
+        act.sa_sigaction = kick_handler as extern "C" fn(libc::c_int) as usize;
+        libc::sigemptyset(&mut act.sa_mask);
+        if libc::sigaction(sig, &act, std::ptr::null_mut()) == 0 {
+            KICK_SIGNAL.store(sig, Ordering::Relaxed);
+        }
+    }
+}
+
+/* Die from `sig` like the guest did, so that whoever waits for us sees it. */
+pub fn exit_by_signal(sig: i32) -> ! {
+    unsafe {
+        /* The guest's core dump would not be one of the simulator. */
+        let limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        libc::setrlimit(libc::RLIMIT_CORE, &limit);
+        libc::signal(sig, libc::SIG_DFL);
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, sig);
+        libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+        libc::raise(sig);
+    }
+    /* E.g. a real-time signal the host's libc reserved for itself: Like the shell says. */
+    std::process::exit(128 + sig)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::CSR_FCSR;
+    use crate::cpu::test::*;
+
+    const SIG_SETMASK: usize = 2;
+
+    fn csrwi(csr: u16, imm: u32) -> u32 { ((csr as u32) << 20) | (imm << 15) | (5 << 12) | 0x73 }
+    const RET: u32 = 0x00008067;
+
+    /* A SIGILL handler that skips the illegal instruction, via the saved pc. */
+    #[test]
+    fn delivery() {
+        let (t0, t1, t2, t3, a0, a1, a2, a3, a7) = (5, 6, 7, 28, 10, 11, 12, 13, 17);
+        let mut code = vec![
+            auipc(t0), csrwi(CSR_FCSR, 0x3),
+            addi(a0, 0, SIGILL), addi(a1, t0, 0x100), addi(a2, 0, 0), addi(a3, 0, 8),
+            addi(a7, 0, 134), ECALL,
+            0x00000000,
+            ld(a0, t0, 0x118), addi(a7, 0, 93), ECALL,
+        ];
+        code.resize(16, 0);
+        /* At +0x40, a0 = sig, a2 = &uc */
+        code.extend([
+            ld(t1, a2, UC_MCONTEXT as i32), addi(t1, t1, 4), sd(t1, a2, UC_MCONTEXT as i32),
+            auipc(t2), addi(t3, a0, 38), sd(t3, t2, 0x118 - 0x4c),
+            csrwi(CSR_FCSR, 0x1f), RET,
+        ]);
+        let (cpu, res) = run(&code, |base| {
+            let action = SigAction { handler: base + 0x40, flags: SA_RESTART, mask: sigbit(SIGUSR1) };
+            action.to_bytes().to_vec()
+        });
+        assert_eq!(res.unwrap(), 42);
+        assert_eq!(cpu.signals.blocked(), 0);
+        assert_eq!(cpu.fcsr, 0x3);
+        assert_eq!(cpu.get_reg(REG_SP) as usize % 16, 0);
+
+        /* Without a handler, the guest dies. Blocking SIGILL does not help. */
+        code[2] = addi(a0, 0, SIG_SETMASK as i32);
+        code[3] = addi(a1, t0, 0x100);
+        code[6] = addi(a7, 0, 135);
+        let (_, res) = run(&code, |_| sigbit(SIGILL).to_le_bytes().to_vec());
+        assert!(matches!(res, Err(Error::Signal(SIGILL))));
+    }
+
+    #[test]
+    fn pending_and_blocked() {
+        let mut signals = Signals::default();
+        signals.queue(SigInfo::user(SIGUSR2, SI_USER));
+        signals.queue(SigInfo::user(SIGTERM, SI_TKILL));
+        signals.set_blocked(sigbit(SIGTERM) | sigbit(SIGKILL));
+        assert_eq!(signals.blocked(), sigbit(SIGTERM));
+        assert_eq!(signals.next().map(|info| info.signo), Some(SIGUSR2));
+        assert_eq!(signals.next(), None);
+        signals.set_blocked(0);
+        assert_eq!(signals.next().map(|info| (info.signo, info.code)), Some((SIGTERM, SI_TKILL)));
+
+        /* Ignoring a signal discards it, faults cannot be ignored. */
+        signals.queue(SigInfo::user(SIGCHLD, SI_USER));
+        let ignore = SigAction { handler: SIG_IGN, ..SigAction::default() };
+        signals.set_action(SIGCHLD, ignore).unwrap();
+        signals.set_action(SIGSEGV, ignore).unwrap();
+        assert!(!signals.has_deliverable());
+        signals.force(SigInfo::fault(SIGSEGV, SEGV_MAPERR, 0x10));
+        assert_eq!(signals.action(SIGSEGV), Ok(SigAction::default()));
+        assert_eq!(signals.next().map(|info| info.addr), Some(Some(0x10)));
+        assert_eq!(signals.set_action(SIGKILL, SigAction::default()), Err(Errno::EINVAL));
+        assert_eq!(signals.action(65), Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn sigaction_layout() {
+        let action = SigAction { handler: 0x1000, flags: SA_ONSTACK | SA_RESTART, mask: 0x3 };
+        assert_eq!(SigAction::from_bytes(&action.to_bytes()), action);
+        let stack = AltStack { sp: 0x4000, flags: 0, size: 0x2000 };
+        assert_eq!(AltStack::from_bytes(&stack.to_bytes()), stack);
+        assert!(stack.contains(0x5000) && !stack.contains(0x7000));
+        assert_eq!(RT_SIGFRAME_SIZE, 1088);
+    }
+}
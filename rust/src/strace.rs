@@ -0,0 +1,381 @@
+/*
+ * strace-like tracing of the guest's syscalls, e.g.:
+ *
+ *   openat(AT_FDCWD, "/etc/passwd", O_RDONLY) = 3
+ *   read(3, "root:x:0:0:root:/root:/bin/bash\n"..., 4096) = 1432
+ *   openat(AT_FDCWD, "/nonexistent", O_RDONLY) = -1 ENOENT (No such file or directory)
+ *
+ * Arguments are decoded according to the ArgKinds in the syscall table.
+ */
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use syscalls::Errno;
+
+use crate::cpu::CPU;
+use crate::insts::Error;
+use crate::net::SockAddr;
+use crate::sys::{ArgKind, SysResult, Syscall};
+
+/* Like `strace -s 32`: Longer strings and buffers are truncated. */
+const MAX_STRING_LEN: usize = 32;
+
+const AT_FDCWD: i32 = -100;
+
+const OPEN_FLAGS: &[(usize, &str)] = &[
+    (0o100, "O_CREAT"), (0o200, "O_EXCL"), (0o400, "O_NOCTTY"), (0o1000, "O_TRUNC"),
+    (0o2000, "O_APPEND"), (0o4000, "O_NONBLOCK"), (0o4010000, "O_SYNC"), (0o10000, "O_DSYNC"),
+    (0o20000, "O_ASYNC"), (0o40000, "O_DIRECT"), (0o100000, "O_LARGEFILE"),
+    (0o200000, "O_DIRECTORY"), (0o400000, "O_NOFOLLOW"), (0o1000000, "O_NOATIME"),
+    (0o2000000, "O_CLOEXEC"), (0o10000000, "O_PATH"),
+];
+
+const AT_FLAGS: &[(usize, &str)] = &[
+    (0x100, "AT_SYMLINK_NOFOLLOW"), (0x200, "AT_REMOVEDIR"), (0x400, "AT_SYMLINK_FOLLOW"),
+    (0x800, "AT_NO_AUTOMOUNT"), (0x1000, "AT_EMPTY_PATH"), (0x2000, "AT_STATX_FORCE_SYNC"),
+    (0x4000, "AT_STATX_DONT_SYNC"),
+];
+
+const MAP_FLAGS: &[(usize, &str)] = &[
+    (0x03, "MAP_SHARED_VALIDATE"), (0x01, "MAP_SHARED"), (0x02, "MAP_PRIVATE"),
+    (0x10, "MAP_FIXED"), (0x20, "MAP_ANONYMOUS"), (0x100, "MAP_GROWSDOWN"),
+    (0x4000, "MAP_NORESERVE"), (0x8000, "MAP_POPULATE"), (0x20000, "MAP_STACK"),
+    (0x100000, "MAP_FIXED_NOREPLACE"),
+];
+
+const CLOCK_IDS: &[&str] = &[
+    "CLOCK_REALTIME", "CLOCK_MONOTONIC", "CLOCK_PROCESS_CPUTIME_ID", "CLOCK_THREAD_CPUTIME_ID",
+    "CLOCK_MONOTONIC_RAW", "CLOCK_REALTIME_COARSE", "CLOCK_MONOTONIC_COARSE", "CLOCK_BOOTTIME",
+    "CLOCK_REALTIME_ALARM", "CLOCK_BOOTTIME_ALARM", "10", "CLOCK_TAI",
+];
+
+const SIGNAL_NAMES: &[&str] = &[
+    "0", "SIGHUP", "SIGINT", "SIGQUIT", "SIGILL", "SIGTRAP", "SIGABRT", "SIGBUS", "SIGFPE",
+    "SIGKILL", "SIGUSR1", "SIGSEGV", "SIGUSR2", "SIGPIPE", "SIGALRM", "SIGTERM", "SIGSTKFLT",
+    "SIGCHLD", "SIGCONT", "SIGSTOP", "SIGTSTP", "SIGTTIN", "SIGTTOU", "SIGURG", "SIGXCPU",
+    "SIGXFSZ", "SIGVTALRM", "SIGPROF", "SIGWINCH", "SIGIO", "SIGPWR", "SIGSYS",
+];
+
+const FCNTL_CMDS: &[(usize, &str)] = &[
+    (0, "F_DUPFD"), (1, "F_GETFD"), (2, "F_SETFD"), (3, "F_GETFL"), (4, "F_SETFL"),
+    (5, "F_GETLK"), (6, "F_SETLK"), (7, "F_SETLKW"), (1030, "F_DUPFD_CLOEXEC"),
+];
+
+const IOCTL_CMDS: &[(usize, &str)] = &[
+    (0x5401, "TCGETS"), (0x5402, "TCSETS"), (0x5403, "TCSETSW"), (0x5404, "TCSETSF"),
+    (0x540f, "TIOCGPGRP"), (0x5410, "TIOCSPGRP"), (0x5413, "TIOCGWINSZ"), (0x5414, "TIOCSWINSZ"),
+    (0x541b, "FIONREAD"), (0x5421, "FIONBIO"),
+];
+
+const RETURNS_ADDRESS: &[&str] = &["brk", "mmap", "mremap"];
+
+/* Names for syscall classes that cannot be derived from the argument kinds. */
+const CLASSES: &[(&str, &[&str])] = &[
+    ("%memory", &["brk", "mmap", "munmap", "mprotect", "mremap", "madvise", "msync"]),
+    ("%process", &["exit", "exit_group", "clone", "clone3", "fork", "vfork", "execve",
+                   "execveat", "wait4", "waitid", "kill", "tkill", "tgkill"]),
+    ("%signal", &["rt_sigaction", "rt_sigprocmask", "rt_sigpending", "rt_sigreturn",
+                  "rt_sigsuspend", "sigaltstack", "kill", "tkill", "tgkill"]),
+    ("%network", &["socket", "socketpair", "bind", "connect", "listen", "accept", "accept4",
+                   "sendto", "recvfrom", "sendmsg", "recvmsg", "shutdown", "getsockname",
+                   "getpeername", "setsockopt", "getsockopt"]),
+];
+
+/* Which syscalls to trace: `all`, or a comma-separated list of names and classes
+ * (`%file`, `%desc`, `%memory`, ...), optionally negated with a leading `!`. */
+#[derive(Debug, Clone)]
+pub struct Filter {
+    entries: Vec<String>,
+    negated: bool,
+}
+
+impl std::str::FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negated, list) = match s.strip_prefix('!') {
+            Some(list) => (true, list),
+            None => (false, s)
+        };
+        let entries: Vec<String> = list.split(',')
+            .filter(|e| !e.is_empty())
+            .map(|e| e.to_string())
+            .collect();
+        if entries.is_empty() {
+            return Err(format!("invalid strace filter {:?}", s))
+        }
+        Ok(Filter { entries, negated })
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, name: &str, args: &[ArgKind]) -> bool {
+        let matches = self.entries.iter().any(|entry| match entry.as_str() {
+            "all" => true,
+            "%file" => args.contains(&ArgKind::CStr),
+            "%desc" => args.contains(&ArgKind::Fd),
+            class if class.starts_with('%') => CLASSES.iter()
+                .any(|(c, names)| *c == class && names.contains(&name)),
+            entry => entry == name
+        });
+        matches != self.negated
+    }
+}
+
+#[derive(Clone)]
+pub struct Strace {
+    /// Shared by all threads of the guest.
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+    filter: Filter,
+    /// `[pid <tid>] ` for threads other than the first one, like `strace -f`.
+    prefix: String,
+}
+
+impl Strace {
+    pub fn new(out: Box<dyn Write + Send>, filter: Filter) -> Self {
+        Self { out: Arc::new(Mutex::new(out)), filter, prefix: String::new() }
+    }
+
+    /* For the clone of a Strace a new thread of the guest got. */
+    pub fn set_thread(&mut self, tid: i32) {
+        self.prefix = format!("[pid {}] ", tid);
+    }
+
+    pub fn traces(&self, syscall: &Syscall) -> bool {
+        self.filter.matches(syscall.name, syscall.args)
+    }
+
+    pub fn traces_unknown(&self, nr: u64) -> bool {
+        self.filter.matches(&format!("syscall_{:#x}", nr), &[])
+    }
+
+    /* Tracing is best effort, a broken output file should not stop the guest. */
+    pub fn write(&mut self, line: &str) {
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = out.write_all(format!("{}{}", self.prefix, line).as_bytes());
+    }
+}
+
+fn format_string(bytes: &[u8]) -> String {
+    let mut s = String::from("\"");
+    for b in bytes.iter().take(MAX_STRING_LEN) {
+        match b {
+            b'\n' => s.push_str("\\n"),
+            b'\t' => s.push_str("\\t"),
+            b'\r' => s.push_str("\\r"),
+            b'"' => s.push_str("\\\""),
+            b'\\' => s.push_str("\\\\"),
+            0x20..=0x7e => s.push(*b as char),
+            _ => s.push_str(&format!("\\{:o}", b))
+        }
+    }
+    s.push('"');
+    if bytes.len() > MAX_STRING_LEN {
+        s.push_str("...");
+    }
+    s
+}
+
+fn format_ptr(addr: usize) -> String {
+    if addr == 0 { "NULL".to_string() } else { format!("{:#x}", addr) }
+}
+
+pub fn format_flags(val: usize, names: &[(usize, &str)]) -> String {
+    let mut rest = val;
+    let mut parts = Vec::new();
+    for (bits, name) in names {
+        if *bits != 0 && rest & bits == *bits {
+            parts.push(name.to_string());
+            rest &= !bits;
+        }
+    }
+    if rest != 0 || parts.is_empty() {
+        parts.push(format!("{:#x}", rest));
+    }
+    parts.join("|")
+}
+
+fn format_open_flags(flags: usize) -> String {
+    let mode = ["O_RDONLY", "O_WRONLY", "O_RDWR", "O_ACCMODE"][flags & 0b11];
+    match flags & !0b11 {
+        0 => mode.to_string(),
+        rest => format!("{}|{}", mode, format_flags(rest, OPEN_FLAGS))
+    }
+}
+
+fn format_mode(mode: u32) -> String {
+    let ty = match mode & 0o170000 {
+        0o140000 => "S_IFSOCK|", 0o120000 => "S_IFLNK|", 0o100000 => "S_IFREG|",
+        0o060000 => "S_IFBLK|", 0o040000 => "S_IFDIR|", 0o020000 => "S_IFCHR|",
+        0o010000 => "S_IFIFO|", _ => ""
+    };
+    format!("{}{:04o}", ty, mode & 0o7777)
+}
+
+/* Structs as the guest sees them (the RISC-V layout), like strace does it without -v. */
+fn format_sockaddr(addr: &SockAddr) -> String {
+    match addr {
+        SockAddr::Unix(path) if path.first() == Some(&0) =>
+            format!("{{sa_family=AF_UNIX, sun_path=@{}}}", format_string(&path[1..])),
+        SockAddr::Unix(path) => format!("{{sa_family=AF_UNIX, sun_path={}}}",
+            format_string(path.split(|c| *c == 0).next().unwrap())),
+        SockAddr::V4(v4) => format!(
+            "{{sa_family=AF_INET, sin_port=htons({}), sin_addr=inet_addr(\"{}\")}}",
+            v4.port(), v4.ip()),
+        SockAddr::V6(v6) => format!("{{sa_family=AF_INET6, sin6_port=htons({}), \
+            sin6_flowinfo=htonl({}), inet_pton(AF_INET6, \"{}\", &sin6_addr), sin6_scope_id={}}}",
+            v6.port(), v6.flowinfo(), v6.ip(), v6.scope_id()),
+        SockAddr::Other(family, data) =>
+            format!("{{sa_family={}, sa_data={}}}", family, format_string(data))
+    }
+}
+
+fn format_cmd(cmd: usize, names: &[(usize, &str)]) -> String {
+    match names.iter().find(|(val, _)| *val == cmd) {
+        Some((_, name)) => name.to_string(),
+        None => format!("{:#x}", cmd)
+    }
+}
+
+fn format_struct(cpu: &CPU, name: &str, addr: usize) -> Option<String> {
+    let mem = &cpu.memory;
+    match name {
+        "stat" => {
+            let mode = mem.load_u32(addr + 16).ok()?;
+            let size = match mode & 0o170000 {
+                0o020000 | 0o060000 => {
+                    let rdev = mem.load_u64(addr + 32).ok()?;
+                    format!("st_rdev=makedev({:#x}, {:#x})",
+                        ((rdev >> 32) & 0xfffff000) | ((rdev >> 8) & 0xfff),
+                        ((rdev >> 12) & 0xffffff00) | (rdev & 0xff))
+                },
+                _ => format!("st_size={}", mem.load_u64(addr + 48).ok()? as i64)
+            };
+            Some(format!("{{st_mode={}, {}, ...}}", format_mode(mode), size))
+        },
+        "int[2]" => Some(format!("[{}, {}]",
+            mem.load_u32(addr).ok()? as i32, mem.load_u32(addr + 4).ok()? as i32)),
+        "timespec" => Some(format!("{{tv_sec={}, tv_nsec={}}}",
+            mem.load_u64(addr).ok()? as i64, mem.load_u64(addr + 8).ok()? as i64)),
+        "timeval" => Some(format!("{{tv_sec={}, tv_usec={}}}",
+            mem.load_u64(addr).ok()? as i64, mem.load_u64(addr + 8).ok()? as i64)),
+        "tms" => Some(format!("{{tms_utime={}, tms_stime={}, tms_cutime={}, tms_cstime={}}}",
+            mem.load_u64(addr).ok()?, mem.load_u64(addr + 8).ok()?,
+            mem.load_u64(addr + 16).ok()?, mem.load_u64(addr + 24).ok()?)),
+        "statx" => Some(format!("{{stx_mask={:#x}, stx_mode={}, stx_size={}, ...}}",
+            mem.load_u32(addr).ok()?, format_mode(mem.load_u16(addr + 28).ok()? as u32),
+            mem.load_u64(addr + 40).ok()?)),
+        _ => None
+    }
+}
+
+pub fn format_errno(errno: i32) -> String {
+    let desc = std::io::Error::from_raw_os_error(errno).to_string();
+    let desc = desc.split(" (os error").next().unwrap_or("");
+    match Errno::new(errno).name() {
+        Some(name) => format!("-1 {} ({})", name, desc),
+        None => format!("-1 errno {} ({})", errno, desc)
+    }
+}
+
+/*
+ * Arguments the kernel reads are decoded before the syscall (it might change them,
+ * e.g. the guest memory), the ones it writes only afterwards, if it succeeded.
+ */
+pub fn format_args_before(cpu: &CPU, kinds: &[ArgKind], args: &[usize; 6]) -> Vec<Option<String>> {
+    kinds.iter().zip(args.iter()).map(|(kind, arg)| match *kind {
+        ArgKind::Int => Some(format!("{}", *arg as i64)),
+        ArgKind::Fd if *arg as i32 == AT_FDCWD => Some("AT_FDCWD".to_string()),
+        ArgKind::Fd => Some(format!("{}", *arg as i32)),
+        ArgKind::InBuf { len } => Some(cpu.memory.guest_slice(*arg, args[len])
+            .map(format_string)
+            .unwrap_or_else(|_| format_ptr(*arg))),
+        ArgKind::CStr => Some(match cpu.memory.guest_cstr(*arg) {
+            Ok(s) => format_string(s.to_bytes()),
+            Err(_) => format_ptr(*arg)
+        }),
+        ArgKind::Flags => Some(format!("{:#x}", arg)),
+        ArgKind::OpenFlags => Some(format_open_flags(*arg)),
+        ArgKind::AtFlags => Some(format_flags(*arg, AT_FLAGS)),
+        ArgKind::Prot if *arg == 0 => Some("PROT_NONE".to_string()),
+        ArgKind::Prot => Some(format_flags(*arg,
+            &[(1, "PROT_READ"), (2, "PROT_WRITE"), (4, "PROT_EXEC")])),
+        ArgKind::MapFlags => Some(format_flags(*arg, MAP_FLAGS)),
+        ArgKind::ClockId => Some(CLOCK_IDS.get(*arg).map(|s| s.to_string())
+            .unwrap_or_else(|| format!("{}", *arg as i32))),
+        ArgKind::Signal => Some(SIGNAL_NAMES.get(*arg).map(|s| s.to_string())
+            .unwrap_or_else(|| format!("SIGRT_{}", *arg as i64 - 32))),
+        ArgKind::SockAddr { len } => Some(cpu.memory.guest_slice(*arg, args[len]).ok()
+            .filter(|_| *arg != 0)
+            .and_then(|addr| SockAddr::from_bytes(addr).ok())
+            .map_or_else(|| format_ptr(*arg), |addr| format_sockaddr(&addr))),
+        ArgKind::FcntlCmd => Some(format_cmd(*arg, FCNTL_CMDS)),
+        ArgKind::IoctlCmd => Some(format_cmd(*arg, IOCTL_CMDS)),
+        ArgKind::Mode => Some(format!("{:#o}", arg).replace("0o", "0")),
+        ArgKind::Ptr => Some(format_ptr(*arg)),
+        ArgKind::OutBuf { .. } | ArgKind::Struct(_) => None
+    }).collect()
+}
+
+pub fn format_call(
+        cpu: &CPU,
+        syscall: &Syscall,
+        args: &[usize; 6],
+        before: Vec<Option<String>>,
+        res: &Result<SysResult, Error>) -> String {
+    let args = syscall.args.iter().zip(args.iter()).zip(before)
+        .map(|((kind, arg), before)| before.unwrap_or_else(|| match (kind, res) {
+            (ArgKind::OutBuf { .. }, Ok(Ok(n))) => cpu.memory.guest_slice(*arg, *n)
+                .map(format_string)
+                .unwrap_or_else(|_| format_ptr(*arg)),
+            (ArgKind::Struct(name), Ok(Ok(_))) => format_struct(cpu, name, *arg)
+                .unwrap_or_else(|| format_ptr(*arg)),
+            _ => format_ptr(*arg)
+        }))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let res = match res {
+        Ok(Ok(val)) if RETURNS_ADDRESS.contains(&syscall.name) => format!("{:#x}", val),
+        Ok(Ok(val)) => format!("{}", *val as i64),
+        Ok(Err(errno)) => format_errno(errno.into_raw()),
+        Err(Error::Exit(code)) => format!("?\n+++ exited with {} +++", code),
+        Err(Error::Exec) => "0".to_string(),
+        Err(_) => "?".to_string()
+    };
+    format!("{}({}) = {}\n", syscall.name, args, res)
+}
+
+pub fn format_unknown(nr: u64, args: &[usize; 6]) -> String {
+    let args = args.iter().map(|arg| format!("{:#x}", arg)).collect::<Vec<_>>().join(", ");
+    format!("syscall_{:#x}({}) = {}\n", nr, args, format_errno(libc::ENOSYS))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filter() {
+        let filter: Filter = "openat,%memory".parse().unwrap();
+        assert!(filter.matches("openat", &[ArgKind::Fd, ArgKind::CStr]));
+        assert!(filter.matches("brk", &[ArgKind::Ptr]));
+        assert!(!filter.matches("read", &[ArgKind::Fd]));
+
+        let filter: Filter = "!%desc".parse().unwrap();
+        assert!(!filter.matches("read", &[ArgKind::Fd]));
+        assert!(filter.matches("brk", &[ArgKind::Ptr]));
+    }
+
+    #[test]
+    fn decoding() {
+        assert_eq!(format_open_flags(0), "O_RDONLY");
+        assert_eq!(format_open_flags(0o2001101), "O_WRONLY|O_CREAT|O_TRUNC|O_CLOEXEC");
+        assert_eq!(format_string(b"Hello\n\0"), "\"Hello\\n\\0\"");
+        assert_eq!(format_string(&[b'x'; 40]), format!("\"{}\"...", "x".repeat(32)));
+        assert_eq!(format_errno(libc::ENOENT), "-1 ENOENT (No such file or directory)");
+        assert_eq!(format_sockaddr(&SockAddr::V4("127.0.0.1:80".parse().unwrap())),
+            "{sa_family=AF_INET, sin_port=htons(80), sin_addr=inet_addr(\"127.0.0.1\")}");
+        assert_eq!(format_sockaddr(&SockAddr::Unix(b"/tmp/sock\0".to_vec())),
+            "{sa_family=AF_UNIX, sun_path=\"/tmp/sock\"}");
+    }
+}
@@ -7,7 +7,15 @@ pub struct Symbol<'a> {
 
 pub type Symbols<'a> = Vec<Symbol<'a>>;
 
-pub fn get_symbols<'a>(elf_file: &elf::ElfBytes<'a, elf::endian::AnyEndian>) -> Symbols<'a> {
+/*
+ * `bias` is the load bias of position-independent executables (0 otherwise).
+ * The values of TLS symbols are offsets into the TLS block, they are placed relative to
+ * `tls_base` (the thread pointer of the main thread), or skipped if that is 0.
+ */
+pub fn get_symbols<'a>(
+        elf_file: &elf::ElfBytes<'a, elf::endian::AnyEndian>,
+        bias: u64,
+        tls_base: u64) -> Symbols<'a> {
     let (symtab, strtab) = match elf_file.symbol_table() {
         Ok(Some((symtab, strtab))) => (symtab, strtab),
         Ok(None) | Err(_) => return Symbols::default()
@@ -16,10 +24,15 @@ pub fn get_symbols<'a>(elf_file: &elf::ElfBytes<'a, elf::endian::AnyEndian>) ->
     let mut symbols = Symbols::new();
 
     for sym in symtab {
+        let addr = match sym.st_symtype() {
+            elf::abi::STT_TLS if tls_base == 0 => continue,
+            elf::abi::STT_TLS => tls_base.wrapping_add(sym.st_value),
+            _ => sym.st_value.wrapping_add(bias)
+        };
         if let Ok(name) = strtab.get(sym.st_name as usize) {
             symbols.push(Symbol {
                 name,
-                addr: sym.st_value as i64,
+                addr: addr as i64,
                 size: sym.st_size as i64
             });
         }
@@ -0,0 +1,3259 @@
+/*
+ * The syscalls the guest can do: A table of handlers keyed by the RISC-V syscall number.
+ * Linux syscall numbers, for whatever reason, are different on different architectures
+ * (see https://jborza.com/post/2021-05-11-riscv-linux-syscalls/), so every handler
+ * translates to the host version, which the `syscalls` crate provides.
+ */
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::sync::Arc;
+
+use crate::clock::*;
+use crate::cpu::CPU;
+use crate::exec::{self, Executable, HostExecPolicy};
+use crate::fds;
+use crate::net;
+use crate::insts::*;
+use crate::mem::*;
+use crate::net::SockAddr;
+use crate::policy::{Action, Policy};
+use crate::signals::{self, *};
+use crate::strace;
+use crate::tar;
+use crate::threads;
+use crate::vfs::{self, Vfs};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use syscalls::{syscall, Errno, Sysno};
+
+pub const SYS_GETCWD:     u64 = 17;
+pub const SYS_EVENTFD2:   u64 = 19;
+pub const SYS_EPOLL_CREATE1: u64 = 20;
+pub const SYS_EPOLL_CTL:  u64 = 21;
+pub const SYS_EPOLL_PWAIT: u64 = 22;
+pub const SYS_DUP:        u64 = 23;
+pub const SYS_DUP3:       u64 = 24;
+pub const SYS_FCNTL:      u64 = 25;
+pub const SYS_IOCTL:      u64 = 29;
+pub const SYS_MKDIRAT:    u64 = 34;
+pub const SYS_UNLINKAT:   u64 = 35;
+pub const SYS_SYMLINKAT:  u64 = 36;
+pub const SYS_FTRUNCATE:  u64 = 46;
+pub const SYS_FACCESSAT:  u64 = 48;
+pub const SYS_CHDIR:      u64 = 49;
+pub const SYS_FCHDIR:     u64 = 50;
+pub const SYS_OPENAT:     u64 = 56;
+pub const SYS_CLOSE:      u64 = 57;
+pub const SYS_PIPE2:      u64 = 59;
+pub const SYS_GETDENTS64: u64 = 61;
+pub const SYS_LSEEK:      u64 = 62;
+pub const SYS_READ:       u64 = 63;
+pub const SYS_WRITE:      u64 = 64;
+pub const SYS_READV:      u64 = 65;
+pub const SYS_WRITEV:     u64 = 66;
+pub const SYS_PREAD64:    u64 = 67;
+pub const SYS_PWRITE64:   u64 = 68;
+pub const SYS_SENDFILE:   u64 = 71;
+pub const SYS_PSELECT6:   u64 = 72;
+pub const SYS_PPOLL:      u64 = 73;
+pub const SYS_READLINKAT: u64 = 78;
+pub const SYS_NEWFSTATAT: u64 = 79;
+pub const SYS_FSTAT:      u64 = 80;
+pub const SYS_FSYNC:      u64 = 82;
+pub const SYS_FDATASYNC:  u64 = 83;
+pub const SYS_EXIT:       u64 = 93;
+pub const SYS_EXIT_GROUP: u64 = 94;
+pub const SYS_WAITID:     u64 = 95;
+pub const SYS_SET_TID_ADDRESS: u64 = 96;
+pub const SYS_FUTEX:      u64 = 98;
+pub const SYS_SET_ROBUST_LIST: u64 = 99;
+pub const SYS_NANOSLEEP:  u64 = 101;
+pub const SYS_CLOCK_GETTIME:   u64 = 113;
+pub const SYS_CLOCK_GETRES:    u64 = 114;
+pub const SYS_CLOCK_NANOSLEEP: u64 = 115;
+pub const SYS_TIMES:      u64 = 153;
+pub const SYS_KILL:       u64 = 129;
+pub const SYS_TKILL:      u64 = 130;
+pub const SYS_TGKILL:     u64 = 131;
+pub const SYS_SIGALTSTACK: u64 = 132;
+pub const SYS_RT_SIGSUSPEND: u64 = 133;
+pub const SYS_RT_SIGACTION: u64 = 134;
+pub const SYS_RT_SIGPROCMASK: u64 = 135;
+pub const SYS_RT_SIGPENDING: u64 = 136;
+pub const SYS_RT_SIGRETURN: u64 = 139;
+pub const SYS_UNAME:      u64 = 160;
+pub const SYS_GETTIMEOFDAY: u64 = 169;
+pub const SYS_GETPID:     u64 = 172;
+pub const SYS_GETPPID:    u64 = 173;
+pub const SYS_GETUID:     u64 = 174;
+pub const SYS_GETEUID:    u64 = 175;
+pub const SYS_GETGID:     u64 = 176;
+pub const SYS_GETEGID:    u64 = 177;
+pub const SYS_GETTID:     u64 = 178;
+pub const SYS_SOCKET:     u64 = 198;
+pub const SYS_BIND:       u64 = 200;
+pub const SYS_LISTEN:     u64 = 201;
+pub const SYS_ACCEPT:     u64 = 202;
+pub const SYS_CONNECT:    u64 = 203;
+pub const SYS_GETSOCKNAME: u64 = 204;
+pub const SYS_GETPEERNAME: u64 = 205;
+pub const SYS_SENDTO:     u64 = 206;
+pub const SYS_RECVFROM:   u64 = 207;
+pub const SYS_SETSOCKOPT: u64 = 208;
+pub const SYS_GETSOCKOPT: u64 = 209;
+pub const SYS_SHUTDOWN:   u64 = 210;
+pub const SYS_SENDMSG:    u64 = 211;
+pub const SYS_RECVMSG:    u64 = 212;
+pub const SYS_BRK:        u64 = 214;
+pub const SYS_MUNMAP:     u64 = 215;
+pub const SYS_MREMAP:     u64 = 216;
+pub const SYS_CLONE:      u64 = 220;
+pub const SYS_EXECVE:     u64 = 221;
+pub const SYS_MMAP:       u64 = 222;
+pub const SYS_MPROTECT:   u64 = 226;
+pub const SYS_MADVISE:    u64 = 233;
+pub const SYS_ACCEPT4:    u64 = 242;
+pub const SYS_RISCV_HWPROBE: u64 = 258;
+pub const SYS_WAIT4:      u64 = 260;
+pub const SYS_PRLIMIT64:  u64 = 261;
+pub const SYS_RENAMEAT2:  u64 = 276;
+pub const SYS_GETRANDOM:  u64 = 278;
+pub const SYS_STATX:      u64 = 291;
+pub const SYS_RSEQ:       u64 = 293;
+pub const SYS_CLONE3:     u64 = 435;
+pub const SYS_FACCESSAT2: u64 = 439;
+
+/* What an argument is, so that it can be printed (see strace.rs) generically. */
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgKind {
+    Int,
+    /// Guest file descriptor.
+    Fd,
+    /// Pointer to a buffer the kernel reads from, the length is in argument `len`.
+    InBuf { len: usize },
+    /// Pointer to a buffer the kernel writes to, the length is in argument `len`.
+    OutBuf { len: usize },
+    /// NUL-terminated string, e.g. a path.
+    CStr,
+    Flags,
+    /// O_* flags of open()/openat().
+    OpenFlags,
+    /// AT_* flags of the *at() syscalls.
+    AtFlags,
+    /// PROT_* of mmap()/mprotect().
+    Prot,
+    /// MAP_* flags of mmap().
+    MapFlags,
+    /// CLOCK_* ids.
+    ClockId,
+    /// Signal numbers.
+    Signal,
+    /// struct sockaddr the kernel reads, its length is in argument `len`.
+    SockAddr { len: usize },
+    /// F_* commands of fcntl().
+    FcntlCmd,
+    /// Request numbers of ioctl().
+    IoctlCmd,
+    Mode,
+    /// Pointer to a struct of the given (C) type.
+    Struct(&'static str),
+    Ptr,
+}
+
+/* The value for a0: Either a result or an errno (returned as -errno). */
+pub type SysResult = Result<usize, Errno>;
+
+/* Return the errno of a failed step of a handler to the guest. */
+macro_rules! try_errno {
+    ($e:expr) => {
+        match $e {
+            Ok(val) => val,
+            Err(errno) => return Ok(Err(errno))
+        }
+    };
+}
+
+/* Errors (e.g. Error::Exit) stop the simulation, the guest never sees them. */
+pub type Handler = Arc<dyn Fn(&mut CPU, [usize; 6]) -> Result<SysResult, Error> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Syscall {
+    pub name: &'static str,
+    pub args: &'static [ArgKind],
+    pub handler: Handler,
+}
+
+/* What to do when the guest does a syscall that is not in the table. */
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum UnknownSyscallPolicy {
+    /// Print a warning and return -ENOSYS to the guest.
+    Warn,
+    /// Return -ENOSYS to the guest silently.
+    Ignore,
+    /// Stop the simulation.
+    Abort,
+}
+
+#[derive(Clone)]
+pub struct SyscallTable {
+    syscalls: HashMap<u64, Syscall>,
+    pub unknown: UnknownSyscallPolicy,
+    pub policy: Policy,
+}
+
+impl Default for SyscallTable {
+    /* All syscalls implemented by the simulator. */
+    fn default() -> Self {
+        use ArgKind::*;
+        let mut table = Self::empty();
+        table.register(SYS_GETCWD, "getcwd", &[OutBuf { len: 1 }, Int], sys_getcwd);
+        table.register(SYS_MKDIRAT, "mkdirat", &[Fd, CStr, Mode], sys_mkdirat);
+        table.register(SYS_UNLINKAT, "unlinkat", &[Fd, CStr, AtFlags], sys_unlinkat);
+        table.register(SYS_SYMLINKAT, "symlinkat", &[CStr, Fd, CStr], sys_symlinkat);
+        table.register(SYS_FTRUNCATE, "ftruncate", &[Fd, Int], sys_ftruncate);
+        table.register(SYS_FACCESSAT, "faccessat", &[Fd, CStr, Int], sys_faccessat);
+        table.register(SYS_CHDIR, "chdir", &[CStr], sys_chdir);
+        table.register(SYS_FCHDIR, "fchdir", &[Fd], sys_fchdir);
+        table.register(SYS_OPENAT, "openat", &[Fd, CStr, OpenFlags, Mode], sys_openat);
+        table.register(SYS_CLOSE, "close", &[Fd], sys_close);
+        table.register(SYS_DUP, "dup", &[Fd], sys_dup);
+        table.register(SYS_DUP3, "dup3", &[Fd, Fd, OpenFlags], sys_dup3);
+        table.register(SYS_FCNTL, "fcntl", &[Fd, FcntlCmd, Int], sys_fcntl);
+        table.register(SYS_IOCTL, "ioctl", &[Fd, IoctlCmd, Ptr], sys_ioctl);
+        table.register(SYS_PIPE2, "pipe2", &[Struct("int[2]"), OpenFlags], sys_pipe2);
+        table.register(SYS_GETDENTS64, "getdents64", &[Fd, Ptr, Int], sys_getdents64);
+        table.register(SYS_LSEEK, "lseek", &[Fd, Int, Int], sys_lseek);
+        table.register(SYS_READ, "read", &[Fd, OutBuf { len: 2 }, Int], sys_read);
+        table.register(SYS_WRITE, "write", &[Fd, InBuf { len: 2 }, Int], sys_write);
+        table.register(SYS_READV, "readv", &[Fd, Ptr, Int], sys_readv);
+        table.register(SYS_WRITEV, "writev", &[Fd, Ptr, Int], sys_writev);
+        table.register(SYS_PREAD64, "pread64", &[Fd, OutBuf { len: 2 }, Int, Int], sys_pread64);
+        table.register(SYS_PWRITE64, "pwrite64", &[Fd, InBuf { len: 2 }, Int, Int], sys_pwrite64);
+        table.register(SYS_SENDFILE, "sendfile", &[Fd, Fd, Ptr, Int], sys_sendfile);
+        table.register(SYS_PSELECT6, "pselect6", &[Int, Ptr, Ptr, Ptr, Struct("timespec"), Ptr],
+            sys_pselect6);
+        table.register(SYS_PPOLL, "ppoll", &[Ptr, Int, Struct("timespec"), Ptr, Int], sys_ppoll);
+        table.register(SYS_EVENTFD2, "eventfd2", &[Int, Flags], sys_eventfd2);
+        table.register(SYS_EPOLL_CREATE1, "epoll_create1", &[Flags], sys_epoll_create1);
+        table.register(SYS_EPOLL_CTL, "epoll_ctl", &[Fd, Int, Fd, Ptr], sys_epoll_ctl);
+        table.register(SYS_EPOLL_PWAIT, "epoll_pwait", &[Fd, Ptr, Int, Int, Ptr, Int],
+            sys_epoll_pwait);
+        table.register(SYS_READLINKAT, "readlinkat", &[Fd, CStr, OutBuf { len: 3 }, Int],
+            sys_readlinkat);
+        table.register(SYS_NEWFSTATAT, "newfstatat", &[Fd, CStr, Struct("stat"), AtFlags],
+            sys_newfstatat);
+        table.register(SYS_FSTAT, "fstat", &[Fd, Struct("stat")], sys_fstat);
+        table.register(SYS_FSYNC, "fsync", &[Fd], sys_fsync);
+        table.register(SYS_FDATASYNC, "fdatasync", &[Fd], sys_fdatasync);
+        table.register(SYS_EXIT, "exit", &[Int], sys_exit);
+        table.register(SYS_EXIT_GROUP, "exit_group", &[Int], sys_exit_group);
+        table.register(SYS_WAITID, "waitid",
+            &[Int, Int, Struct("siginfo"), Flags, Struct("rusage")], sys_waitid);
+        table.register(SYS_SET_TID_ADDRESS, "set_tid_address", &[Ptr], sys_set_tid_address);
+        table.register(SYS_FUTEX, "futex", &[Ptr, Int, Int, Ptr, Ptr, Int], sys_futex);
+        table.register(SYS_SET_ROBUST_LIST, "set_robust_list", &[Ptr, Int], sys_set_robust_list);
+        table.register(SYS_NANOSLEEP, "nanosleep", &[Struct("timespec"), Struct("timespec")],
+            sys_nanosleep);
+        table.register(SYS_CLOCK_GETTIME, "clock_gettime", &[ClockId, Struct("timespec")],
+            sys_clock_gettime);
+        table.register(SYS_CLOCK_GETRES, "clock_getres", &[ClockId, Struct("timespec")],
+            sys_clock_getres);
+        table.register(SYS_CLOCK_NANOSLEEP, "clock_nanosleep",
+            &[ClockId, Flags, Struct("timespec"), Struct("timespec")], sys_clock_nanosleep);
+        table.register(SYS_KILL, "kill", &[Int, Signal], sys_kill);
+        table.register(SYS_TKILL, "tkill", &[Int, Signal], sys_tkill);
+        table.register(SYS_TGKILL, "tgkill", &[Int, Int, Signal], sys_tgkill);
+        table.register(SYS_SIGALTSTACK, "sigaltstack", &[Ptr, Ptr], sys_sigaltstack);
+        table.register(SYS_RT_SIGSUSPEND, "rt_sigsuspend", &[Ptr, Int], sys_rt_sigsuspend);
+        table.register(SYS_RT_SIGACTION, "rt_sigaction", &[Signal, Ptr, Ptr, Int],
+            sys_rt_sigaction);
+        table.register(SYS_RT_SIGPROCMASK, "rt_sigprocmask", &[Int, Ptr, Ptr, Int],
+            sys_rt_sigprocmask);
+        table.register(SYS_RT_SIGPENDING, "rt_sigpending", &[Ptr, Int], sys_rt_sigpending);
+        table.register(SYS_RT_SIGRETURN, "rt_sigreturn", &[], sys_rt_sigreturn);
+        table.register(SYS_TIMES, "times", &[Struct("tms")], sys_times);
+        table.register(SYS_UNAME, "uname", &[Struct("utsname")], sys_uname);
+        table.register(SYS_GETTIMEOFDAY, "gettimeofday", &[Struct("timeval"), Ptr],
+            sys_gettimeofday);
+        table.register(SYS_GETPID, "getpid", &[], sys_getpid);
+        table.register(SYS_GETPPID, "getppid", &[], sys_getppid);
+        table.register(SYS_GETUID, "getuid", &[], sys_getuid);
+        table.register(SYS_GETEUID, "geteuid", &[], sys_geteuid);
+        table.register(SYS_GETGID, "getgid", &[], sys_getgid);
+        table.register(SYS_GETEGID, "getegid", &[], sys_getegid);
+        table.register(SYS_GETTID, "gettid", &[], sys_gettid);
+        table.register(SYS_SOCKET, "socket", &[Int, Flags, Int], sys_socket);
+        table.register(SYS_BIND, "bind", &[Fd, SockAddr { len: 2 }, Int], sys_bind);
+        table.register(SYS_LISTEN, "listen", &[Fd, Int], sys_listen);
+        table.register(SYS_ACCEPT, "accept", &[Fd, Ptr, Ptr], sys_accept);
+        table.register(SYS_ACCEPT4, "accept4", &[Fd, Ptr, Ptr, Flags], sys_accept4);
+        table.register(SYS_CONNECT, "connect", &[Fd, SockAddr { len: 2 }, Int], sys_connect);
+        table.register(SYS_GETSOCKNAME, "getsockname", &[Fd, Ptr, Ptr], sys_getsockname);
+        table.register(SYS_GETPEERNAME, "getpeername", &[Fd, Ptr, Ptr], sys_getpeername);
+        table.register(SYS_SENDTO, "sendto",
+            &[Fd, InBuf { len: 2 }, Int, Flags, SockAddr { len: 5 }, Int], sys_sendto);
+        table.register(SYS_RECVFROM, "recvfrom", &[Fd, OutBuf { len: 2 }, Int, Flags, Ptr, Ptr],
+            sys_recvfrom);
+        table.register(SYS_SETSOCKOPT, "setsockopt", &[Fd, Int, Int, InBuf { len: 4 }, Int],
+            sys_setsockopt);
+        table.register(SYS_GETSOCKOPT, "getsockopt", &[Fd, Int, Int, Ptr, Ptr], sys_getsockopt);
+        table.register(SYS_SHUTDOWN, "shutdown", &[Fd, Int], sys_shutdown);
+        table.register(SYS_SENDMSG, "sendmsg", &[Fd, Ptr, Flags], sys_sendmsg);
+        table.register(SYS_RECVMSG, "recvmsg", &[Fd, Ptr, Flags], sys_recvmsg);
+        table.register(SYS_BRK, "brk", &[Ptr], sys_brk);
+        table.register(SYS_MUNMAP, "munmap", &[Ptr, Int], sys_munmap);
+        table.register(SYS_MREMAP, "mremap", &[Ptr, Int, Int, Flags, Ptr], sys_mremap);
+        table.register(SYS_CLONE, "clone", &[Flags, Ptr, Ptr, Ptr, Ptr], sys_clone);
+        table.register(SYS_EXECVE, "execve", &[CStr, Ptr, Ptr], sys_execve);
+        table.register(SYS_MMAP, "mmap", &[Ptr, Int, Prot, MapFlags, Fd, Int], sys_mmap);
+        table.register(SYS_MPROTECT, "mprotect", &[Ptr, Int, Prot], sys_mprotect);
+        table.register(SYS_MADVISE, "madvise", &[Ptr, Int, Int], sys_madvise);
+        table.register(SYS_RISCV_HWPROBE, "riscv_hwprobe", &[Ptr, Int, Int, Ptr, Flags],
+            sys_riscv_hwprobe);
+        table.register(SYS_WAIT4, "wait4", &[Int, Ptr, Flags, Struct("rusage")], sys_wait4);
+        table.register(SYS_PRLIMIT64, "prlimit64", &[Int, Int, Ptr, Ptr], sys_prlimit64);
+        table.register(SYS_RENAMEAT2, "renameat2", &[Fd, CStr, Fd, CStr, Flags], sys_renameat2);
+        table.register(SYS_GETRANDOM, "getrandom", &[OutBuf { len: 1 }, Int, Flags],
+            sys_getrandom);
+        table.register(SYS_STATX, "statx", &[Fd, CStr, AtFlags, Flags, Struct("statx")],
+            sys_statx);
+        table.register(SYS_RSEQ, "rseq", &[Ptr, Int, Flags, Int], sys_rseq);
+        table.register(SYS_CLONE3, "clone3", &[Struct("clone_args"), Int], sys_clone3);
+        table.register(SYS_FACCESSAT2, "faccessat2", &[Fd, CStr, Int, AtFlags], sys_faccessat2);
+        table
+    }
+}
+
+impl SyscallTable {
+    pub fn empty() -> Self {
+        Self {
+            syscalls: HashMap::new(),
+            unknown: UnknownSyscallPolicy::Warn,
+            policy: Policy::default(),
+        }
+    }
+
+    /* Add a syscall or replace an existing one, returns the old one. */
+    pub fn register<F>(&mut self, nr: u64, name: &'static str, args: &'static [ArgKind], handler: F)
+            -> Option<Syscall>
+            where F: Fn(&mut CPU, [usize; 6]) -> Result<SysResult, Error> + Send + Sync + 'static {
+        self.syscalls.insert(nr, Syscall { name, args, handler: Arc::new(handler) })
+    }
+
+    #[allow(dead_code)]
+    pub fn unregister(&mut self, nr: u64) -> Option<Syscall> {
+        self.syscalls.remove(&nr)
+    }
+
+    pub fn get(&self, nr: u64) -> Option<&Syscall> {
+        self.syscalls.get(&nr)
+    }
+}
+
+/* Handle an `ecall` of the guest: Syscall number in a7, arguments in a0-a5. */
+pub fn dispatch(cpu: &mut CPU) -> Result<(), Error> {
+    let nr = cpu.get_reg(REG_A7);
+    let args = [REG_A0, REG_A1, REG_A2, REG_A3, REG_A4, REG_A5]
+        .map(|reg| cpu.get_reg(reg) as usize);
+
+    let syscall = cpu.syscalls.get(nr).cloned();
+    let res = match &syscall {
+        Some(syscall) => {
+            let action = cpu.syscalls.policy.action(syscall.name, syscall.args);
+            let traced = cpu.strace.as_ref().is_some_and(|strace| strace.traces(syscall));
+            let before = (traced || action != Action::Allow)
+                .then(|| strace::format_args_before(cpu, syscall.args, &args));
+            let res = match action {
+                Action::Deny => Ok(Err(Errno::EPERM)),
+                _ => (syscall.handler)(cpu, args)
+            };
+            if let Some(before) = before {
+                let line = strace::format_call(cpu, syscall, &args, before, &res);
+                match action {
+                    Action::Deny => eprint!("[simrv64i] policy: denied {}", line),
+                    Action::Log => eprint!("[simrv64i] policy: {}", line),
+                    Action::Allow => {}
+                }
+                if traced {
+                    cpu.strace.as_mut().unwrap().write(&line);
+                }
+            }
+            res?
+        },
+        None => {
+            if let Some(strace) = cpu.strace.as_mut().filter(|strace| strace.traces_unknown(nr)) {
+                strace.write(&strace::format_unknown(nr, &args));
+            }
+            match cpu.syscalls.unknown {
+                UnknownSyscallPolicy::Abort => return Err(Error::UnknownSyscall(nr)),
+                UnknownSyscallPolicy::Warn => {
+                    eprintln!("[simrv64i] unknown syscall {} (pc={:#08x?}, args={:#x?}) -> ENOSYS",
+                        nr, cpu.pc, args);
+                    Err(Errno::ENOSYS)
+                },
+                UnknownSyscallPolicy::Ignore => Err(Errno::ENOSYS)
+            }
+        }
+    };
+
+    /* Restarted if a signal handler with SA_RESTART interrupted it (see signals::deliver()),
+     * except for the waits that Linux never restarts after a handler either. */
+    cpu.signals.interrupted = match res {
+        Err(Errno::EINTR) if !matches!(nr, SYS_PPOLL | SYS_PSELECT6 | SYS_EPOLL_PWAIT |
+            SYS_NANOSLEEP | SYS_CLOCK_NANOSLEEP | SYS_RT_SIGSUSPEND) =>
+            Some((cpu.pc + 4, args[0] as u64)),
+        _ => None
+    };
+    cpu.set_reg(REG_A0, match res {
+        Ok(val) => val as u64,
+        Err(errno) => -(errno.into_raw() as i64) as u64
+    });
+    Ok(())
+}
+
+const AT_FDCWD: i32 = -100;
+/* The host fds are always O_CLOEXEC, whether the guest's are is in the fd table. */
+const O_CLOEXEC: usize = 0o2000000;
+
+/* A path from the guest, absolute ones are redirected into the sysroot if they exist there. */
+fn host_path(cpu: &CPU, addr: usize) -> Result<CString, Errno> {
+    sysroot_redirect(cpu, cpu.memory.guest_cstr(addr)?)
+}
+
+fn sysroot_redirect(cpu: &CPU, path: &CStr) -> Result<CString, Errno> {
+    match cpu.sysroot_path(path.to_bytes()) {
+        Some(host_path) => CString::new(host_path.into_os_string().into_vec())
+            .map_err(|_| Errno::EINVAL),
+        None => Ok(path.to_owned())
+    }
+}
+
+/* struct stat of riscv64 (the asm-generic one), which differs from the x86-64 one. */
+pub const GUEST_STAT_SIZE: usize = 128;
+
+/* The field types of libc::stat differ between hosts, hence the casts. */
+#[allow(clippy::unnecessary_cast)]
+pub fn guest_stat(st: &libc::stat) -> [u8; GUEST_STAT_SIZE] {
+    let mut buf = [0u8; GUEST_STAT_SIZE];
+    let mut put = |offset: usize, bytes: &[u8]| buf[offset..(offset + bytes.len())].copy_from_slice(bytes);
+    put(0, &(st.st_dev as u64).to_le_bytes());
+    put(8, &(st.st_ino as u64).to_le_bytes());
+    put(16, &(st.st_mode as u32).to_le_bytes());
+    put(20, &(st.st_nlink as u32).to_le_bytes());
+    put(24, &(st.st_uid as u32).to_le_bytes());
+    put(28, &(st.st_gid as u32).to_le_bytes());
+    put(32, &(st.st_rdev as u64).to_le_bytes());
+    put(48, &(st.st_size as i64).to_le_bytes());
+    put(56, &(st.st_blksize as i32).to_le_bytes());
+    put(64, &(st.st_blocks as i64).to_le_bytes());
+    put(72, &(st.st_atime as i64).to_le_bytes());
+    put(80, &(st.st_atime_nsec as u64).to_le_bytes());
+    put(88, &(st.st_mtime as i64).to_le_bytes());
+    put(96, &(st.st_mtime_nsec as u64).to_le_bytes());
+    put(104, &(st.st_ctime as i64).to_le_bytes());
+    put(112, &(st.st_ctime_nsec as u64).to_le_bytes());
+    buf
+}
+
+fn write_guest_stat(cpu: &mut CPU, addr: usize, st: &libc::stat) -> SysResult {
+    cpu.memory.guest_slice_mut(addr, GUEST_STAT_SIZE)?.copy_from_slice(&guest_stat(st));
+    Ok(0)
+}
+
+/* dirfd arguments can also be AT_FDCWD, which needs no translation. */
+fn host_dirfd(cpu: &CPU, dirfd: usize) -> Result<usize, Errno> {
+    match dirfd as i32 {
+        AT_FDCWD => Ok(dirfd),
+        _ => cpu.fds.host_fd(dirfd)
+    }
+}
+
+/*
+ * With mounts, guest paths are resolved by the VFS: Absolute ones as they are, relative
+ * ones against the guest's working directory or `dirfd`, which has to be a VFS file then.
+ */
+fn vfs_path(cpu: &CPU, vfs: &Vfs, dirfd: usize, path: usize) -> Result<Vec<u8>, Errno> {
+    let path = cpu.memory.guest_cstr(path)?.to_bytes();
+    if path.is_empty() {
+        return Err(Errno::ENOENT)
+    }
+    let base = match dirfd as i32 {
+        _ if path.starts_with(b"/") => b"/".to_vec(),
+        AT_FDCWD => vfs.cwd(),
+        _ => cpu.fds.opened(dirfd)?.ok_or(Errno::ENOTDIR)?.path.clone()
+    };
+    Ok(vfs::normalize(&base, path))
+}
+
+fn sys_openat(cpu: &mut CPU, [dirfd, path, flags, mode, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if let Some(vfs) = cpu.vfs.clone() {
+        let (file, opened) = try_errno!(vfs_path(cpu, &vfs, dirfd, path)
+            .and_then(|path| vfs.open(&path, flags, mode)));
+        return Ok(cpu.fds.insert_opened(file, flags & O_CLOEXEC != 0, opened))
+    }
+    let dirfd = try_errno!(host_dirfd(cpu, dirfd));
+    let host_flags = flags | O_CLOEXEC;
+    Ok(host_path(cpu, path)
+        .and_then(|path| unsafe { syscall!(Sysno::openat, dirfd, path.as_ptr(), host_flags, mode) })
+        .and_then(|fd| cpu.fds.insert(unsafe { OwnedFd::from_raw_fd(fd as i32) },
+                                      flags & O_CLOEXEC != 0)))
+}
+
+fn sys_close(cpu: &mut CPU, [fd, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(cpu.fds.close(fd).map(|_| 0))
+}
+
+fn sys_dup(cpu: &mut CPU, [fd, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(cpu.fds.dup(fd, 0, false))
+}
+
+/* There is no dup2() on RISC-V, the libc implements it with dup3() (or fcntl()). */
+fn sys_dup3(cpu: &mut CPU, [oldfd, newfd, flags, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if oldfd == newfd || flags & !O_CLOEXEC != 0 {
+        return Ok(Err(Errno::EINVAL))
+    }
+    Ok(cpu.fds.dup2(oldfd, newfd, flags & O_CLOEXEC != 0))
+}
+
+fn sys_read(cpu: &mut CPU, [fd, buf, len, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(cpu.memory.guest_slice_mut(buf, len)
+        .and_then(|buf| unsafe { syscall!(Sysno::read, fd, buf.as_mut_ptr(), buf.len()) }))
+}
+
+fn sys_write(cpu: &mut CPU, [fd, buf, len, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(cpu.memory.guest_slice(buf, len)
+        .and_then(|buf| unsafe { syscall!(Sysno::write, fd, buf.as_ptr(), buf.len()) }))
+}
+
+const IOV_MAX: usize = 1024;
+
+/*
+ * Translate a guest array of struct iovec (u64 iov_base, u64 iov_len) into host iovecs
+ * pointing into guest memory. All buffers are checked, `writable` ones for readv().
+ */
+fn host_iovecs(cpu: &mut CPU, iov: usize, iovcnt: usize, writable: bool)
+        -> Result<Vec<libc::iovec>, Errno> {
+    if iovcnt > IOV_MAX {
+        return Err(Errno::EINVAL)
+    }
+    let guest_iovecs = cpu.memory.guest_slice(iov, iovcnt * 16)?.to_vec();
+    let mut total: usize = 0;
+    guest_iovecs.chunks(16).map(|iov| {
+        let base = u64::from_le_bytes(iov[0..8].try_into().unwrap()) as usize;
+        let len = u64::from_le_bytes(iov[8..16].try_into().unwrap()) as usize;
+        total = total.checked_add(len).filter(|total| *total <= isize::MAX as usize)
+            .ok_or(Errno::EINVAL)?;
+        let base = match writable {
+            true => cpu.memory.guest_slice_mut(base, len)?.as_mut_ptr(),
+            false => cpu.memory.guest_slice(base, len)?.as_ptr() as *mut u8
+        };
+        Ok(libc::iovec { iov_base: base as *mut libc::c_void, iov_len: len })
+    }).collect()
+}
+
+fn sys_readv(cpu: &mut CPU, [fd, iov, iovcnt, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(host_iovecs(cpu, iov, iovcnt, true)
+        .and_then(|iov| unsafe { syscall!(Sysno::readv, fd, iov.as_ptr(), iov.len()) }))
+}
+
+fn sys_writev(cpu: &mut CPU, [fd, iov, iovcnt, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(host_iovecs(cpu, iov, iovcnt, false)
+        .and_then(|iov| unsafe { syscall!(Sysno::writev, fd, iov.as_ptr(), iov.len()) }))
+}
+
+fn sys_pread64(cpu: &mut CPU, [fd, buf, len, offset, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(cpu.memory.guest_slice_mut(buf, len)
+        .and_then(|buf| unsafe { syscall!(Sysno::pread64, fd, buf.as_mut_ptr(), buf.len(), offset) }))
+}
+
+fn sys_pwrite64(cpu: &mut CPU, [fd, buf, len, offset, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(cpu.memory.guest_slice(buf, len)
+        .and_then(|buf| unsafe { syscall!(Sysno::pwrite64, fd, buf.as_ptr(), buf.len(), offset) }))
+}
+
+/* The offset (a loff_t) is read from and written back to guest memory if given. */
+fn sys_sendfile(cpu: &mut CPU, [out_fd, in_fd, offset, count, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let out_fd = try_errno!(cpu.fds.host_fd(out_fd));
+    let in_fd = try_errno!(cpu.fds.host_fd(in_fd));
+    if offset == 0 {
+        return Ok(unsafe { syscall!(Sysno::sendfile, out_fd, in_fd, 0, count) })
+    }
+    let buf = try_errno!(cpu.memory.guest_slice_mut(offset, 8));
+    let mut off = i64::from_le_bytes((&*buf).try_into().unwrap());
+    let res = unsafe { syscall!(Sysno::sendfile, out_fd, in_fd, &mut off as *mut i64, count) };
+    if res.is_ok() {
+        buf.copy_from_slice(&off.to_le_bytes());
+    }
+    Ok(res)
+}
+
+fn sys_pipe2(cpu: &mut CPU, [pipefd, flags, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    /* Check first, so that there is nothing to undo later. */
+    try_errno!(cpu.memory.guest_slice_mut(pipefd, 8));
+    let mut host_fds = [-1i32; 2];
+    try_errno!(unsafe { syscall!(Sysno::pipe2, host_fds.as_mut_ptr(), flags | O_CLOEXEC) });
+    let cloexec = flags & O_CLOEXEC != 0;
+    let (r, w) = unsafe { (OwnedFd::from_raw_fd(host_fds[0]), OwnedFd::from_raw_fd(host_fds[1])) };
+    let r = try_errno!(cpu.fds.insert(r, cloexec));
+    let w = match cpu.fds.insert(w, cloexec) {
+        Ok(w) => w,
+        Err(errno) => {
+            cpu.fds.close(r).ok();
+            return Ok(Err(errno))
+        }
+    };
+    let buf = cpu.memory.guest_slice_mut(pipefd, 8).unwrap();
+    buf[0..4].copy_from_slice(&(r as i32).to_le_bytes());
+    buf[4..8].copy_from_slice(&(w as i32).to_le_bytes());
+    Ok(Ok(0))
+}
+
+const F_DUPFD: usize = 0;
+const F_GETFD: usize = 1;
+const F_SETFD: usize = 2;
+const F_GETFL: usize = 3;
+const F_SETFL: usize = 4;
+const F_DUPFD_CLOEXEC: usize = 1030;
+const FD_CLOEXEC: usize = 1;
+
+fn sys_fcntl(cpu: &mut CPU, [fd, cmd, arg, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(match cmd {
+        F_DUPFD | F_DUPFD_CLOEXEC if arg >= fds::MAX_FDS => Err(Errno::EINVAL),
+        F_DUPFD => cpu.fds.dup(fd, arg, false),
+        F_DUPFD_CLOEXEC => cpu.fds.dup(fd, arg, true),
+        F_GETFD => cpu.fds.cloexec(fd).map(|cloexec| if cloexec { FD_CLOEXEC } else { 0 }),
+        F_SETFD => cpu.fds.set_cloexec(fd, arg & FD_CLOEXEC != 0).map(|_| 0),
+        /* The file status flags belong to the host's open file description. */
+        F_GETFL | F_SETFL => cpu.fds.host_fd(fd)
+            .and_then(|fd| unsafe { syscall!(Sysno::fcntl, fd, cmd, arg) }),
+        _ => Err(Errno::EINVAL)
+    })
+}
+
+const TCGETS: usize = 0x5401;
+const TCSETS: usize = 0x5402;
+const TCSETSW: usize = 0x5403;
+const TCSETSF: usize = 0x5404;
+const TIOCGPGRP: usize = 0x540f;
+const TIOCSPGRP: usize = 0x5410;
+const TIOCGWINSZ: usize = 0x5413;
+const TIOCSWINSZ: usize = 0x5414;
+const FIONREAD: usize = 0x541b;
+const FIONBIO: usize = 0x5421;
+
+/* The kernel's struct termios (not the one of the libc), for the host's ioctl(). */
+#[repr(C)]
+#[derive(Default)]
+struct Termios {
+    c_iflag: u32,
+    c_oflag: u32,
+    c_cflag: u32,
+    c_lflag: u32,
+    c_line: u8,
+    c_cc: [u8; 19],
+}
+
+/* struct termios of riscv64 (asm-generic): Four u32 flags, c_line and c_cc[19]. */
+const GUEST_TERMIOS_SIZE: usize = 36;
+
+fn read_guest_termios(cpu: &CPU, addr: usize) -> Result<Termios, Errno> {
+    let buf = cpu.memory.guest_slice(addr, GUEST_TERMIOS_SIZE)?;
+    let flag = |i: usize| u32::from_le_bytes(buf[(i * 4)..(i * 4 + 4)].try_into().unwrap());
+    Ok(Termios {
+        c_iflag: flag(0),
+        c_oflag: flag(1),
+        c_cflag: flag(2),
+        c_lflag: flag(3),
+        c_line: buf[16],
+        c_cc: buf[17..36].try_into().unwrap(),
+    })
+}
+
+fn write_guest_termios(cpu: &mut CPU, addr: usize, t: &Termios) -> Result<(), Errno> {
+    let buf = cpu.memory.guest_slice_mut(addr, GUEST_TERMIOS_SIZE)?;
+    for (i, flag) in [t.c_iflag, t.c_oflag, t.c_cflag, t.c_lflag].iter().enumerate() {
+        buf[(i * 4)..(i * 4 + 4)].copy_from_slice(&flag.to_le_bytes());
+    }
+    buf[16] = t.c_line;
+    buf[17..36].copy_from_slice(&t.c_cc);
+    Ok(())
+}
+
+/*
+ * Only the common terminal ioctls, everything else is -ENOTTY (which is also what the
+ * host answers for these if the fd is not a terminal). struct winsize (four u16) and
+ * the ints are the same on the host.
+ */
+fn sys_ioctl(cpu: &mut CPU, [fd, request, arg, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(match request {
+        TCGETS => {
+            let mut termios = Termios::default();
+            unsafe { syscall!(Sysno::ioctl, fd, TCGETS, &mut termios as *mut Termios) }
+                .and_then(|res| write_guest_termios(cpu, arg, &termios).map(|_| res))
+        },
+        TCSETS | TCSETSW | TCSETSF => read_guest_termios(cpu, arg).and_then(|termios| unsafe {
+            syscall!(Sysno::ioctl, fd, request, &termios as *const Termios)
+        }),
+        TIOCGWINSZ | TIOCGPGRP | FIONREAD => {
+            let size = if request == TIOCGWINSZ { 8 } else { 4 };
+            cpu.memory.guest_slice_mut(arg, size)
+                .and_then(|buf| unsafe { syscall!(Sysno::ioctl, fd, request, buf.as_mut_ptr()) })
+        },
+        TIOCSWINSZ | TIOCSPGRP | FIONBIO => {
+            let size = if request == TIOCSWINSZ { 8 } else { 4 };
+            cpu.memory.guest_slice(arg, size)
+                .and_then(|buf| unsafe { syscall!(Sysno::ioctl, fd, request, buf.as_ptr()) })
+        },
+        _ => Err(Errno::ENOTTY)
+    })
+}
+
+/* The guest's sigset_t is the kernel's, 64 bits. */
+const SIGSET_SIZE: usize = 8;
+
+/*
+ * Host pointer to the guest's signal mask (or NULL) for the p*() variants below. The
+ * host's mask follows the guest's (see Signals::set_blocked()), so the host kernel can
+ * apply it while waiting.
+ */
+fn host_sigmask(cpu: &CPU, addr: usize, size: usize) -> Result<*const u8, Errno> {
+    match addr {
+        0 => Ok(std::ptr::null()),
+        _ if size != SIGSET_SIZE => Err(Errno::EINVAL),
+        _ => cpu.memory.guest_slice(addr, SIGSET_SIZE).map(|mask| mask.as_ptr())
+    }
+}
+
+fn host_timeout(cpu: &CPU, addr: usize) -> Result<Option<libc::timespec>, Errno> {
+    match addr {
+        0 => Ok(None),
+        _ => read_timespec(cpu, addr)
+            .map(|(sec, nsec)| Some(libc::timespec { tv_sec: sec, tv_nsec: nsec }))
+    }
+}
+
+/*
+ * ppoll() and pselect6() write back the time that was left. With the virtual clock,
+ * a wait that timed out takes its whole timeout, like a sleep.
+ */
+fn update_timeout(cpu: &mut CPU, addr: usize, before: Option<libc::timespec>,
+                  after: Option<libc::timespec>, res: &SysResult) {
+    let (Some(before), Some(after)) = (before, after) else {
+        return
+    };
+    if cpu.clock.source == ClockSource::Virtual && *res == Ok(0) {
+        cpu.clock.slept_ns += before.tv_sec as u128 * 1_000_000_000 + before.tv_nsec as u128;
+    }
+    write_timespec(cpu, addr, (after.tv_sec, after.tv_nsec)).ok();
+}
+
+const POLLNVAL: i16 = 0x20;
+
+/* struct pollfd (i32 fd, i16 events, i16 revents) is the same on the host, the fds are not. */
+fn sys_ppoll(cpu: &mut CPU, [fds, nfds, tmo, sigmask, sigsetsize, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    if nfds > fds::MAX_FDS {
+        return Ok(Err(Errno::EINVAL))
+    }
+    /* The revents are written back, so the array has to be writable up front. */
+    let buf = try_errno!(cpu.memory.guest_slice_mut(fds, nfds * 8));
+    let mut pollfds = buf.chunks(8).map(|pollfd| {
+        libc::pollfd {
+            fd: i32::from_le_bytes(pollfd[0..4].try_into().unwrap()),
+            events: i16::from_le_bytes(pollfd[4..6].try_into().unwrap()),
+            revents: 0
+        }
+    }).collect::<Vec<_>>();
+    let guest_fds = pollfds.iter().map(|pollfd| pollfd.fd).collect::<Vec<_>>();
+    /* Negative fds are ignored, fds that are not open are always ready (with POLLNVAL). */
+    let mut invalid = 0;
+    for pollfd in pollfds.iter_mut().filter(|pollfd| pollfd.fd >= 0) {
+        pollfd.fd = match cpu.fds.host_fd(pollfd.fd as usize) {
+            Ok(fd) => fd as i32,
+            Err(_) => {
+                invalid += 1;
+                -1
+            }
+        };
+    }
+    let timeout = try_errno!(host_timeout(cpu, tmo));
+    let mut left = match invalid {
+        0 => timeout,
+        _ => Some(libc::timespec { tv_sec: 0, tv_nsec: 0 })
+    };
+    let sigmask = try_errno!(host_sigmask(cpu, sigmask, sigsetsize));
+    let tmo_ptr = left.as_mut().map_or(std::ptr::null_mut(), |left| left as *mut libc::timespec);
+    let res = unsafe {
+        syscall!(Sysno::ppoll, pollfds.as_mut_ptr(), nfds, tmo_ptr, sigmask, sigsetsize)
+    }.map(|ready| ready + invalid);
+    if res.is_ok() {
+        for (i, pollfd) in pollfds.iter().enumerate() {
+            let revents = match pollfd.fd {
+                -1 if guest_fds[i] >= 0 => POLLNVAL,
+                _ => pollfd.revents
+            };
+            buf[(i * 8 + 6)..(i * 8 + 8)].copy_from_slice(&revents.to_le_bytes());
+        }
+    }
+    if invalid == 0 {
+        update_timeout(cpu, tmo, timeout, left, &res);
+    }
+    Ok(res)
+}
+
+/*
+ * The guest's fd_sets are bitmaps of guest fds (in u64 words), they are translated
+ * into bitmaps of the host fds and back. All fds in the sets have to be open.
+ */
+fn sys_pselect6(cpu: &mut CPU, [nfds, readfds, writefds, exceptfds, tmo, sig]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let nfds = std::cmp::min(nfds, fds::MAX_FDS);
+    let words = nfds.div_ceil(64);
+    let mut guest_sets = Vec::new();
+    for addr in [readfds, writefds, exceptfds] {
+        guest_sets.push(match addr {
+            0 => None,
+            _ => Some(try_errno!(cpu.memory.guest_slice_mut(addr, words * 8)).chunks(8)
+                .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+                .collect::<Vec<_>>())
+        });
+    }
+    let is_set = |set: &[u64], fd: usize| set[fd / 64] & (1 << (fd % 64)) != 0;
+
+    let mut host_fds = vec![0; nfds];
+    for (fd, host_fd) in host_fds.iter_mut().enumerate() {
+        if guest_sets.iter().flatten().any(|set| is_set(set, fd)) {
+            *host_fd = try_errno!(cpu.fds.host_fd(fd));
+        }
+    }
+    let host_nfds = host_fds.iter().max().map_or(0, |fd| fd + 1);
+    let mut host_sets = guest_sets.iter().map(|set| set.as_ref().map(|set| {
+        let mut host_set = vec![0u64; host_nfds.div_ceil(64)];
+        for (fd, host_fd) in host_fds.iter().enumerate() {
+            if is_set(set, fd) {
+                host_set[host_fd / 64] |= 1 << (host_fd % 64);
+            }
+        }
+        host_set
+    })).collect::<Vec<_>>();
+
+    /* The last argument is a struct { const sigset_t *ss; size_t ss_len; }. */
+    let sigmask = match sig {
+        0 => [0usize; 2],
+        _ => {
+            let sig = try_errno!(cpu.memory.guest_slice(sig, 16));
+            let ss = u64::from_le_bytes(sig[0..8].try_into().unwrap()) as usize;
+            let ss_len = u64::from_le_bytes(sig[8..16].try_into().unwrap()) as usize;
+            [try_errno!(host_sigmask(cpu, ss, ss_len)) as usize, ss_len]
+        }
+    };
+    let timeout = try_errno!(host_timeout(cpu, tmo));
+    let mut left = timeout;
+    let tmo_ptr = left.as_mut().map_or(std::ptr::null_mut(), |left| left as *mut libc::timespec);
+    let sig_ptr = if sig == 0 { std::ptr::null() } else { sigmask.as_ptr() };
+    let [r, w, e] = &mut host_sets[..] else {
+        unreachable!()
+    };
+    let set_ptr = |set: &mut Option<Vec<u64>>| {
+        set.as_mut().map_or(std::ptr::null_mut(), |set| set.as_mut_ptr())
+    };
+    let res = unsafe {
+        syscall!(Sysno::pselect6, host_nfds, set_ptr(r), set_ptr(w), set_ptr(e), tmo_ptr, sig_ptr)
+    };
+    if res.is_err() {
+        return Ok(res)
+    }
+
+    /* Back to guest fds, dups of the same host fd count separately. */
+    let mut ready = 0;
+    let sets = [readfds, writefds, exceptfds].into_iter().zip(&guest_sets).zip(&host_sets);
+    for ((addr, guest_set), host_set) in sets {
+        let (Some(guest_set), Some(host_set)) = (guest_set, host_set) else {
+            continue
+        };
+        let mut result = vec![0u64; words];
+        for (fd, host_fd) in host_fds.iter().enumerate() {
+            if is_set(guest_set, fd) && is_set(host_set, *host_fd) {
+                result[fd / 64] |= 1 << (fd % 64);
+                ready += 1;
+            }
+        }
+        let buf = cpu.memory.guest_slice_mut(addr, words * 8).unwrap();
+        for (i, word) in result.iter().enumerate() {
+            buf[(i * 8)..(i * 8 + 8)].copy_from_slice(&word.to_le_bytes());
+        }
+    }
+    let res = Ok(ready);
+    update_timeout(cpu, tmo, timeout, left, &res);
+    Ok(res)
+}
+
+/* The flags (EFD_CLOEXEC, EFD_NONBLOCK, EFD_SEMAPHORE) are the same on all architectures. */
+fn sys_eventfd2(cpu: &mut CPU, [initval, flags, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(unsafe { syscall!(Sysno::eventfd2, initval, flags | O_CLOEXEC) }
+        .and_then(|fd| cpu.fds.insert(unsafe { OwnedFd::from_raw_fd(fd as i32) },
+                                      flags & O_CLOEXEC != 0)))
+}
+
+fn sys_epoll_create1(cpu: &mut CPU, [flags, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if flags & !O_CLOEXEC != 0 {
+        return Ok(Err(Errno::EINVAL))
+    }
+    Ok(unsafe { syscall!(Sysno::epoll_create1, O_CLOEXEC) }
+        .and_then(|fd| cpu.fds.insert(unsafe { OwnedFd::from_raw_fd(fd as i32) },
+                                      flags & O_CLOEXEC != 0)))
+}
+
+/*
+ * struct epoll_event is packed on x86_64 (12 bytes) but not on riscv64 (u32 events,
+ * padding, u64 data). The data is the guest's, the kernel just hands it back, so
+ * no host fds ever show up in guest memory.
+ */
+const GUEST_EPOLL_EVENT_SIZE: usize = 16;
+const EPOLL_CTL_DEL: usize = 2;
+
+fn sys_epoll_ctl(cpu: &mut CPU, [epfd, op, fd, event, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let epfd = try_errno!(cpu.fds.host_fd(epfd));
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    let mut host_event = libc::epoll_event { events: 0, u64: 0 };
+    if op != EPOLL_CTL_DEL {
+        let buf = try_errno!(cpu.memory.guest_slice(event, GUEST_EPOLL_EVENT_SIZE));
+        host_event.events = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        host_event.u64 = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+    }
+    Ok(unsafe {
+        syscall!(Sysno::epoll_ctl, epfd, op, fd, &mut host_event as *mut libc::epoll_event)
+    })
+}
+
+fn sys_epoll_pwait(cpu: &mut CPU, args: [usize; 6]) -> Result<SysResult, Error> {
+    let [epfd, events, maxevents, timeout, sigmask, sigsetsize] = args;
+    let epfd = try_errno!(cpu.fds.host_fd(epfd));
+    let maxevents = maxevents as i32;
+    if maxevents <= 0 || maxevents as usize > i32::MAX as usize / GUEST_EPOLL_EVENT_SIZE {
+        return Ok(Err(Errno::EINVAL))
+    }
+    let maxevents = maxevents as usize;
+    try_errno!(cpu.memory.guest_slice_mut(events, maxevents * GUEST_EPOLL_EVENT_SIZE));
+    let sigmask = try_errno!(host_sigmask(cpu, sigmask, sigsetsize));
+    let mut host_events = vec![libc::epoll_event { events: 0, u64: 0 }; maxevents];
+    let res = unsafe {
+        syscall!(Sysno::epoll_pwait, epfd, host_events.as_mut_ptr(), maxevents, timeout, sigmask,
+                 sigsetsize)
+    };
+    if let Ok(n) = res {
+        let buf = cpu.memory.guest_slice_mut(events, n * GUEST_EPOLL_EVENT_SIZE).unwrap();
+        for (i, event) in host_events[..n].iter().enumerate() {
+            let out = &mut buf[(i * GUEST_EPOLL_EVENT_SIZE)..((i + 1) * GUEST_EPOLL_EVENT_SIZE)];
+            out[0..4].copy_from_slice(&{ event.events }.to_le_bytes());
+            out[4..8].fill(0);
+            out[8..16].copy_from_slice(&{ event.u64 }.to_le_bytes());
+        }
+        let timeout = timeout as i32;
+        if n == 0 && timeout > 0 && cpu.clock.source == ClockSource::Virtual {
+            cpu.clock.slept_ns += timeout as u128 * 1_000_000;
+        }
+    }
+    Ok(res)
+}
+
+fn host_fstat(fd: usize) -> Result<libc::stat, Errno> {
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    unsafe { syscall!(Sysno::fstat, fd, &mut st as *mut libc::stat) }.map(|_| st)
+}
+
+/* Files from tar archives have a memfd as host fd, their metadata is in the node. */
+fn stat_fd(cpu: &CPU, fd: usize) -> Result<libc::stat, Errno> {
+    match cpu.fds.opened(fd)?.and_then(|opened| opened.node.clone()) {
+        Some(node) => Ok(node.stat()),
+        None => host_fstat(cpu.fds.host_fd(fd)?)
+    }
+}
+
+/*
+ * What a stat()-like syscall through the VFS is about: The host fd (kept open by the
+ * OwnedFd, if there is one) and the node for files from tar archives. An empty path with
+ * AT_EMPTY_PATH is `dirfd` itself.
+ */
+type StatTarget = (usize, Option<OwnedFd>, Option<Arc<tar::Node>>);
+
+fn vfs_stat_target(cpu: &CPU, vfs: &Vfs, dirfd: usize, path: usize, flags: usize)
+        -> Result<StatTarget, Errno> {
+    let empty = flags & vfs::AT_EMPTY_PATH != 0 && cpu.memory.guest_cstr(path)?.is_empty();
+    let path = match dirfd as i32 {
+        _ if !empty => vfs_path(cpu, vfs, dirfd, path)?,
+        AT_FDCWD => vfs.cwd(),
+        _ => {
+            let node = cpu.fds.opened(dirfd)?.and_then(|opened| opened.node.clone());
+            return Ok((cpu.fds.host_fd(dirfd)?, None, node))
+        }
+    };
+    let (file, opened) = vfs.open(&path, vfs::O_PATH | vfs::nofollow(flags), 0)?;
+    Ok((file.as_raw_fd() as usize, Some(file), opened.node))
+}
+
+fn sys_newfstatat(cpu: &mut CPU, [dirfd, path, statbuf, flags, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    if let Some(vfs) = cpu.vfs.clone() {
+        let (fd, _file, node) = try_errno!(vfs_stat_target(cpu, &vfs, dirfd, path, flags));
+        let st = node.map_or_else(|| host_fstat(fd), |node| Ok(node.stat()));
+        return Ok(st.and_then(|st| write_guest_stat(cpu, statbuf, &st)))
+    }
+    let dirfd = try_errno!(host_dirfd(cpu, dirfd));
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    Ok(host_path(cpu, path)
+        .and_then(|path| unsafe {
+            syscall!(Sysno::newfstatat, dirfd, path.as_ptr(), &mut st as *mut libc::stat, flags)
+        })
+        .and_then(|_| write_guest_stat(cpu, statbuf, &st)))
+}
+
+fn sys_fstat(cpu: &mut CPU, [fd, statbuf, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(stat_fd(cpu, fd).and_then(|st| write_guest_stat(cpu, statbuf, &st)))
+}
+
+/* struct statx is the same on all architectures (that's what it was made for). */
+fn sys_statx(cpu: &mut CPU, [dirfd, path, flags, mask, statxbuf, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    const STATX_SIZE: usize = std::mem::size_of::<libc::statx>();
+    if let Some(vfs) = cpu.vfs.clone() {
+        let (fd, _file, node) = try_errno!(vfs_stat_target(cpu, &vfs, dirfd, path, flags));
+        let buf = try_errno!(cpu.memory.guest_slice_mut(statxbuf, STATX_SIZE));
+        let host_flags = (flags | vfs::AT_EMPTY_PATH) & !vfs::AT_SYMLINK_NOFOLLOW;
+        return Ok(match node {
+            Some(node) => {
+                let stx = node.statx();
+                buf.copy_from_slice(unsafe {
+                    std::slice::from_raw_parts(&stx as *const libc::statx as *const u8, STATX_SIZE)
+                });
+                Ok(0)
+            },
+            None => unsafe {
+                syscall!(Sysno::statx, fd, c"".as_ptr(), host_flags, mask, buf.as_mut_ptr())
+            }
+        })
+    }
+    let dirfd = try_errno!(host_dirfd(cpu, dirfd));
+    let path = try_errno!(host_path(cpu, path));
+    Ok(cpu.memory.guest_slice_mut(statxbuf, STATX_SIZE)
+        .and_then(|buf| unsafe {
+            syscall!(Sysno::statx, dirfd, path.as_ptr(), flags, mask, buf.as_mut_ptr())
+        }))
+}
+
+/* exit() ends the calling thread only, the guest once it was the last one. */
+fn sys_exit(_: &mut CPU, [status, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Err(Error::ThreadExit(status as i32))
+}
+
+fn sys_exit_group(_: &mut CPU, [status, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Err(Error::Exit(status as i32))
+}
+
+fn sys_set_tid_address(cpu: &mut CPU, [tidptr, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    cpu.clear_child_tid = tidptr as u64;
+    Ok(Ok(cpu.tid as usize))
+}
+
+fn sys_gettid(cpu: &mut CPU, _: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(Ok(cpu.tid as usize))
+}
+
+/* sizeof(struct robust_list_head) */
+const ROBUST_LIST_HEAD_SIZE: usize = 24;
+
+fn sys_set_robust_list(cpu: &mut CPU, [head, len, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if len != ROBUST_LIST_HEAD_SIZE {
+        return Ok(Err(Errno::EINVAL))
+    }
+    cpu.robust_list = head as u64;
+    Ok(Ok(0))
+}
+
+/* The original struct rseq: cpu_id_start, cpu_id, rseq_cs, flags, 32 byte aligned. */
+const RSEQ_SIZE: usize = 32;
+const RSEQ_FLAG_UNREGISTER: usize = 1;
+const RSEQ_CPU_ID_UNINITIALIZED: u32 = -1i32 as u32;
+
+/*
+ * Registration works, but cpu_id stays RSEQ_CPU_ID_UNINITIALIZED: Guest threads run in
+ * parallel on host threads, and critical sections are never aborted. Users of rseq (e.g.
+ * glibc's sched_getcpu()) check cpu_id and fall back to what they do without it.
+ */
+fn sys_rseq(cpu: &mut CPU, [rseq, len, flags, sig, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let sig = sig as u32;
+    if flags & RSEQ_FLAG_UNREGISTER != 0 {
+        return Ok(match cpu.rseq {
+            _ if flags != RSEQ_FLAG_UNREGISTER => Err(Errno::EINVAL),
+            Some((addr, l, _)) if addr != rseq || l != len => Err(Errno::EINVAL),
+            Some((_, _, s)) if s != sig => Err(Errno::EPERM),
+            Some(_) => {
+                cpu.rseq = None;
+                Ok(0)
+            },
+            None => Err(Errno::EINVAL)
+        })
+    }
+    match cpu.rseq {
+        _ if flags != 0 => return Ok(Err(Errno::EINVAL)),
+        Some((addr, l, s)) if addr == rseq && l == len =>
+            return Ok(Err(if s != sig { Errno::EPERM } else { Errno::EBUSY })),
+        Some(_) => return Ok(Err(Errno::EINVAL)),
+        None => {}
+    }
+    if len < RSEQ_SIZE || !rseq.is_multiple_of(RSEQ_SIZE) {
+        return Ok(Err(Errno::EINVAL))
+    }
+    let area = try_errno!(cpu.memory.guest_slice_mut(rseq, RSEQ_SIZE));
+    area[0..4].copy_from_slice(&0u32.to_le_bytes());
+    area[4..8].copy_from_slice(&RSEQ_CPU_ID_UNINITIALIZED.to_le_bytes());
+    cpu.rseq = Some((rseq, len, sig));
+    Ok(Ok(0))
+}
+
+/* The exit signal of fork()-like clones. */
+const CSIGNAL: usize = 0xff;
+const CLONE_VM: usize = 0x100;
+const CLONE_FS: usize = 0x200;
+const CLONE_FILES: usize = 0x400;
+const CLONE_SIGHAND: usize = 0x800;
+const CLONE_VFORK: usize = 0x4000;
+const CLONE_THREAD: usize = 0x10000;
+const CLONE_SYSVSEM: usize = 0x40000;
+const CLONE_SETTLS: usize = 0x80000;
+const CLONE_PARENT_SETTID: usize = 0x100000;
+const CLONE_CHILD_CLEARTID: usize = 0x200000;
+const CLONE_DETACHED: usize = 0x400000;
+const CLONE_CHILD_SETTID: usize = 0x1000000;
+/* What pthread_create() of glibc and musl passes, minus the optional parts. */
+const CLONE_THREAD_FLAGS: usize = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD;
+const CLONE_THREAD_OPTIONAL: usize = CLONE_SYSVSEM | CLONE_SETTLS | CLONE_PARENT_SETTID
+    | CLONE_CHILD_CLEARTID | CLONE_DETACHED | CLONE_CHILD_SETTID;
+/* What fork(), vfork() and posix_spawn() of glibc and musl pass. */
+const CLONE_FORK_FLAGS: usize = CSIGNAL | CLONE_VM | CLONE_VFORK | CLONE_PARENT_SETTID
+    | CLONE_CHILD_CLEARTID | CLONE_CHILD_SETTID;
+
+/* The arguments of clone() and clone3() that matter for threads and forks. */
+struct CloneArgs {
+    flags: usize,
+    /// Initial sp of the child (the top of its stack), 0 for the parent's.
+    stack: usize,
+    parent_tid: usize,
+    child_tid: usize,
+    tls: usize,
+}
+
+/*
+ * Threads share everything with the parent, and start after the ecall with a0 = 0 on
+ * a hart (host thread) of their own, see threads.rs. Everything else is a fork().
+ */
+fn clone(cpu: &mut CPU, args: CloneArgs) -> SysResult {
+    if args.flags & CLONE_THREAD == 0 {
+        return fork(cpu, args)
+    }
+    /* Sharing only some of it, e.g. CLONE_VM without CLONE_FILES. */
+    if args.flags & CLONE_THREAD_FLAGS != CLONE_THREAD_FLAGS {
+        return Err(Errno::ENOSYS)
+    }
+    /* Includes an exit signal (CSIGNAL), which threads cannot have. */
+    if args.flags & !(CLONE_THREAD_FLAGS | CLONE_THREAD_OPTIONAL) != 0 {
+        return Err(Errno::EINVAL)
+    }
+    let mut child = cpu.new_thread();
+    child.pc += 4;
+    child.set_reg(REG_A0, 0);
+    if args.stack != 0 {
+        child.set_reg(REG_SP, args.stack as u64);
+    }
+    if args.flags & CLONE_SETTLS != 0 {
+        child.set_reg(REG_TP, args.tls as u64);
+    }
+    if args.flags & CLONE_CHILD_CLEARTID != 0 {
+        child.clear_child_tid = args.child_tid as u64;
+    }
+    let mut set_tid = Vec::new();
+    if args.flags & CLONE_PARENT_SETTID != 0 {
+        set_tid.push(args.parent_tid);
+    }
+    if args.flags & CLONE_CHILD_SETTID != 0 {
+        set_tid.push(args.child_tid);
+    }
+    threads::spawn(child, set_tid).map(|tid| tid as usize)
+}
+
+/*
+ * fork() and vfork(): The whole simulator is forked, the child's copy of the guest goes
+ * on after the ecall with a0 = 0. Its memory is a copy even with CLONE_VM (vfork()),
+ * but the parent still waits until the child did execve() or exited. The exit signal
+ * is always SIGCHLD.
+ */
+fn fork(cpu: &mut CPU, args: CloneArgs) -> SysResult {
+    let vfork = args.flags & CLONE_VFORK != 0;
+    if args.flags & !CLONE_FORK_FLAGS != 0 || (args.flags & CLONE_VM != 0 && !vfork) {
+        return Err(Errno::EINVAL)
+    }
+    let vfork_done = match vfork {
+        true => {
+            let mut pipe = [-1; 2];
+            if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+                return Err(Errno::EMFILE)
+            }
+            Some(pipe.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }))
+        },
+        false => None
+    };
+    /* Made before, in the child the locks of other threads would stay locked forever. */
+    let fds = cpu.fds.duplicate();
+    let signals = cpu.signals.fork();
+    let pid = {
+        /* No mmap() of another thread half done in the child. */
+        let _mm = cpu.memory.mm();
+        unsafe { libc::fork() }
+    };
+    match pid {
+        -1 => Err(Errno::EAGAIN),
+        0 => {
+            cpu.fds = fds;
+            cpu.signals = signals;
+            threads::forked(cpu);
+            if args.stack != 0 {
+                cpu.set_reg(REG_SP, args.stack as u64);
+            }
+            if args.flags & CLONE_CHILD_SETTID != 0 {
+                cpu.memory.store_u32(args.child_tid, cpu.tid as u32).ok();
+            }
+            if args.flags & CLONE_CHILD_CLEARTID != 0 {
+                cpu.clear_child_tid = args.child_tid as u64;
+            }
+            cpu.vfork_done = vfork_done.map(|[_, write]| write);
+            Ok(0)
+        },
+        pid => {
+            if args.flags & CLONE_PARENT_SETTID != 0 {
+                cpu.memory.store_u32(args.parent_tid, pid as u32).ok();
+            }
+            if let Some([read, write]) = vfork_done {
+                drop(write);
+                let mut buf = [0u8; 1];
+                while unsafe { libc::read(read.as_raw_fd(), buf.as_mut_ptr().cast(), 1) } < 0
+                    && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {}
+            }
+            Ok(pid as usize)
+        }
+    }
+}
+
+/* The argument order of riscv64 (and most others): flags, stack, ptid, tls, ctid. */
+fn sys_clone(cpu: &mut CPU, [flags, stack, parent_tid, tls, child_tid, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    Ok(clone(cpu, CloneArgs { flags, stack, parent_tid, child_tid, tls }))
+}
+
+/* struct clone_args: flags, pidfd, child_tid, parent_tid, exit_signal, stack, stack_size,
+ * tls, ..., all u64. Newer (longer) versions of it are fine if the rest is zero. */
+const CLONE_ARGS_SIZE_VER0: usize = 64;
+
+fn sys_clone3(cpu: &mut CPU, [uargs, size, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if size < CLONE_ARGS_SIZE_VER0 {
+        return Ok(Err(Errno::EINVAL))
+    }
+    let bytes = try_errno!(cpu.memory.guest_slice(uargs, size));
+    if bytes[CLONE_ARGS_SIZE_VER0..].iter().any(|b| *b != 0) {
+        return Ok(Err(Errno::E2BIG))
+    }
+    let field = |i: usize|
+        u64::from_le_bytes(bytes[(i * 8)..(i * 8 + 8)].try_into().unwrap()) as usize;
+    let (flags, exit_signal, stack, stack_size) = (field(0), field(4), field(5), field(6));
+    if flags & CSIGNAL != 0 || exit_signal > CSIGNAL || (stack == 0) != (stack_size == 0) {
+        return Ok(Err(Errno::EINVAL))
+    }
+    let stack = match stack {
+        0 => 0,
+        _ => try_errno!(stack.checked_add(stack_size).ok_or(Errno::EINVAL))
+    };
+    let args = CloneArgs {
+        flags: flags | exit_signal,
+        stack,
+        parent_tid: field(3),
+        child_tid: field(2),
+        tls: field(7),
+    };
+    Ok(clone(cpu, args))
+}
+
+/* A NULL-terminated array of strings (argv, envp), NULL is the same as an empty one. */
+fn guest_cstr_array(cpu: &CPU, addr: usize) -> Result<Vec<CString>, Errno> {
+    let mut strings = Vec::new();
+    if addr == 0 {
+        return Ok(strings)
+    }
+    loop {
+        let ptr = cpu.memory.guest_slice(addr + strings.len() * 8, 8)?;
+        match u64::from_le_bytes(ptr.try_into().unwrap()) as usize {
+            0 => return Ok(strings),
+            ptr => strings.push(cpu.memory.guest_cstr(ptr)?.to_owned())
+        }
+    }
+}
+
+/*
+ * An executable for execve(): The open file, what /proc/self/exe of it is and the host
+ * path that host binaries are run from. Those have none with mounts, the VFS would not
+ * apply to them.
+ */
+struct ExecFile {
+    file: std::fs::File,
+    exe: std::path::PathBuf,
+    host: Option<CString>,
+}
+
+fn open_exec(cpu: &CPU, path: &CStr) -> Result<ExecFile, Errno> {
+    if path.is_empty() {
+        return Err(Errno::ENOENT)
+    }
+    let (file, exe, host) = match &cpu.vfs {
+        Some(vfs) => {
+            let guest = vfs::normalize(&vfs.cwd(), path.to_bytes());
+            vfs.access(&guest, libc::X_OK as usize, 0)?;
+            let (file, opened) = vfs.open(&guest, 0, 0)?;
+            if opened.node.is_some_and(|node| !matches!(node.kind, tar::Kind::File(_))) {
+                return Err(Errno::EACCES)
+            }
+            (std::fs::File::from(file), OsStr::from_bytes(&guest).into(), None)
+        },
+        None => {
+            let host = sysroot_redirect(cpu, path)?;
+            if unsafe { libc::access(host.as_ptr(), libc::X_OK) } != 0 {
+                return Err(vfs::host_errno(std::io::Error::last_os_error()))
+            }
+            let path = std::path::Path::new(OsStr::from_bytes(host.to_bytes()));
+            let file = std::fs::File::open(path).map_err(vfs::host_errno)?;
+            (file, std::fs::canonicalize(path).unwrap_or_else(|_| path.into()), Some(host))
+        }
+    };
+    if !file.metadata().map_err(vfs::host_errno)?.is_file() {
+        return Err(Errno::EACCES)
+    }
+    Ok(ExecFile { file, exe, host })
+}
+
+/* Like Linux (BINPRM_MAX_RECURSION): A script's interpreter can be a script, 4 levels deep. */
+const MAX_INTERP_DEPTH: usize = 4;
+
+/*
+ * RISC-V executables replace the guest's image (see CPU::exec()), once all other threads
+ * are gone. Returns Error::Exec then, the guest goes on at the entry of the new image.
+ */
+fn sys_execve(cpu: &mut CPU, [path, argv, envp, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let mut path = try_errno!(cpu.memory.guest_cstr(path)).to_owned();
+    let mut argv = try_errno!(guest_cstr_array(cpu, argv));
+    let envp = try_errno!(guest_cstr_array(cpu, envp));
+    /* Like Linux: The strings (and pointers to them) can take up to 1/4 of the stack. */
+    let size: usize = argv.iter().chain(&envp).map(|s| s.as_bytes_with_nul().len() + 8).sum();
+    if size > cpu.memory.layout.stack().len() / 4 {
+        return Ok(Err(Errno::E2BIG))
+    }
+
+    for _ in 0..=MAX_INTERP_DEPTH {
+        use std::io::{Read, Seek};
+        let mut file = try_errno!(open_exec(cpu, &path));
+        let mut header = Vec::with_capacity(exec::HEADER_SIZE);
+        try_errno!((&file.file).take(exec::HEADER_SIZE as u64).read_to_end(&mut header)
+            .map_err(vfs::host_errno));
+        match try_errno!(Executable::from_header(&header)) {
+            Executable::RiscV => {
+                let mut raw = Vec::new();
+                try_errno!(file.file.rewind().and_then(|_| file.file.read_to_end(&mut raw))
+                    .map_err(vfs::host_errno));
+                let Ok(elf_file) = elf::ElfBytes::<'_, elf::endian::AnyEndian>::minimal_parse(&raw)
+                    else { return Ok(Err(Errno::ENOEXEC)) };
+                if !cpu.process.de_thread(cpu.tid) {
+                    return Ok(Err(Errno::EAGAIN))
+                }
+                cpu.exe = Some(file.exe);
+                cpu.exec(&elf_file, argv.iter().map(|s| s.to_bytes()).collect(),
+                         envp.iter().map(|s| s.to_bytes()).collect())?;
+                return Err(Error::Exec)
+            },
+            /* The interpreter gets the script's path instead of argv[0]. */
+            Executable::Script(interp, arg) => {
+                let mut script_argv = vec![interp.clone()];
+                script_argv.extend(arg);
+                script_argv.push(path);
+                script_argv.extend(argv.into_iter().skip(1));
+                (path, argv) = (interp, script_argv);
+            },
+            Executable::Host => return Ok(Err(match (cpu.host_exec, file.host) {
+                (HostExecPolicy::Deny, _) | (_, None) => Errno::EACCES,
+                (HostExecPolicy::Host, Some(host)) =>
+                    exec::host_exec(&host, &argv, &envp, &cpu.fds.inheritable(), &cpu.signals)
+            }))
+        }
+    }
+    Ok(Err(Errno::ELOOP))
+}
+
+/* Host pointer to a struct the kernel writes to, or NULL. */
+fn host_out_ptr(cpu: &mut CPU, addr: usize, size: usize) -> Result<*mut u8, Errno> {
+    match addr {
+        0 => Ok(std::ptr::null_mut()),
+        _ => cpu.memory.guest_slice_mut(addr, size).map(|buf| buf.as_mut_ptr())
+    }
+}
+
+/*
+ * Children are forked simulators, so the host's wait4()/waitid() see them as they are.
+ * The wait status, struct rusage and siginfo_t of riscv64 are those of the host, and a
+ * child that is killed by a signal kills its simulator with that signal.
+ */
+const RUSAGE_SIZE: usize = 144;
+
+fn sys_wait4(cpu: &mut CPU, [pid, status, options, rusage, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let status = try_errno!(host_out_ptr(cpu, status, 4));
+    let rusage = try_errno!(host_out_ptr(cpu, rusage, RUSAGE_SIZE));
+    Ok(unsafe { syscall!(Sysno::wait4, pid as i32, status, options, rusage) })
+}
+
+fn sys_waitid(cpu: &mut CPU, [idtype, id, infop, options, rusage, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let infop = try_errno!(host_out_ptr(cpu, infop, signals::SIGINFO_SIZE));
+    let rusage = try_errno!(host_out_ptr(cpu, rusage, RUSAGE_SIZE));
+    Ok(unsafe { syscall!(Sysno::waitid, idtype, id, infop, options, rusage) })
+}
+
+/* The guest's main thread is the simulator's, ids are the host's. */
+fn sys_getpid(_: &mut CPU, _: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(unsafe { syscall!(Sysno::getpid) })
+}
+
+fn sys_getppid(_: &mut CPU, _: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(unsafe { syscall!(Sysno::getppid) })
+}
+
+fn sys_getuid(_: &mut CPU, _: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(unsafe { syscall!(Sysno::getuid) })
+}
+
+fn sys_geteuid(_: &mut CPU, _: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(unsafe { syscall!(Sysno::geteuid) })
+}
+
+fn sys_getgid(_: &mut CPU, _: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(unsafe { syscall!(Sysno::getgid) })
+}
+
+fn sys_getegid(_: &mut CPU, _: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(unsafe { syscall!(Sysno::getegid) })
+}
+
+const RLIMIT_STACK: usize = 3;
+const RLIMIT_NOFILE: usize = 7;
+
+/*
+ * The guest's stack size and number of fds are fixed by the simulator, they can be lowered
+ * but that is not enforced. All other limits (and those of other processes) are the host's,
+ * struct rlimit64 is the same on all architectures.
+ */
+fn sys_prlimit64(cpu: &mut CPU, [pid, resource, new, old, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let fixed = match resource {
+        RLIMIT_STACK => cpu.memory.layout.stack().len() as u64,
+        RLIMIT_NOFILE => fds::MAX_FDS as u64,
+        _ => 0
+    };
+    if (pid != 0 && pid as i32 != unsafe { libc::getpid() }) || fixed == 0 {
+        let new = try_errno!(match new {
+            0 => Ok(std::ptr::null()),
+            _ => cpu.memory.guest_slice(new, 16).map(|buf| buf.as_ptr())
+        });
+        let old = try_errno!(host_out_ptr(cpu, old, 16));
+        return Ok(unsafe { syscall!(Sysno::prlimit64, pid, resource, new, old) })
+    }
+    if new != 0 {
+        let limits = try_errno!(cpu.memory.guest_slice(new, 16));
+        let cur = u64::from_le_bytes(limits[0..8].try_into().unwrap());
+        let max = u64::from_le_bytes(limits[8..16].try_into().unwrap());
+        if cur > max {
+            return Ok(Err(Errno::EINVAL))
+        }
+        if max > fixed {
+            return Ok(Err(Errno::EPERM))
+        }
+    }
+    if old != 0 {
+        let limits = try_errno!(cpu.memory.guest_slice_mut(old, 16));
+        limits[0..8].copy_from_slice(&fixed.to_le_bytes());
+        limits[8..16].copy_from_slice(&fixed.to_le_bytes());
+    }
+    Ok(Ok(0))
+}
+
+fn sys_getrandom(cpu: &mut CPU, [buf, len, flags, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(cpu.memory.guest_slice_mut(buf, len)
+        .and_then(|buf| unsafe { syscall!(Sysno::getrandom, buf.as_mut_ptr(), buf.len(), flags) }))
+}
+
+/* struct new_utsname: Six strings of 65 bytes. */
+const UTSNAME_FIELD: usize = 65;
+
+/* The host's, except that the machine is a riscv64 one. */
+fn sys_uname(cpu: &mut CPU, [buf, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    try_errno!(unsafe { syscall!(Sysno::uname, &mut uts as *mut libc::utsname) });
+    let out = try_errno!(cpu.memory.guest_slice_mut(buf, 6 * UTSNAME_FIELD));
+    let fields = [&uts.sysname, &uts.nodename, &uts.release, &uts.version, &uts.machine,
+                  &uts.domainname];
+    for (i, field) in fields.into_iter().enumerate() {
+        let bytes = field.map(|c| c as u8);
+        out[(i * UTSNAME_FIELD)..((i + 1) * UTSNAME_FIELD)].copy_from_slice(&bytes);
+    }
+    let machine = &mut out[(4 * UTSNAME_FIELD)..(5 * UTSNAME_FIELD)];
+    machine.fill(0);
+    machine[..7].copy_from_slice(b"riscv64");
+    Ok(Ok(0))
+}
+
+const RISCV_HWPROBE_KEY_MVENDORID: i64 = 0;
+const RISCV_HWPROBE_KEY_MARCHID: i64 = 1;
+const RISCV_HWPROBE_KEY_MIMPID: i64 = 2;
+const RISCV_HWPROBE_KEY_BASE_BEHAVIOR: i64 = 3;
+const RISCV_HWPROBE_KEY_IMA_EXT_0: i64 = 4;
+/* rv64ima, the A extension is part of the base behavior, IMA_EXT_0 has no bit for it. */
+const RISCV_HWPROBE_BASE_BEHAVIOR_IMA: u64 = 1;
+const RISCV_HWPROBE_IMA_C: u64 = 1 << 1;
+
+/*
+ * What glibc's ifunc resolvers ask about the harts: All of them are the same, rv64imac
+ * from no vendor in particular. Unknown keys are set to -1, like Linux does.
+ */
+fn sys_riscv_hwprobe(cpu: &mut CPU, [pairs, count, _, _, flags, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    if flags != 0 {
+        return Ok(Err(Errno::EINVAL))
+    }
+    let pairs = try_errno!(cpu.memory.guest_slice_mut(pairs, count.saturating_mul(16)));
+    for pair in pairs.chunks_exact_mut(16) {
+        let key = i64::from_le_bytes(pair[0..8].try_into().unwrap());
+        let (key, value) = match key {
+            RISCV_HWPROBE_KEY_MVENDORID | RISCV_HWPROBE_KEY_MARCHID |
+            RISCV_HWPROBE_KEY_MIMPID => (key, 0),
+            RISCV_HWPROBE_KEY_BASE_BEHAVIOR => (key, RISCV_HWPROBE_BASE_BEHAVIOR_IMA),
+            RISCV_HWPROBE_KEY_IMA_EXT_0 => (key, RISCV_HWPROBE_IMA_C),
+            _ => (-1, 0)
+        };
+        pair[0..8].copy_from_slice(&key.to_le_bytes());
+        pair[8..16].copy_from_slice(&value.to_le_bytes());
+    }
+    Ok(Ok(0))
+}
+
+/* Futex operations, without FUTEX_PRIVATE_FLAG (128). */
+const FUTEX_WAIT: usize = 0;
+const FUTEX_REQUEUE: usize = 3;
+const FUTEX_CMP_REQUEUE: usize = 4;
+const FUTEX_WAKE_OP: usize = 5;
+const FUTEX_LOCK_PI: usize = 6;
+const FUTEX_WAIT_BITSET: usize = 9;
+const FUTEX_WAIT_REQUEUE_PI: usize = 11;
+const FUTEX_CMP_REQUEUE_PI: usize = 12;
+const FUTEX_LOCK_PI2: usize = 13;
+const FUTEX_CMD_MASK: usize = 0x7f;
+const FUTEX_CLOCK_REALTIME: usize = 256;
+
+/* A futex word of the guest as a host pointer. */
+fn futex_word(cpu: &CPU, addr: usize) -> Result<usize, Errno> {
+    if !addr.is_multiple_of(4) {
+        return Err(Errno::EINVAL)
+    }
+    cpu.memory.guest_slice(addr, 4).map(|word| word.as_ptr() as usize)
+}
+
+/*
+ * The guest's threads are host threads of the simulator, so the host's futexes work on
+ * the guest's memory as they are. Only the pointers are translated, and the absolute
+ * timeouts, if they are on the virtual clock: Those become a host deadline that is as
+ * far in the future.
+ */
+fn sys_futex(cpu: &mut CPU, [uaddr, op, val, timeout, uaddr2, val3]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let cmd = op & FUTEX_CMD_MASK;
+    let uaddr = try_errno!(futex_word(cpu, uaddr));
+    let uaddr2 = match cmd {
+        FUTEX_REQUEUE | FUTEX_CMP_REQUEUE | FUTEX_WAKE_OP | FUTEX_WAIT_REQUEUE_PI
+            | FUTEX_CMP_REQUEUE_PI => try_errno!(futex_word(cpu, uaddr2)),
+        _ => uaddr2
+    };
+    /* For the other operations, the 4th argument is an int (val2). */
+    let clockid = match cmd == FUTEX_LOCK_PI || op & FUTEX_CLOCK_REALTIME != 0 {
+        true => CLOCK_REALTIME,
+        false => CLOCK_MONOTONIC
+    };
+    let absolute = match cmd {
+        FUTEX_WAIT => Some(false),
+        FUTEX_WAIT_BITSET | FUTEX_WAIT_REQUEUE_PI | FUTEX_LOCK_PI | FUTEX_LOCK_PI2 => Some(true),
+        _ => None
+    };
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    let timeout = match absolute {
+        Some(absolute) if timeout != 0 => {
+            let (sec, nsec) = try_errno!(read_timespec(cpu, timeout));
+            (ts.tv_sec, ts.tv_nsec) = match cpu.clock.source {
+                ClockSource::Virtual if absolute => host_deadline(cpu, clockid, sec, nsec),
+                _ => (sec as _, nsec as _)
+            };
+            &ts as *const libc::timespec as usize
+        },
+        _ => timeout
+    };
+    Ok(unsafe { syscall!(Sysno::futex, uaddr, op, val, timeout, uaddr2, val3) })
+}
+
+fn read_sigset(cpu: &CPU, addr: usize) -> Result<u64, Errno> {
+    cpu.memory.guest_slice(addr, SIGSET_SIZE).map(|set| u64::from_le_bytes(set.try_into().unwrap()))
+}
+
+fn write_sigset(cpu: &mut CPU, addr: usize, set: u64) -> Result<(), Errno> {
+    cpu.memory.guest_slice_mut(addr, SIGSET_SIZE).map(|buf| buf.copy_from_slice(&set.to_le_bytes()))
+}
+
+/* Signals the guest sends to itself are emulated, all others are the host's business. */
+fn queue_self(cpu: &mut CPU, sig: usize, code: i32) -> SysResult {
+    match sig {
+        0 => Ok(0),
+        _ if sig > NSIG => Err(Errno::EINVAL),
+        _ => {
+            cpu.signals.queue(SigInfo::user(sig as i32, code));
+            Ok(0)
+        }
+    }
+}
+
+fn sys_kill(cpu: &mut CPU, [pid, sig, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if sig > NSIG {
+        return Ok(Err(Errno::EINVAL))
+    }
+    if pid as i32 == unsafe { libc::getpid() } {
+        return Ok(queue_self(cpu, sig, SI_USER))
+    }
+    Ok(unsafe { syscall!(Sysno::kill, pid as i32, sig) })
+}
+
+/* A signal for thread `tid`, if that is one of the guest's. */
+fn queue_thread(cpu: &mut CPU, tid: i32, sig: usize) -> Option<SysResult> {
+    if tid == cpu.tid {
+        return Some(queue_self(cpu, sig, SI_TKILL))
+    }
+    let queue = cpu.process.thread(tid)?;
+    Some(match sig {
+        0 => Ok(0),
+        _ if sig > NSIG => Err(Errno::EINVAL),
+        _ => {
+            cpu.signals.send(tid, &queue, SigInfo::user(sig as i32, SI_TKILL));
+            Ok(0)
+        }
+    })
+}
+
+fn sys_tkill(cpu: &mut CPU, [tid, sig, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if let Some(res) = queue_thread(cpu, tid as i32, sig) {
+        return Ok(res)
+    }
+    Ok(unsafe { syscall!(Sysno::tkill, tid as i32, sig) })
+}
+
+fn sys_tgkill(cpu: &mut CPU, [tgid, tid, sig, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if tgid as i32 == unsafe { libc::getpid() } {
+        if let Some(res) = queue_thread(cpu, tid as i32, sig) {
+            return Ok(res)
+        }
+    }
+    Ok(unsafe { syscall!(Sysno::tgkill, tgid as i32, tid as i32, sig) })
+}
+
+fn sys_sigaltstack(cpu: &mut CPU, [ss, old_ss, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let mut old = cpu.signals.altstack;
+    if old.contains(cpu.get_reg(REG_SP)) {
+        old.flags |= SS_ONSTACK;
+    }
+    if ss != 0 {
+        let new = AltStack::from_bytes(try_errno!(cpu.memory.guest_slice(ss, GUEST_STACK_T_SIZE)));
+        if old.flags & SS_ONSTACK != 0 {
+            return Ok(Err(Errno::EPERM))
+        }
+        cpu.signals.altstack = match new.flags {
+            SS_DISABLE => AltStack::default(),
+            0 | SS_ONSTACK if new.size < MINSIGSTKSZ => return Ok(Err(Errno::ENOMEM)),
+            0 | SS_ONSTACK => AltStack { flags: 0, ..new },
+            _ => return Ok(Err(Errno::EINVAL))
+        };
+    }
+    if old_ss != 0 {
+        let buf = try_errno!(cpu.memory.guest_slice_mut(old_ss, GUEST_STACK_T_SIZE));
+        buf.copy_from_slice(&old.to_bytes());
+    }
+    Ok(Ok(0))
+}
+
+/* Always "fails" with EINTR, after the handler of the signal it waited for ran. */
+fn sys_rt_sigsuspend(cpu: &mut CPU, [mask, sigsetsize, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    if sigsetsize != SIGSET_SIZE {
+        return Ok(Err(Errno::EINVAL))
+    }
+    let mask = try_errno!(read_sigset(cpu, mask));
+    cpu.signals.suspend(mask);
+    Ok(Err(Errno::EINTR))
+}
+
+fn sys_rt_sigaction(cpu: &mut CPU, [sig, act, oact, sigsetsize, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    if sigsetsize != SIGSET_SIZE {
+        return Ok(Err(Errno::EINVAL))
+    }
+    let sig = sig as i32;
+    let mut old = try_errno!(cpu.signals.action(sig));
+    if act != 0 {
+        let act = try_errno!(cpu.memory.guest_slice(act, GUEST_SIGACTION_SIZE));
+        old = try_errno!(cpu.signals.set_action(sig, SigAction::from_bytes(act)));
+    }
+    if oact != 0 {
+        let buf = try_errno!(cpu.memory.guest_slice_mut(oact, GUEST_SIGACTION_SIZE));
+        buf.copy_from_slice(&old.to_bytes());
+    }
+    Ok(Ok(0))
+}
+
+const SIG_BLOCK: usize = 0;
+const SIG_UNBLOCK: usize = 1;
+const SIG_SETMASK: usize = 2;
+
+fn sys_rt_sigprocmask(cpu: &mut CPU, [how, set, oldset, sigsetsize, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    if sigsetsize != SIGSET_SIZE {
+        return Ok(Err(Errno::EINVAL))
+    }
+    let old = cpu.signals.blocked();
+    if set != 0 {
+        let set = try_errno!(read_sigset(cpu, set));
+        cpu.signals.set_blocked(match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Ok(Err(Errno::EINVAL))
+        });
+    }
+    if oldset != 0 {
+        try_errno!(write_sigset(cpu, oldset, old));
+    }
+    Ok(Ok(0))
+}
+
+fn sys_rt_sigpending(cpu: &mut CPU, [set, sigsetsize, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if sigsetsize != SIGSET_SIZE {
+        return Ok(Err(Errno::EINVAL))
+    }
+    let pending = cpu.signals.pending() & cpu.signals.blocked();
+    Ok(write_sigset(cpu, set, pending).map(|_| 0))
+}
+
+/* Returns the restored a0, so that dispatch() does not clobber it. */
+fn sys_rt_sigreturn(cpu: &mut CPU, _: [usize; 6]) -> Result<SysResult, Error> {
+    signals::sigreturn(cpu).map(|a0| Ok(a0 as usize))
+}
+
+/* Never fails, the guest notices that the break did not move. */
+fn sys_brk(cpu: &mut CPU, [addr, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(Ok(cpu.memory.mm().set_brk(addr)))
+}
+
+const MAP_SHARED: usize = 0x01;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+const MAP_FIXED_NOREPLACE: usize = 0x100000;
+const MREMAP_MAYMOVE: usize = 1;
+const MREMAP_FIXED: usize = 2;
+const MADV_DONTNEED: usize = 4;
+
+fn sys_mmap(cpu: &mut CPU, [addr, len, prot, flags, fd, offset]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let prot = (prot & 0b111) as u8;
+    /* Also true for MAP_SHARED_VALIDATE (0x3). */
+    let shared = flags & MAP_SHARED != 0;
+    let placement = if flags & MAP_FIXED_NOREPLACE != 0 {
+        Placement::FixedNoReplace(addr)
+    } else if flags & MAP_FIXED != 0 {
+        Placement::Fixed(addr)
+    } else {
+        Placement::Hint(addr)
+    };
+    if flags & MAP_ANONYMOUS != 0 {
+        return Ok(cpu.memory.mm().mmap(placement, len, prot, shared, None, 0))
+    }
+
+    /* File mappings are copies, so changes could not be written back. */
+    if shared && prot & PROT_WRITE != 0 {
+        return Ok(Err(Errno::ENODEV))
+    }
+    if !is_page_aligned(offset) || len == 0 {
+        return Ok(Err(Errno::EINVAL))
+    }
+    let host_fd = try_errno!(cpu.fds.host_fd(fd));
+    let name = std::fs::read_link(format!("/proc/self/fd/{}", host_fd)).ok()
+        .map(|path| path.to_string_lossy().into_owned());
+
+    /* Read straight into the new mapping (whatever its protection), it is zero-filled
+     * beyond the end of the file. */
+    let mut mm = cpu.memory.mm();
+    let addr = try_errno!(mm.mmap(placement, len, prot, shared, name, offset as u64));
+    let contents = cpu.memory.host_ptr().wrapping_add(addr);
+    let mut pos = 0;
+    while pos < len {
+        let res = unsafe {
+            syscall!(Sysno::pread64, host_fd, contents.add(pos), len - pos, offset + pos)
+        };
+        match res {
+            Ok(0) => break,
+            Ok(n) => pos += n,
+            Err(Errno::EINTR) => continue,
+            Err(errno) => {
+                mm.munmap(addr, len).ok();
+                return Ok(Err(errno))
+            }
+        }
+    }
+    Ok(Ok(addr))
+}
+
+fn sys_munmap(cpu: &mut CPU, [addr, len, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(cpu.memory.mm().munmap(addr, len).map(|_| 0))
+}
+
+fn sys_mprotect(cpu: &mut CPU, [addr, len, prot, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(cpu.memory.mm().mprotect(addr, len, (prot & 0b111) as u8).map(|_| 0))
+}
+
+fn sys_mremap(cpu: &mut CPU, [addr, old_len, new_len, flags, new_addr, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let fixed = (flags & MREMAP_FIXED != 0).then_some(new_addr);
+    Ok(cpu.memory.mm().mremap(addr, old_len, new_len, flags & MREMAP_MAYMOVE != 0, fixed))
+}
+
+/* Only MADV_DONTNEED changes what the guest sees, all other advice can be ignored. */
+fn sys_madvise(cpu: &mut CPU, [addr, len, advice, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    match advice {
+        MADV_DONTNEED => Ok(cpu.memory.mm().discard(addr, len).map(|_| 0)),
+        _ => Ok(Ok(0))
+    }
+}
+
+/* struct timespec and struct timeval of riscv64: Two 64 bit values. */
+fn read_timespec(cpu: &CPU, addr: usize) -> Result<(i64, i64), Errno> {
+    let buf = cpu.memory.guest_slice(addr, 16)?;
+    let sec = i64::from_le_bytes(buf[0..8].try_into().unwrap());
+    let nsec = i64::from_le_bytes(buf[8..16].try_into().unwrap());
+    if sec < 0 || !(0..1_000_000_000).contains(&nsec) {
+        return Err(Errno::EINVAL)
+    }
+    Ok((sec, nsec))
+}
+
+fn write_timespec(cpu: &mut CPU, addr: usize, (sec, nsec): (i64, i64)) -> Result<(), Errno> {
+    let buf = cpu.memory.guest_slice_mut(addr, 16)?;
+    buf[0..8].copy_from_slice(&sec.to_le_bytes());
+    buf[8..16].copy_from_slice(&nsec.to_le_bytes());
+    Ok(())
+}
+
+/* Clocks of other processes/threads (negative ids) only exist with the host clock. */
+fn check_virtual_clockid(clockid: usize) -> Result<(), Errno> {
+    match clockid {
+        0..=9 | CLOCK_TAI => Ok(()),
+        _ => Err(Errno::EINVAL)
+    }
+}
+
+fn clock_gettime(cpu: &CPU, clockid: usize) -> Result<(i64, i64), Errno> {
+    match cpu.clock.source {
+        ClockSource::Host => {
+            let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            unsafe { syscall!(Sysno::clock_gettime, clockid, &mut ts as *mut libc::timespec) }?;
+            Ok((ts.tv_sec, ts.tv_nsec))
+        },
+        ClockSource::Virtual => {
+            check_virtual_clockid(clockid)?;
+            Ok(cpu.clock.virtual_time(clockid, cpu.instret))
+        }
+    }
+}
+
+/* The host time of `clockid` when the virtual clock reaches `sec`/`nsec`, if it ran at
+ * wall-clock speed from now on. */
+fn host_deadline(cpu: &CPU, clockid: usize, sec: i64, nsec: i64) -> (i64, i64) {
+    let (now_sec, now_nsec) = cpu.clock.virtual_time(clockid, cpu.instret);
+    let left = (sec - now_sec) as i128 * 1_000_000_000 + (nsec - now_nsec) as i128;
+    let left = std::cmp::max(0, left);
+    let mut host = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(clockid as libc::clockid_t, &mut host) };
+    let deadline = host.tv_sec as i128 * 1_000_000_000 + host.tv_nsec as i128 + left;
+    ((deadline / 1_000_000_000) as i64, (deadline % 1_000_000_000) as i64)
+}
+
+fn sys_clock_gettime(cpu: &mut CPU, [clockid, tp, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(clock_gettime(cpu, clockid).and_then(|ts| write_timespec(cpu, tp, ts)).map(|_| 0))
+}
+
+fn sys_clock_getres(cpu: &mut CPU, [clockid, res, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let resolution = match cpu.clock.source {
+        ClockSource::Host => {
+            let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            unsafe { syscall!(Sysno::clock_getres, clockid, &mut ts as *mut libc::timespec) }
+                .map(|_| (ts.tv_sec, ts.tv_nsec))
+        },
+        ClockSource::Virtual => check_virtual_clockid(clockid)
+            .map(|_| (0, cpu.clock.virtual_resolution_ns()))
+    };
+    Ok(resolution.and_then(|ts| match res {
+        0 => Ok(0),
+        _ => write_timespec(cpu, res, ts).map(|_| 0)
+    }))
+}
+
+fn sys_gettimeofday(cpu: &mut CPU, [tv, tz, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let res = clock_gettime(cpu, CLOCK_REALTIME).and_then(|(sec, nsec)| {
+        if tv != 0 {
+            write_timespec(cpu, tv, (sec, nsec / 1000))?;
+        }
+        /* struct timezone is obsolete, always UTC. */
+        if tz != 0 {
+            cpu.memory.guest_slice_mut(tz, 8)?.fill(0);
+        }
+        Ok(0)
+    });
+    Ok(res)
+}
+
+/*
+ * With the virtual clock, sleeping just advances the clock of the calling thread (like its
+ * instret, it is per thread). With the host clock, the host thread of the caller sleeps
+ * and the other guest threads keep running.
+ */
+fn sys_clock_nanosleep(cpu: &mut CPU, [clockid, flags, req, rem, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let (sec, nsec) = try_errno!(read_timespec(cpu, req));
+    match cpu.clock.source {
+        ClockSource::Host => {
+            let req = libc::timespec { tv_sec: sec as _, tv_nsec: nsec as _ };
+            let mut left = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            let res = unsafe {
+                syscall!(Sysno::clock_nanosleep, clockid, flags,
+                         &req as *const libc::timespec, &mut left as *mut libc::timespec)
+            };
+            if res == Err(Errno::EINTR) && rem != 0 && flags & TIMER_ABSTIME == 0 {
+                write_timespec(cpu, rem, (left.tv_sec, left.tv_nsec)).ok();
+            }
+            Ok(res)
+        },
+        ClockSource::Virtual => {
+            try_errno!(check_virtual_clockid(clockid));
+            let duration = sec as u128 * 1_000_000_000 + nsec as u128;
+            let duration = if flags & TIMER_ABSTIME != 0 {
+                let (now_sec, now_nsec) = cpu.clock.virtual_time(clockid, cpu.instret);
+                duration.saturating_sub(now_sec as u128 * 1_000_000_000 + now_nsec as u128)
+            } else {
+                duration
+            };
+            cpu.clock.slept_ns += duration;
+            Ok(Ok(0))
+        }
+    }
+}
+
+fn sys_nanosleep(cpu: &mut CPU, [req, rem, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    sys_clock_nanosleep(cpu, [CLOCK_MONOTONIC, 0, req, rem, 0, 0])
+}
+
+/* struct tms of riscv64: Four 64 bit clock_t, in CLK_TCK ticks. */
+fn sys_times(cpu: &mut CPU, [buf, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let (ticks, tms) = match cpu.clock.source {
+        ClockSource::Host => {
+            let mut tms: libc::tms = unsafe { std::mem::zeroed() };
+            let ticks = try_errno!(unsafe { syscall!(Sysno::times, &mut tms as *mut libc::tms) });
+            (ticks, [tms.tms_utime, tms.tms_stime, tms.tms_cutime, tms.tms_cstime])
+        },
+        ClockSource::Virtual => {
+            let ticks = (cpu.clock.virtual_ns(cpu.instret) * CLK_TCK as u128 / 1_000_000_000) as i64;
+            (ticks as usize, [ticks, 0, 0, 0])
+        }
+    };
+    if buf != 0 {
+        let out = try_errno!(cpu.memory.guest_slice_mut(buf, 32));
+        for (i, t) in tms.iter().enumerate() {
+            out[(i * 8)..(i * 8 + 8)].copy_from_slice(&t.to_le_bytes());
+        }
+    }
+    Ok(Ok(ticks))
+}
+
+fn sys_getcwd(cpu: &mut CPU, [buf, size, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if let Some(mut cwd) = cpu.vfs.as_ref().map(|vfs| vfs.cwd()) {
+        cwd.push(0);
+        if size < cwd.len() {
+            return Ok(Err(Errno::ERANGE))
+        }
+        return Ok(cpu.memory.guest_slice_mut(buf, cwd.len()).map(|buf| {
+            buf.copy_from_slice(&cwd);
+            buf.len()
+        }))
+    }
+    Ok(cpu.memory.guest_slice_mut(buf, size)
+        .and_then(|buf| unsafe { syscall!(Sysno::getcwd, buf.as_mut_ptr(), buf.len()) }))
+}
+
+fn sys_mkdirat(cpu: &mut CPU, [dirfd, path, mode, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if let Some(vfs) = cpu.vfs.clone() {
+        return Ok(vfs_path(cpu, &vfs, dirfd, path).and_then(|path| vfs.mkdir(&path, mode))
+            .map(|_| 0))
+    }
+    let dirfd = try_errno!(host_dirfd(cpu, dirfd));
+    Ok(host_path(cpu, path)
+        .and_then(|path| unsafe { syscall!(Sysno::mkdirat, dirfd, path.as_ptr(), mode) }))
+}
+
+fn sys_unlinkat(cpu: &mut CPU, [dirfd, path, flags, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if let Some(vfs) = cpu.vfs.clone() {
+        return Ok(vfs_path(cpu, &vfs, dirfd, path).and_then(|path| vfs.unlink(&path, flags))
+            .map(|_| 0))
+    }
+    let dirfd = try_errno!(host_dirfd(cpu, dirfd));
+    Ok(host_path(cpu, path)
+        .and_then(|path| unsafe { syscall!(Sysno::unlinkat, dirfd, path.as_ptr(), flags) }))
+}
+
+/* The link target is stored as it is, it is not a path we access. */
+fn sys_symlinkat(cpu: &mut CPU, [target, dirfd, path, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if let Some(vfs) = cpu.vfs.clone() {
+        let target = try_errno!(cpu.memory.guest_cstr(target)).to_owned();
+        return Ok(vfs_path(cpu, &vfs, dirfd, path).and_then(|path| vfs.symlink(&target, &path))
+            .map(|_| 0))
+    }
+    let dirfd = try_errno!(host_dirfd(cpu, dirfd));
+    Ok(cpu.memory.guest_cstr(target).map(|target| target.to_owned())
+        .and_then(|target| Ok((target, host_path(cpu, path)?)))
+        .and_then(|(target, path)| unsafe {
+            syscall!(Sysno::symlinkat, target.as_ptr(), dirfd, path.as_ptr())
+        }))
+}
+
+fn sys_ftruncate(cpu: &mut CPU, [fd, len, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(unsafe { syscall!(Sysno::ftruncate, fd, len) })
+}
+
+fn sys_faccessat(cpu: &mut CPU, [dirfd, path, mode, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    sys_faccessat2(cpu, [dirfd, path, mode, 0, 0, 0])
+}
+
+fn sys_faccessat2(cpu: &mut CPU, [dirfd, path, mode, flags, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    if let Some(vfs) = cpu.vfs.clone() {
+        return Ok(vfs_path(cpu, &vfs, dirfd, path).and_then(|path| vfs.access(&path, mode, flags))
+            .map(|_| 0))
+    }
+    let dirfd = try_errno!(host_dirfd(cpu, dirfd));
+    Ok(host_path(cpu, path)
+        .and_then(|path| unsafe { syscall!(Sysno::faccessat2, dirfd, path.as_ptr(), mode, flags) }))
+}
+
+/* Changes the working directory of the simulator, like qemu-user does (or the VFS's). */
+fn sys_chdir(cpu: &mut CPU, [path, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if let Some(vfs) = cpu.vfs.clone() {
+        let at_fdcwd = AT_FDCWD as usize;
+        return Ok(vfs_path(cpu, &vfs, at_fdcwd, path).and_then(|path| vfs.chdir(&path)).map(|_| 0))
+    }
+    Ok(host_path(cpu, path).and_then(|path| unsafe { syscall!(Sysno::chdir, path.as_ptr()) }))
+}
+
+fn sys_fchdir(cpu: &mut CPU, [fd, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    if let Some(vfs) = cpu.vfs.clone() {
+        let opened = try_errno!(cpu.fds.opened(fd));
+        return Ok(opened.ok_or(Errno::ENOTDIR).and_then(|opened| vfs.chdir(&opened.path))
+            .map(|_| 0))
+    }
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(unsafe { syscall!(Sysno::fchdir, fd) })
+}
+
+/*
+ * struct linux_dirent64 (u64 d_ino, i64 d_off, u16 d_reclen, u8 d_type, char d_name[])
+ * has the same layout everywhere, so the host can fill the guest buffer directly.
+ */
+fn sys_getdents64(cpu: &mut CPU, [fd, dirp, count, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let node = try_errno!(cpu.fds.opened(fd)).and_then(|opened| opened.node.clone());
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    /* Directories from tar archives: The offset of their memfd is the position. */
+    if let Some(node) = node {
+        let pos = try_errno!(unsafe { syscall!(Sysno::lseek, fd, 0, libc::SEEK_CUR) });
+        let buf = try_errno!(cpu.memory.guest_slice_mut(dirp, count));
+        let (len, next) = try_errno!(vfs::dirents(&node, pos, buf));
+        return Ok(unsafe { syscall!(Sysno::lseek, fd, next, libc::SEEK_SET) }.map(|_| len))
+    }
+    Ok(cpu.memory.guest_slice_mut(dirp, count)
+        .and_then(|buf| unsafe { syscall!(Sysno::getdents64, fd, buf.as_mut_ptr(), buf.len()) }))
+}
+
+fn sys_lseek(cpu: &mut CPU, [fd, offset, whence, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(unsafe { syscall!(Sysno::lseek, fd, offset, whence) })
+}
+
+/* /proc/self/exe (or /proc/<pid>/exe of the guest itself) is the guest's executable. */
+fn is_proc_self_exe(path: &[u8]) -> bool {
+    let pid = format!("/proc/{}/exe", unsafe { libc::getpid() });
+    path == b"/proc/self/exe" || path == pid.as_bytes()
+}
+
+/* The target is truncated to the buffer, without a NUL (like readlink() does). */
+fn write_link(cpu: &mut CPU, buf: usize, size: usize, target: &[u8]) -> SysResult {
+    if size == 0 {
+        return Err(Errno::EINVAL)
+    }
+    let len = target.len().min(size);
+    cpu.memory.guest_slice_mut(buf, len).map(|buf| {
+        buf.copy_from_slice(&target[..len]);
+        len
+    })
+}
+
+fn sys_readlinkat(cpu: &mut CPU, [dirfd, path, buf, size, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let guest_path = try_errno!(cpu.memory.guest_cstr(path)).to_bytes();
+    if let Some(exe) = cpu.exe.as_ref().filter(|_| is_proc_self_exe(guest_path)) {
+        let exe = exe.as_os_str().as_bytes().to_vec();
+        return Ok(write_link(cpu, buf, size, &exe))
+    }
+    if let Some(vfs) = cpu.vfs.clone() {
+        let target = try_errno!(vfs_path(cpu, &vfs, dirfd, path)
+            .and_then(|path| vfs.readlink(&path)));
+        return Ok(write_link(cpu, buf, size, &target))
+    }
+    let dirfd = try_errno!(host_dirfd(cpu, dirfd));
+    let path = try_errno!(host_path(cpu, path));
+    Ok(cpu.memory.guest_slice_mut(buf, size)
+        .and_then(|buf| unsafe {
+            syscall!(Sysno::readlinkat, dirfd, path.as_ptr(), buf.as_mut_ptr(), buf.len())
+        }))
+}
+
+fn sys_fsync(cpu: &mut CPU, [fd, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(unsafe { syscall!(Sysno::fsync, fd) })
+}
+
+fn sys_fdatasync(cpu: &mut CPU, [fd, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(unsafe { syscall!(Sysno::fdatasync, fd) })
+}
+
+fn sys_renameat2(cpu: &mut CPU, [olddirfd, oldpath, newdirfd, newpath, flags, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    if let Some(vfs) = cpu.vfs.clone() {
+        let oldpath = try_errno!(vfs_path(cpu, &vfs, olddirfd, oldpath));
+        let newpath = try_errno!(vfs_path(cpu, &vfs, newdirfd, newpath));
+        return Ok(vfs.rename(&oldpath, &newpath, flags).map(|_| 0))
+    }
+    let olddirfd = try_errno!(host_dirfd(cpu, olddirfd));
+    let newdirfd = try_errno!(host_dirfd(cpu, newdirfd));
+    Ok(host_path(cpu, oldpath)
+        .and_then(|oldpath| Ok((oldpath, host_path(cpu, newpath)?)))
+        .and_then(|(oldpath, newpath)| unsafe {
+            syscall!(Sysno::renameat2, olddirfd, oldpath.as_ptr(), newdirfd, newpath.as_ptr(), flags)
+        }))
+}
+
+/* The host fds are always SOCK_CLOEXEC (which is O_CLOEXEC), like for openat(). */
+fn sys_socket(cpu: &mut CPU, [domain, kind, protocol, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    try_errno!(cpu.network.check_domain(domain));
+    Ok(unsafe { syscall!(Sysno::socket, domain, kind | O_CLOEXEC, protocol) }
+        .and_then(|fd| cpu.fds.insert(unsafe { OwnedFd::from_raw_fd(fd as i32) },
+                                      kind & O_CLOEXEC != 0)))
+}
+
+/*
+ * A sockaddr of the guest, translated for the host and checked against the policy. With
+ * a VFS, the paths of Unix sockets (not the abstract ones) are guest paths as well, see
+ * Vfs::socket_path(). The fd that comes with them has to stay open for the syscall.
+ */
+fn host_sockaddr(cpu: &CPU, addr: usize, len: usize, bind: bool)
+        -> Result<(Vec<u8>, Option<OwnedFd>), Errno> {
+    if len > net::SOCKADDR_MAX_SIZE {
+        return Err(Errno::EINVAL)
+    }
+    let addr = SockAddr::from_bytes(cpu.memory.guest_slice(addr, len)?)?;
+    match (&cpu.vfs, cpu.network.check_addr(addr, bind)?) {
+        (Some(vfs), SockAddr::Unix(path)) if path.first().is_some_and(|c| *c != 0) => {
+            let path = path.split(|c| *c == 0).next().unwrap();
+            let (fd, host) = vfs.socket_path(&vfs::normalize(&vfs.cwd(), path), bind)?;
+            Ok((SockAddr::Unix(host).to_bytes(), Some(fd)))
+        },
+        (_, addr) => Ok((addr.to_bytes(), None))
+    }
+}
+
+/*
+ * For the value-result sockaddrs of accept(), getsockname(), ...: Check that the guest
+ * can take up to *addrlen bytes and *addrlen itself before the syscall (afterwards, it
+ * cannot be undone).
+ */
+fn check_guest_sockaddr(cpu: &mut CPU, addr: usize, addrlen: usize) -> Result<(), Errno> {
+    if addr == 0 {
+        return Ok(())
+    }
+    let len = u32::from_le_bytes((&*cpu.memory.guest_slice_mut(addrlen, 4)?).try_into().unwrap());
+    if (len as i32) < 0 {
+        return Err(Errno::EINVAL)
+    }
+    cpu.memory.guest_slice_mut(addr, len as usize).map(|_| ())
+}
+
+/* The address is truncated to what fits, *addrlen is set to its real length. */
+fn write_guest_sockaddr(cpu: &mut CPU, addr: usize, addrlen: usize, host: &[u8])
+        -> Result<(), Errno> {
+    if addr == 0 {
+        return Ok(())
+    }
+    let bytes = SockAddr::from_bytes(host).map_or_else(|_| host.to_vec(), |addr| addr.to_bytes());
+    let buf = cpu.memory.guest_slice_mut(addrlen, 4)?;
+    let len = std::cmp::min(u32::from_le_bytes((&*buf).try_into().unwrap()) as usize, bytes.len());
+    buf.copy_from_slice(&(bytes.len() as u32).to_le_bytes());
+    cpu.memory.guest_slice_mut(addr, len)?.copy_from_slice(&bytes[..len]);
+    Ok(())
+}
+
+/*
+ * Under --network=loopback, bind an unbound INET socket to loopback before the host binds
+ * it to the wildcard address, see NetworkPolicy::loopback_bind(). `to` is the (host)
+ * destination address, if any.
+ */
+fn bind_loopback(cpu: &CPU, fd: usize, to: &[u8]) -> Result<(), Errno> {
+    if cpu.network == net::NetworkPolicy::Host {
+        return Ok(())
+    }
+    let mut local = [0u8; net::SOCKADDR_MAX_SIZE];
+    let mut len = local.len() as libc::socklen_t;
+    unsafe {
+        syscall!(Sysno::getsockname, fd, local.as_mut_ptr(), &mut len as *mut libc::socklen_t)
+    }?;
+    let Ok(local) = SockAddr::from_bytes(&local[..(len as usize)]) else { return Ok(()) };
+    match cpu.network.loopback_bind(&local, SockAddr::from_bytes(to).ok().as_ref()) {
+        Some(addr) => {
+            let addr = addr.to_bytes();
+            unsafe { syscall!(Sysno::bind, fd, addr.as_ptr(), addr.len()) }.map(|_| ())
+        },
+        None => Ok(())
+    }
+}
+
+fn sys_bind(cpu: &mut CPU, [fd, addr, addrlen, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(host_sockaddr(cpu, addr, addrlen, true)
+        .and_then(|(addr, _dir)| unsafe { syscall!(Sysno::bind, fd, addr.as_ptr(), addr.len()) }))
+}
+
+fn sys_connect(cpu: &mut CPU, [fd, addr, addrlen, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(host_sockaddr(cpu, addr, addrlen, false)
+        .and_then(|(addr, _sock)| unsafe {
+            syscall!(Sysno::connect, fd, addr.as_ptr(), addr.len())
+        }))
+}
+
+fn sys_listen(cpu: &mut CPU, [fd, backlog, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    try_errno!(bind_loopback(cpu, fd, &[]));
+    Ok(unsafe { syscall!(Sysno::listen, fd, backlog) })
+}
+
+fn sys_accept(cpu: &mut CPU, [fd, addr, addrlen, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    sys_accept4(cpu, [fd, addr, addrlen, 0, 0, 0])
+}
+
+fn sys_accept4(cpu: &mut CPU, [fd, addr, addrlen, flags, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    try_errno!(check_guest_sockaddr(cpu, addr, addrlen));
+    let mut host_addr = [0u8; net::SOCKADDR_MAX_SIZE];
+    let mut host_len = host_addr.len() as libc::socklen_t;
+    let newfd = try_errno!(unsafe {
+        syscall!(Sysno::accept4, fd, host_addr.as_mut_ptr(), &mut host_len as *mut libc::socklen_t,
+                 flags | O_CLOEXEC)
+    });
+    let newfd = unsafe { OwnedFd::from_raw_fd(newfd as i32) };
+    try_errno!(write_guest_sockaddr(cpu, addr, addrlen, &host_addr[..(host_len as usize)]));
+    Ok(cpu.fds.insert(newfd, flags & O_CLOEXEC != 0))
+}
+
+fn socket_name(cpu: &mut CPU, sysno: Sysno, [fd, addr, addrlen, ..]: [usize; 6]) -> SysResult {
+    let fd = cpu.fds.host_fd(fd)?;
+    check_guest_sockaddr(cpu, addr, addrlen)?;
+    let mut host_addr = [0u8; net::SOCKADDR_MAX_SIZE];
+    let mut host_len = host_addr.len() as libc::socklen_t;
+    unsafe {
+        syscall!(sysno, fd, host_addr.as_mut_ptr(), &mut host_len as *mut libc::socklen_t)
+    }?;
+    write_guest_sockaddr(cpu, addr, addrlen, &host_addr[..(host_len as usize)])?;
+    Ok(0)
+}
+
+fn sys_getsockname(cpu: &mut CPU, args: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(socket_name(cpu, Sysno::getsockname, args))
+}
+
+fn sys_getpeername(cpu: &mut CPU, args: [usize; 6]) -> Result<SysResult, Error> {
+    Ok(socket_name(cpu, Sysno::getpeername, args))
+}
+
+fn sys_sendto(cpu: &mut CPU, [fd, buf, len, flags, addr, addrlen]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    let (host_addr, _sock) = match addr {
+        0 => (Vec::new(), None),
+        _ => try_errno!(host_sockaddr(cpu, addr, addrlen, false))
+    };
+    try_errno!(bind_loopback(cpu, fd, &host_addr));
+    let addr_ptr = if addr == 0 { std::ptr::null() } else { host_addr.as_ptr() };
+    Ok(cpu.memory.guest_slice(buf, len).and_then(|buf| unsafe {
+        syscall!(Sysno::sendto, fd, buf.as_ptr(), buf.len(), flags, addr_ptr, host_addr.len())
+    }))
+}
+
+fn sys_recvfrom(cpu: &mut CPU, [fd, buf, len, flags, addr, addrlen]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    try_errno!(check_guest_sockaddr(cpu, addr, addrlen));
+    let mut host_addr = [0u8; net::SOCKADDR_MAX_SIZE];
+    let mut host_len = host_addr.len() as libc::socklen_t;
+    let (addr_ptr, len_ptr) = match addr {
+        0 => (std::ptr::null_mut(), std::ptr::null_mut()),
+        _ => (host_addr.as_mut_ptr(), &mut host_len as *mut libc::socklen_t)
+    };
+    let res = cpu.memory.guest_slice_mut(buf, len).and_then(|buf| unsafe {
+        syscall!(Sysno::recvfrom, fd, buf.as_mut_ptr(), buf.len(), flags, addr_ptr, len_ptr)
+    });
+    Ok(res.and_then(|received| {
+        write_guest_sockaddr(cpu, addr, addrlen, &host_addr[..(host_len as usize)])
+            .map(|_| received)
+    }))
+}
+
+/* Options are ints, struct timeval, struct linger, ..., which all look the same on the host. */
+fn sys_setsockopt(cpu: &mut CPU, [fd, level, name, optval, optlen, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(cpu.memory.guest_slice(optval, optlen).and_then(|optval| unsafe {
+        syscall!(Sysno::setsockopt, fd, level, name, optval.as_ptr(), optval.len())
+    }))
+}
+
+fn sys_getsockopt(cpu: &mut CPU, [fd, level, name, optval, optlen, ..]: [usize; 6])
+        -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    /* *optlen is written back, so it has to be writable before the syscall. */
+    let len = try_errno!(cpu.memory.guest_slice_mut(optlen, 4));
+    let mut len = u32::from_le_bytes((&*len).try_into().unwrap()) as libc::socklen_t;
+    let res = cpu.memory.guest_slice_mut(optval, len as usize).and_then(|optval| unsafe {
+        syscall!(Sysno::getsockopt, fd, level, name, optval.as_mut_ptr(),
+                 &mut len as *mut libc::socklen_t)
+    });
+    Ok(res.and_then(|res| {
+        cpu.memory.guest_slice_mut(optlen, 4)?.copy_from_slice(&len.to_le_bytes());
+        Ok(res)
+    }))
+}
+
+fn sys_shutdown(cpu: &mut CPU, [fd, how, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    Ok(unsafe { syscall!(Sysno::shutdown, fd, how) })
+}
+
+/*
+ * struct msghdr of riscv64: u64 msg_name, u32 msg_namelen (+ padding), u64 msg_iov,
+ * u64 msg_iovlen, u64 msg_control, u64 msg_controllen, i32 msg_flags (+ padding).
+ */
+const GUEST_MSGHDR_SIZE: usize = 56;
+const MSG_CMSG_CLOEXEC: usize = 0x40000000;
+
+struct GuestMsghdr {
+    name: usize,
+    namelen: usize,
+    iov: usize,
+    iovlen: usize,
+    control: usize,
+    controllen: usize,
+}
+
+fn read_guest_msghdr(cpu: &CPU, addr: usize) -> Result<GuestMsghdr, Errno> {
+    let buf = cpu.memory.guest_slice(addr, GUEST_MSGHDR_SIZE)?;
+    let field = |pos: usize| u64::from_le_bytes(buf[pos..(pos + 8)].try_into().unwrap()) as usize;
+    Ok(GuestMsghdr {
+        name: field(0),
+        namelen: u32::from_le_bytes(buf[8..12].try_into().unwrap()) as usize,
+        iov: field(16),
+        iovlen: field(24),
+        control: field(32),
+        controllen: field(40),
+    })
+}
+
+/* Fds passed with SCM_RIGHTS are guest fds, the host gets the corresponding host fds. */
+fn sys_sendmsg(cpu: &mut CPU, [fd, msg, flags, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    let msg = try_errno!(read_guest_msghdr(cpu, msg));
+    let (mut name, _sock) = match msg.name {
+        0 => (Vec::new(), None),
+        _ => try_errno!(host_sockaddr(cpu, msg.name, msg.namelen, false))
+    };
+    try_errno!(bind_loopback(cpu, fd, &name));
+    let mut iov = try_errno!(host_iovecs(cpu, msg.iov, msg.iovlen, false));
+    let mut control = try_errno!(cpu.memory.guest_slice(msg.control, msg.controllen)).to_vec();
+    try_errno!(net::map_scm_rights(&mut control,
+        |fd| cpu.fds.host_fd(fd as usize).map(|fd| fd as i32)));
+
+    let mut host_msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    if !name.is_empty() {
+        host_msg.msg_name = name.as_mut_ptr() as *mut libc::c_void;
+        host_msg.msg_namelen = name.len() as libc::socklen_t;
+    }
+    host_msg.msg_iov = iov.as_mut_ptr();
+    host_msg.msg_iovlen = iov.len() as _;
+    if !control.is_empty() {
+        host_msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        host_msg.msg_controllen = control.len() as _;
+    }
+    Ok(unsafe { syscall!(Sysno::sendmsg, fd, &host_msg as *const libc::msghdr, flags) })
+}
+
+/* Received fds are added to the guest's fd table, with O_CLOEXEC for MSG_CMSG_CLOEXEC. */
+fn sys_recvmsg(cpu: &mut CPU, [fd, msg_addr, flags, ..]: [usize; 6]) -> Result<SysResult, Error> {
+    let fd = try_errno!(cpu.fds.host_fd(fd));
+    let msg = try_errno!(read_guest_msghdr(cpu, msg_addr));
+    try_errno!(cpu.memory.guest_slice_mut(msg_addr, GUEST_MSGHDR_SIZE));
+    if msg.name != 0 {
+        try_errno!(cpu.memory.guest_slice_mut(msg.name, msg.namelen));
+    }
+    try_errno!(cpu.memory.guest_slice_mut(msg.control, msg.controllen));
+    let mut iov = try_errno!(host_iovecs(cpu, msg.iov, msg.iovlen, true));
+    let mut name = [0u8; net::SOCKADDR_MAX_SIZE];
+    let mut control = vec![0u8; msg.controllen];
+
+    let mut host_msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    if msg.name != 0 {
+        host_msg.msg_name = name.as_mut_ptr() as *mut libc::c_void;
+        host_msg.msg_namelen = name.len() as libc::socklen_t;
+    }
+    host_msg.msg_iov = iov.as_mut_ptr();
+    host_msg.msg_iovlen = iov.len() as _;
+    if !control.is_empty() {
+        host_msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        host_msg.msg_controllen = control.len() as _;
+    }
+    let res = unsafe {
+        syscall!(Sysno::recvmsg, fd, &mut host_msg as *mut libc::msghdr, flags | MSG_CMSG_CLOEXEC)
+    };
+    let Ok(n) = res else {
+        return Ok(res)
+    };
+
+    let cloexec = flags & MSG_CMSG_CLOEXEC != 0;
+    let control = &mut control[..(host_msg.msg_controllen as usize)];
+    net::map_scm_rights(control, |host_fd| {
+        /* If the guest has too many fds, the ones that do not fit are closed. */
+        let host_fd = unsafe { OwnedFd::from_raw_fd(host_fd) };
+        Ok(cpu.fds.insert(host_fd, cloexec).map_or(-1, |fd| fd as i32))
+    }).unwrap();
+    cpu.memory.guest_slice_mut(msg.control, control.len()).unwrap().copy_from_slice(control);
+    let mut namelen = 0;
+    if msg.name != 0 {
+        let name = &name[..(host_msg.msg_namelen as usize)];
+        let bytes = SockAddr::from_bytes(name).map_or_else(|_| name.to_vec(), |addr| addr.to_bytes());
+        namelen = bytes.len();
+        let len = std::cmp::min(msg.namelen, bytes.len());
+        cpu.memory.guest_slice_mut(msg.name, len).unwrap().copy_from_slice(&bytes[..len]);
+    }
+    let buf = cpu.memory.guest_slice_mut(msg_addr, GUEST_MSGHDR_SIZE).unwrap();
+    buf[8..12].copy_from_slice(&(namelen as u32).to_le_bytes());
+    buf[40..48].copy_from_slice(&(control.len() as u64).to_le_bytes());
+    buf[48..52].copy_from_slice(&host_msg.msg_flags.to_le_bytes());
+    Ok(Ok(n))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    /* Compare the guest's struct stat at `addr` with what the host says about `path`. */
+    fn check_stat(cpu: &CPU, addr: usize, path: &str) {
+        let meta = std::fs::metadata(path).unwrap();
+        let mem = &cpu.memory;
+        assert_eq!(mem.load_u64(addr).unwrap(), meta.dev());
+        assert_eq!(mem.load_u64(addr + 8).unwrap(), meta.ino());
+        assert_eq!(mem.load_u32(addr + 16).unwrap(), meta.mode());
+        assert_eq!(mem.load_u32(addr + 20).unwrap() as u64, meta.nlink());
+        assert_eq!(mem.load_u32(addr + 24).unwrap(), meta.uid());
+        assert_eq!(mem.load_u32(addr + 28).unwrap(), meta.gid());
+        assert_eq!(mem.load_u64(addr + 32).unwrap(), meta.rdev());
+        assert_eq!(mem.load_u64(addr + 48).unwrap(), meta.size());
+        assert_eq!(mem.load_u32(addr + 56).unwrap() as u64, meta.blksize());
+        assert_eq!(mem.load_u64(addr + 64).unwrap(), meta.blocks());
+        assert_eq!(mem.load_u64(addr + 88).unwrap() as i64, meta.mtime());
+        assert_eq!(mem.load_u64(addr + 96).unwrap() as i64, meta.mtime_nsec());
+        assert_eq!(mem.load_u64(addr + 104).unwrap() as i64, meta.ctime());
+        assert_eq!(mem.load_u64(addr + 112).unwrap() as i64, meta.ctime_nsec());
+    }
+
+    fn test_cpu(path: &str) -> (CPU, usize, usize) {
+        let mut cpu = CPU::new(false);
+        let stack = cpu.memory.layout.stack();
+        let (path_addr, buf_addr) = (stack.start, stack.start + 4096);
+        put_cstr(&mut cpu, path_addr, path);
+        (cpu, path_addr, buf_addr)
+    }
+
+    fn put_cstr(cpu: &mut CPU, addr: usize, s: &str) {
+        cpu.memory.copy_bulk(addr as u64, s.as_bytes()).unwrap();
+        cpu.memory.copy_bulk((addr + s.len()) as u64, b"\0").unwrap();
+    }
+
+    #[test]
+    fn stat() {
+        let path = env!("CARGO_MANIFEST_DIR").to_string() + "/Cargo.toml";
+        let (mut cpu, path_addr, buf_addr) = test_cpu(&path);
+
+        let at_fdcwd = -100i64 as usize;
+        let res = sys_newfstatat(&mut cpu, [at_fdcwd, path_addr, buf_addr, 0, 0, 0]).unwrap();
+        assert_eq!(res, Ok(0));
+        check_stat(&cpu, buf_addr, &path);
+
+        let fd = cpu.fds.insert(std::fs::File::open(&path).unwrap().into(), false).unwrap();
+        cpu.memory.zero_bulk(buf_addr, GUEST_STAT_SIZE).unwrap();
+        let res = sys_fstat(&mut cpu, [fd, buf_addr, 0, 0, 0, 0]).unwrap();
+        assert_eq!(res, Ok(0));
+        check_stat(&cpu, buf_addr, &path);
+
+        let res = sys_fstat(&mut cpu, [-1i64 as usize, buf_addr, 0, 0, 0, 0]).unwrap();
+        assert_eq!(res, Err(Errno::EBADF));
+    }
+
+    #[test]
+    fn statx() {
+        let path = env!("CARGO_MANIFEST_DIR").to_string() + "/Cargo.toml";
+        let (mut cpu, path_addr, buf_addr) = test_cpu(&path);
+        let meta = std::fs::metadata(&path).unwrap();
+
+        let at_fdcwd = -100i64 as usize;
+        let args = [at_fdcwd, path_addr, 0, libc::STATX_BASIC_STATS as usize, buf_addr, 0];
+        assert_eq!(sys_statx(&mut cpu, args).unwrap(), Ok(0));
+        let mem = &cpu.memory;
+        assert_eq!(mem.load_u16(buf_addr + 28).unwrap() as u32, meta.mode());
+        assert_eq!(mem.load_u64(buf_addr + 32).unwrap(), meta.ino());
+        assert_eq!(mem.load_u64(buf_addr + 40).unwrap(), meta.size());
+        assert_eq!(mem.load_u64(buf_addr + 112).unwrap() as i64, meta.mtime());
+    }
+
+    #[test]
+    fn file_syscalls() {
+        const AT_FDCWD: usize = -100i64 as usize;
+        let dir = std::env::temp_dir().join(format!("simrv64i-test-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let (mut cpu, dir_addr, buf_addr) = test_cpu(dir);
+        let (file_addr, link_addr, renamed_addr) = (buf_addr + 256, buf_addr + 512, buf_addr + 768);
+        let dents_addr = buf_addr + 1024;
+        put_cstr(&mut cpu, file_addr, &format!("{}/file", dir));
+        put_cstr(&mut cpu, link_addr, &format!("{}/link", dir));
+        put_cstr(&mut cpu, renamed_addr, &format!("{}/renamed", dir));
+
+        assert_eq!(sys_mkdirat(&mut cpu, [AT_FDCWD, dir_addr, 0o755, 0, 0, 0]).unwrap(), Ok(0));
+        let flags = (libc::O_CREAT | libc::O_WRONLY) as usize;
+        let fd = sys_openat(&mut cpu, [AT_FDCWD, file_addr, flags, 0o644, 0, 0]).unwrap().unwrap();
+        assert_eq!(sys_ftruncate(&mut cpu, [fd, 1234, 0, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(sys_lseek(&mut cpu, [fd, 0, libc::SEEK_END as usize, 0, 0, 0]).unwrap(), Ok(1234));
+        assert_eq!(sys_fsync(&mut cpu, [fd, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        let dupped = sys_dup(&mut cpu, [fd, 0, 0, 0, 0, 0]).unwrap().unwrap();
+        assert_eq!(sys_close(&mut cpu, [fd, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(sys_close(&mut cpu, [fd, 0, 0, 0, 0, 0]).unwrap(), Err(Errno::EBADF));
+        assert_eq!(sys_lseek(&mut cpu, [dupped, 0, libc::SEEK_CUR as usize, 0, 0, 0]).unwrap(), Ok(1234));
+        assert_eq!(sys_dup3(&mut cpu, [dupped, dupped, 0, 0, 0, 0]).unwrap(), Err(Errno::EINVAL));
+        assert_eq!(sys_dup3(&mut cpu, [dupped, 1, O_CLOEXEC, 0, 0, 0]).unwrap(), Ok(1));
+        assert_eq!(sys_close(&mut cpu, [dupped, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(sys_close(&mut cpu, [1, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+
+        assert_eq!(sys_faccessat(&mut cpu, [AT_FDCWD, file_addr, libc::R_OK as usize, 0, 0, 0])
+            .unwrap(), Ok(0));
+        assert_eq!(sys_symlinkat(&mut cpu, [file_addr, AT_FDCWD, link_addr, 0, 0, 0]).unwrap(), Ok(0));
+        let len = sys_readlinkat(&mut cpu, [AT_FDCWD, link_addr, buf_addr, 256, 0, 0]).unwrap();
+        assert_eq!(cpu.memory.guest_slice(buf_addr, len.unwrap()).unwrap(),
+            format!("{}/file", dir).as_bytes());
+        assert_eq!(sys_renameat2(&mut cpu, [AT_FDCWD, file_addr, AT_FDCWD, renamed_addr, 0, 0])
+            .unwrap(), Ok(0));
+        assert_eq!(sys_faccessat(&mut cpu, [AT_FDCWD, file_addr, 0, 0, 0, 0]).unwrap(),
+            Err(Errno::ENOENT));
+
+        /* Walk the directory, the names are at offset 19 of each struct linux_dirent64. */
+        let flags = (libc::O_RDONLY | libc::O_DIRECTORY) as usize;
+        let fd = sys_openat(&mut cpu, [AT_FDCWD, dir_addr, flags, 0, 0, 0]).unwrap().unwrap();
+        let len = sys_getdents64(&mut cpu, [fd, dents_addr, 4096, 0, 0, 0]).unwrap().unwrap();
+        let mut names = Vec::new();
+        let mut pos = dents_addr;
+        while pos < dents_addr + len {
+            let reclen = cpu.memory.load_u16(pos + 16).unwrap() as usize;
+            let name = cpu.memory.guest_cstr(pos + 19).unwrap();
+            names.push(name.to_str().unwrap().to_string());
+            pos += reclen;
+        }
+        names.sort();
+        assert_eq!(names, vec![".", "..", "link", "renamed"]);
+        assert_eq!(sys_close(&mut cpu, [fd, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+
+        assert_eq!(sys_unlinkat(&mut cpu, [AT_FDCWD, link_addr, 0, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(sys_unlinkat(&mut cpu, [AT_FDCWD, renamed_addr, 0, 0, 0, 0]).unwrap(), Ok(0));
+        let at_removedir = libc::AT_REMOVEDIR as usize;
+        assert_eq!(sys_unlinkat(&mut cpu, [AT_FDCWD, dir_addr, at_removedir, 0, 0, 0]).unwrap(),
+            Ok(0));
+
+        let len = sys_getcwd(&mut cpu, [buf_addr, 4096, 0, 0, 0, 0]).unwrap().unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(cpu.memory.guest_slice(buf_addr, len - 1).unwrap(),
+            cwd.to_str().unwrap().as_bytes());
+    }
+
+    /* With mounts, paths go through the VFS, and files from tar archives look like files. */
+    #[test]
+    fn vfs_syscalls() {
+        const AT_FDCWD: usize = -100i64 as usize;
+        let dir = std::env::temp_dir().join(format!("simrv64i-vfs-sys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("tree.tar");
+        std::fs::write(&archive, tar::test::archive()).unwrap();
+        let mounts = [
+            vfs::parse_mount(&format!("/work={}:rw", dir.display())).unwrap(),
+            vfs::parse_mount_tar(&format!("/tree={}", archive.display())).unwrap(),
+        ];
+        let (mut cpu, dir_addr, buf_addr) = test_cpu("/tree/dir");
+        cpu.vfs = Some(Arc::new(Vfs::new(&mounts).unwrap()));
+        let name_addr = buf_addr + 2048;
+
+        assert_eq!(sys_chdir(&mut cpu, [dir_addr, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        let len = sys_getcwd(&mut cpu, [buf_addr, 256, 0, 0, 0, 0]).unwrap().unwrap();
+        assert_eq!(cpu.memory.guest_slice(buf_addr, len).unwrap(), b"/tree/dir\0");
+        assert_eq!(sys_getcwd(&mut cpu, [buf_addr, 4, 0, 0, 0, 0]).unwrap(), Err(Errno::ERANGE));
+
+        /* Relative to the working directory, and fstat() the way glibc does it. */
+        put_cstr(&mut cpu, name_addr, "rel");
+        let fd = sys_openat(&mut cpu, [AT_FDCWD, name_addr, 0, 0, 0, 0]).unwrap().unwrap();
+        assert_eq!(sys_read(&mut cpu, [fd, buf_addr, 64, 0, 0, 0]).unwrap(), Ok(11));
+        assert_eq!(cpu.memory.guest_slice(buf_addr, 11).unwrap(), b"hello, tar\n");
+        put_cstr(&mut cpu, name_addr, "");
+        let args = [fd, name_addr, buf_addr, vfs::AT_EMPTY_PATH, 0, 0];
+        assert_eq!(sys_newfstatat(&mut cpu, args).unwrap(), Ok(0));
+        assert_eq!(cpu.memory.load_u32(buf_addr + 16).unwrap(), libc::S_IFREG | 0o644);
+        assert_eq!(cpu.memory.load_u64(buf_addr + 48).unwrap(), 11);
+
+        let flags = vfs::O_DIRECTORY;
+        let dirfd = sys_openat(&mut cpu, [AT_FDCWD, dir_addr, flags, 0, 0, 0]).unwrap().unwrap();
+        let mut names = Vec::new();
+        loop {
+            let len = sys_getdents64(&mut cpu, [dirfd, buf_addr, 512, 0, 0, 0]).unwrap().unwrap();
+            let mut pos = buf_addr;
+            while pos < buf_addr + len {
+                names.push(cpu.memory.guest_cstr(pos + 19).unwrap().to_bytes().to_vec());
+                pos += cpu.memory.load_u16(pos + 16).unwrap() as usize;
+            }
+            if len == 0 {
+                break
+            }
+        }
+        assert_eq!(names.len(), 10);
+        assert!(names.starts_with(&[b".".to_vec(), b"..".to_vec(), b"abs".to_vec()]));
+
+        /* Relative to a directory fd, the archive is read-only. */
+        put_cstr(&mut cpu, name_addr, "abs");
+        let len = sys_readlinkat(&mut cpu, [dirfd, name_addr, buf_addr, 64, 0, 0]).unwrap();
+        assert_eq!(cpu.memory.guest_slice(buf_addr, len.unwrap()).unwrap(), b"/dir/file.txt");
+        put_cstr(&mut cpu, name_addr, "new");
+        assert_eq!(sys_mkdirat(&mut cpu, [dirfd, name_addr, 0o755, 0, 0, 0]).unwrap(),
+            Err(Errno::EROFS));
+
+        put_cstr(&mut cpu, name_addr, "/etc/passwd");
+        assert_eq!(sys_openat(&mut cpu, [AT_FDCWD, name_addr, 0, 0, 0, 0]).unwrap(),
+            Err(Errno::ENOENT));
+        put_cstr(&mut cpu, name_addr, "../../work/out");
+        let flags = vfs::O_CREAT | 1;
+        let fd = sys_openat(&mut cpu, [AT_FDCWD, name_addr, flags, 0o644, 0, 0]).unwrap().unwrap();
+        assert_eq!(sys_write(&mut cpu, [fd, dir_addr, 5, 0, 0, 0]).unwrap(), Ok(5));
+        assert_eq!(std::fs::read(dir.join("out")).unwrap(), b"/tree");
+
+        /* Unix sockets only in writable mounts, not anywhere else on the host. */
+        let outside = std::env::temp_dir().join(format!("simrv64i-outside-{}", std::process::id()));
+        let _listener = std::os::unix::net::UnixListener::bind(&outside).unwrap();
+        let (af_unix, sock_stream) = (libc::AF_UNIX as usize, libc::SOCK_STREAM as usize);
+        let connect = |cpu: &mut CPU, path: &str, bind: bool| {
+            let addr = SockAddr::Unix(path.as_bytes().to_vec()).to_bytes();
+            cpu.memory.copy_bulk(name_addr as u64, &addr).unwrap();
+            let sock = sys_socket(cpu, [af_unix, sock_stream, 0, 0, 0, 0]).unwrap().unwrap();
+            let args = [sock, name_addr, addr.len(), 0, 0, 0];
+            match bind {
+                true => sys_bind(cpu, args).unwrap()
+                    .and_then(|_| sys_listen(cpu, [sock, 1, 0, 0, 0, 0]).unwrap()),
+                false => sys_connect(cpu, args).unwrap()
+            }
+        };
+        assert_eq!(connect(&mut cpu, "/work/sock", true), Ok(0));
+        assert!(dir.join("sock").exists());
+        assert_eq!(connect(&mut cpu, "../../work/sock", false), Ok(0));
+        assert_eq!(connect(&mut cpu, outside.to_str().unwrap(), false), Err(Errno::EACCES));
+        assert_eq!(connect(&mut cpu, "/tree/sock", true), Err(Errno::EROFS));
+        std::fs::remove_file(&outside).unwrap();
+
+        /* The policy applies to all syscalls that go through dispatch(). */
+        cpu.syscalls.policy = Policy::new(vec!["%file=deny".parse().unwrap()]);
+        cpu.set_reg(REG_A7, SYS_OPENAT);
+        for (reg, val) in [(REG_A0, AT_FDCWD), (REG_A1, name_addr), (REG_A2, 0)] {
+            cpu.set_reg(reg, val as u64);
+        }
+        dispatch(&mut cpu).unwrap();
+        assert_eq!(cpu.get_reg(REG_A0) as i64, -libc::EPERM as i64);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mmap_file() {
+        let path = env!("CARGO_MANIFEST_DIR").to_string() + "/Cargo.toml";
+        let (mut cpu, _, _) = test_cpu(&path);
+        let contents = std::fs::read(&path).unwrap();
+
+        let fd = cpu.fds.insert(std::fs::File::open(&path).unwrap().into(), false).unwrap();
+        let (prot, flags) = ((libc::PROT_READ) as usize, libc::MAP_PRIVATE as usize);
+        let args = [0, 0x2000, prot, flags, fd, 0];
+        let addr = sys_mmap(&mut cpu, args).unwrap().unwrap();
+        assert_eq!(cpu.memory.guest_slice(addr, contents.len()).unwrap(), contents.as_slice());
+        assert_eq!(cpu.memory.load_u8(addr + contents.len()).unwrap(), 0);
+        assert_eq!(cpu.memory.mm().vmas.find(addr).unwrap().name.as_deref(), Some(path.as_str()));
+
+        let flags = libc::MAP_SHARED as usize;
+        let args = [0, 0x1000, (libc::PROT_READ | libc::PROT_WRITE) as usize, flags, fd, 0];
+        assert_eq!(sys_mmap(&mut cpu, args).unwrap(), Err(Errno::ENODEV));
+
+        /* Larger than the guest memory, nothing is allocated for it on the host. */
+        let flags = libc::MAP_PRIVATE as usize;
+        for len in [1 << 40, usize::MAX] {
+            let args = [0, len, prot, flags, fd, 0];
+            assert_eq!(sys_mmap(&mut cpu, args).unwrap(), Err(Errno::ENOMEM));
+        }
+    }
+
+    #[test]
+    fn io_syscalls() {
+        let (mut cpu, fds_addr, buf_addr) = test_cpu("");
+        let iov_addr = buf_addr + 2048;
+        let put_iovecs = |cpu: &mut CPU, iovecs: &[(usize, usize)]| {
+            for (i, (base, len)) in iovecs.iter().enumerate() {
+                cpu.memory.store_u64(iov_addr + i * 16, *base as u64).unwrap();
+                cpu.memory.store_u64(iov_addr + i * 16 + 8, *len as u64).unwrap();
+            }
+        };
+
+        let flags = O_CLOEXEC | libc::O_NONBLOCK as usize;
+        assert_eq!(sys_pipe2(&mut cpu, [fds_addr, flags, 0, 0, 0, 0]).unwrap(), Ok(0));
+        let (r, w) = (cpu.memory.load_u32(fds_addr).unwrap() as usize,
+                      cpu.memory.load_u32(fds_addr + 4).unwrap() as usize);
+        assert_eq!((r, w), (3, 4));
+        assert_eq!(sys_fcntl(&mut cpu, [r, F_GETFD, 0, 0, 0, 0]).unwrap(), Ok(FD_CLOEXEC));
+        assert_eq!(sys_fcntl(&mut cpu, [r, F_SETFD, 0, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(sys_fcntl(&mut cpu, [r, F_GETFD, 0, 0, 0, 0]).unwrap(), Ok(0));
+        let fl = sys_fcntl(&mut cpu, [r, F_GETFL, 0, 0, 0, 0]).unwrap().unwrap();
+        assert!(fl & libc::O_NONBLOCK as usize != 0);
+        assert_eq!(sys_fcntl(&mut cpu, [r, F_DUPFD, 10, 0, 0, 0]).unwrap(), Ok(10));
+        assert_eq!(sys_fcntl(&mut cpu, [r, 1234, 0, 0, 0, 0]).unwrap(), Err(Errno::EINVAL));
+
+        cpu.memory.copy_bulk(buf_addr as u64, b"Hello, World!").unwrap();
+        put_iovecs(&mut cpu, &[(buf_addr, 7), (buf_addr + 7, 0), (buf_addr + 7, 6)]);
+        assert_eq!(sys_writev(&mut cpu, [w, iov_addr, 3, 0, 0, 0]).unwrap(), Ok(13));
+        put_iovecs(&mut cpu, &[(buf_addr + 100, 5), (buf_addr + 200, 100)]);
+        assert_eq!(sys_readv(&mut cpu, [10, iov_addr, 2, 0, 0, 0]).unwrap(), Ok(13));
+        assert_eq!(cpu.memory.guest_slice(buf_addr + 100, 5).unwrap(), b"Hello");
+        assert_eq!(cpu.memory.guest_slice(buf_addr + 200, 8).unwrap(), b", World!");
+        assert_eq!(sys_readv(&mut cpu, [r, iov_addr, 2, 0, 0, 0]).unwrap(), Err(Errno::EAGAIN));
+
+        /* Buffers are checked against guest memory, read-only memory cannot be read into. */
+        put_iovecs(&mut cpu, &[(buf_addr, 4), (0x10, 4)]);
+        assert_eq!(sys_writev(&mut cpu, [w, iov_addr, 2, 0, 0, 0]).unwrap(), Err(Errno::EFAULT));
+        assert_eq!(sys_writev(&mut cpu, [w, iov_addr, IOV_MAX + 1, 0, 0, 0]).unwrap(),
+                   Err(Errno::EINVAL));
+        put_iovecs(&mut cpu, &[(buf_addr, 4)]);
+        cpu.memory.mm().mprotect(buf_addr, PAGE_SIZE, PROT_READ).unwrap();
+        assert_eq!(sys_readv(&mut cpu, [r, iov_addr, 1, 0, 0, 0]).unwrap(), Err(Errno::EFAULT));
+
+        let args = [r, TCGETS, iov_addr, 0, 0, 0];
+        assert_eq!(sys_ioctl(&mut cpu, args).unwrap(), Err(Errno::ENOTTY));
+        assert_eq!(sys_ioctl(&mut cpu, [r, 0x1234, 0, 0, 0, 0]).unwrap(), Err(Errno::ENOTTY));
+        assert_eq!(sys_ioctl(&mut cpu, [r, FIONREAD, fds_addr, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(cpu.memory.load_u32(fds_addr).unwrap(), 0);
+    }
+
+    #[test]
+    fn multiplexing() {
+        let (mut cpu, pollfds, buf_addr) = test_cpu("");
+        let efd = sys_eventfd2(&mut cpu, [0, O_CLOEXEC, 0, 0, 0, 0]).unwrap().unwrap();
+        assert_eq!(sys_pipe2(&mut cpu, [buf_addr, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        let r = cpu.memory.load_u32(buf_addr).unwrap() as usize;
+        assert_eq!((efd, r), (3, 4));
+        cpu.memory.store_u64(buf_addr, 1).unwrap();
+        assert_eq!(sys_write(&mut cpu, [efd, buf_addr, 8, 0, 0, 0]).unwrap(), Ok(8));
+
+        /* ppoll(): The eventfd is readable, the pipe is not, fd 100 is not open. */
+        for (i, fd) in [efd as i32, r as i32, 100, -1].iter().enumerate() {
+            cpu.memory.store_u32(pollfds + i * 8, *fd as u32).unwrap();
+            cpu.memory.store_u16(pollfds + i * 8 + 4, libc::POLLIN as u16).unwrap();
+            cpu.memory.store_u16(pollfds + i * 8 + 6, 0xffff).unwrap();
+        }
+        write_timespec(&mut cpu, buf_addr, (1, 0)).unwrap();
+        assert_eq!(sys_ppoll(&mut cpu, [pollfds, 4, buf_addr, 0, 0, 0]).unwrap(), Ok(2));
+        let revents = (0..4).map(|i| cpu.memory.load_u16(pollfds + i * 8 + 6).unwrap() as i16)
+            .collect::<Vec<_>>();
+        assert_eq!(revents, vec![libc::POLLIN, 0, POLLNVAL, 0]);
+        cpu.memory.mm().mprotect(pollfds, PAGE_SIZE, PROT_READ).unwrap();
+        let args = [pollfds, 4, buf_addr, 0, 0, 0];
+        assert_eq!(sys_ppoll(&mut cpu, args).unwrap(), Err(Errno::EFAULT));
+        cpu.memory.mm().mprotect(pollfds, PAGE_SIZE, PROT_READ | PROT_WRITE).unwrap();
+
+        /* pselect6(): Only the eventfd is readable, an fd that is not open is an error. */
+        cpu.memory.store_u64(buf_addr, (1 << efd) | (1 << r)).unwrap();
+        write_timespec(&mut cpu, buf_addr + 8, (0, 1000)).unwrap();
+        assert_eq!(sys_pselect6(&mut cpu, [r + 1, buf_addr, 0, 0, buf_addr + 8, 0]).unwrap(), Ok(1));
+        assert_eq!(cpu.memory.load_u64(buf_addr).unwrap(), 1 << efd);
+        cpu.memory.store_u64(buf_addr, 1 << 10).unwrap();
+        assert_eq!(sys_pselect6(&mut cpu, [11, buf_addr, 0, 0, 0, 0]).unwrap(), Err(Errno::EBADF));
+
+        /* epoll: The guest gets its own data back, in the riscv64 layout. */
+        let epfd = sys_epoll_create1(&mut cpu, [0, 0, 0, 0, 0, 0]).unwrap().unwrap();
+        cpu.memory.store_u32(buf_addr, libc::EPOLLIN as u32).unwrap();
+        cpu.memory.store_u64(buf_addr + 8, 0xdeadbeef12345678).unwrap();
+        let args = [epfd, libc::EPOLL_CTL_ADD as usize, efd, buf_addr, 0, 0];
+        assert_eq!(sys_epoll_ctl(&mut cpu, args).unwrap(), Ok(0));
+        let args = [epfd, libc::EPOLL_CTL_ADD as usize, r, buf_addr, 0, 0];
+        assert_eq!(sys_epoll_ctl(&mut cpu, args).unwrap(), Ok(0));
+        cpu.memory.zero_bulk(buf_addr, 64).unwrap();
+        let args = [epfd, buf_addr, 4, 0, 0, 0];
+        assert_eq!(sys_epoll_pwait(&mut cpu, args).unwrap(), Ok(1));
+        assert_eq!(cpu.memory.load_u32(buf_addr).unwrap(), libc::EPOLLIN as u32);
+        assert_eq!(cpu.memory.load_u64(buf_addr + 8).unwrap(), 0xdeadbeef12345678);
+        let args = [epfd, buf_addr, 4, 0, buf_addr + 32, 16];
+        assert_eq!(sys_epoll_pwait(&mut cpu, args).unwrap(), Err(Errno::EINVAL));
+
+        /* With the virtual clock, timing out takes the whole timeout. */
+        cpu.clock.source = ClockSource::Virtual;
+        let args = [epfd, libc::EPOLL_CTL_DEL as usize, efd, 0, 0, 0];
+        assert_eq!(sys_epoll_ctl(&mut cpu, args).unwrap(), Ok(0));
+        assert_eq!(sys_epoll_pwait(&mut cpu, [epfd, buf_addr, 4, 5, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(cpu.clock.slept_ns, 5_000_000);
+    }
+
+    /* The time that was left is written back, fds closed in the meantime are POLLNVAL. */
+    #[test]
+    fn multiplexing_timeouts() {
+        let (mut cpu, pollfds, buf_addr) = test_cpu("");
+        assert_eq!(sys_pipe2(&mut cpu, [buf_addr, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        let r = cpu.memory.load_u32(buf_addr).unwrap() as usize;
+        let efd = sys_eventfd2(&mut cpu, [0, 0, 0, 0, 0, 0]).unwrap().unwrap();
+        let tmo = buf_addr + 64;
+
+        /* ppoll() and pselect6() that time out have no time left. */
+        cpu.memory.store_u32(pollfds, r as u32).unwrap();
+        cpu.memory.store_u16(pollfds + 4, libc::POLLIN as u16).unwrap();
+        write_timespec(&mut cpu, tmo, (0, 1_000_000)).unwrap();
+        assert_eq!(sys_ppoll(&mut cpu, [pollfds, 1, tmo, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(read_timespec(&cpu, tmo), Ok((0, 0)));
+        cpu.memory.store_u64(buf_addr, 1 << r).unwrap();
+        write_timespec(&mut cpu, tmo, (0, 1_000_000)).unwrap();
+        assert_eq!(sys_pselect6(&mut cpu, [r + 1, buf_addr, 0, 0, tmo, 0]).unwrap(), Ok(0));
+        assert_eq!(read_timespec(&cpu, tmo), Ok((0, 0)));
+
+        /* Ready right away: Nearly all of it is left. */
+        cpu.memory.store_u64(buf_addr, 1).unwrap();
+        assert_eq!(sys_write(&mut cpu, [efd, buf_addr, 8, 0, 0, 0]).unwrap(), Ok(8));
+        cpu.memory.store_u32(pollfds, efd as u32).unwrap();
+        write_timespec(&mut cpu, tmo, (10, 0)).unwrap();
+        assert_eq!(sys_ppoll(&mut cpu, [pollfds, 1, tmo, 0, 0, 0]).unwrap(), Ok(1));
+        assert!(read_timespec(&cpu, tmo).unwrap().0 >= 9);
+
+        /* The closed eventfd is reported, without waiting for the timeout. */
+        assert_eq!(sys_close(&mut cpu, [efd, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        cpu.memory.store_u16(pollfds + 6, 0).unwrap();
+        write_timespec(&mut cpu, tmo, (10, 0)).unwrap();
+        assert_eq!(sys_ppoll(&mut cpu, [pollfds, 1, tmo, 0, 0, 0]).unwrap(), Ok(1));
+        assert_eq!(cpu.memory.load_u16(pollfds + 6).unwrap() as i16, POLLNVAL);
+
+        /* The signal masks have to be a whole sigset_t. */
+        cpu.memory.store_u64(buf_addr, 0).unwrap();
+        let args = [pollfds, 1, tmo, buf_addr, 4, 0];
+        assert_eq!(sys_ppoll(&mut cpu, args).unwrap(), Err(Errno::EINVAL));
+        let epfd = sys_epoll_create1(&mut cpu, [0, 0, 0, 0, 0, 0]).unwrap().unwrap();
+        for sigsetsize in [0, 4, 16] {
+            let args = [epfd, buf_addr + 8, 4, 0, buf_addr, sigsetsize];
+            assert_eq!(sys_epoll_pwait(&mut cpu, args).unwrap(), Err(Errno::EINVAL));
+        }
+        let args = [epfd, buf_addr + 8, 4, 0, buf_addr, 8];
+        assert_eq!(sys_epoll_pwait(&mut cpu, args).unwrap(), Ok(0));
+    }
+
+    #[test]
+    fn sockets() {
+        let (mut cpu, addr, buf_addr) = test_cpu("");
+        cpu.network = net::NetworkPolicy::Loopback;
+        let addrlen = addr + 128;
+        let (af_inet, sock_stream) = (libc::AF_INET as usize, libc::SOCK_STREAM as usize);
+        let put_addr = |cpu: &mut CPU, a: SockAddr| {
+            cpu.memory.copy_bulk(addr as u64, &a.to_bytes()).unwrap();
+            a.to_bytes().len()
+        };
+        assert_eq!(sys_socket(&mut cpu, [16 /* AF_NETLINK */, 3, 0, 0, 0, 0]).unwrap(),
+                   Err(Errno::EAFNOSUPPORT));
+
+        /* The wildcard address is loopback. */
+        let server = sys_socket(&mut cpu, [af_inet, sock_stream, 0, 0, 0, 0]).unwrap().unwrap();
+        let len = put_addr(&mut cpu, SockAddr::V4("0.0.0.0:0".parse().unwrap()));
+        assert_eq!(sys_bind(&mut cpu, [server, addr, len, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(sys_listen(&mut cpu, [server, 1, 0, 0, 0, 0]).unwrap(), Ok(0));
+        cpu.memory.store_u32(addrlen, 128).unwrap();
+        assert_eq!(sys_getsockname(&mut cpu, [server, addr, addrlen, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(cpu.memory.load_u32(addrlen).unwrap(), 16);
+        let SockAddr::V4(server_addr) = SockAddr::from_bytes(cpu.memory.guest_slice(addr, 16).unwrap())
+            .unwrap() else { panic!() };
+        assert!(server_addr.ip().is_loopback());
+
+        let client = sys_socket(&mut cpu, [af_inet, sock_stream, 0, 0, 0, 0]).unwrap().unwrap();
+        let len = put_addr(&mut cpu, SockAddr::V4("10.0.0.1:80".parse().unwrap()));
+        assert_eq!(sys_connect(&mut cpu, [client, addr, len, 0, 0, 0]).unwrap(),
+                   Err(Errno::ENETUNREACH));
+        let len = put_addr(&mut cpu, SockAddr::V4(server_addr));
+        assert_eq!(sys_connect(&mut cpu, [client, addr, len, 0, 0, 0]).unwrap(), Ok(0));
+        cpu.memory.store_u32(addrlen, 8).unwrap();
+        let conn = sys_accept4(&mut cpu, [server, addr, addrlen, O_CLOEXEC, 0, 0]).unwrap().unwrap();
+        assert_eq!(cpu.memory.load_u32(addrlen).unwrap(), 16);
+        assert_eq!(cpu.fds.cloexec(conn), Ok(true));
+
+        cpu.memory.copy_bulk(buf_addr as u64, b"ping").unwrap();
+        assert_eq!(sys_sendto(&mut cpu, [client, buf_addr, 4, 0, 0, 0]).unwrap(), Ok(4));
+        let args = [conn, buf_addr + 16, 16, 0, 0, 0];
+        assert_eq!(sys_recvfrom(&mut cpu, args).unwrap(), Ok(4));
+        assert_eq!(cpu.memory.guest_slice(buf_addr + 16, 4).unwrap(), b"ping");
+
+        cpu.memory.store_u32(buf_addr, 1).unwrap();
+        let (sol_socket, so_reuseaddr) = (libc::SOL_SOCKET as usize, libc::SO_REUSEADDR as usize);
+        let args = [server, sol_socket, so_reuseaddr, buf_addr, 4, 0];
+        assert_eq!(sys_setsockopt(&mut cpu, args).unwrap(), Ok(0));
+        cpu.memory.store_u32(buf_addr, 0).unwrap();
+        cpu.memory.store_u32(buf_addr + 4, 4).unwrap();
+        let args = [server, sol_socket, so_reuseaddr, buf_addr, buf_addr + 4, 0];
+        assert_eq!(sys_getsockopt(&mut cpu, args).unwrap(), Ok(0));
+        assert_eq!(cpu.memory.load_u32(buf_addr).unwrap(), 1);
+
+        /* Read-only *addrlen and *optlen are EFAULT, checked before the syscall. */
+        let ro = buf_addr + PAGE_SIZE;
+        cpu.memory.store_u32(ro, 128).unwrap();
+        cpu.memory.mm().mprotect(ro, PAGE_SIZE, PROT_READ).unwrap();
+        assert_eq!(sys_getsockname(&mut cpu, [server, addr, ro, 0, 0, 0]).unwrap(),
+                   Err(Errno::EFAULT));
+        assert_eq!(sys_accept4(&mut cpu, [server, addr, ro, 0, 0, 0]).unwrap(),
+                   Err(Errno::EFAULT));
+        let args = [conn, buf_addr + 16, 16, libc::MSG_DONTWAIT as usize, addr, ro];
+        assert_eq!(sys_recvfrom(&mut cpu, args).unwrap(), Err(Errno::EFAULT));
+        let args = [server, sol_socket, so_reuseaddr, buf_addr, ro, 0];
+        assert_eq!(sys_getsockopt(&mut cpu, args).unwrap(), Err(Errno::EFAULT));
+        assert_eq!(sys_shutdown(&mut cpu, [client, libc::SHUT_WR as usize, 0, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(sys_recvfrom(&mut cpu, [conn, buf_addr, 16, 0, 0, 0]).unwrap(), Ok(0));
+
+        /* listen() and sendto() bind unbound sockets to loopback, not to the wildcard address. */
+        let local_addr = |cpu: &mut CPU, fd: usize| {
+            cpu.memory.store_u32(addrlen, 128).unwrap();
+            assert_eq!(sys_getsockname(cpu, [fd, addr, addrlen, 0, 0, 0]).unwrap(), Ok(0));
+            match SockAddr::from_bytes(cpu.memory.guest_slice(addr, 16).unwrap()).unwrap() {
+                SockAddr::V4(local) => local,
+                _ => panic!()
+            }
+        };
+        let unbound = sys_socket(&mut cpu, [af_inet, sock_stream, 0, 0, 0, 0]).unwrap().unwrap();
+        assert_eq!(sys_listen(&mut cpu, [unbound, 1, 0, 0, 0, 0]).unwrap(), Ok(0));
+        let local = local_addr(&mut cpu, unbound);
+        assert!(local.ip().is_loopback() && local.port() != 0);
+        let sock_dgram = libc::SOCK_DGRAM as usize;
+        let udp_server = sys_socket(&mut cpu, [af_inet, sock_dgram, 0, 0, 0, 0]).unwrap().unwrap();
+        let len = put_addr(&mut cpu, SockAddr::V4("127.0.0.1:0".parse().unwrap()));
+        assert_eq!(sys_bind(&mut cpu, [udp_server, addr, len, 0, 0, 0]).unwrap(), Ok(0));
+        let udp_server_addr = local_addr(&mut cpu, udp_server);
+        let udp_client = sys_socket(&mut cpu, [af_inet, sock_dgram, 0, 0, 0, 0]).unwrap().unwrap();
+        let len = put_addr(&mut cpu, SockAddr::V4(udp_server_addr));
+        let args = [udp_client, buf_addr, 4, 0, addr, len];
+        assert_eq!(sys_sendto(&mut cpu, args).unwrap(), Ok(4));
+        assert!(local_addr(&mut cpu, udp_client).ip().is_loopback());
+    }
+
+    /* SCM_RIGHTS: The fd that is received is a new guest fd of the same pipe. */
+    #[test]
+    fn pass_fds() {
+        let (mut cpu, addr, buf_addr) = test_cpu("");
+        let name = format!("\0simrv64i-test-{}", std::process::id());
+        let len = 2 + name.len();
+        cpu.memory.copy_bulk(addr as u64, &SockAddr::Unix(name.into_bytes()).to_bytes()).unwrap();
+        let (af_unix, sock_dgram) = (libc::AF_UNIX as usize, libc::SOCK_DGRAM as usize);
+        let server = sys_socket(&mut cpu, [af_unix, sock_dgram, 0, 0, 0, 0]).unwrap().unwrap();
+        assert_eq!(sys_bind(&mut cpu, [server, addr, len, 0, 0, 0]).unwrap(), Ok(0));
+        let client = sys_socket(&mut cpu, [af_unix, sock_dgram, 0, 0, 0, 0]).unwrap().unwrap();
+        assert_eq!(sys_pipe2(&mut cpu, [buf_addr, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        let (r, w) = (cpu.memory.load_u32(buf_addr).unwrap(), cpu.memory.load_u32(buf_addr + 4).unwrap());
+
+        /* msghdr at buf_addr, iovec at +64, cmsg at +128, data at +256. */
+        let (iov, control, data) = (buf_addr + 64, buf_addr + 128, buf_addr + 256);
+        cpu.memory.copy_bulk(data as u64, b"fd").unwrap();
+        for (offset, val) in [(0, addr), (8, len), (16, iov), (24, 1), (32, control), (40, 24), (48, 0)] {
+            cpu.memory.store_u64(buf_addr + offset, val as u64).unwrap();
+        }
+        for (offset, val) in [(0, data as u64), (8, 2)] {
+            cpu.memory.store_u64(iov + offset, val).unwrap();
+        }
+        cpu.memory.store_u64(control, 20).unwrap();
+        cpu.memory.store_u32(control + 8, libc::SOL_SOCKET as u32).unwrap();
+        cpu.memory.store_u32(control + 12, libc::SCM_RIGHTS as u32).unwrap();
+        cpu.memory.store_u32(control + 16, w).unwrap();
+        assert_eq!(sys_sendmsg(&mut cpu, [client, buf_addr, 0, 0, 0, 0]).unwrap(), Ok(2));
+
+        cpu.memory.zero_bulk(data, 2).unwrap();
+        cpu.memory.zero_bulk(control, 24).unwrap();
+        cpu.memory.store_u64(buf_addr, 0).unwrap();
+        assert_eq!(sys_recvmsg(&mut cpu, [server, buf_addr, MSG_CMSG_CLOEXEC, 0, 0, 0]).unwrap(), Ok(2));
+        assert_eq!(cpu.memory.guest_slice(data, 2).unwrap(), b"fd");
+        assert_eq!(cpu.memory.load_u64(buf_addr + 40).unwrap(), 24);
+        let received = cpu.memory.load_u32(control + 16).unwrap() as usize;
+        assert_eq!(received, w as usize + 1);
+        assert_eq!(cpu.fds.cloexec(received), Ok(true));
+
+        cpu.memory.copy_bulk(data as u64, b"ok").unwrap();
+        assert_eq!(sys_write(&mut cpu, [received, data, 2, 0, 0, 0]).unwrap(), Ok(2));
+        assert_eq!(sys_read(&mut cpu, [r as usize, data + 2, 2, 0, 0, 0]).unwrap(), Ok(2));
+        assert_eq!(cpu.memory.guest_slice(data + 2, 2).unwrap(), b"ok");
+    }
+
+    #[test]
+    fn positional_io() {
+        let path = std::env::temp_dir().join(format!("simrv64i-pio-{}", std::process::id()));
+        let (mut cpu, path_addr, buf_addr) = test_cpu(path.to_str().unwrap());
+        let flags = (libc::O_CREAT | libc::O_RDWR) as usize;
+        let args = [-100i64 as usize, path_addr, flags, 0o600, 0, 0];
+        let fd = sys_openat(&mut cpu, args).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        cpu.memory.copy_bulk(buf_addr as u64, b"0123456789").unwrap();
+        assert_eq!(sys_pwrite64(&mut cpu, [fd, buf_addr, 10, 100, 0, 0]).unwrap(), Ok(10));
+        assert_eq!(sys_pread64(&mut cpu, [fd, buf_addr + 16, 4, 103, 0, 0]).unwrap(), Ok(4));
+        assert_eq!(cpu.memory.guest_slice(buf_addr + 16, 4).unwrap(), b"3456");
+        assert_eq!(sys_lseek(&mut cpu, [fd, 0, libc::SEEK_CUR as usize, 0, 0, 0]).unwrap(), Ok(0));
+
+        /* sendfile() from an offset into a pipe, the offset is updated. */
+        assert_eq!(sys_pipe2(&mut cpu, [buf_addr + 32, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        let (r, w) = (cpu.memory.load_u32(buf_addr + 32).unwrap() as usize,
+                      cpu.memory.load_u32(buf_addr + 36).unwrap() as usize);
+        cpu.memory.store_u64(buf_addr + 40, 105).unwrap();
+        assert_eq!(sys_sendfile(&mut cpu, [w, fd, buf_addr + 40, 100, 0, 0]).unwrap(), Ok(5));
+        assert_eq!(cpu.memory.load_u64(buf_addr + 40).unwrap(), 110);
+        assert_eq!(sys_read(&mut cpu, [r, buf_addr + 48, 16, 0, 0, 0]).unwrap(), Ok(5));
+        assert_eq!(cpu.memory.guest_slice(buf_addr + 48, 5).unwrap(), b"56789");
+    }
+
+    #[test]
+    fn virtual_clock() {
+        let (mut cpu, req_addr, buf_addr) = test_cpu("");
+        cpu.clock.source = ClockSource::Virtual;
+        cpu.clock.freq_hz = 1_000_000;
+        cpu.instret = 2_500_000;
+        let load = |cpu: &CPU| (cpu.memory.load_u64(buf_addr).unwrap() as i64,
+                                cpu.memory.load_u64(buf_addr + 8).unwrap() as i64);
+
+        let args = [CLOCK_MONOTONIC, buf_addr, 0, 0, 0, 0];
+        assert_eq!(sys_clock_gettime(&mut cpu, args).unwrap(), Ok(0));
+        assert_eq!(load(&cpu), (2, 500_000_000));
+
+        write_timespec(&mut cpu, req_addr, (1, 250_000_000)).unwrap();
+        assert_eq!(sys_nanosleep(&mut cpu, [req_addr, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(sys_clock_gettime(&mut cpu, args).unwrap(), Ok(0));
+        assert_eq!(load(&cpu), (3, 750_000_000));
+
+        /* An absolute time in the past does not sleep at all. */
+        let args = [CLOCK_MONOTONIC, TIMER_ABSTIME, req_addr, 0, 0, 0];
+        assert_eq!(sys_clock_nanosleep(&mut cpu, args).unwrap(), Ok(0));
+        assert_eq!(cpu.clock.slept_ns, 1_250_000_000);
+
+        assert_eq!(sys_gettimeofday(&mut cpu, [buf_addr, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(load(&cpu), (1_704_067_203, 750_000));
+
+        assert_eq!(sys_clock_getres(&mut cpu, [CLOCK_REALTIME, buf_addr, 0, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(load(&cpu), (0, 1000));
+        assert_eq!(sys_times(&mut cpu, [buf_addr, 0, 0, 0, 0, 0]).unwrap(), Ok(375));
+        assert_eq!(sys_clock_gettime(&mut cpu, [-6i64 as usize, buf_addr, 0, 0, 0, 0]).unwrap(),
+                   Err(Errno::EINVAL));
+    }
+
+    #[test]
+    fn host_clock() {
+        let (mut cpu, _, buf_addr) = test_cpu("");
+        let before = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        let args = [CLOCK_REALTIME, buf_addr, 0, 0, 0, 0];
+        assert_eq!(sys_clock_gettime(&mut cpu, args).unwrap(), Ok(0));
+        let after = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        let sec = cpu.memory.load_u64(buf_addr).unwrap();
+        assert!(before.as_secs() <= sec && sec <= after.as_secs());
+        assert!(cpu.memory.load_u64(buf_addr + 8).unwrap() < 1_000_000_000);
+
+        cpu.memory.store_u64(buf_addr + 8, 1_000_000_000).unwrap();
+        assert_eq!(sys_nanosleep(&mut cpu, [buf_addr, 0, 0, 0, 0, 0]).unwrap(), Err(Errno::EINVAL));
+    }
+
+    /* What the startup code of glibc and musl asks for. */
+    #[test]
+    fn startup_syscalls() {
+        let (mut cpu, path_addr, buf_addr) = test_cpu("/proc/self/exe");
+        assert_eq!(sys_uname(&mut cpu, [buf_addr, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(cpu.memory.guest_cstr(buf_addr + 4 * UTSNAME_FIELD).unwrap().to_bytes(),
+                   b"riscv64");
+
+        cpu.exe = Some(std::path::PathBuf::from("/bin/guest"));
+        let args = [-100i64 as usize, path_addr, buf_addr, 64, 0, 0];
+        assert_eq!(sys_readlinkat(&mut cpu, args).unwrap(), Ok(10));
+        assert_eq!(cpu.memory.guest_slice(buf_addr, 10).unwrap(), b"/bin/guest");
+
+        /* Limits the simulator fixes, and the host's. */
+        let args = [0, RLIMIT_STACK, 0, buf_addr, 0, 0];
+        assert_eq!(sys_prlimit64(&mut cpu, args).unwrap(), Ok(0));
+        let stack_size = cpu.memory.layout.stack().len() as u64;
+        assert_eq!(cpu.memory.load_u64(buf_addr + 8).unwrap(), stack_size);
+        cpu.memory.store_u64(buf_addr, 64).unwrap();
+        cpu.memory.store_u64(buf_addr + 8, fds::MAX_FDS as u64).unwrap();
+        let args = [0, RLIMIT_NOFILE, buf_addr, 0, 0, 0];
+        assert_eq!(sys_prlimit64(&mut cpu, args).unwrap(), Ok(0));
+        cpu.memory.store_u64(buf_addr + 8, u64::MAX).unwrap();
+        assert_eq!(sys_prlimit64(&mut cpu, args).unwrap(), Err(Errno::EPERM));
+        let args = [0, libc::RLIMIT_CORE as usize, 0, buf_addr, 0, 0];
+        assert_eq!(sys_prlimit64(&mut cpu, args).unwrap(), Ok(0));
+
+        assert_eq!(sys_getrandom(&mut cpu, [buf_addr, 64, 0, 0, 0, 0]).unwrap(), Ok(64));
+
+        /* rseq: Registered once, with cpu_id left uninitialized. */
+        let (rseq, sig) = (buf_addr + 128, 0x53053053);
+        let args = [rseq, RSEQ_SIZE, 0, sig, 0, 0];
+        assert_eq!(sys_rseq(&mut cpu, args).unwrap(), Ok(0));
+        assert_eq!(cpu.memory.load_u32(rseq + 4).unwrap(), RSEQ_CPU_ID_UNINITIALIZED);
+        assert_eq!(sys_rseq(&mut cpu, args).unwrap(), Err(Errno::EBUSY));
+        let args = [rseq, RSEQ_SIZE, RSEQ_FLAG_UNREGISTER, sig + 1, 0, 0];
+        assert_eq!(sys_rseq(&mut cpu, args).unwrap(), Err(Errno::EPERM));
+        let args = [rseq, RSEQ_SIZE, RSEQ_FLAG_UNREGISTER, sig, 0, 0];
+        assert_eq!(sys_rseq(&mut cpu, args).unwrap(), Ok(0));
+        assert_eq!(sys_rseq(&mut cpu, [rseq + 8, RSEQ_SIZE, 0, sig, 0, 0]).unwrap(),
+                   Err(Errno::EINVAL));
+
+        let keys = [RISCV_HWPROBE_KEY_IMA_EXT_0, 1000, RISCV_HWPROBE_KEY_BASE_BEHAVIOR];
+        for (i, key) in keys.iter().enumerate() {
+            cpu.memory.store_u64(buf_addr + i * 16, *key as u64).unwrap();
+        }
+        assert_eq!(sys_riscv_hwprobe(&mut cpu, [buf_addr, 3, 0, 0, 0, 0]).unwrap(), Ok(0));
+        assert_eq!(cpu.memory.load_u64(buf_addr + 8).unwrap(), RISCV_HWPROBE_IMA_C);
+        assert_eq!(cpu.memory.load_u64(buf_addr + 16).unwrap() as i64, -1);
+        assert_eq!(cpu.memory.load_u64(buf_addr + 40).unwrap(), RISCV_HWPROBE_BASE_BEHAVIOR_IMA);
+    }
+
+    #[test]
+    fn signal_syscalls() {
+        let (mut cpu, _, buf_addr) = test_cpu("");
+        for sigsetsize in [0, 4, 16] {
+            let args = [buf_addr, sigsetsize, 0, 0, 0, 0];
+            assert_eq!(sys_rt_sigpending(&mut cpu, args).unwrap(), Err(Errno::EINVAL));
+        }
+        assert_eq!(sys_rt_sigpending(&mut cpu, [buf_addr, 8, 0, 0, 0, 0]).unwrap(), Ok(0));
+
+        /* Invalid signals are rejected for any target, not just for the guest itself. */
+        let pid = unsafe { libc::getpid() } as usize;
+        for pid in [pid, 1, -1i64 as usize] {
+            assert_eq!(sys_kill(&mut cpu, [pid, NSIG + 1, 0, 0, 0, 0]).unwrap(),
+                       Err(Errno::EINVAL));
+        }
+        assert_eq!(sys_kill(&mut cpu, [pid, 0, 0, 0, 0, 0]).unwrap(), Ok(0));
+    }
+
+    /* The child of a fork() is a copy of the simulator, with a copy of the guest. */
+    #[test]
+    fn fork_and_wait() {
+        /* Not in the multi-threaded test harness, another test's thread could hold a lock
+         * (e.g. of malloc) that the child needs. So it runs in a process of its own. */
+        if std::env::var_os("SIMRV64I_FORK_TEST").is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["sys::test::fork_and_wait", "--exact", "--test-threads=1"])
+                .env("SIMRV64I_FORK_TEST", "1")
+                .output().unwrap();
+            assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+            assert!(String::from_utf8_lossy(&output.stdout).contains("1 passed"));
+            return
+        }
+        let (mut cpu, status_addr, _) = test_cpu("");
+        let (sigchld, parent) = (libc::SIGCHLD as usize, std::process::id() as usize);
+        let flags = [sigchld, CLONE_VM | CLONE_VFORK | sigchld];
+        for (i, flags) in flags.into_iter().enumerate() {
+            cpu.memory.store_u32(status_addr, 0x1234).unwrap();
+            let pid = match sys_clone(&mut cpu, [flags, 0, 0, 0, 0, 0]).unwrap() {
+                Ok(0) => {
+                    let ok = sys_getppid(&mut cpu, [0; 6]).unwrap() == Ok(parent)
+                        && cpu.memory.load_u32(status_addr).unwrap() == 0x1234;
+                    unsafe { libc::_exit(if ok { 40 + i as i32 } else { 1 }) }
+                },
+                pid => pid.unwrap()
+            };
+            assert_eq!(sys_wait4(&mut cpu, [pid, status_addr, 0, 0, 0, 0]).unwrap(), Ok(pid));
+            assert_eq!(cpu.memory.load_u32(status_addr).unwrap(), (40 + i as u32) << 8);
+        }
+        let args = [-1i64 as usize, 0, 0, 0, 0, 0];
+        assert_eq!(sys_wait4(&mut cpu, args).unwrap(), Err(Errno::ECHILD));
+        assert_eq!(sys_clone(&mut cpu, [CLONE_VM, 0, 0, 0, 0, 0]).unwrap(), Err(Errno::EINVAL));
+        assert_eq!(sys_getpid(&mut cpu, [0; 6]).unwrap(), Ok(parent));
+    }
+
+    #[test]
+    fn execve_errors() {
+        let dir = std::env::temp_dir().join(format!("simrv64i-exec-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("loop.sh");
+        std::fs::write(&script, format!("#!{}\n", script.display())).unwrap();
+        let (mut cpu, path_addr, _) = test_cpu(script.to_str().unwrap());
+        let args = [path_addr, 0, 0, 0, 0, 0];
+        assert_eq!(sys_execve(&mut cpu, args).unwrap(), Err(Errno::EACCES));
+        std::fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        assert_eq!(sys_execve(&mut cpu, args).unwrap(), Err(Errno::ELOOP));
+        put_cstr(&mut cpu, path_addr, dir.to_str().unwrap());
+        assert_eq!(sys_execve(&mut cpu, args).unwrap(), Err(Errno::EACCES));
+        put_cstr(&mut cpu, path_addr, "/nonexistent");
+        assert_eq!(sys_execve(&mut cpu, args).unwrap(), Err(Errno::ENOENT));
+
+        /* A host binary, here the test itself. */
+        put_cstr(&mut cpu, path_addr, std::env::current_exe().unwrap().to_str().unwrap());
+        cpu.host_exec = HostExecPolicy::Deny;
+        assert_eq!(sys_execve(&mut cpu, args).unwrap(), Err(Errno::EACCES));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,430 @@
+/*
+ * Read-only file trees from tar archives (ustar, with GNU long names and pax headers),
+ * kept in memory for `--mount-tar`. Regular files, directories, symlinks and hard links
+ * are kept, everything else (devices, FIFOs) is skipped. Directories that only appear
+ * in the paths of other entries are created with mode 0755.
+ */
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use syscalls::Errno;
+
+const BLOCK_SIZE: usize = 512;
+
+/* Like Linux (MAXSYMLINKS). */
+const MAX_SYMLINKS: usize = 40;
+
+#[derive(Debug)]
+pub enum Kind {
+    File(Vec<u8>),
+    /// Entries by name, without `.` and `..`.
+    Dir(BTreeMap<Vec<u8>, Arc<Node>>),
+    Symlink(Vec<u8>),
+}
+
+/* Hard links are the same Node (with the same inode number) in several directories. */
+#[derive(Debug)]
+pub struct Node {
+    pub ino: u64,
+    /// Permission bits, the file type is the Kind.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub kind: Kind,
+}
+
+impl Node {
+    pub fn file_type(&self) -> u32 {
+        match self.kind {
+            Kind::File(_) => libc::S_IFREG,
+            Kind::Dir(_) => libc::S_IFDIR,
+            Kind::Symlink(_) => libc::S_IFLNK,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match &self.kind {
+            Kind::File(data) => data.len() as u64,
+            Kind::Dir(_) => BLOCK_SIZE as u64,
+            Kind::Symlink(target) => target.len() as u64,
+        }
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    pub fn stat(&self) -> libc::stat {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        st.st_ino = self.ino as _;
+        st.st_mode = (self.file_type() | self.mode) as _;
+        st.st_nlink = if matches!(self.kind, Kind::Dir(_)) { 2 } else { 1 };
+        st.st_uid = self.uid;
+        st.st_gid = self.gid;
+        st.st_size = self.size() as _;
+        st.st_blksize = BLOCK_SIZE as _;
+        st.st_blocks = self.size().div_ceil(BLOCK_SIZE as u64) as _;
+        st.st_atime = self.mtime as _;
+        st.st_mtime = self.mtime as _;
+        st.st_ctime = self.mtime as _;
+        st
+    }
+
+    pub fn statx(&self) -> libc::statx {
+        let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+        stx.stx_mask = libc::STATX_BASIC_STATS;
+        stx.stx_blksize = BLOCK_SIZE as u32;
+        stx.stx_nlink = if matches!(self.kind, Kind::Dir(_)) { 2 } else { 1 };
+        stx.stx_uid = self.uid;
+        stx.stx_gid = self.gid;
+        stx.stx_mode = (self.file_type() | self.mode) as u16;
+        stx.stx_ino = self.ino;
+        stx.stx_size = self.size();
+        stx.stx_blocks = self.size().div_ceil(BLOCK_SIZE as u64);
+        for time in [&mut stx.stx_atime, &mut stx.stx_ctime, &mut stx.stx_mtime] {
+            time.tv_sec = self.mtime;
+        }
+        stx
+    }
+
+    /* For directories that are in an archive more than once. */
+    fn copy_metadata(&mut self, other: &Node) {
+        (self.mode, self.uid, self.gid) = (other.mode, other.uid, other.gid);
+        self.mtime = other.mtime;
+    }
+
+    /*
+     * Look up a path relative to this (directory) node. Symlinks are followed (the last
+     * component only if `follow`), absolute ones start over at this node: Nothing
+     * outside of the tree can be reached, like with openat2(RESOLVE_IN_ROOT).
+     */
+    pub fn resolve(self: &Arc<Node>, path: &[u8], follow: bool) -> Result<Arc<Node>, Errno> {
+        let mut queue: VecDeque<Vec<u8>> = components(path).map(<[u8]>::to_vec).collect();
+        let mut stack = vec![self.clone()];
+        let mut links = 0;
+        while let Some(name) = queue.pop_front() {
+            if name == b".." {
+                if stack.len() > 1 {
+                    stack.pop();
+                }
+                continue
+            }
+            let Kind::Dir(entries) = &stack.last().unwrap().kind else {
+                return Err(Errno::ENOTDIR)
+            };
+            let node = entries.get(&name).ok_or(Errno::ENOENT)?.clone();
+            match &node.kind {
+                Kind::Symlink(target) if follow || !queue.is_empty() => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(Errno::ELOOP)
+                    }
+                    if target.starts_with(b"/") {
+                        stack.truncate(1);
+                    }
+                    for component in components(target).rev() {
+                        queue.push_front(component.to_vec());
+                    }
+                },
+                _ => stack.push(node)
+            }
+        }
+        Ok(stack.pop().unwrap())
+    }
+}
+
+/* The components of a path, without empty ones and `.`. */
+fn components(path: &[u8]) -> impl DoubleEndedIterator<Item = &[u8]> {
+    path.split(|c| *c == b'/').filter(|c| !c.is_empty() && *c != b".")
+}
+
+/* A tree under construction, the directories in it are not shared yet. */
+#[derive(Debug)]
+pub struct Builder {
+    root: Arc<Node>,
+    next_ino: u64,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder { root: Arc::new(Self::dir(1, 0o755, 0)), next_ino: 2 }
+    }
+}
+
+impl Builder {
+    fn dir(ino: u64, mode: u32, mtime: i64) -> Node {
+        Node { ino, mode, uid: 0, gid: 0, mtime, kind: Kind::Dir(BTreeMap::new()) }
+    }
+
+    fn entries(node: &mut Arc<Node>) -> Option<&mut BTreeMap<Vec<u8>, Arc<Node>>> {
+        match &mut Arc::get_mut(node)?.kind {
+            Kind::Dir(entries) => Some(entries),
+            _ => None
+        }
+    }
+
+    /*
+     * The entries of the directory `path` is in and the last component of `path`, None
+     * for the root itself. Missing directories on the way are created.
+     */
+    #[allow(clippy::type_complexity)]
+    fn parent(&mut self, path: &[u8])
+            -> Result<Option<(&mut BTreeMap<Vec<u8>, Arc<Node>>, Vec<u8>)>, String> {
+        let names: Vec<&[u8]> = components(path).collect();
+        if names.contains(&&b".."[..]) {
+            return Err(format!("{:?}: path leaves the archive", String::from_utf8_lossy(path)))
+        }
+        let not_a_dir = || format!("{:?}: not a directory", String::from_utf8_lossy(path));
+        let Some((last, parents)) = names.split_last() else {
+            return Ok(None)
+        };
+        let (mut dir, next_ino) = (&mut self.root, &mut self.next_ino);
+        for name in parents {
+            dir = Self::entries(dir).ok_or_else(not_a_dir)?.entry(name.to_vec())
+                .or_insert_with(|| {
+                    *next_ino += 1;
+                    Arc::new(Self::dir(*next_ino - 1, 0o755, 0))
+                });
+        }
+        Ok(Some((Self::entries(dir).ok_or_else(not_a_dir)?, last.to_vec())))
+    }
+
+    /*
+     * Add a node at `path`. A directory that is already there keeps its entries and only
+     * takes the new one's metadata, other nodes are replaced (like tar does when extracting).
+     */
+    fn insert(&mut self, path: &[u8], mut node: Node) -> Result<(), String> {
+        node.ino = self.next_ino;
+        self.next_ino += 1;
+        let Some((entries, name)) = self.parent(path)? else {
+            /* `./`, the root itself. */
+            Arc::get_mut(&mut self.root).unwrap().copy_metadata(&node);
+            return Ok(())
+        };
+        match entries.get_mut(&name) {
+            Some(old) if matches!((&old.kind, &node.kind), (Kind::Dir(_), Kind::Dir(_))) => {
+                Arc::get_mut(old).unwrap().copy_metadata(&node);
+            },
+            _ => {
+                entries.insert(name, Arc::new(node));
+            }
+        }
+        Ok(())
+    }
+
+    /* A hard link: The node at `target` (a regular file) is at `path` as well. */
+    fn link(&mut self, path: &[u8], target: &[u8]) -> Result<(), String> {
+        let invalid = || format!("{:?}: bad hard link target {:?}",
+            String::from_utf8_lossy(path), String::from_utf8_lossy(target));
+        let node = self.root.resolve(target, false).ok()
+            .filter(|node| matches!(node.kind, Kind::File(_)))
+            .ok_or_else(invalid)?;
+        let (entries, name) = self.parent(path)?.ok_or_else(invalid)?;
+        entries.insert(name, node);
+        Ok(())
+    }
+
+    /* An (empty) directory at `path`, unless there is one already. */
+    pub fn mkdir(&mut self, path: &[u8]) -> Result<(), String> {
+        if self.root.resolve(path, false).is_ok_and(|node| matches!(node.kind, Kind::Dir(_))) {
+            return Ok(())
+        }
+        self.insert(path, Self::dir(0, 0o755, 0))
+    }
+
+    pub fn finish(self) -> Arc<Node> {
+        self.root
+    }
+}
+
+/* A NUL-terminated (or full-length) string field of a header. */
+fn field(bytes: &[u8]) -> &[u8] {
+    bytes.split(|c| *c == 0).next().unwrap_or_default()
+}
+
+/* Numbers are octal, large ones (GNU) big-endian binary with the top bit set. */
+fn number(bytes: &[u8]) -> Result<u64, String> {
+    if bytes.first().is_some_and(|b| b & 0x80 != 0) {
+        return Ok(bytes[1..].iter().fold((bytes[0] & 0x7f) as u64, |n, b| (n << 8) | *b as u64))
+    }
+    let digits = std::str::from_utf8(field(bytes)).map_err(|_| "invalid number".to_string())?
+        .trim_matches(' ');
+    match digits {
+        "" => Ok(0),
+        digits => u64::from_str_radix(digits, 8).map_err(|e| format!("invalid number: {}", e))
+    }
+}
+
+/* The fields of a pax extended header that matter here. */
+#[derive(Default)]
+struct Pax {
+    path: Option<Vec<u8>>,
+    linkpath: Option<Vec<u8>>,
+    size: Option<u64>,
+}
+
+/* Records are `<length> <key>=<value>\n`, the length includes all of it. */
+fn parse_pax(mut data: &[u8], pax: &mut Pax) -> Result<(), String> {
+    let invalid = || "invalid pax header".to_string();
+    while !data.is_empty() && data[0] != 0 {
+        let space = data.iter().position(|c| *c == b' ').ok_or_else(invalid)?;
+        let len: usize = std::str::from_utf8(&data[..space]).ok()
+            .and_then(|len| len.parse().ok()).ok_or_else(invalid)?;
+        let record = data.get(space + 1..len).and_then(|r| r.strip_suffix(b"\n"))
+            .ok_or_else(invalid)?;
+        let eq = record.iter().position(|c| *c == b'=').ok_or_else(invalid)?;
+        let (key, value) = (&record[..eq], &record[eq + 1..]);
+        match key {
+            b"path" => pax.path = Some(value.to_vec()),
+            b"linkpath" => pax.linkpath = Some(value.to_vec()),
+            b"size" => pax.size = Some(std::str::from_utf8(value).ok()
+                .and_then(|size| size.parse().ok()).ok_or_else(invalid)?),
+            _ => {}
+        }
+        data = &data[len..];
+    }
+    Ok(())
+}
+
+pub fn parse(archive: &[u8]) -> Result<Arc<Node>, String> {
+    let mut builder = Builder::default();
+    let (mut pax, mut long_name, mut long_link) = (Pax::default(), None, None);
+    let mut offset = 0;
+    while let Some(header) = archive.get(offset..offset + BLOCK_SIZE) {
+        /* The end is marked by (two) blocks of zeros. */
+        if header.iter().all(|b| *b == 0) {
+            break
+        }
+        /* The checksum is computed with the checksum field itself filled with blanks. */
+        let sum: u64 = header.iter().enumerate()
+            .map(|(i, b)| if (148..156).contains(&i) { b' ' as u64 } else { *b as u64 }).sum();
+        if number(&header[148..156])? != sum {
+            return Err(format!("bad header checksum at offset {}", offset))
+        }
+
+        let typeflag = header[156];
+        let size = match typeflag {
+            b'x' | b'g' | b'L' | b'K' => number(&header[124..136])?,
+            _ => pax.size.take().map_or_else(|| number(&header[124..136]), Ok)?
+        } as usize;
+        let start = offset + BLOCK_SIZE;
+        let data = archive.get(start..start + size)
+            .ok_or_else(|| format!("truncated archive at offset {}", offset))?;
+        offset = start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+        match typeflag {
+            b'x' => { parse_pax(data, &mut pax)?; continue },
+            b'g' => continue,
+            b'L' => { long_name = Some(field(data).to_vec()); continue },
+            b'K' => { long_link = Some(field(data).to_vec()); continue },
+            _ => {}
+        }
+        let name = pax.path.take().or(long_name.take()).unwrap_or_else(|| {
+            let (name, prefix) = (field(&header[0..100]), field(&header[345..500]));
+            match &header[257..262] == b"ustar" && !prefix.is_empty() {
+                true => [prefix, b"/", name].concat(),
+                false => name.to_vec()
+            }
+        });
+        let link = pax.linkpath.take().or(long_link.take())
+            .unwrap_or_else(|| field(&header[157..257]).to_vec());
+        pax = Pax::default();
+
+        let kind = match typeflag {
+            b'0' | 0 | b'7' => Kind::File(data.to_vec()),
+            b'5' => Kind::Dir(BTreeMap::new()),
+            b'2' => Kind::Symlink(link),
+            b'1' => { builder.link(&name, &link)?; continue },
+            _ => continue
+        };
+        let node = Node {
+            ino: 0,
+            mode: number(&header[100..108])? as u32 & 0o7777,
+            uid: number(&header[108..116])? as u32,
+            gid: number(&header[116..124])? as u32,
+            mtime: number(&header[136..148])? as i64,
+            kind,
+        };
+        builder.insert(&name, node)?;
+    }
+    Ok(builder.finish())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /* One entry of a ustar archive, the header and the data padded to full blocks. */
+    pub fn entry(name: &str, typeflag: u8, data: &[u8], link: &str) -> Vec<u8> {
+        let mut header = vec![0u8; BLOCK_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            header[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+        put(0, name.as_bytes());
+        put(100, format!("{:07o}\0", if typeflag == b'5' { 0o755 } else { 0o644 }).as_bytes());
+        put(108, b"0001750\0");
+        put(116, b"0001750\0");
+        put(124, format!("{:011o}\0", data.len()).as_bytes());
+        put(136, format!("{:011o}\0", 1700000000).as_bytes());
+        put(148, b"        ");
+        put(156, &[typeflag]);
+        put(157, link.as_bytes());
+        put(257, b"ustar\0");
+        put(263, b"00");
+        let sum: u32 = header.iter().map(|b| *b as u32).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        header.extend_from_slice(data);
+        header.resize(header.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+        header
+    }
+
+    pub fn archive() -> Vec<u8> {
+        let long = format!("dir/{}", "x".repeat(120));
+        let pax_path = format!("dir/{}", "p".repeat(110));
+        let pax = format!("{} path={}\n", 6 + pax_path.len() + 4, pax_path);
+        [
+            entry("./", b'5', b"", ""),
+            entry("dir/file.txt", b'0', b"hello, tar\n", ""),
+            entry("././@LongLink", b'L', format!("{}\0", long).as_bytes(), ""),
+            entry("truncated-name", b'0', b"long", ""),
+            entry("PaxHeaders/p", b'x', pax.as_bytes(), ""),
+            entry("ignored", b'0', b"pax", ""),
+            entry("dir/hard", b'1', b"", "dir/file.txt"),
+            entry("dir/rel", b'2', b"", "file.txt"),
+            entry("dir/abs", b'2', b"", "/dir/file.txt"),
+            entry("dir/escape", b'2', b"", "../../../etc/passwd"),
+            entry("dir/loop", b'2', b"", "loop"),
+            entry("implied/sub/file", b'0', b"", ""),
+            vec![0; 2 * BLOCK_SIZE],
+        ].concat()
+    }
+
+    #[test]
+    fn parse_and_resolve() {
+        let root = parse(&archive()).unwrap();
+        let file = root.resolve(b"dir/file.txt", true).unwrap();
+        assert!(matches!(&file.kind, Kind::File(data) if data == b"hello, tar\n"));
+        assert_eq!((file.mode, file.uid, file.mtime), (0o644, 1000, 1700000000));
+        assert_eq!(file.stat().st_mode, libc::S_IFREG | 0o644);
+
+        assert!(matches!(&root.resolve(format!("dir/{}", "x".repeat(120)).as_bytes(), true)
+            .unwrap().kind, Kind::File(data) if data == b"long"));
+        assert!(matches!(&root.resolve(format!("dir/{}", "p".repeat(110)).as_bytes(), true)
+            .unwrap().kind, Kind::File(data) if data == b"pax"));
+        assert_eq!(root.resolve(b"ignored", true).err(), Some(Errno::ENOENT));
+
+        /* Hard links are the same node, symlinks stay within the tree. */
+        assert!(Arc::ptr_eq(&root.resolve(b"dir/hard", true).unwrap(), &file));
+        assert!(Arc::ptr_eq(&root.resolve(b"dir/rel", true).unwrap(), &file));
+        assert!(Arc::ptr_eq(&root.resolve(b"/dir/./abs", true).unwrap(), &file));
+        assert!(matches!(root.resolve(b"dir/abs", false).unwrap().kind, Kind::Symlink(_)));
+        assert_eq!(root.resolve(b"dir/escape", true).err(), Some(Errno::ENOENT));
+        assert!(Arc::ptr_eq(&root.resolve(b"../../dir/rel", true).unwrap(), &file));
+        assert_eq!(root.resolve(b"dir/loop", true).err(), Some(Errno::ELOOP));
+        assert_eq!(root.resolve(b"dir/file.txt/x", true).err(), Some(Errno::ENOTDIR));
+        let implied = root.resolve(b"implied/sub", true).unwrap();
+        assert_eq!((implied.file_type(), implied.mode), (libc::S_IFDIR, 0o755));
+
+        let mut bad = archive();
+        bad[0] = b'X';
+        assert!(parse(&bad).unwrap_err().starts_with("bad header checksum"));
+        assert!(parse(&entry("../evil", b'0', b"", "")).is_err());
+    }
+}
@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
 use crate::insts::*;
 
 pub const TB_KICK_IN_JIT: i64 = 1_000;
@@ -7,27 +10,45 @@ pub struct TranslationBlock {
     pub start:      i64,
     pub exec_count: std::sync::atomic::AtomicI64,
     pub valid:      bool,
-    pub label:      Option<std::rc::Rc<str>>,
+    pub label:      Option<Arc<str>>,
     pub instrs:     Vec<(Inst, u8)>,
 
     pub jit_failed: bool,
     pub jit_fn: Option<extern "C" fn(regs: *mut u64, memory: *mut u8) -> u64>
 }
 
+/*
+ * The TB cache, shared by all harts. A TB never changes once it is in there, kick_in()
+ * replaces the ones it compiled (a hart still running the old version just finishes).
+ */
 pub struct JIT {
-    pub tbs: std::collections::HashMap<i64, TranslationBlock>,
-    pub buffer: Vec<(Inst, u8)>,
+    pub tbs: RwLock<HashMap<i64, Arc<TranslationBlock>>>,
+    /// Held while compiling, the other harts go on interpreting in the meantime.
+    compiling: Mutex<()>,
 }
 
 impl JIT {
     pub fn new() -> Self {
         Self {
-            tbs: std::collections::HashMap::with_capacity(1024),
-            buffer : Vec::with_capacity(32),
+            tbs: RwLock::new(HashMap::with_capacity(1024)),
+            compiling: Mutex::new(()),
         }
     }
 
-    pub unsafe fn kick_in(&mut self) {
+    pub fn get(&self, pc: i64) -> Option<Arc<TranslationBlock>> {
+        self.tbs.read().unwrap().get(&pc).cloned()
+    }
+
+    /* Two harts can translate the same TB at the same time, the last one wins. */
+    pub fn insert(&self, tb: TranslationBlock) {
+        self.tbs.write().unwrap().insert(tb.start, Arc::new(tb));
+    }
+
+    pub unsafe fn kick_in(&self) {
+        let Ok(_compiling) = self.compiling.try_lock() else {
+            return
+        };
+
         use std::fmt::Write;
         use gccjit::ToRValue;
 
@@ -51,11 +72,13 @@ impl JIT {
         let mut string_buf = String::new();
         // eprintln!("[simrv64i] JIT: kicking in...");
 
-        let mut jitted_tbs: Vec<(String, i64)> = Vec::new();
+        let mut jitted_tbs: Vec<(String, &TranslationBlock)> = Vec::new();
 
-        for tb in self.tbs.values_mut().filter(|tb|
+        let candidates = self.tbs.read().unwrap().values().filter(|tb|
                 !tb.jit_failed && tb.jit_fn.is_none() &&
-                tb.exec_count.load(std::sync::atomic::Ordering::Relaxed) > 100) {
+                tb.exec_count.load(std::sync::atomic::Ordering::Relaxed) > 100)
+            .cloned().collect::<Vec<_>>();
+        for tb in &candidates {
 
             // eprintln!("[simrv64i] JIT: TB candidate: {:#08x} (freq={})",
             //     tb.start, tb.exec_count.load(std::sync::atomic::Ordering::Relaxed));
@@ -66,7 +89,7 @@ impl JIT {
 
             string_buf.clear();
             write!(&mut string_buf, "jit_tb_{:08x}", tb.start).unwrap();
-            jitted_tbs.push((string_buf.clone(), tb.start));
+            jitted_tbs.push((string_buf.clone(), tb));
 
             /* The jit TB functions return the new PC and take as arguments:
              * - The register file
@@ -232,15 +255,24 @@ impl JIT {
         }
 
         /* After functions for TBs have been created, compile the module,
-         * get the function pointer, and store it in the TB. */
+         * get the function pointer, and store it in a copy of the TB. */
         let res = ctx.compile();
-        for (name, pc) in jitted_tbs {
-            let tb = self.tbs.get_mut(&pc).unwrap();
+        let mut tbs = self.tbs.write().unwrap();
+        for (name, tb) in jitted_tbs {
             let fnptr = res.get_function(name.as_str());
             assert!(!fnptr.is_null());
-            tb.jit_fn = Some(unsafe {
-                std::mem::transmute(fnptr as usize)
-            });
+            tbs.insert(tb.start, Arc::new(TranslationBlock {
+                start: tb.start,
+                exec_count: std::sync::atomic::AtomicI64::new(
+                    tb.exec_count.load(std::sync::atomic::Ordering::Relaxed)),
+                valid: tb.valid,
+                label: tb.label.clone(),
+                instrs: tb.instrs.clone(),
+                jit_failed: false,
+                jit_fn: Some(unsafe {
+                    std::mem::transmute(fnptr as usize)
+                })
+            }));
         }
 
         /* res must not be dropped! For whatever reason, if dropped, it unmaps
@@ -0,0 +1,250 @@
+/*
+ * Threads of the guest. Every clone(CLONE_THREAD) runs on a host thread of its own with
+ * its own CPU (hart state), over the memory, fd table, signal actions and TB cache of the
+ * thread that created it. Guest thread ids are the host ones, so that tgkill() and PI
+ * futexes of the guest just work.
+ */
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use syscalls::Errno;
+
+use crate::cpu::CPU;
+use crate::insts::Error;
+use crate::signals::{self, SigQueue};
+use crate::tbs::JIT;
+
+/* How the guest ended: Its exit status or an error, and the pc of the thread that ended it. */
+pub type Outcome = (Result<i32, Error>, i64);
+
+#[derive(Default)]
+struct State {
+    /// Thread id -> its signal queue, for all threads that did not exit yet.
+    threads: HashMap<i32, Arc<SigQueue>>,
+    /// The main thread: The first one, or the last one that did execve().
+    leader: i32,
+    /// What the main thread passed to exit(), the guest's exit status once all are gone.
+    leader_status: i32,
+    outcome: Option<Outcome>,
+}
+
+/* What the threads of a guest share, besides memory, fds and signal actions. */
+#[derive(Default)]
+pub struct Process {
+    state: Mutex<State>,
+    /// Notified when a thread exits, or the whole guest.
+    changed: Condvar,
+    /// Set with the outcome, all threads stop at their next TB boundary.
+    exiting: AtomicBool,
+    /// Thread in execve(), all others stop at their next TB boundary. 0 if there is none.
+    execing: AtomicI32,
+}
+
+impl Process {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /* The first thread registered is the main thread. */
+    pub fn register(&self, tid: i32, queue: Arc<SigQueue>) {
+        let mut state = self.state();
+        if state.threads.is_empty() {
+            state.leader = tid;
+        }
+        state.threads.insert(tid, queue);
+    }
+
+    /* The signal queue of thread `tid`, if that is one of the guest's. */
+    pub fn thread(&self, tid: i32) -> Option<Arc<SigQueue>> {
+        self.state().threads.get(&tid).cloned()
+    }
+
+    /* Checked by every thread after every TB. */
+    pub fn must_stop(&self, tid: i32) -> bool {
+        self.exiting.load(Ordering::Relaxed)
+            || ![0, tid].contains(&self.execing.load(Ordering::Relaxed))
+    }
+
+    /*
+     * exit_group(), or a thread died: The first outcome is the one that counts. The other
+     * threads are kicked out of blocking syscalls, so that they notice.
+     */
+    pub fn exit(&self, tid: i32, res: Result<i32, Error>, pc: i64) {
+        let mut state = self.state();
+        if state.outcome.is_none() {
+            state.outcome = Some((res, pc));
+        }
+        self.exiting.store(true, Ordering::Relaxed);
+        for other in state.threads.keys().filter(|other| **other != tid) {
+            signals::kick(*other);
+        }
+        self.changed.notify_all();
+    }
+
+    /* Thread `tid` did exit(). */
+    pub fn exit_thread(&self, tid: i32, status: i32) {
+        let mut state = self.state();
+        if tid == state.leader {
+            state.leader_status = status;
+        }
+        state.threads.remove(&tid);
+        self.changed.notify_all();
+    }
+
+    /* Thread `tid` was stopped, because the guest exits or another thread did execve(). */
+    pub fn remove(&self, tid: i32) {
+        self.state().threads.remove(&tid);
+        self.changed.notify_all();
+    }
+
+    /*
+     * execve(): Stop all other threads and wait until they are gone, `tid` becomes the
+     * main thread. Returns false if the guest exits or another thread is in execve().
+     */
+    pub fn de_thread(&self, tid: i32) -> bool {
+        let mut state = self.state();
+        if state.outcome.is_some() ||
+           self.execing.compare_exchange(0, tid, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+            return false
+        }
+        for other in state.threads.keys().filter(|other| **other != tid) {
+            signals::kick(*other);
+        }
+        while state.threads.len() > 1 && state.outcome.is_none() {
+            state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        self.execing.store(0, Ordering::Relaxed);
+        state.leader = tid;
+        state.outcome.is_none()
+    }
+
+    /*
+     * Once a thread is done (the main thread in CPU::run()), the guest lives on until all
+     * other threads did exit() as well, or one of them ended it. Returns how it ended.
+     */
+    pub fn wait(&self) -> Outcome {
+        let mut state = self.state();
+        while !state.threads.is_empty() && state.outcome.is_none() {
+            state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        let status = state.leader_status;
+        state.outcome.take().unwrap_or((Ok(status), 0))
+    }
+}
+
+/*
+ * Run `cpu`, set up by clone(), on a new host thread. Returns its thread id (that of
+ * the host thread), after it was registered, so that it can get signals right away.
+ * The tid is stored at the `set_tid` addresses (CLONE_*_SETTID) before the thread runs.
+ */
+pub fn spawn(mut cpu: CPU, set_tid: Vec<usize>) -> Result<i32, Errno> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::Builder::new()
+        .name("guest".to_string())
+        .spawn(move || {
+            cpu.tid = unsafe { libc::gettid() };
+            for addr in set_tid {
+                let _ = cpu.memory.store_u32(addr, cpu.tid as u32);
+            }
+            if let Some(strace) = &mut cpu.strace {
+                strace.set_thread(cpu.tid);
+            }
+            cpu.process.register(cpu.tid, cpu.signals.queue_handle());
+            let _ = tx.send(cpu.tid);
+            cpu.run_clone();
+        })
+        .map_err(|_| Errno::EAGAIN)?;
+    rx.recv().map_err(|_| Errno::EAGAIN)
+}
+
+/*
+ * In the child of a fork(): The thread that forked is the only one, in a Process of its
+ * own. So is the TB cache, threads that are gone now could have held its locks.
+ */
+pub fn forked(cpu: &mut CPU) {
+    cpu.tid = unsafe { libc::gettid() };
+    cpu.process = Arc::default();
+    cpu.process.register(cpu.tid, cpu.signals.queue_handle());
+    cpu.jit = Arc::new(JIT::new());
+    cpu.misaligned_accesses = Arc::default();
+    if let Some(strace) = &mut cpu.strace {
+        strace.set_thread(cpu.tid);
+    }
+}
+
+/*
+ * The end of a forked child that is not the simulator's main thread, but the one that
+ * forked: There is no main() that would report the outcome, so exit like it does.
+ */
+pub fn exit_process(outcome: Outcome) -> ! {
+    match outcome {
+        (Ok(status), _) => std::process::exit(status),
+        (Err(Error::Signal(sig)), _) => signals::exit_by_signal(sig),
+        (Err(e), pc) => {
+            eprintln!("[simrv64i]: error(pc={:#08x?}): {:?}", pc, e);
+            std::process::exit(1)
+        }
+    }
+}
+
+/*
+ * CLONE_CHILD_CLEARTID: What the kernel does when a thread exits, pthread_join() waits
+ * for it. The wake is not FUTEX_PRIVATE_FLAG, the kernel's is not either.
+ */
+pub fn clear_child_tid(cpu: &mut CPU) {
+    if cpu.clear_child_tid == 0 || cpu.memory.store_u32(cpu.clear_child_tid as usize, 0).is_err() {
+        return
+    }
+    let addr = cpu.memory.host_ptr().wrapping_add(cpu.clear_child_tid as usize);
+    unsafe { libc::syscall(libc::SYS_futex, addr, libc::FUTEX_WAKE, 1, 0, 0, 0) };
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::test::*;
+
+    fn lui(rd: u32, imm: u32) -> u32 { (imm << 12) | (rd << 7) | 0x37 }
+    fn lw(rd: u32, rs1: u32, imm: i32) -> u32 {
+        ((imm as u32) << 20) | (rs1 << 15) | (2 << 12) | (rd << 7) | 0x03
+    }
+    /* The .w variants of the A extension: AMOADD.W (0), LR.W (2), SC.W (3). */
+    fn amo(funct5: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+        (funct5 << 27) | (rs2 << 20) | (rs1 << 15) | (2 << 12) | (rd << 7) | 0x2f
+    }
+
+    /*
+     * The main thread adds 1 to a counter with an AMO, a thread adds 41 with LR/SC. The
+     * main thread waits for it like pthread_join(): Until the kernel (us) clears its tid.
+     */
+    #[test]
+    fn clone_and_join() {
+        let (zero, t0, a0, a1, a2, a3, a4, a7) = (0, 5, 10, 11, 12, 13, 14, 17);
+        let (beq, bne) = (0, 1);
+        let mut code = vec![
+            auipc(t0),
+            /* CLONE_VM|FS|FILES|SIGHAND|THREAD|PARENT_SETTID|CHILD_CLEARTID */
+            lui(a0, 0x311), addi(a0, a0, -0x100),
+            addi(a1, t0, 0x7f0), addi(a2, t0, 0x100), addi(a3, zero, 0), addi(a4, t0, 0x100),
+            addi(a7, zero, 220), ECALL,
+            branch(beq, a0, zero, 4 * 15),
+            addi(a0, t0, 0x108), addi(a1, zero, 1), amo(0, zero, a0, a1),
+            /* Loop until the tid at +0x100 is 0, with futex(FUTEX_WAIT). */
+            lw(a2, t0, 0x100), branch(beq, a2, zero, 4 * 7),
+            addi(a0, t0, 0x100), addi(a1, zero, 0), addi(a3, zero, 0), addi(a7, zero, 98), ECALL,
+            branch(beq, zero, zero, -4 * 7),
+            lw(a0, t0, 0x108), addi(a7, zero, 94), ECALL,
+        ];
+        assert_eq!(code.len(), 24);
+        code.extend([
+            addi(a0, t0, 0x108),
+            amo(2, a1, a0, zero), addi(a1, a1, 41), amo(3, a2, a0, a1),
+            branch(bne, a2, zero, -4 * 3),
+            addi(a0, zero, 0), addi(a7, zero, 93), ECALL,
+        ]);
+        assert_eq!(run(&code, |_| Vec::new()).1.ok(), Some(42));
+
+        /* exit() of the main thread (the last one) ends the guest too. */
+        code[22] = addi(a7, zero, 93);
+        assert_eq!(run(&code, |_| Vec::new()).1.ok(), Some(42));
+    }
+}
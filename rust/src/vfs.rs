@@ -0,0 +1,538 @@
+/*
+ * The guest's file system with `--mount`/`--mount-tar`: Host directories (read-only or
+ * read-write) and tar archives (read-only, in memory, see tar.rs) at guest paths, and
+ * nothing else. Without mounts there is no VFS, guest paths are host paths (sys.rs).
+ *
+ * Guest paths are made absolute and normalized lexically first, so `..` cannot leave
+ * a mount through its root. The mount with the longest matching mount point serves
+ * them. Symlinks in host mounts are resolved by the kernel (openat2() with
+ * RESOLVE_IN_ROOT), those in tar archives by Node::resolve(), both as if the root of
+ * the mount was `/`: Nothing outside of a mount can be reached through it, but absolute
+ * symlinks only work within their own mount.
+ * Unless something is mounted at `/`, the parents of the mount points are read-only
+ * directories of their own, and anything else does not exist.
+ */
+use std::ffi::{CStr, CString};
+use std::io::{Read, Seek, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use syscalls::{syscall, Errno, Sysno};
+
+use crate::tar::{self, Kind, Node};
+
+/* Flags of open() and the *at() syscalls of the guest (asm-generic). */
+pub const O_ACCMODE: usize = 0o3;
+pub const O_CREAT: usize = 0o100;
+pub const O_EXCL: usize = 0o200;
+pub const O_TRUNC: usize = 0o1000;
+pub const O_DIRECTORY: usize = 0o200000;
+pub const O_NOFOLLOW: usize = 0o400000;
+pub const O_CLOEXEC: usize = 0o2000000;
+pub const O_PATH: usize = 0o10000000;
+pub const O_TMPFILE: usize = 0o20200000;
+pub const AT_SYMLINK_NOFOLLOW: usize = 0x100;
+pub const AT_EACCESS: usize = 0x200;
+pub const AT_EMPTY_PATH: usize = 0x1000;
+
+/* sizeof(sun_path) of struct sockaddr_un, including the NUL. */
+const SUN_PATH_SIZE: usize = 108;
+
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+const RESOLVE_IN_ROOT: u64 = 0x10;
+
+/* struct open_how of openat2(). */
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Source {
+    Host { dir: PathBuf, writable: bool },
+    Tar(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct MountSpec {
+    /// Normalized guest path of the mount point.
+    pub guest: Vec<u8>,
+    pub source: Source,
+}
+
+fn mount_point(s: &str) -> Result<Vec<u8>, String> {
+    match s.starts_with('/') {
+        true => Ok(normalize(b"/", s.as_bytes())),
+        false => Err(format!("mount point {:?} is not an absolute path", s))
+    }
+}
+
+/* `GUEST=DIR[:ro|:rw]`, read-only unless `:rw` is given. */
+pub fn parse_mount(s: &str) -> Result<MountSpec, String> {
+    let (guest, dir) = s.split_once('=').ok_or_else(|| format!("invalid mount {:?}", s))?;
+    let (dir, writable) = match dir.rsplit_once(':') {
+        Some((dir, "ro")) => (dir, false),
+        Some((dir, "rw")) => (dir, true),
+        _ => (dir, false)
+    };
+    Ok(MountSpec { guest: mount_point(guest)?, source: Source::Host { dir: dir.into(), writable } })
+}
+
+/* `GUEST=ARCHIVE` */
+pub fn parse_mount_tar(s: &str) -> Result<MountSpec, String> {
+    let (guest, archive) = s.split_once('=').ok_or_else(|| format!("invalid mount {:?}", s))?;
+    Ok(MountSpec { guest: mount_point(guest)?, source: Source::Tar(archive.into()) })
+}
+
+/* `path` (relative to the absolute `base` unless absolute itself) without `.`, `..`
+ * and repeated or trailing slashes. */
+pub fn normalize(base: &[u8], path: &[u8]) -> Vec<u8> {
+    let base = if path.starts_with(b"/") { &b""[..] } else { base };
+    let mut components: Vec<&[u8]> = Vec::new();
+    for component in base.split(|c| *c == b'/').chain(path.split(|c| *c == b'/')) {
+        match component {
+            b"" | b"." => {},
+            b".." => { components.pop(); },
+            component => components.push(component)
+        }
+    }
+    match components.is_empty() {
+        true => b"/".to_vec(),
+        false => components.iter().flat_map(|c| [&b"/"[..], c]).flatten().copied().collect()
+    }
+}
+
+enum Backend {
+    /// O_PATH fd of the host directory.
+    Host { root: OwnedFd, writable: bool },
+    Tar(Arc<Node>),
+}
+
+struct Mount {
+    guest: Vec<u8>,
+    backend: Backend,
+}
+
+/* What the fd table keeps of a file opened through the VFS, besides the host fd. */
+#[derive(Debug)]
+pub struct OpenFile {
+    /// Guest path, for *at() syscalls relative to the fd and for fchdir().
+    pub path: Vec<u8>,
+    /// Files from tar archives, their host fd is a memfd with the contents (if any).
+    pub node: Option<Arc<Node>>,
+}
+
+pub struct Vfs {
+    /// Longest mount point first, so that the first match is the right one.
+    mounts: Vec<Mount>,
+    /// Guest path of the working directory, shared by all threads (CLONE_FS).
+    cwd: Mutex<Vec<u8>>,
+}
+
+pub fn host_errno(e: std::io::Error) -> Errno {
+    Errno::new(e.raw_os_error().unwrap_or(libc::EIO))
+}
+
+/* Open `rel` below `root`, the host fd is O_CLOEXEC like all of the simulator's. */
+fn openat2(root: &OwnedFd, rel: &[u8], flags: usize, mode: usize) -> Result<OwnedFd, Errno> {
+    let rel = CString::new(if rel.is_empty() { b"." } else { rel }).map_err(|_| Errno::EINVAL)?;
+    let creates = flags & O_CREAT != 0 || flags & O_TMPFILE == O_TMPFILE;
+    let how = OpenHow {
+        flags: (flags | O_CLOEXEC) as u64,
+        mode: if creates { (mode & 0o7777) as u64 } else { 0 },
+        resolve: RESOLVE_IN_ROOT | RESOLVE_NO_MAGICLINKS,
+    };
+    let fd = unsafe {
+        syscall!(Sysno::openat2, root.as_raw_fd(), rel.as_ptr(), &how as *const OpenHow,
+                 std::mem::size_of::<OpenHow>())
+    }?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/* A host fd for a node: A sealed memfd with the contents of files (unless opened with
+ * O_PATH), an empty one otherwise. For directories, its offset is the getdents64() position. */
+fn memfd(node: &Node, flags: usize) -> Result<OwnedFd, Errno> {
+    let memfd_flags = libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING;
+    let fd = unsafe { libc::memfd_create(c"tar".as_ptr(), memfd_flags) };
+    if fd < 0 {
+        return Err(host_errno(std::io::Error::last_os_error()))
+    }
+    let mut file = std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    if let (Kind::File(data), 0) = (&node.kind, flags & O_PATH) {
+        file.write_all(data).and_then(|_| file.rewind()).map_err(host_errno)?;
+    }
+    let seals = libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } != 0 {
+        return Err(host_errno(std::io::Error::last_os_error()))
+    }
+    Ok(file.into())
+}
+
+/* The open() flag for the AT_SYMLINK_NOFOLLOW of `at_flags`. */
+pub fn nofollow(at_flags: usize) -> usize {
+    if at_flags & AT_SYMLINK_NOFOLLOW != 0 { O_NOFOLLOW } else { 0 }
+}
+
+/* Whether open() `flags` modify the file (or create one). */
+fn writes(flags: usize) -> bool {
+    (flags & O_PATH == 0 && (flags & O_ACCMODE != 0 || flags & O_TRUNC != 0))
+        || flags & O_TMPFILE == O_TMPFILE
+}
+
+/* open() of a node of a tar archive, which can never be written. */
+fn open_node(root: &Arc<Node>, rel: &[u8], flags: usize) -> Result<Arc<Node>, Errno> {
+    let node = match root.resolve(rel, flags & O_NOFOLLOW == 0) {
+        Err(Errno::ENOENT) if flags & O_CREAT != 0 => return Err(Errno::EROFS),
+        res => res?
+    };
+    match &node.kind {
+        _ if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL => Err(Errno::EEXIST),
+        Kind::Symlink(_) if flags & O_PATH == 0 => Err(Errno::ELOOP),
+        Kind::Dir(_) if writes(flags) => Err(Errno::EISDIR),
+        Kind::Dir(_) => Ok(node.clone()),
+        _ if flags & O_DIRECTORY != 0 => Err(Errno::ENOTDIR),
+        _ if writes(flags) => Err(Errno::EROFS),
+        _ => Ok(node.clone())
+    }
+}
+
+impl Vfs {
+    pub fn new(specs: &[MountSpec]) -> Result<Vfs, String> {
+        let mut mounts: Vec<Mount> = Vec::new();
+        for spec in specs {
+            if mounts.iter().any(|mount| mount.guest == spec.guest) {
+                return Err(format!("{:?} is mounted twice", String::from_utf8_lossy(&spec.guest)))
+            }
+            let backend = match &spec.source {
+                Source::Host { dir, writable } => {
+                    let path = CString::new(dir.as_os_str().as_bytes())
+                        .map_err(|_| format!("invalid path {:?}", dir))?;
+                    let flags = O_PATH | O_DIRECTORY | O_CLOEXEC;
+                    let fd = unsafe {
+                        syscall!(Sysno::openat, libc::AT_FDCWD, path.as_ptr(), flags)
+                    }.map_err(|e| format!("cannot mount {:?}: {}", dir,
+                            std::io::Error::from_raw_os_error(e.into_raw())))?;
+                    let root = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+                    Backend::Host { root, writable: *writable }
+                },
+                Source::Tar(archive) => {
+                    let bytes = std::fs::read(archive)
+                        .map_err(|e| format!("cannot read {:?}: {}", archive, e))?;
+                    Backend::Tar(tar::parse(&bytes).map_err(|e| format!("{:?}: {}", archive, e))?)
+                }
+            };
+            mounts.push(Mount { guest: spec.guest.clone(), backend });
+        }
+        if !mounts.iter().any(|mount| mount.guest == b"/") {
+            let mut skeleton = tar::Builder::default();
+            for mount in &mounts {
+                skeleton.mkdir(&mount.guest)?;
+            }
+            mounts.push(Mount { guest: b"/".to_vec(), backend: Backend::Tar(skeleton.finish()) });
+        }
+        mounts.sort_by_key(|mount| std::cmp::Reverse(mount.guest.len()));
+        Ok(Vfs { mounts, cwd: Mutex::new(b"/".to_vec()) })
+    }
+
+    /* The mount of a normalized guest path, and the path relative to its root. */
+    fn mount<'a>(&self, path: &'a [u8]) -> (&Mount, &'a [u8]) {
+        self.mounts.iter().find_map(|mount| match path.strip_prefix(mount.guest.as_slice())? {
+            rel if mount.guest == b"/" => Some((mount, rel)),
+            [] => Some((mount, &b""[..])),
+            [b'/', rel @ ..] => Some((mount, rel)),
+            _ => None
+        }).expect("/ is always mounted")
+    }
+
+    pub fn cwd(&self) -> Vec<u8> {
+        self.cwd.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn chdir(&self, path: &[u8]) -> Result<(), Errno> {
+        self.open(path, O_PATH | O_DIRECTORY, 0)?;
+        *self.cwd.lock().unwrap_or_else(|e| e.into_inner()) = path.to_vec();
+        Ok(())
+    }
+
+    pub fn open(&self, path: &[u8], flags: usize, mode: usize)
+            -> Result<(OwnedFd, OpenFile), Errno> {
+        let (file, node) = match self.mount(path) {
+            (Mount { backend: Backend::Host { root, writable: true }, .. }, rel) =>
+                (openat2(root, rel, flags, mode)?, None),
+            /* O_CREAT of a file that exists is fine, it is not created. */
+            (Mount { backend: Backend::Host { root, writable: false }, .. }, rel) => {
+                if writes(flags) {
+                    return Err(Errno::EROFS)
+                }
+                let file = match openat2(root, rel, flags & !(O_CREAT | O_EXCL), 0) {
+                    Err(Errno::ENOENT) if flags & O_CREAT != 0 => return Err(Errno::EROFS),
+                    Ok(_) if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL =>
+                        return Err(Errno::EEXIST),
+                    res => res?
+                };
+                (file, None)
+            },
+            (Mount { backend: Backend::Tar(root), .. }, rel) => {
+                let node = open_node(root, rel, flags)?;
+                (memfd(&node, flags)?, Some(node))
+            }
+        };
+        Ok((file, OpenFile { path: path.to_vec(), node }))
+    }
+
+    /* All of a (regular) file, for the loader. */
+    pub fn read(&self, path: &[u8]) -> Result<Vec<u8>, Errno> {
+        let (file, _) = self.open(path, 0, 0)?;
+        let mut contents = Vec::new();
+        std::fs::File::from(file).read_to_end(&mut contents).map_err(host_errno)?;
+        Ok(contents)
+    }
+
+    /* faccessat2() without AT_EMPTY_PATH (the caller handles it). */
+    pub fn access(&self, path: &[u8], mode: usize, flags: usize) -> Result<(), Errno> {
+        let (file, opened) = self.open(path, O_PATH | nofollow(flags), 0)?;
+        match (&self.mount(path).0.backend, opened.node) {
+            (_, Some(_)) if mode & libc::W_OK as usize != 0 => Err(Errno::EROFS),
+            (_, Some(node)) => match mode & libc::X_OK as usize != 0 && node.mode & 0o111 == 0 {
+                true => Err(Errno::EACCES),
+                false => Ok(())
+            },
+            (Backend::Host { writable: false, .. }, _) if mode & libc::W_OK as usize != 0 =>
+                Err(Errno::EROFS),
+            _ => unsafe {
+                syscall!(Sysno::faccessat2, file.as_raw_fd(), c"".as_ptr(), mode,
+                         (flags & AT_EACCESS) | AT_EMPTY_PATH)
+            }.map(|_| ())
+        }
+    }
+
+    pub fn readlink(&self, path: &[u8]) -> Result<Vec<u8>, Errno> {
+        let (file, opened) = self.open(path, O_PATH | O_NOFOLLOW, 0)?;
+        if let Some(node) = opened.node {
+            return match &node.kind {
+                Kind::Symlink(target) => Ok(target.clone()),
+                _ => Err(Errno::EINVAL)
+            }
+        }
+        let mut target = vec![0u8; libc::PATH_MAX as usize];
+        let len = unsafe {
+            syscall!(Sysno::readlinkat, file.as_raw_fd(), c"".as_ptr(), target.as_mut_ptr(),
+                     target.len())
+        }?;
+        target.truncate(len);
+        Ok(target)
+    }
+
+    /*
+     * The directory a path to create or remove is in (as O_PATH fd) and its last component.
+     * Only writable host mounts can be changed, and not their mount points.
+     */
+    fn parent(&self, path: &[u8]) -> Result<(OwnedFd, CString, &Mount), Errno> {
+        let (mount, rel) = self.mount(path);
+        let Backend::Host { root, writable: true } = &mount.backend else {
+            return Err(Errno::EROFS)
+        };
+        if rel.is_empty() {
+            return Err(Errno::EBUSY)
+        }
+        let (dir, name) = match rel.iter().rposition(|c| *c == b'/') {
+            Some(pos) => (&rel[..pos], &rel[pos + 1..]),
+            None => (&b""[..], rel)
+        };
+        let dir = openat2(root, dir, O_PATH | O_DIRECTORY, 0)?;
+        Ok((dir, CString::new(name).map_err(|_| Errno::EINVAL)?, mount))
+    }
+
+    pub fn mkdir(&self, path: &[u8], mode: usize) -> Result<(), Errno> {
+        let (dir, name, _) = self.parent(path)
+            .map_err(|e| if e == Errno::EBUSY { Errno::EEXIST } else { e })?;
+        unsafe { syscall!(Sysno::mkdirat, dir.as_raw_fd(), name.as_ptr(), mode) }.map(|_| ())
+    }
+
+    pub fn unlink(&self, path: &[u8], flags: usize) -> Result<(), Errno> {
+        let (dir, name, _) = self.parent(path)?;
+        unsafe { syscall!(Sysno::unlinkat, dir.as_raw_fd(), name.as_ptr(), flags) }.map(|_| ())
+    }
+
+    /* The target is stored as it is, see above for how absolute ones are resolved. */
+    pub fn symlink(&self, target: &CStr, path: &[u8]) -> Result<(), Errno> {
+        let (dir, name, _) = self.parent(path)
+            .map_err(|e| if e == Errno::EBUSY { Errno::EEXIST } else { e })?;
+        unsafe { syscall!(Sysno::symlinkat, target.as_ptr(), dir.as_raw_fd(), name.as_ptr()) }
+            .map(|_| ())
+    }
+
+    /*
+     * The host sun_path for the guest path of a Unix socket, of bind() (`bind` set) or
+     * connect(). Sockets can only be in writable host mounts, anywhere else they could
+     * lead to any service of the host. The host path goes through /proc/self/fd of the
+     * directory the socket is created in or of the socket itself, opened like by open(),
+     * so the returned fd has to stay open until the syscall is done.
+     */
+    pub fn socket_path(&self, path: &[u8], bind: bool) -> Result<(OwnedFd, Vec<u8>), Errno> {
+        let (fd, name) = match bind {
+            true => {
+                let (dir, name, _) = self.parent(path)
+                    .map_err(|e| if e == Errno::EBUSY { Errno::EADDRINUSE } else { e })?;
+                (dir, Some(name))
+            },
+            false => match self.mount(path) {
+                (Mount { backend: Backend::Host { root, writable: true }, .. }, rel) =>
+                    (openat2(root, rel, O_PATH, 0)?, None),
+                _ => return Err(Errno::EACCES)
+            }
+        };
+        let mut host = format!("/proc/self/fd/{}", fd.as_raw_fd()).into_bytes();
+        if let Some(name) = name {
+            host.push(b'/');
+            host.extend_from_slice(name.to_bytes());
+        }
+        if host.len() >= SUN_PATH_SIZE {
+            return Err(Errno::ENAMETOOLONG)
+        }
+        Ok((fd, host))
+    }
+
+    pub fn rename(&self, old: &[u8], new: &[u8], flags: usize) -> Result<(), Errno> {
+        if !std::ptr::eq(self.mount(old).0, self.mount(new).0) {
+            return Err(Errno::EXDEV)
+        }
+        let (old_dir, old_name, _) = self.parent(old)?;
+        let (new_dir, new_name, _) = self.parent(new)?;
+        unsafe {
+            syscall!(Sysno::renameat2, old_dir.as_raw_fd(), old_name.as_ptr(), new_dir.as_raw_fd(),
+                     new_name.as_ptr(), flags)
+        }.map(|_| ())
+    }
+}
+
+/* d_type of struct linux_dirent64 */
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+/*
+ * getdents64() of a directory from a tar archive: The entries from position `pos` on
+ * (`.` and `..` first) that fit into `buf`. Returns the bytes used and the next position.
+ */
+pub fn dirents(node: &Node, pos: usize, buf: &mut [u8]) -> Result<(usize, usize), Errno> {
+    let Kind::Dir(entries) = &node.kind else {
+        return Err(Errno::ENOTDIR)
+    };
+    let dots = [(&b"."[..], node.ino, DT_DIR), (&b".."[..], node.ino, DT_DIR)];
+    let all = dots.into_iter().chain(entries.iter().map(|(name, child)| {
+        let kind = match child.kind {
+            Kind::File(_) => DT_REG,
+            Kind::Dir(_) => DT_DIR,
+            Kind::Symlink(_) => DT_LNK,
+        };
+        (name.as_slice(), child.ino, kind)
+    }));
+    let (mut len, mut next) = (0, pos);
+    for (name, ino, kind) in all.skip(pos) {
+        let reclen = (19 + name.len() + 1).next_multiple_of(8);
+        let Some(dirent) = buf.get_mut(len..len + reclen) else {
+            break
+        };
+        dirent.fill(0);
+        dirent[0..8].copy_from_slice(&ino.to_le_bytes());
+        dirent[8..16].copy_from_slice(&(next as i64 + 1).to_le_bytes());
+        dirent[16..18].copy_from_slice(&(reclen as u16).to_le_bytes());
+        dirent[18] = kind;
+        dirent[19..19 + name.len()].copy_from_slice(name);
+        (len, next) = (len + reclen, next + 1);
+    }
+    if len == 0 && next < 2 + entries.len() {
+        return Err(Errno::EINVAL)
+    }
+    Ok((len, next))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn host_mount(guest: &str, dir: &std::path::Path, writable: bool) -> MountSpec {
+        let source = Source::Host { dir: dir.into(), writable };
+        MountSpec { guest: guest.as_bytes().to_vec(), source }
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(normalize(b"/", b"a//b/./c/"), b"/a/b/c");
+        assert_eq!(normalize(b"/work", b"../../etc/passwd"), b"/etc/passwd");
+        assert_eq!(normalize(b"/work", b"/"), b"/");
+        assert_eq!(normalize(b"/work/dir", b".."), b"/work");
+        assert_eq!(parse_mount("/work/=/tmp/x:rw").unwrap().guest, b"/work");
+        assert!(matches!(parse_mount("/data=/srv/data").unwrap().source,
+            Source::Host { writable: false, .. }));
+        assert!(parse_mount("work=/tmp").is_err());
+        assert!(parse_mount_tar("/lib").is_err());
+    }
+
+    #[test]
+    fn mounts() {
+        let dir = std::env::temp_dir().join(format!("simrv64i-vfs-{}", std::process::id()));
+        let (ro, rw) = (dir.join("ro"), dir.join("rw"));
+        std::fs::create_dir_all(&ro).unwrap();
+        std::fs::create_dir_all(&rw).unwrap();
+        std::fs::write(ro.join("input"), "secret input\n").unwrap();
+        std::os::unix::fs::symlink("/input", rw.join("abs")).unwrap();
+        std::os::unix::fs::symlink("../../../../ro/input", rw.join("escape")).unwrap();
+        let archive = dir.join("tree.tar");
+        std::fs::write(&archive, tar::test::archive()).unwrap();
+
+        let vfs = Vfs::new(&[
+            host_mount("/data", &ro, false),
+            host_mount("/work", &rw, true),
+            MountSpec { guest: b"/opt/tree".to_vec(), source: Source::Tar(archive) },
+        ]).unwrap();
+        let cat = |path: &[u8]| vfs.read(path);
+        assert_eq!(cat(b"/data/input").unwrap(), b"secret input\n");
+        assert_eq!(cat(b"/opt/tree/dir/rel").unwrap(), b"hello, tar\n");
+        assert_eq!(vfs.readlink(b"/opt/tree/dir/abs").unwrap(), b"/dir/file.txt");
+
+        /* Nothing outside of the mounts, not even through symlinks. */
+        assert_eq!(cat(b"/etc/passwd").err(), Some(Errno::ENOENT));
+        assert_eq!(cat(b"/work/abs").err(), Some(Errno::ENOENT));
+        assert_eq!(cat(b"/work/escape").err(), Some(Errno::ENOENT));
+        assert!(vfs.open(b"/", O_DIRECTORY, 0).unwrap().1.node.is_some());
+        assert!(vfs.open(b"/opt", O_DIRECTORY, 0).is_ok());
+
+        /* Read-only mounts and archives. */
+        assert_eq!(vfs.open(b"/data/input", 1, 0).err(), Some(Errno::EROFS));
+        assert_eq!(vfs.open(b"/data/new", O_CREAT | 1, 0o644).err(), Some(Errno::EROFS));
+        assert!(vfs.open(b"/data/input", O_CREAT, 0o644).is_ok());
+        assert_eq!(vfs.open(b"/opt/tree/dir/file.txt", 2, 0).err(), Some(Errno::EROFS));
+        assert_eq!(vfs.mkdir(b"/data/dir", 0o755).err(), Some(Errno::EROFS));
+        assert_eq!(vfs.unlink(b"/opt/tree/dir/file.txt", 0).err(), Some(Errno::EROFS));
+        assert_eq!(vfs.access(b"/data/input", libc::W_OK as usize, 0).err(), Some(Errno::EROFS));
+        assert_eq!(vfs.access(b"/opt/tree/dir/file.txt", libc::X_OK as usize, 0).err(),
+            Some(Errno::EACCES));
+        assert_eq!(vfs.unlink(b"/work", 0).err(), Some(Errno::EBUSY));
+
+        /* Writable mounts. */
+        let (file, _) = vfs.open(b"/work/out", O_CREAT | 1, 0o644).unwrap();
+        std::fs::File::from(file).write_all(b"result").unwrap();
+        assert_eq!(std::fs::read(rw.join("out")).unwrap(), b"result");
+        assert_eq!(vfs.rename(b"/work/out", b"/data/out", 0).err(), Some(Errno::EXDEV));
+        vfs.mkdir(b"/work/sub", 0o755).unwrap();
+        vfs.rename(b"/work/out", b"/work/sub/out", 0).unwrap();
+        vfs.chdir(b"/work/sub").unwrap();
+        assert_eq!(vfs.cwd(), b"/work/sub");
+        assert_eq!(vfs.chdir(b"/work/sub/out").err(), Some(Errno::ENOTDIR));
+        vfs.unlink(b"/work/sub/out", 0).unwrap();
+
+        let (_, opened) = vfs.open(b"/opt/tree/dir", O_DIRECTORY, 0).unwrap();
+        let mut buf = [0u8; 64];
+        let (len, next) = dirents(opened.node.as_ref().unwrap(), 0, &mut buf).unwrap();
+        assert_eq!((len, next), (48, 2));
+        assert_eq!(&buf[24 + 19..24 + 21], b"..");
+        assert_eq!(dirents(opened.node.as_ref().unwrap(), 2, &mut buf[..16]).err(),
+            Some(Errno::EINVAL));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ * Bookkeeping for the guest's memory mappings (mmap() and friends). The permissions
+ * that are actually checked are in Memory, this only remembers what was mapped how,
+ * so that munmap()/mprotect()/mremap() on parts of a mapping and the maps dump work.
+ */
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub prot: u8,
+    /// MAP_SHARED (only matters once there is more than one process).
+    pub shared: bool,
+    /// Host path of the mapped file, None for anonymous mappings.
+    pub name: Option<String>,
+    /// Offset into the file of `start`.
+    pub offset: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct VmaList {
+    vmas: BTreeMap<usize, Vma>,
+}
+
+impl VmaList {
+    /* The caller has to make sure there is nothing at vma.start..vma.end yet. */
+    pub fn insert(&mut self, vma: Vma) {
+        debug_assert!(!self.overlaps(vma.start, vma.end));
+        self.vmas.insert(vma.start, vma);
+    }
+
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        self.vmas.range(..=addr).next_back().map(|(_, vma)| vma).filter(|vma| addr < vma.end)
+    }
+
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.vmas.range(..end).next_back().is_some_and(|(_, vma)| vma.end > start)
+    }
+
+    /* Split the VMA containing `addr` (if any) so that one starts exactly at `addr`. */
+    fn split_at(&mut self, addr: usize) {
+        let Some(vma) = self.find(addr).filter(|vma| vma.start != addr).cloned() else {
+            return
+        };
+        let upper = Vma {
+            start: addr,
+            offset: vma.offset + (addr - vma.start) as u64,
+            ..vma.clone()
+        };
+        self.vmas.get_mut(&vma.start).unwrap().end = addr;
+        self.vmas.insert(addr, upper);
+    }
+
+    /* Remove everything in start..end, VMAs reaching over the borders are split. */
+    pub fn remove(&mut self, start: usize, end: usize) {
+        self.split_at(start);
+        self.split_at(end);
+        let keys = self.vmas.range(start..end).map(|(k, _)| *k).collect::<Vec<_>>();
+        for key in keys {
+            self.vmas.remove(&key);
+        }
+    }
+
+    pub fn protect(&mut self, start: usize, end: usize, prot: u8) {
+        self.split_at(start);
+        self.split_at(end);
+        for (_, vma) in self.vmas.range_mut(start..end) {
+            vma.prot = prot;
+        }
+    }
+
+    /* Grow or shrink the VMA at `start` (which must exist) in place. */
+    pub fn resize(&mut self, start: usize, end: usize) {
+        self.vmas.get_mut(&start).unwrap().end = end;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn vma(start: usize, end: usize, prot: u8) -> Vma {
+        Vma { start, end, prot, shared: false, name: None, offset: 0 }
+    }
+
+    #[test]
+    fn split() {
+        let mut vmas = VmaList::default();
+        vmas.insert(vma(0x1000, 0x5000, 0b011));
+        vmas.protect(0x2000, 0x3000, 0b001);
+        vmas.remove(0x4000, 0x6000);
+        let ranges = vmas.vmas.values().map(|v| (v.start, v.end, v.prot, v.offset)).collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(0x1000, 0x2000, 0b011, 0),
+                                (0x2000, 0x3000, 0b001, 0x1000),
+                                (0x3000, 0x4000, 0b011, 0x2000)]);
+        assert!(vmas.overlaps(0x3fff, 0x5000));
+        assert!(!vmas.overlaps(0x4000, 0x5000));
+        assert_eq!(vmas.find(0x2fff).map(|v| v.start), Some(0x2000));
+        assert!(vmas.find(0x4000).is_none());
+    }
+}